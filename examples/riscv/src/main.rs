@@ -1,6 +1,8 @@
 #![no_std]
 #![no_main]
 #![deny(warnings)]
+// the interactive demos are replaced by the end-to-end tests
+#![cfg_attr(feature = "qemu-test", allow(dead_code))]
 
 #[macro_use]
 extern crate alloc;
@@ -14,6 +16,8 @@ use device_tree::{DeviceTree, Node};
 use log::LevelFilter;
 use virtio_drivers::*;
 
+#[cfg(feature = "qemu-test")]
+mod qemu_test;
 mod virtio_impl;
 
 #[no_mangle]
@@ -21,6 +25,8 @@ extern "C" fn main(_hartid: usize, device_tree_paddr: usize) {
     log::set_max_level(LevelFilter::Info);
     init_dt(device_tree_paddr);
     info!("test end");
+    #[cfg(feature = "qemu-test")]
+    qemu_test::exit();
 }
 
 fn init_dt(dtb: usize) {
@@ -62,6 +68,13 @@ fn virtio_probe(node: &Node) {
             header.vendor_id()
         );
         info!("Device tree node {:?}", node);
+        #[cfg(feature = "qemu-test")]
+        match header.device_type() {
+            DeviceType::Block => qemu_test::blk(header),
+            DeviceType::Network => qemu_test::net(header),
+            t => warn!("Unrecognized virtio device: {:?}", t),
+        }
+        #[cfg(not(feature = "qemu-test"))]
         match header.device_type() {
             DeviceType::Block => virtio_blk(header),
             DeviceType::GPU => virtio_gpu(header),
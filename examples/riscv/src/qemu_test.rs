@@ -0,0 +1,127 @@
+//! End-to-end tests against the devices of `make test`.
+//!
+//! Each test checks the driver against a real QEMU device and records the
+//! result. QEMU is then stopped through the test finisher of the `virt`
+//! machine, so that its exit status tells whether every test passed.
+
+use core::sync::atomic::*;
+use virtio_drivers::{Error, VirtIOBlk, VirtIOHeader, VirtIONet};
+
+/// The test finisher of the QEMU `virt` machine.
+const FINISHER: usize = 0x10_0000;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_FAIL: u32 = 0x3333;
+
+/// The number of blocks written and read back.
+const TEST_BLOCKS: usize = 32;
+
+/// The addresses of QEMU user-mode networking.
+const GUEST_IP: [u8; 4] = [10, 0, 2, 15];
+const GATEWAY_IP: [u8; 4] = [10, 0, 2, 2];
+
+/// Bits of the tests which have passed.
+static PASSED: AtomicU32 = AtomicU32::new(0);
+static FAILED: AtomicBool = AtomicBool::new(false);
+
+const BLK: u32 = 1 << 0;
+const NET: u32 = 1 << 1;
+const ALL: u32 = BLK | NET;
+
+/// Test the block device: write blocks and read them back.
+pub fn blk(header: &'static mut VirtIOHeader) {
+    record(BLK, "virtio-blk", test_blk(header));
+}
+
+fn test_blk(header: &'static mut VirtIOHeader) -> Result<(), &'static str> {
+    let mut blk = VirtIOBlk::new(header).map_err(|_| "failed to create driver")?;
+    let mut input = [0u8; 512];
+    let mut output = [0u8; 512];
+    for i in 0..TEST_BLOCKS {
+        for (j, x) in input.iter_mut().enumerate() {
+            *x = (i + j) as u8;
+        }
+        blk.write_block(i, &input).map_err(|_| "failed to write")?;
+    }
+    for i in 0..TEST_BLOCKS {
+        blk.read_block(i, &mut output)
+            .map_err(|_| "failed to read")?;
+        if output.iter().enumerate().any(|(j, &x)| x != (i + j) as u8) {
+            return Err("read data differs from written data");
+        }
+    }
+    if blk.read_block(0, &mut output[..256]) != Err(Error::InvalidParam) {
+        return Err("short buffer accepted");
+    }
+    Ok(())
+}
+
+/// Test the network device: resolve the address of the gateway of QEMU
+/// user-mode networking with ARP.
+pub fn net(header: &'static mut VirtIOHeader) {
+    record(NET, "virtio-net", test_net(header));
+}
+
+fn test_net(header: &'static mut VirtIOHeader) -> Result<(), &'static str> {
+    let mut net = VirtIONet::new(header).map_err(|_| "failed to create driver")?;
+    let mac = net.mac();
+
+    let mut request = [0u8; 42];
+    // Ethernet header
+    request[0..6].copy_from_slice(&[0xff; 6]);
+    request[6..12].copy_from_slice(&mac);
+    request[12..14].copy_from_slice(&[0x08, 0x06]);
+    // ARP request for the gateway over Ethernet and IPv4
+    request[14..22].copy_from_slice(&[0, 1, 0x08, 0x00, 6, 4, 0, 1]);
+    request[22..28].copy_from_slice(&mac);
+    request[28..32].copy_from_slice(&GUEST_IP);
+    request[38..42].copy_from_slice(&GATEWAY_IP);
+    net.send(&request).map_err(|_| "failed to send")?;
+
+    // skip unrelated packets, e.g. IPv6 router advertisements
+    let mut buf = [0u8; 1514];
+    for _ in 0..16 {
+        let len = net.recv(&mut buf).map_err(|_| "failed to receive")?;
+        let reply = &buf[..len];
+        if len >= 42
+            && reply[0..6] == mac
+            && reply[12..14] == [0x08, 0x06]
+            && reply[20..22] == [0, 2]
+            && reply[28..32] == GATEWAY_IP
+        {
+            info!("gateway {:x?} is at {:x?}", GATEWAY_IP, &reply[22..28]);
+            return Ok(());
+        }
+    }
+    Err("no ARP reply from the gateway")
+}
+
+fn record(test: u32, name: &str, result: Result<(), &'static str>) {
+    match result {
+        Ok(()) => {
+            info!("{} test passed", name);
+            PASSED.fetch_or(test, Ordering::SeqCst);
+        }
+        Err(err) => {
+            error!("{} test failed: {}", name, err);
+            FAILED.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Stop QEMU, with a failure status if a test failed or a device was not
+/// found.
+pub fn exit() -> ! {
+    let passed = PASSED.load(Ordering::SeqCst);
+    let value = if FAILED.load(Ordering::SeqCst) {
+        (1 << 16) | FINISHER_FAIL
+    } else if passed != ALL {
+        error!("missing devices, tests passed: {:#b}", passed);
+        (2 << 16) | FINISHER_FAIL
+    } else {
+        FINISHER_PASS
+    };
+    unsafe { (FINISHER as *mut u32).write_volatile(value) };
+    loop {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use virtio_drivers::testing::{destroy_fake_device, fake_device};
+use virtio_drivers::{DeviceType, VirtIOGpu};
+use virtio_drivers_fuzz::FuzzDevice;
+
+// Feed arbitrary control responses to the GPU driver.
+fuzz_target!(|data: &[u8]| {
+    let config = [0; 16];
+    let header = fake_device(FuzzDevice::new(DeviceType::GPU, &config, data));
+    let header_ptr = header as *mut _;
+    if let Ok(mut gpu) = VirtIOGpu::new(header) {
+        if gpu.setup_framebuffer().is_ok() {
+            let _ = gpu.flush();
+        }
+    }
+    unsafe { destroy_fake_device(header_ptr) };
+});
@@ -0,0 +1,20 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use virtio_drivers::testing::{destroy_fake_device, fake_device};
+use virtio_drivers::{DeviceType, VirtIONet};
+use virtio_drivers_fuzz::FuzzDevice;
+
+// Feed arbitrary received packets, including their headers, to the net driver.
+fuzz_target!(|data: &[u8]| {
+    let config = [0x52, 0x54, 0, 0x12, 0x34, 0x56, 1, 0];
+    let header = fake_device(FuzzDevice::new(DeviceType::Network, &config, data));
+    let header_ptr = header as *mut _;
+    if let Ok(mut net) = VirtIONet::new(header) {
+        let mut buf = [0; 1514];
+        for _ in 0..4 {
+            let _ = net.recv(&mut buf);
+        }
+    }
+    unsafe { destroy_fake_device(header_ptr) };
+});
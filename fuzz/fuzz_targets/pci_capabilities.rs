@@ -0,0 +1,15 @@
+#![no_main]
+
+use core::ptr::NonNull;
+use libfuzzer_sys::fuzz_target;
+use virtio_drivers::PciTransport;
+use virtio_drivers_fuzz::pci_config_space;
+
+// Parse arbitrary BARs and capability lists of a PCI function.
+fuzz_target!(|data: &[u8]| {
+    let mut config = pci_config_space(data);
+    let config = NonNull::new(config.as_mut_ptr() as *mut u8).unwrap();
+    if let Ok(transport) = unsafe { PciTransport::new(config) } {
+        let _ = transport.msix_table_size();
+    }
+});
@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use virtio_drivers::testing::{destroy_fake_device, fake_device};
+use virtio_drivers::{DeviceType, VirtIOBlk};
+use virtio_drivers_fuzz::FuzzDevice;
+
+// Feed arbitrary used ring entries and block responses to the block driver.
+fuzz_target!(|data: &[u8]| {
+    let capacity = 16u64.to_le_bytes();
+    let header = fake_device(FuzzDevice::new(DeviceType::Block, &capacity, data));
+    let header_ptr = header as *mut _;
+    if let Ok(mut blk) = VirtIOBlk::new(header) {
+        let mut buf = [0; 512];
+        for block_id in 0..4 {
+            let _ = blk.read_block(block_id, &mut buf);
+            let _ = blk.write_block(block_id, &buf);
+        }
+    }
+    unsafe { destroy_fake_device(header_ptr) };
+});
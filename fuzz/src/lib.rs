@@ -0,0 +1,91 @@
+//! A fake device whose responses are taken from the fuzzer input.
+
+use virtio_drivers::testing::{write_chain, FakeBackend};
+use virtio_drivers::DeviceType;
+
+/// A device which answers every request with bytes from the fuzzer input.
+///
+/// For each chain, it takes the length to report and the ID to put in the
+/// used ring, followed by the bytes to write into the writable buffers.
+pub struct FuzzDevice {
+    device_type: DeviceType,
+    config: Vec<u8>,
+    data: Vec<u8>,
+    used_ids: Vec<u32>,
+}
+
+impl FuzzDevice {
+    /// Create a device of `device_type` with `config` as its config space,
+    /// answering requests with `data`.
+    pub fn new(device_type: DeviceType, config: &[u8], data: &[u8]) -> Self {
+        FuzzDevice {
+            device_type,
+            config: config.to_vec(),
+            data: data.to_vec(),
+            used_ids: Vec::new(),
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Vec<u8> {
+        let len = len.min(self.data.len());
+        self.data.drain(..len).collect()
+    }
+
+    fn take_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        let taken = self.take(4);
+        bytes[..taken.len()].copy_from_slice(&taken);
+        u32::from_le_bytes(bytes)
+    }
+}
+
+impl FakeBackend for FuzzDevice {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn config(&self) -> Vec<u8> {
+        self.config.clone()
+    }
+
+    fn process(
+        &mut self,
+        _queue: u32,
+        _inputs: &[&[u8]],
+        outputs: &mut [&mut [u8]],
+    ) -> Option<u32> {
+        let len = self.take_u32();
+        let used_id = self.take_u32();
+        self.used_ids.push(used_id);
+        let capacity = outputs.iter().map(|buf| buf.len()).sum();
+        let response = self.take(capacity);
+        write_chain(outputs, &response);
+        Some(len)
+    }
+
+    fn used_id(&mut self, _queue: u32, head: u16) -> u32 {
+        // keep the right ID most of the time, so that the fuzzer gets past it
+        match self.used_ids.pop() {
+            Some(id) if id & 0x8000_0000 != 0 => id & 0xffff,
+            _ => head as u32,
+        }
+    }
+}
+
+/// The configuration space of a virtio PCI function, with the BARs and the
+/// capabilities taken from the fuzzer input.
+///
+/// The header is fixed to a modern device with a capability list, so that
+/// the fuzzer gets to the parser of the list. The space is 4-byte aligned,
+/// as the registers are accessed as words.
+pub fn pci_config_space(data: &[u8]) -> Box<[u32; 1024]> {
+    let mut config = Box::new([0u32; 1024]);
+    let bytes = unsafe { &mut *(config.as_mut() as *mut [u32; 1024] as *mut [u8; 4096]) };
+    // everything from the BARs onwards
+    let len = data.len().min(bytes.len() - 0x10);
+    bytes[0x10..0x10 + len].copy_from_slice(&data[..len]);
+    // vendor ID, device ID of a modern block device, and the status
+    bytes[..4].copy_from_slice(&[0xf4, 0x1a, 0x42, 0x10]);
+    bytes[6] = 0x10;
+    config
+}
@@ -0,0 +1,251 @@
+use super::*;
+
+/// The hardware ID of virtio-mmio devices.
+const VIRTIO_MMIO_HID: &str = "LNRO0005";
+
+// the resource descriptors of virtio-mmio devices
+const SMALL_IRQ: u8 = 0x04;
+const SMALL_END_TAG: u8 = 0x0f;
+const LARGE_MEMORY32_FIXED: u8 = 0x06;
+const LARGE_EXTENDED_INTERRUPT: u8 = 0x09;
+
+/// A device of the ACPI namespace, with the objects which virtio-mmio
+/// devices have, as evaluated by the AML interpreter of the caller.
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiDevice<'a> {
+    /// The `_HID` object, e.g. `LNRO0005` for virtio-mmio devices.
+    pub hid: &'a str,
+    /// The `_CRS` object, a buffer of resource descriptors.
+    pub crs: &'a [u8],
+}
+
+/// Find the virtio-mmio devices among the ACPI `devices`, e.g. those of the
+/// DSDT of QEMU microvm machines, and return their headers with their GSIs,
+/// in the order of the devices.
+///
+/// The registers are the first 32-bit fixed memory range of `_CRS`, and the
+/// GSI its first interrupt. They are checked as
+/// [`virtio_mmio_devices`](crate::virtio_mmio_devices) checks those of
+/// device tree nodes.
+///
+/// ```ignore
+/// let devices = namespace.devices().filter_map(|device| {
+///     Some(AcpiDevice {
+///         hid: device.eval_string("_HID")?,
+///         crs: device.eval_buffer("_CRS")?,
+///     })
+/// });
+/// for (header, gsi) in unsafe { acpi_virtio_mmio_devices(devices) } {
+///     manager.add(gsi, header)?;
+/// }
+/// ```
+///
+/// # Safety
+///
+/// The registers of the devices must be mapped at the addresses which the
+/// HAL translates them to with `phys_to_virt`, and the devices must not be
+/// accessed through other headers.
+pub unsafe fn acpi_virtio_mmio_devices<'a, I>(
+    devices: I,
+) -> impl Iterator<Item = (&'static mut VirtIOHeader, usize)> + 'a
+where
+    I: IntoIterator<Item = AcpiDevice<'a>>,
+    I::IntoIter: 'a,
+{
+    devices
+        .into_iter()
+        .filter(|device| device.hid == VIRTIO_MMIO_HID)
+        .filter_map(|device| {
+            let resources = Resources::parse(device.crs);
+            let (address, size) = match resources.memory {
+                Some(memory) => memory,
+                None => {
+                    warn!("virtio-mmio device lacks a 32-bit fixed memory range");
+                    return None;
+                }
+            };
+            let gsi = match resources.gsi {
+                Some(gsi) => gsi,
+                None => {
+                    warn!("virtio-mmio device at {:#x} lacks an interrupt", address);
+                    return None;
+                }
+            };
+            let header = unsafe { VirtIOHeader::from_region(address, size) }?;
+            debug!(
+                "virtio-mmio device {:?} at {:#x}, GSI {}",
+                header.device_type(),
+                address,
+                gsi
+            );
+            Some((header, gsi))
+        })
+}
+
+/// The resources of a virtio-mmio device, from the first descriptor of each
+/// type in its `_CRS`.
+///
+/// Ref: ACPI 6.4, 6.4 Resource Data Types for ACPI
+#[derive(Debug, Default)]
+struct Resources {
+    /// The address and size of the registers.
+    memory: Option<(u64, u64)>,
+    /// The interrupt.
+    gsi: Option<usize>,
+}
+
+impl Resources {
+    /// Parse the resource descriptors in `crs`, up to the end tag or a
+    /// truncated descriptor.
+    fn parse(crs: &[u8]) -> Self {
+        let mut resources = Resources::default();
+        let mut rest = crs;
+        while let Some(&tag) = rest.first() {
+            // small descriptors have their length in the tag, large ones in
+            // the two bytes following it
+            let large = tag & 0x80 != 0;
+            let (name, data_start, len) = if large {
+                match rest.get(1..3) {
+                    Some(len) => (tag & 0x7f, 3, u16::from_le_bytes([len[0], len[1]]) as usize),
+                    None => break,
+                }
+            } else {
+                ((tag >> 3) & 0xf, 1, (tag & 0x7) as usize)
+            };
+            let data = match rest.get(data_start..data_start + len) {
+                Some(data) => data,
+                None => {
+                    warn!("Truncated resource descriptor {:#x} in _CRS", tag);
+                    break;
+                }
+            };
+            rest = &rest[data_start + len..];
+            match (large, name) {
+                (false, SMALL_END_TAG) => break,
+                (false, SMALL_IRQ) if resources.gsi.is_none() && data.len() >= 2 => {
+                    // a mask of the IRQs the device may use
+                    let mask = u16::from_le_bytes([data[0], data[1]]);
+                    if mask != 0 {
+                        resources.gsi = Some(mask.trailing_zeros() as usize);
+                    }
+                }
+                (true, LARGE_MEMORY32_FIXED) if resources.memory.is_none() && data.len() >= 9 => {
+                    let base = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+                    let size = u32::from_le_bytes([data[5], data[6], data[7], data[8]]);
+                    resources.memory = Some((base as u64, size as u64));
+                }
+                (true, LARGE_EXTENDED_INTERRUPT)
+                    if resources.gsi.is_none() && data.len() >= 6 && data[1] >= 1 =>
+                {
+                    let gsi = u32::from_le_bytes([data[2], data[3], data[4], data[5]]);
+                    resources.gsi = Some(gsi as usize);
+                }
+                _ => {}
+            }
+        }
+        resources
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    extern crate std;
+
+    use super::*;
+    use crate::header::tests::fake_mmio_device;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// A 32-bit fixed memory range descriptor of `size` bytes at `base`.
+    fn memory32_fixed(base: u32, size: u32) -> Vec<u8> {
+        let mut descriptor = vec![0x86, 9, 0, 1];
+        descriptor.extend_from_slice(&base.to_le_bytes());
+        descriptor.extend_from_slice(&size.to_le_bytes());
+        descriptor
+    }
+
+    /// An extended interrupt descriptor of `gsi`.
+    fn extended_interrupt(gsi: u32) -> Vec<u8> {
+        let mut descriptor = vec![0x89, 6, 0, 0x01, 1];
+        descriptor.extend_from_slice(&gsi.to_le_bytes());
+        descriptor
+    }
+
+    const END_TAG: [u8; 2] = [0x79, 0];
+
+    #[test]
+    fn parses_memory_and_extended_interrupt() {
+        let crs = [
+            memory32_fixed(0xfeb0_0000, 0x200),
+            extended_interrupt(40),
+            // later descriptors of the same type are ignored
+            extended_interrupt(41),
+            END_TAG.to_vec(),
+        ]
+        .concat();
+        let resources = Resources::parse(&crs);
+        assert_eq!(resources.memory, Some((0xfeb0_0000, 0x200)));
+        assert_eq!(resources.gsi, Some(40));
+    }
+
+    #[test]
+    fn parses_small_irq() {
+        // IRQ 5, with its flags byte
+        let crs = [vec![0x23, 0x20, 0x00, 0x01], memory32_fixed(0x1000, 0x200)].concat();
+        let resources = Resources::parse(&crs);
+        assert_eq!(resources.gsi, Some(5));
+        assert_eq!(resources.memory, Some((0x1000, 0x200)));
+
+        // an empty mask leaves the device without an interrupt
+        assert_eq!(Resources::parse(&[0x22, 0, 0]).gsi, None);
+    }
+
+    #[test]
+    fn stops_at_end_tag_and_truncated_descriptors() {
+        let crs = [END_TAG.to_vec(), extended_interrupt(40)].concat();
+        assert_eq!(Resources::parse(&crs).gsi, None);
+
+        let memory = memory32_fixed(0x1000, 0x200);
+        let crs = [extended_interrupt(40), memory[..8].to_vec()].concat();
+        let resources = Resources::parse(&crs);
+        assert_eq!(resources.gsi, Some(40));
+        assert_eq!(resources.memory, None);
+
+        // a large tag without its length
+        assert_eq!(Resources::parse(&[0x86, 9]).memory, None);
+    }
+
+    #[test]
+    fn finds_virtio_mmio_devices() {
+        let dma = fake_mmio_device(2, DeviceType::Network);
+        let registers = memory32_fixed(dma.paddr() as u32, 0x200);
+        let with_gsi = [registers.clone(), extended_interrupt(12), END_TAG.to_vec()].concat();
+        let without_memory = [extended_interrupt(13), END_TAG.to_vec()].concat();
+        let without_gsi = [registers, END_TAG.to_vec()].concat();
+        let devices = [
+            AcpiDevice {
+                hid: "PNP0501",
+                crs: &with_gsi,
+            },
+            AcpiDevice {
+                hid: VIRTIO_MMIO_HID,
+                crs: &without_memory,
+            },
+            AcpiDevice {
+                hid: VIRTIO_MMIO_HID,
+                crs: &without_gsi,
+            },
+            AcpiDevice {
+                hid: VIRTIO_MMIO_HID,
+                crs: &with_gsi,
+            },
+        ];
+        let found: Vec<_> = unsafe { acpi_virtio_mmio_devices(devices) }.collect();
+        assert_eq!(found.len(), 1);
+        let (header, gsi) = &found[0];
+        assert_eq!(header.device_type(), DeviceType::Network);
+        assert_eq!(*gsi, 12);
+    }
+}
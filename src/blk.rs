@@ -1,94 +1,689 @@
 use super::*;
-use crate::header::VirtIOHeader;
-use crate::queue::VirtQueue;
+use crate::queue::{QueueBuf, QueueState, SgList, VirtQueue};
+use crate::volatile::Volatile;
 use bitflags::*;
-use core::sync::atomic::spin_loop_hint;
-use log::*;
-use volatile::Volatile;
+use core::future::Future;
+use core::mem::offset_of;
+use core::pin::Pin;
+use core::task::{ready, Context, Poll};
 
 /// The virtio block device is a simple virtual block device (ie. disk).
 ///
 /// Read and write requests (and other exotic requests) are placed in the queue,
 /// and serviced (probably out of order) by the device except where noted.
 pub struct VirtIOBlk<'a> {
-    header: &'static mut VirtIOHeader,
+    header: &'static mut dyn Transport,
     queue: VirtQueue<'a>,
-    capacity: usize,
+    /// Number of 512 Bytes sectors
+    capacity: u64,
+    features: BlkFeature,
 }
 
-impl VirtIOBlk<'_> {
+impl<'a> VirtIOBlk<'a> {
     /// Create a new VirtIO-Blk driver.
-    pub fn new(header: &'static mut VirtIOHeader) -> Result<Self> {
-        header.begin_init(|features| {
-            let features = BlkFeature::from_bits_truncate(features);
-            info!("device features: {:?}", features);
-            // negotiate these flags only
-            let supported_features = BlkFeature::empty();
-            (features & supported_features).bits()
-        });
+    pub fn new(header: &'static mut dyn Transport) -> Result<Self> {
+        Self::from_init(DeviceInit::new(header))
+    }
+
+    /// Create a new VirtIO-Blk driver, initializing the device as customized
+    /// by `init`.
+    pub fn from_init(init: DeviceInit<Acknowledged>) -> Result<Self> {
+        let features = BlkFeature::from_bits_truncate(init.device_features());
+        info!("device features: {:?}", features);
+        // negotiate these flags only
+        let supported_features = BlkFeature::RING_INDIRECT_DESC
+            | BlkFeature::SIZE_MAX
+            | BlkFeature::SEG_MAX
+            | BlkFeature::ORDER_PLATFORM
+            | BlkFeature::RING_PACKED;
+        let mut init = init.negotiate(supported_features.bits())?;
 
         // read configuration space
-        let config = unsafe { &mut *(header.config_space() as *mut BlkConfig) };
-        info!("config: {:?}", config);
-        info!(
-            "found a block device of size {}KB",
-            config.capacity.read() / 2
-        );
+        let capacity = init
+            .read_config_atomic(|| init.read_config::<Le64>(offset_of!(BlkConfig, capacity)))?
+            .get();
+        info!("found a block device of size {}KB", capacity / 2);
+
+        let features = BlkFeature::from_bits_truncate(init.features());
+        let mut queue = init.queue(0, 16)?;
+        if features.contains(BlkFeature::RING_INDIRECT_DESC) {
+            queue.enable_indirect()?;
+        }
+        if features.contains(BlkFeature::ORDER_PLATFORM) {
+            queue.set_order_platform(true);
+        }
+        if features.contains(BlkFeature::SIZE_MAX) {
+            let size_max = init
+                .read_config::<Le32>(offset_of!(BlkConfig, size_max))?
+                .get();
+            if size_max > 0 {
+                queue.set_max_desc_len(size_max)?;
+            }
+        }
+        if features.contains(BlkFeature::SEG_MAX) {
+            // the header and status of a request are not counted as segments
+            let seg_max = init
+                .read_config::<Le32>(offset_of!(BlkConfig, seg_max))?
+                .get() as usize;
+            queue.set_max_chain_len(seg_max.saturating_add(2))?;
+        }
+        let header = init.finish();
 
-        let queue = VirtQueue::new(header, 0, 16)?;
-        header.finish_init();
+        Ok(VirtIOBlk {
+            header,
+            queue,
+            capacity,
+            features,
+        })
+    }
 
+    /// Re-attach to the device with the state saved by [`save`](Self::save),
+    /// after the VM is restored from a snapshot.
+    ///
+    /// The device is expected to be restored by the VMM, so it is not
+    /// initialized again.
+    ///
+    /// # Safety
+    ///
+    /// The memory of the saved driver must be intact, still allocated from
+    /// the HAL, and not used by any other driver.
+    pub unsafe fn restore(header: &'static mut dyn Transport, state: &BlkState) -> Result<Self> {
+        if header.device_type() != DeviceType::Block {
+            return Err(Error::InvalidParam);
+        }
+        let features = BlkFeature::from_bits_truncate(state.features);
+        let mut queue = VirtQueue::restore(header, &state.queue)?;
+        if features.contains(BlkFeature::RING_INDIRECT_DESC) {
+            queue.enable_indirect()?;
+        }
+        if features.contains(BlkFeature::ORDER_PLATFORM) {
+            queue.set_order_platform(true);
+        }
         Ok(VirtIOBlk {
             header,
             queue,
-            capacity: config.capacity.read() as usize,
+            capacity: state.capacity,
+            features,
         })
     }
 
+    /// Save the state of the driver, e.g. with a snapshot of the VM.
+    pub fn save(&self) -> BlkState {
+        BlkState {
+            features: self.features.bits(),
+            capacity: self.capacity,
+            queue: self.queue.save(),
+        }
+    }
+
     /// Acknowledge interrupt.
     pub fn ack_interrupt(&mut self) -> bool {
         self.header.ack_interrupt()
     }
 
+    /// Check whether the capacity changed since it was last read, e.g. after
+    /// the device signals [`InterruptStatus::CONFIG_CHANGE`].
+    pub fn config_change(&mut self) -> Option<ConfigChange> {
+        let capacity = self
+            .header
+            .read_config_atomic(|| {
+                self.header
+                    .read_config::<Le64>(offset_of!(BlkConfig, capacity))
+            })
+            .ok()?
+            .get();
+        if capacity == self.capacity {
+            return None;
+        }
+        info!("capacity changed to {}KB", capacity / 2);
+        self.capacity = capacity;
+        Some(ConfigChange::Capacity(capacity))
+    }
+
+    /// The maximum number of data segments in a request, as limited by the
+    /// queue and the `seg_max` of the device, not counting the header and
+    /// status of the request.
+    pub fn max_segments(&self) -> usize {
+        self.queue.max_chain_len().saturating_sub(2)
+    }
+
+    /// Suspend the device, e.g. before the guest enters S3.
+    ///
+    /// The device is reset so that it stops using the queue, which is set up
+    /// again by [`resume`](Self::resume).
+    pub fn suspend(&mut self) -> Result {
+        self.header.reset();
+        Ok(())
+    }
+
+    /// Resume the device after [`suspend`](Self::suspend), negotiating the
+    /// same features and setting up the queue again.
+    ///
+    /// The requests in flight are made available to the device again, so
+    /// that they complete as if it was never suspended. They are lost if the
+    /// queue is packed, or if the device completed requests which were not
+    /// taken before it was suspended.
+    pub fn resume(&mut self) -> Result {
+        self.header.begin_reinit(self.features.bits())?;
+        let resumed = self.queue.resume(self.header).is_ok();
+        if !resumed {
+            self.queue.reinit(self.header);
+        }
+        self.header.finish_init();
+        if resumed && self.queue.should_notify() {
+            self.header.notify(0);
+        }
+        Ok(())
+    }
+
     /// Read a block.
     pub fn read_block(&mut self, block_id: usize, buf: &mut [u8]) -> Result {
-        assert_eq!(buf.len(), BLK_SIZE);
+        if buf.len() != BLK_SIZE {
+            return Err(Error::InvalidParam);
+        }
         let req = BlkReq {
             type_: ReqType::In,
             reserved: 0,
             sector: block_id as u64,
         };
         let mut resp = BlkResp::default();
-        self.queue.add(&[req.as_buf()], &[buf, resp.as_buf_mut()])?;
-        self.header.notify(0);
-        while !self.queue.can_pop() {
-            spin_loop_hint();
+        let sg = SgList::new()
+            .readable(req.as_buf())?
+            .writable(buf)?
+            .writable(resp.as_buf_mut())?;
+        let token = self.queue.add_sg(sg)?;
+        if self.queue.should_notify() {
+            self.header.notify(0);
         }
-        self.queue.pop_used()?;
+        self.queue.wait_for(self.header, token)?;
         match resp.status {
             RespStatus::Ok => Ok(()),
-            _ => Err(Error::IoError),
+            status => {
+                warn!("Failed to read block {}: {:?}", block_id, status);
+                Err(Error::BlkStatus(status as u8))
+            }
         }
     }
 
     /// Write a block.
     pub fn write_block(&mut self, block_id: usize, buf: &[u8]) -> Result {
-        assert_eq!(buf.len(), BLK_SIZE);
+        if buf.len() != BLK_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        let req = BlkReq {
+            type_: ReqType::Out,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let mut resp = BlkResp::default();
+        let sg = SgList::new()
+            .readable(req.as_buf())?
+            .readable(buf)?
+            .writable(resp.as_buf_mut())?;
+        let token = self.queue.add_sg(sg)?;
+        if self.queue.should_notify() {
+            self.header.notify(0);
+        }
+        self.queue.wait_for(self.header, token)?;
+        match resp.status {
+            RespStatus::Ok => Ok(()),
+            status => {
+                warn!("Failed to write block {}: {:?}", block_id, status);
+                Err(Error::BlkStatus(status as u8))
+            }
+        }
+    }
+
+    /// Read consecutive blocks from `block_id` into `bufs`, which must each be
+    /// 512 bytes long, notifying the device once per 16 blocks.
+    pub fn read_blocks(&mut self, block_id: usize, bufs: &mut [&mut [u8]]) -> Result {
+        if bufs.iter().any(|buf| buf.len() != BLK_SIZE) {
+            return Err(Error::InvalidParam);
+        }
+        let mut done = 0;
+        while done < bufs.len() {
+            let batch = &mut bufs[done..];
+            let count = batch.len().min(MAX_BATCH);
+            let first = block_id + done;
+            let reqs: [BlkReq; MAX_BATCH] = core::array::from_fn(|i| BlkReq {
+                type_: ReqType::In,
+                reserved: 0,
+                sector: (first + i) as u64,
+            });
+            let mut resps: [BlkResp; MAX_BATCH] = Default::default();
+            let inputs: [[&[u8]; 1]; MAX_BATCH] = core::array::from_fn(|i| [reqs[i].as_buf()]);
+            let mut outputs: [[&mut [u8]; 2]; MAX_BATCH] = Default::default();
+            for ((output, buf), resp) in outputs.iter_mut().zip(batch.iter_mut()).zip(&mut resps) {
+                *output = [&mut **buf, resp.as_buf_mut()];
+            }
+            let chains = inputs.iter().zip(&outputs).take(count);
+            let mut tokens = [0; MAX_BATCH];
+            let added = self.queue.add_batch(
+                chains.map(|(inputs, outputs)| (&inputs[..], &outputs[..])),
+                &mut tokens,
+            )?;
+            self.wait_batch("read", first, &tokens[..added], &resps[..added])?;
+            done += added;
+        }
+        Ok(())
+    }
+
+    /// Write `bufs`, which must each be 512 bytes long, to consecutive blocks
+    /// from `block_id`, notifying the device once per 16 blocks.
+    pub fn write_blocks(&mut self, block_id: usize, bufs: &[&[u8]]) -> Result {
+        if bufs.iter().any(|buf| buf.len() != BLK_SIZE) {
+            return Err(Error::InvalidParam);
+        }
+        let mut done = 0;
+        while done < bufs.len() {
+            let batch = &bufs[done..];
+            let count = batch.len().min(MAX_BATCH);
+            let first = block_id + done;
+            let reqs: [BlkReq; MAX_BATCH] = core::array::from_fn(|i| BlkReq {
+                type_: ReqType::Out,
+                reserved: 0,
+                sector: (first + i) as u64,
+            });
+            let mut resps: [BlkResp; MAX_BATCH] = Default::default();
+            let inputs: [[&[u8]; 2]; MAX_BATCH] =
+                core::array::from_fn(|i| [reqs[i].as_buf(), batch.get(i).copied().unwrap_or(&[])]);
+            let mut outputs: [[&mut [u8]; 1]; MAX_BATCH] = Default::default();
+            for (output, resp) in outputs.iter_mut().zip(&mut resps) {
+                *output = [resp.as_buf_mut()];
+            }
+            let chains = inputs.iter().zip(&outputs).take(count);
+            let mut tokens = [0; MAX_BATCH];
+            let added = self.queue.add_batch(
+                chains.map(|(inputs, outputs)| (&inputs[..], &outputs[..])),
+                &mut tokens,
+            )?;
+            self.wait_batch("write", first, &tokens[..added], &resps[..added])?;
+            done += added;
+        }
+        Ok(())
+    }
+
+    /// Notify the device of a batch of requests for the blocks from
+    /// `block_id`, and wait for all of them, with `tokens`, to complete into
+    /// `resps`.
+    fn wait_batch(
+        &mut self,
+        op: &str,
+        block_id: usize,
+        tokens: &[u16],
+        resps: &[BlkResp],
+    ) -> Result {
+        if self.queue.should_notify() {
+            self.header.notify(0);
+        }
+        self.queue.wait_for_all(self.header, tokens)?;
+        for (i, resp) in resps.iter().enumerate() {
+            if resp.status != RespStatus::Ok {
+                warn!("Failed to {} block {}: {:?}", op, block_id + i, resp.status);
+                return Err(Error::BlkStatus(resp.status as u8));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a block into `buf`, which must be 512 bytes long.
+    ///
+    /// The buffer is not copied, so it can be handed on afterwards.
+    pub fn read_block_dma(&mut self, block_id: usize, buf: &DmaBuf) -> Result {
+        if buf.len() != BLK_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        let req = BlkReq {
+            type_: ReqType::In,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let mut resp = BlkResp::default();
+        let token = self.queue.add_parts(
+            &[QueueBuf::Slice(req.as_buf())],
+            &[QueueBuf::Dma(buf), QueueBuf::SliceMut(resp.as_buf_mut())],
+        )?;
+        if self.queue.should_notify() {
+            self.header.notify(0);
+        }
+        self.queue.wait_for(self.header, token)?;
+        match resp.status {
+            RespStatus::Ok => Ok(()),
+            status => {
+                warn!("Failed to read block {}: {:?}", block_id, status);
+                Err(Error::BlkStatus(status as u8))
+            }
+        }
+    }
+
+    /// Write a block from `buf`, which must be 512 bytes long.
+    pub fn write_block_dma(&mut self, block_id: usize, buf: &DmaBuf) -> Result {
+        if buf.len() != BLK_SIZE {
+            return Err(Error::InvalidParam);
+        }
         let req = BlkReq {
             type_: ReqType::Out,
             reserved: 0,
             sector: block_id as u64,
         };
         let mut resp = BlkResp::default();
-        self.queue.add(&[req.as_buf(), buf], &[resp.as_buf_mut()])?;
-        self.header.notify(0);
-        while !self.queue.can_pop() {
-            spin_loop_hint();
+        let token = self.queue.add_parts(
+            &[QueueBuf::Slice(req.as_buf()), QueueBuf::Dma(buf)],
+            &[QueueBuf::SliceMut(resp.as_buf_mut())],
+        )?;
+        if self.queue.should_notify() {
+            self.header.notify(0);
         }
-        self.queue.pop_used()?;
+        self.queue.wait_for(self.header, token)?;
         match resp.status {
             RespStatus::Ok => Ok(()),
-            _ => Err(Error::IoError),
+            status => {
+                warn!("Failed to write block {}: {:?}", block_id, status);
+                Err(Error::BlkStatus(status as u8))
+            }
+        }
+    }
+
+    /// Read a block into the 512 bytes at physical address `paddr`.
+    ///
+    /// The address is not translated, so a buffer mapped once can be read
+    /// into again and again.
+    ///
+    /// # Safety
+    ///
+    /// The 512 bytes at `paddr` must be valid for the device to write, and
+    /// not be accessed until this returns.
+    pub unsafe fn read_block_premapped(&mut self, block_id: usize, paddr: usize) -> Result {
+        self.request_premapped(ReqType::In, block_id, paddr)
+    }
+
+    /// Write a block from the 512 bytes at physical address `paddr`.
+    ///
+    /// # Safety
+    ///
+    /// The 512 bytes at `paddr` must be valid for the device to read, and not
+    /// be written until this returns.
+    pub unsafe fn write_block_premapped(&mut self, block_id: usize, paddr: usize) -> Result {
+        self.request_premapped(ReqType::Out, block_id, paddr)
+    }
+
+    /// Make a request of `type_` for a block with the data at `paddr`, and
+    /// wait for it.
+    unsafe fn request_premapped(
+        &mut self,
+        type_: ReqType,
+        block_id: usize,
+        paddr: usize,
+    ) -> Result {
+        let (op, direction) = match type_ {
+            ReqType::In => ("read", BufferDirection::DeviceToDriver),
+            _ => ("write", BufferDirection::DriverToDevice),
+        };
+        let req = BlkReq {
+            type_,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let mut resp = BlkResp::default();
+        let req_buf = req.as_buf();
+        let resp_buf = resp.as_buf_mut();
+        let token = self.queue.add_premapped(&[
+            (
+                virt_to_phys(req_buf.as_ptr() as usize),
+                req_buf.len(),
+                BufferDirection::DriverToDevice,
+            ),
+            (paddr, BLK_SIZE, direction),
+            (
+                virt_to_phys(resp_buf.as_ptr() as usize),
+                resp_buf.len(),
+                BufferDirection::DeviceToDriver,
+            ),
+        ])?;
+        if self.queue.should_notify() {
+            self.header.notify(0);
+        }
+        self.queue.wait_for(self.header, token)?;
+        match resp.status {
+            RespStatus::Ok => Ok(()),
+            status => {
+                warn!("Failed to {} block {}: {:?}", op, block_id, status);
+                Err(Error::BlkStatus(status as u8))
+            }
+        }
+    }
+
+    /// Submit a request to read a block into `buf`, which must be 512 bytes
+    /// long, without waiting for it, return its token.
+    ///
+    /// The request is complete once [`poll_complete`](Self::poll_complete)
+    /// or the future of [`complete`](Self::complete) is ready with the token.
+    /// If the device is reset before that, [`Driver::recover`] reports the
+    /// token as lost, and polling it fails with [`Error::DeviceReset`].
+    ///
+    /// # Safety
+    ///
+    /// `req`, `buf` and `resp` are handed to the device until the request is
+    /// complete or the device is reset, e.g. by dropping the driver, so they
+    /// must not be moved, freed or accessed meanwhile.
+    pub unsafe fn read_block_nb(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        if buf.len() != BLK_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        *req = BlkReq {
+            type_: ReqType::In,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        *resp = BlkResp::default();
+        let sg = SgList::new()
+            .readable(req.as_buf())?
+            .writable(buf)?
+            .writable(resp.as_buf_mut())?;
+        let token = self.queue.add_sg(sg)?;
+        if self.queue.should_notify() {
+            self.header.notify(0);
+        }
+        Ok(token)
+    }
+
+    /// Submit a request to write a block from `buf`, which must be 512 bytes
+    /// long, without waiting for it, return its token.
+    ///
+    /// The request completes as one of
+    /// [`read_block_nb`](Self::read_block_nb) does.
+    ///
+    /// # Safety
+    ///
+    /// `req`, `buf` and `resp` are handed to the device until the request is
+    /// complete or the device is reset, e.g. by dropping the driver, so they
+    /// must not be moved, freed or accessed meanwhile, except that `buf` may
+    /// be read.
+    pub unsafe fn write_block_nb(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        if buf.len() != BLK_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        *req = BlkReq {
+            type_: ReqType::Out,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        *resp = BlkResp::default();
+        let sg = SgList::new()
+            .readable(req.as_buf())?
+            .readable(buf)?
+            .writable(resp.as_buf_mut())?;
+        let token = self.queue.add_sg(sg)?;
+        if self.queue.should_notify() {
+            self.header.notify(0);
+        }
+        Ok(token)
+    }
+
+    /// Poll for the request with `token` to complete into `resp`, registering
+    /// the waker of `cx` to be woken by [`on_interrupt`](Self::on_interrupt)
+    /// until then.
+    pub fn poll_complete(
+        &mut self,
+        cx: &mut Context<'_>,
+        token: u16,
+        resp: &BlkResp,
+    ) -> Poll<Result> {
+        ready!(self.queue.poll_used(self.header, cx, token))?;
+        Poll::Ready(match resp.status {
+            RespStatus::Ok => Ok(()),
+            status => {
+                warn!("Request {} failed: {:?}", token, status);
+                Err(Error::BlkStatus(status as u8))
+            }
+        })
+    }
+
+    /// A future for the request with `token` to complete into `resp`, which
+    /// resolves as [`poll_complete`](Self::poll_complete) does.
+    pub fn complete<'r>(&'r mut self, token: u16, resp: &'r BlkResp) -> BlkFuture<'r, 'a> {
+        BlkFuture {
+            blk: self,
+            token,
+            resp,
+        }
+    }
+
+    /// Collect the requests the device completed, waking the tasks polling
+    /// for them, and return how many there were.
+    ///
+    /// This is to be called when the device raises a used buffer interrupt,
+    /// after acknowledging it.
+    pub fn on_interrupt(&mut self) -> Result<usize> {
+        self.queue.on_interrupt(self.header)
+    }
+}
+
+/// A future for a request submitted without waiting, created by
+/// [`VirtIOBlk::complete`].
+pub struct BlkFuture<'r, 'a> {
+    blk: &'r mut VirtIOBlk<'a>,
+    token: u16,
+    resp: &'r BlkResp,
+}
+
+impl Future for BlkFuture<'_, '_> {
+    type Output = Result;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let (token, resp) = (self.token, self.resp);
+        self.blk.poll_complete(cx, token, resp)
+    }
+}
+
+impl Drop for VirtIOBlk<'_> {
+    fn drop(&mut self) {
+        // stop the device from using the queues, so that they are freed
+        self.header.reset();
+        self.queue.unset(self.header);
+    }
+}
+
+impl Driver for VirtIOBlk<'_> {
+    fn device_type(&self) -> DeviceType {
+        self.header.device_type()
+    }
+
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
+        self.header.ack_interrupt_status()
+    }
+
+    fn handle_interrupt(&mut self) -> InterruptStatus {
+        let status = self.header.ack_interrupt_status();
+        if status.contains(InterruptStatus::USED_BUFFER) {
+            if let Err(err) = self.on_interrupt() {
+                warn!("Failed to collect the completed requests: {:?}", err);
+            }
+        }
+        status
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.features.bits()
+    }
+
+    fn enable_notifications(&mut self) {
+        self.queue.set_used_notifications(true);
+    }
+
+    fn disable_notifications(&mut self) {
+        self.queue.set_used_notifications(false);
+    }
+
+    fn metrics(&self, f: &mut dyn FnMut(Metric)) {
+        self.queue.metrics().report("requestq", f);
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.header.needs_reset()
+    }
+
+    fn recover(&mut self, lost: &mut dyn FnMut(LostRequest)) -> Result {
+        self.queue.report_lost("requestq", lost);
+        self.header.reset();
+        self.header.begin_reinit(self.features.bits())?;
+        self.queue.reinit(self.header);
+        self.header.finish_init();
+        Ok(())
+    }
+
+    #[cfg(feature = "time-hal")]
+    fn set_timeout(&mut self, ticks: Option<u64>) {
+        self.queue.set_timeout(ticks);
+    }
+}
+
+/// The state of a [`VirtIOBlk`], saved to restore the driver after the VM is
+/// restored from a snapshot.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct BlkState {
+    features: u64,
+    capacity: u64,
+    queue: QueueState,
+}
+
+impl BlkState {
+    /// The length of the serialized state.
+    pub const SIZE: usize = 16 + QueueState::SIZE;
+
+    /// Serialize the state as little-endian fields.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..8].copy_from_slice(&self.features.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.capacity.to_le_bytes());
+        bytes[16..].copy_from_slice(&self.queue.to_bytes());
+        bytes
+    }
+
+    /// Deserialize a state serialized by [`to_bytes`](Self::to_bytes).
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let mut features = [0; 8];
+        let mut capacity = [0; 8];
+        let mut queue = [0; QueueState::SIZE];
+        features.copy_from_slice(&bytes[0..8]);
+        capacity.copy_from_slice(&bytes[8..16]);
+        queue.copy_from_slice(&bytes[16..]);
+        BlkState {
+            features: u64::from_le_bytes(features),
+            capacity: u64::from_le_bytes(capacity),
+            queue: QueueState::from_bytes(&queue),
         }
     }
 }
@@ -97,31 +692,43 @@ impl VirtIOBlk<'_> {
 #[derive(Debug)]
 struct BlkConfig {
     /// Number of 512 Bytes sectors
-    capacity: Volatile<u64>,
-    size_max: Volatile<u32>,
-    seg_max: Volatile<u32>,
-    cylinders: Volatile<u16>,
+    capacity: Volatile<Le64>,
+    size_max: Volatile<Le32>,
+    seg_max: Volatile<Le32>,
+    cylinders: Volatile<Le16>,
     heads: Volatile<u8>,
     sectors: Volatile<u8>,
-    blk_size: Volatile<u32>,
+    blk_size: Volatile<Le32>,
     physical_block_exp: Volatile<u8>,
     alignment_offset: Volatile<u8>,
-    min_io_size: Volatile<u16>,
-    opt_io_size: Volatile<u32>,
+    min_io_size: Volatile<Le16>,
+    opt_io_size: Volatile<Le32>,
     // ... ignored
 }
 
+/// The header of a block request, which the device reads.
 #[repr(C)]
 #[derive(Debug)]
-struct BlkReq {
+pub struct BlkReq {
     type_: ReqType,
     reserved: u32,
     sector: u64,
 }
 
+impl Default for BlkReq {
+    fn default() -> Self {
+        BlkReq {
+            type_: ReqType::In,
+            reserved: 0,
+            sector: 0,
+        }
+    }
+}
+
+/// The status of a block request, which the device writes.
 #[repr(C)]
 #[derive(Debug)]
-struct BlkResp {
+pub struct BlkResp {
     status: RespStatus,
 }
 
@@ -136,7 +743,7 @@ enum ReqType {
 }
 
 #[repr(u8)]
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum RespStatus {
     Ok = 0,
     IoErr = 1,
@@ -154,6 +761,10 @@ impl Default for BlkResp {
 
 const BLK_SIZE: usize = 512;
 
+/// The maximum number of requests submitted at once by
+/// [`VirtIOBlk::read_blocks`] and [`VirtIOBlk::write_blocks`].
+const MAX_BATCH: usize = 16;
+
 bitflags! {
     struct BlkFeature: u64 {
         /// Device supports request barriers. (legacy)
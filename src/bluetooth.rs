@@ -0,0 +1,352 @@
+use super::*;
+use crate::queue::VirtQueue;
+use crate::volatile::ReadOnly;
+use bitflags::*;
+use core::mem::offset_of;
+
+/// The virtio Bluetooth device.
+///
+/// It passes HCI packets between the guest Bluetooth stack and a controller
+/// on the host. Each packet is prefixed with its HCI packet type, as in the
+/// UART (H4) transport.
+pub struct VirtIOBluetooth<'a> {
+    header: &'static mut dyn Transport,
+    /// Queue for sending packets to the controller.
+    tx_queue: VirtQueue<'a>,
+    /// Queue for receiving packets from the controller.
+    rx_queue: VirtQueue<'a>,
+    /// DMA area of the RX buffers.
+    rx_buf_dma: DMA,
+    vendor: u16,
+    msft_opcode: u16,
+    features: Features,
+}
+
+impl VirtIOBluetooth<'_> {
+    /// Create a new VirtIO-Bluetooth driver.
+    pub fn new(header: &'static mut dyn Transport) -> Result<Self> {
+        Self::from_init(DeviceInit::new(header))
+    }
+
+    /// Create a new VirtIO-Bluetooth driver, initializing the device as customized
+    /// by `init`.
+    pub fn from_init(init: DeviceInit<Acknowledged>) -> Result<Self> {
+        let features = Features::from_bits_truncate(init.device_features());
+        info!("Device features {:?}", features);
+        let supported_features =
+            Features::VND_HCI | Features::MSFT_EXT | Features::AOSP_EXT | Features::CONFIG_V2;
+        let mut init = init.negotiate(supported_features.bits())?;
+        let negotiated = Features::from_bits_truncate(init.features());
+
+        // read configuration space
+        let (vendor, msft_opcode) = if negotiated.contains(Features::CONFIG_V2) {
+            let vendor = init.read_config::<Le16>(offset_of!(ConfigV2, vendor))?;
+            let msft_opcode = init.read_config::<Le16>(offset_of!(ConfigV2, msft_opcode))?;
+            (vendor.get(), msft_opcode.get())
+        } else {
+            // the first version is packed, so the 16-bit fields are unaligned
+            let vendor = init.read_config::<[u8; 2]>(1)?;
+            let msft_opcode = init.read_config::<[u8; 2]>(3)?;
+            (u16::from_le_bytes(vendor), u16::from_le_bytes(msft_opcode))
+        };
+        info!("vendor={:#x}, msft_opcode={:#x}", vendor, msft_opcode);
+
+        let tx_queue = init.queue(QUEUE_TX, QUEUE_SIZE)?;
+        let mut rx_queue = init.fixed_queue(QUEUE_RX, QUEUE_SIZE)?;
+
+        let rx_buf_dma = DMA::new(pages(RX_BUF_SIZE * QUEUE_SIZE as usize))?;
+        let rx_buf = unsafe { rx_buf_dma.as_buf() };
+        for (i, buf) in rx_buf
+            .chunks_exact_mut(RX_BUF_SIZE)
+            .take(QUEUE_SIZE as usize)
+            .enumerate()
+        {
+            let token = rx_queue.add(&[], &[buf])?;
+            if token != i as u16 {
+                return Err(Error::WrongToken);
+            }
+        }
+
+        let header = init.finish();
+
+        Ok(VirtIOBluetooth {
+            header,
+            tx_queue,
+            rx_queue,
+            rx_buf_dma,
+            vendor,
+            msft_opcode,
+            features: negotiated,
+        })
+    }
+
+    /// Acknowledge interrupt.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.header.ack_interrupt()
+    }
+
+    /// Suspend the device, e.g. before the guest enters S3.
+    ///
+    /// The device is reset so that it stops using the queues, which are set
+    /// up again by [`resume`](Self::resume). Received packets which have not
+    /// been taken are dropped.
+    pub fn suspend(&mut self) -> Result {
+        self.header.reset();
+        Ok(())
+    }
+
+    /// Resume the device after [`suspend`](Self::suspend), negotiating the
+    /// same features, setting up the queues again and posting the RX
+    /// buffers.
+    ///
+    /// The controller may have been reset, so the host stack should reset
+    /// and set it up again.
+    pub fn resume(&mut self) -> Result {
+        self.header.begin_reinit(self.features.bits())?;
+        self.tx_queue.reinit(self.header);
+        self.rx_queue.reinit(self.header);
+        let rx_buf = unsafe { self.rx_buf_dma.as_buf() };
+        for buf in rx_buf
+            .chunks_exact_mut(RX_BUF_SIZE)
+            .take(QUEUE_SIZE as usize)
+        {
+            self.rx_queue.add(&[], &[buf])?;
+        }
+        self.header.finish_init();
+        Ok(())
+    }
+
+    /// The vendor of the controller, as a `VIRTIO_BT_CONFIG_VENDOR_*` value.
+    pub fn vendor(&self) -> u16 {
+        self.vendor
+    }
+
+    /// The opcode of the Microsoft vendor extension commands.
+    pub fn msft_opcode(&self) -> u16 {
+        self.msft_opcode
+    }
+
+    /// Send an HCI packet to the controller, blocking until it is consumed.
+    ///
+    /// `packet` is the packet without the packet type byte.
+    pub fn send(&mut self, packet_type: HciPacketType, packet: &[u8]) -> Result {
+        if packet_type == HciPacketType::Event
+            || hci_packet_len(packet_type, packet) != Some(packet.len())
+        {
+            return Err(Error::InvalidParam);
+        }
+        let packet_type = [packet_type as u8];
+        self.tx_queue
+            .add_notify_wait_pop(self.header, &[&packet_type, packet], &[])?;
+        Ok(())
+    }
+
+    /// Whether a received packet is pending.
+    pub fn can_recv(&self) -> bool {
+        self.rx_queue.can_pop()
+    }
+
+    /// Receive an HCI packet from the controller, if any.
+    ///
+    /// The packet without the packet type byte is copied into `buf`. Returns
+    /// the packet type and the length of the packet. If `buf` is too small,
+    /// this fails with [`Error::BufferTooSmall`] and the packet is kept to be
+    /// received into a larger buffer.
+    pub fn recv(&mut self, buf: &mut [u8]) -> Result<Option<(HciPacketType, usize)>> {
+        if !self.rx_queue.can_pop() {
+            return Ok(None);
+        }
+        // look at the packet before popping it, to keep it if `buf` is too small
+        let (token, len) = match self.rx_queue.peek_used_with_len() {
+            Ok(used) => used,
+            // pop the invalid element, so that it is not peeked again
+            Err(err) => return self.rx_queue.pop_used().and(Err(err)),
+        };
+        let rx_buf = self.rx_buf(token);
+        let len = (len as usize).min(RX_BUF_SIZE);
+        let result = match (
+            len,
+            rx_buf.first().copied().and_then(HciPacketType::from_u8),
+        ) {
+            (1.., Some(packet_type)) => {
+                let packet = &rx_buf[1..len];
+                match hci_packet_len(packet_type, packet) {
+                    Some(packet_len) if packet_len <= packet.len() => {
+                        if buf.len() < packet_len {
+                            Err(Error::BufferTooSmall)
+                        } else {
+                            buf[..packet_len].copy_from_slice(&packet[..packet_len]);
+                            Ok(Some((packet_type, packet_len)))
+                        }
+                    }
+                    _ => Err(Error::IoError),
+                }
+            }
+            _ => Err(Error::IoError),
+        };
+        if result == Err(Error::BufferTooSmall) {
+            return result;
+        }
+        self.rx_queue.pop_used()?;
+        // requeue
+        self.rx_queue.add_notify(self.header, &[], &[rx_buf])?;
+        result
+    }
+
+    /// The RX buffer used by the descriptor chain with the token.
+    fn rx_buf(&self, token: u16) -> &'static mut [u8] {
+        let offset = token as usize * RX_BUF_SIZE;
+        unsafe { &mut self.rx_buf_dma.as_buf()[offset..offset + RX_BUF_SIZE] }
+    }
+}
+
+impl Drop for VirtIOBluetooth<'_> {
+    fn drop(&mut self) {
+        // stop the device from using the queues, so that they are freed
+        self.header.reset();
+        self.tx_queue.unset(self.header);
+        self.rx_queue.unset(self.header);
+    }
+}
+
+impl Driver for VirtIOBluetooth<'_> {
+    fn device_type(&self) -> DeviceType {
+        self.header.device_type()
+    }
+
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
+        self.header.ack_interrupt_status()
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.features.bits()
+    }
+
+    fn enable_notifications(&mut self) {
+        self.tx_queue.set_used_notifications(true);
+        self.rx_queue.set_used_notifications(true);
+    }
+
+    fn disable_notifications(&mut self) {
+        self.tx_queue.set_used_notifications(false);
+        self.rx_queue.set_used_notifications(false);
+    }
+
+    fn metrics(&self, f: &mut dyn FnMut(Metric)) {
+        self.tx_queue.metrics().report("txq", f);
+        self.rx_queue.metrics().report("rxq", f);
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.header.needs_reset()
+    }
+
+    fn recover(&mut self, lost: &mut dyn FnMut(LostRequest)) -> Result {
+        self.tx_queue.report_lost("txq", lost);
+        self.header.reset();
+        self.resume()
+    }
+
+    #[cfg(feature = "time-hal")]
+    fn set_timeout(&mut self, ticks: Option<u64>) {
+        self.tx_queue.set_timeout(ticks);
+        self.rx_queue.set_timeout(ticks);
+    }
+}
+
+/// The type of an HCI packet.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HciPacketType {
+    /// A command sent to the controller.
+    Command = 0x01,
+    /// Asynchronous connection-oriented data.
+    AclData = 0x02,
+    /// Synchronous connection-oriented data.
+    ScoData = 0x03,
+    /// An event sent by the controller.
+    Event = 0x04,
+    /// Isochronous data.
+    IsoData = 0x05,
+}
+
+impl HciPacketType {
+    fn from_u8(packet_type: u8) -> Option<Self> {
+        match packet_type {
+            0x01 => Some(HciPacketType::Command),
+            0x02 => Some(HciPacketType::AclData),
+            0x03 => Some(HciPacketType::ScoData),
+            0x04 => Some(HciPacketType::Event),
+            0x05 => Some(HciPacketType::IsoData),
+            _ => None,
+        }
+    }
+}
+
+/// The full length of an HCI packet according to its header, or `None` if
+/// the header is truncated.
+fn hci_packet_len(packet_type: HciPacketType, packet: &[u8]) -> Option<usize> {
+    let byte = |i: usize| packet.get(i).map(|&b| b as usize);
+    let (header_len, payload_len) = match packet_type {
+        // opcode (2), parameter length (1)
+        HciPacketType::Command => (3, byte(2)?),
+        // handle (2), data length (2)
+        HciPacketType::AclData => (4, byte(2)? | byte(3)? << 8),
+        // handle (2), data length (1)
+        HciPacketType::ScoData => (3, byte(2)?),
+        // event code (1), parameter length (1)
+        HciPacketType::Event => (2, byte(1)?),
+        // handle (2), data length (14 bits)
+        HciPacketType::IsoData => (4, byte(2)? | (byte(3)? & 0x3f) << 8),
+    };
+    Some(header_len + payload_len)
+}
+
+/// The second version of the config space, negotiated with `CONFIG_V2`.
+#[repr(C)]
+#[derive(Debug)]
+struct ConfigV2 {
+    type_: ReadOnly<u8>,
+    alignment: ReadOnly<u8>,
+    vendor: ReadOnly<Le16>,
+    msft_opcode: ReadOnly<Le16>,
+}
+
+bitflags! {
+    struct Features: u64 {
+        /// The controller supports vendor commands.
+        const VND_HCI               = 1 << 0;
+        /// The controller supports the Microsoft vendor extension.
+        const MSFT_EXT              = 1 << 1;
+        /// The controller supports the AOSP vendor extension.
+        const AOSP_EXT              = 1 << 2;
+        /// The config space uses the second version layout.
+        const CONFIG_V2             = 1 << 3;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+const QUEUE_TX: usize = 0;
+const QUEUE_RX: usize = 1;
+
+/// The size of an RX buffer, large enough for any event or ACL packet used
+/// by common controllers.
+const RX_BUF_SIZE: usize = 1024;
+
+// a parameter that can change
+const QUEUE_SIZE: u16 = 8;
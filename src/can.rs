@@ -0,0 +1,769 @@
+use super::*;
+use crate::queue::VirtQueue;
+use crate::volatile::ReadOnly;
+use bitflags::*;
+use core::mem::offset_of;
+
+/// The virtio CAN device.
+///
+/// It exposes a CAN controller which sends the frames placed in the TX queue
+/// on the bus, and places the frames received from the bus in the buffers of
+/// the RX queue. The controller is started and stopped through the control
+/// queue.
+pub struct VirtIOCan<'a> {
+    header: &'static mut dyn Transport,
+    /// Queue for sending frames.
+    tx_queue: VirtQueue<'a>,
+    /// Queue for receiving frames.
+    rx_queue: VirtQueue<'a>,
+    /// Queue for sending control requests.
+    control_queue: VirtQueue<'a>,
+    /// DMA area of the RX buffers.
+    rx_buf_dma: DMA,
+    /// Buffers posted to the RX queue.
+    rx_buf: &'a mut [Frame],
+    /// DMA area of the buffers of the frames queued without blocking.
+    tx_buf_dma: DMA,
+    /// Buffers of the frames queued without blocking.
+    tx_buf: &'a mut [TxSlot],
+    /// The token of the frame queued in each TX buffer, if any.
+    tx_pending: [Option<u16>; TX_SLOTS],
+    features: Features,
+    /// Acceptance filters for received frames.
+    filters: [Option<CanFilter>; MAX_FILTERS],
+    /// The state of the bus when it was last read.
+    bus_state: BusState,
+}
+
+impl VirtIOCan<'_> {
+    /// Create a new VirtIO-Can driver.
+    pub fn new(header: &'static mut dyn Transport) -> Result<Self> {
+        Self::from_init(DeviceInit::new(header))
+    }
+
+    /// Create a new VirtIO-Can driver, initializing the device as customized
+    /// by `init`.
+    pub fn from_init(init: DeviceInit<Acknowledged>) -> Result<Self> {
+        let features = Features::from_bits_truncate(init.device_features());
+        info!("Device features {:?}", features);
+        let supported_features = Features::CAN_CLASSIC | Features::CAN_FD | Features::RTR_FRAMES;
+        let mut init = init.negotiate(supported_features.bits())?;
+        let negotiated = Features::from_bits_truncate(init.features());
+
+        // read configuration space
+        let status = init.read_config::<Le16>(offset_of!(Config, status))?.get();
+        info!("status={:#x}", status);
+
+        let tx_queue = init.queue(QUEUE_TX, QUEUE_SIZE)?;
+        let mut rx_queue = init.fixed_queue(QUEUE_RX, QUEUE_SIZE)?;
+        let control_queue = init.queue(QUEUE_CONTROL, 2)?;
+
+        let rx_buf_dma = DMA::new(pages(size_of::<Frame>() * QUEUE_SIZE as usize))?;
+        let rx_buf = unsafe {
+            core::slice::from_raw_parts_mut(rx_buf_dma.vaddr() as *mut Frame, QUEUE_SIZE as usize)
+        };
+        for (i, frame) in rx_buf.iter_mut().enumerate() {
+            let token = rx_queue.add(&[], &[frame.as_buf_mut()])?;
+            if token != i as u16 {
+                return Err(Error::WrongToken);
+            }
+        }
+
+        let tx_buf_dma = DMA::new(pages(size_of::<TxSlot>() * TX_SLOTS))?;
+        let tx_buf =
+            unsafe { core::slice::from_raw_parts_mut(tx_buf_dma.vaddr() as *mut TxSlot, TX_SLOTS) };
+
+        let header = init.finish();
+
+        Ok(VirtIOCan {
+            header,
+            tx_queue,
+            rx_queue,
+            control_queue,
+            rx_buf_dma,
+            rx_buf,
+            tx_buf_dma,
+            tx_buf,
+            tx_pending: [None; TX_SLOTS],
+            features: negotiated,
+            filters: [None; MAX_FILTERS],
+            bus_state: BusState::Active,
+        })
+    }
+
+    /// Acknowledge interrupt.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.header.ack_interrupt()
+    }
+
+    /// Suspend the device, e.g. before the guest enters S3.
+    ///
+    /// The device is reset so that it stops using the queues, which are set
+    /// up again by [`resume`](Self::resume). Received frames which have not
+    /// been taken are dropped.
+    pub fn suspend(&mut self) -> Result {
+        self.header.reset();
+        Ok(())
+    }
+
+    /// Resume the device after [`suspend`](Self::suspend), negotiating the
+    /// same features, setting up the queues again and posting the RX
+    /// buffers.
+    ///
+    /// The controller is stopped until [`start`](Self::start) is called
+    /// again, while the acceptance filters are kept. Frames queued with
+    /// [`queue_send`](Self::queue_send) which were not reported as sent are
+    /// dropped.
+    pub fn resume(&mut self) -> Result {
+        self.header.begin_reinit(self.features.bits())?;
+        self.tx_pending = [None; TX_SLOTS];
+        self.tx_queue.reinit(self.header);
+        self.rx_queue.reinit(self.header);
+        self.control_queue.reinit(self.header);
+        for frame in self.rx_buf.iter_mut() {
+            self.rx_queue.add(&[], &[frame.as_buf_mut()])?;
+        }
+        self.header.finish_init();
+        Ok(())
+    }
+
+    /// Whether CAN FD frames can be sent and received.
+    pub fn fd_supported(&self) -> bool {
+        self.features.contains(Features::CAN_FD)
+    }
+
+    /// The current state of the bus.
+    pub fn bus_state(&self) -> BusState {
+        let status = self
+            .header
+            .read_config::<Le16>(offset_of!(Config, status))
+            .map_or(0, Le16::get);
+        if status & STATUS_CTRL_BUSOFF != 0 {
+            BusState::BusOff
+        } else {
+            BusState::Active
+        }
+    }
+
+    /// Check whether the state of the bus changed since it was last checked,
+    /// e.g. after the device signals [`InterruptStatus::CONFIG_CHANGE`].
+    pub fn config_change(&mut self) -> Option<ConfigChange> {
+        let bus_state = self.bus_state();
+        if bus_state == self.bus_state {
+            return None;
+        }
+        self.bus_state = bus_state;
+        Some(ConfigChange::BusState(bus_state))
+    }
+
+    /// Start the controller, so that it takes part in bus communication.
+    pub fn start(&mut self) -> Result {
+        self.control(MSG_SET_CTRL_MODE_START)
+    }
+
+    /// Stop the controller, so that it leaves the bus.
+    ///
+    /// This is also the way to recover from the bus off state, by stopping
+    /// and restarting the controller.
+    pub fn stop(&mut self) -> Result {
+        self.control(MSG_SET_CTRL_MODE_STOP)
+    }
+
+    /// Send a frame, blocking until the device has processed it.
+    pub fn send(&mut self, frame: &CanFrame) -> Result {
+        self.check_frame(frame)?;
+        let req = Frame::from_can_frame(MSG_TX, frame);
+        let mut result = RESULT_NOT_OK;
+        self.tx_queue.add_notify_wait_pop(
+            self.header,
+            &[&req.as_buf()[..size_of::<FrameHeader>() + frame.data().len()]],
+            &[core::slice::from_mut(&mut result)],
+        )?;
+        match result {
+            RESULT_OK => Ok(()),
+            _ => Err(Error::IoError),
+        }
+    }
+
+    /// Queue a frame to be sent without blocking, return its token.
+    ///
+    /// The frame is copied into a buffer owned by the driver. When the device
+    /// has processed it, it is reported by [`Self::handle_sent`], which also
+    /// frees the buffer. Fails with [`Error::NotReady`] while all the buffers
+    /// are in use.
+    pub fn queue_send(&mut self, frame: &CanFrame) -> Result<u16> {
+        self.check_frame(frame)?;
+        let slot = self.tx_pending.iter().position(Option::is_none);
+        let slot = slot.ok_or(Error::NotReady)?;
+        let buf = &mut self.tx_buf[slot];
+        buf.frame = Frame::from_can_frame(MSG_TX, frame);
+        buf.result = RESULT_NOT_OK;
+        let len = size_of::<FrameHeader>() + frame.data().len();
+        let token = self.tx_queue.add(
+            &[&buf.frame.as_buf()[..len]],
+            &[core::slice::from_mut(&mut buf.result)],
+        )?;
+        self.tx_pending[slot] = Some(token);
+        if self.tx_queue.should_notify() {
+            self.header.notify(QUEUE_TX as u32);
+        }
+        Ok(token)
+    }
+
+    /// Reclaim the frames queued with [`Self::queue_send`] which the device
+    /// has processed, calling `f` with the token and result of each, return
+    /// the number of frames reclaimed.
+    pub fn handle_sent(&mut self, mut f: impl FnMut(u16, Result)) -> Result<usize> {
+        let mut count = 0;
+        while self.tx_pending.iter().any(Option::is_some) && self.tx_queue.can_pop() {
+            let (token, _) = self.tx_queue.pop_used()?;
+            let slot = self
+                .tx_pending
+                .iter()
+                .position(|&pending| pending == Some(token))
+                .ok_or(Error::IoError)?;
+            self.tx_pending[slot] = None;
+            let result = match self.tx_buf[slot].result {
+                RESULT_OK => Ok(()),
+                _ => Err(Error::IoError),
+            };
+            f(token, result);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Whether a received frame is pending.
+    pub fn can_recv(&self) -> bool {
+        self.rx_queue.can_pop()
+    }
+
+    /// Get a received frame which passes the acceptance filters, if any.
+    ///
+    /// Frames rejected by the filters are dropped.
+    pub fn recv(&mut self) -> Result<Option<CanFrame>> {
+        while self.rx_queue.can_pop() {
+            let (token, _) = self.rx_queue.pop_used()?;
+            let frame = &mut self.rx_buf[token as usize];
+            let result = frame.parse();
+            // requeue
+            self.rx_queue
+                .add_notify(self.header, &[], &[frame.as_buf_mut()])?;
+            let frame = result?;
+            if self.accepts(&frame) {
+                return Ok(Some(frame));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Replace the acceptance filters for received frames.
+    ///
+    /// A frame is accepted if it matches any of the filters, or if no filter
+    /// is set. The virtio-can specification has no control message for
+    /// filters, so they are applied by the driver.
+    pub fn set_filters(&mut self, filters: &[CanFilter]) -> Result {
+        if filters.len() > MAX_FILTERS {
+            return Err(Error::InvalidParam);
+        }
+        self.filters = [None; MAX_FILTERS];
+        for (slot, filter) in self.filters.iter_mut().zip(filters) {
+            *slot = Some(*filter);
+        }
+        Ok(())
+    }
+
+    /// Remove all acceptance filters, accepting every received frame.
+    pub fn clear_filters(&mut self) {
+        self.filters = [None; MAX_FILTERS];
+    }
+
+    /// Check that the device negotiated the features the frame needs.
+    fn check_frame(&self, frame: &CanFrame) -> Result {
+        if (frame.fd && !self.fd_supported())
+            || (!frame.fd && !self.features.contains(Features::CAN_CLASSIC))
+            || (frame.rtr && !self.features.contains(Features::RTR_FRAMES))
+        {
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
+
+    /// Whether the frame passes the acceptance filters.
+    fn accepts(&self, frame: &CanFrame) -> bool {
+        let mut filters = self.filters.iter().flatten().peekable();
+        filters.peek().is_none() || filters.any(|f| f.matches(frame))
+    }
+
+    /// Send a control request and block for the result.
+    fn control(&mut self, msg_type: u16) -> Result {
+        let mut result = RESULT_NOT_OK;
+        self.control_queue.add_notify_wait_pop(
+            self.header,
+            &[&msg_type.to_le_bytes()],
+            &[core::slice::from_mut(&mut result)],
+        )?;
+        match result {
+            RESULT_OK => Ok(()),
+            _ => Err(Error::IoError),
+        }
+    }
+}
+
+impl Drop for VirtIOCan<'_> {
+    fn drop(&mut self) {
+        // stop the device from using the queues, so that they are freed
+        self.header.reset();
+        self.tx_queue.unset(self.header);
+        self.rx_queue.unset(self.header);
+        self.control_queue.unset(self.header);
+    }
+}
+
+impl Driver for VirtIOCan<'_> {
+    fn device_type(&self) -> DeviceType {
+        self.header.device_type()
+    }
+
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
+        self.header.ack_interrupt_status()
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.features.bits()
+    }
+
+    fn enable_notifications(&mut self) {
+        self.tx_queue.set_used_notifications(true);
+        self.rx_queue.set_used_notifications(true);
+        self.control_queue.set_used_notifications(true);
+    }
+
+    fn disable_notifications(&mut self) {
+        self.tx_queue.set_used_notifications(false);
+        self.rx_queue.set_used_notifications(false);
+        self.control_queue.set_used_notifications(false);
+    }
+
+    fn metrics(&self, f: &mut dyn FnMut(Metric)) {
+        self.tx_queue.metrics().report("txq", f);
+        self.rx_queue.metrics().report("rxq", f);
+        self.control_queue.metrics().report("controlq", f);
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.header.needs_reset()
+    }
+
+    fn recover(&mut self, lost: &mut dyn FnMut(LostRequest)) -> Result {
+        self.tx_queue.report_lost("txq", lost);
+        self.control_queue.report_lost("controlq", lost);
+        self.header.reset();
+        self.resume()
+    }
+
+    #[cfg(feature = "time-hal")]
+    fn set_timeout(&mut self, ticks: Option<u64>) {
+        self.tx_queue.set_timeout(ticks);
+        self.rx_queue.set_timeout(ticks);
+        self.control_queue.set_timeout(ticks);
+    }
+}
+
+/// An acceptance filter for received frames.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CanFilter {
+    id: u32,
+    mask: u32,
+    extended: bool,
+}
+
+impl CanFilter {
+    /// Create a filter accepting the frames whose identifier bits selected
+    /// by `mask` are equal to those of `id`.
+    pub fn new(id: u32, mask: u32, extended: bool) -> Self {
+        CanFilter { id, mask, extended }
+    }
+
+    /// Whether the frame matches the filter.
+    pub fn matches(&self, frame: &CanFrame) -> bool {
+        frame.extended == self.extended && (frame.id & self.mask) == (self.id & self.mask)
+    }
+}
+
+/// The state of the CAN bus.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BusState {
+    /// The controller takes part in bus communication.
+    Active,
+    /// The controller went bus off because of too many errors, and has to be
+    /// restarted.
+    BusOff,
+}
+
+/// A CAN or CAN FD frame.
+#[derive(Debug, Copy, Clone)]
+pub struct CanFrame {
+    id: u32,
+    extended: bool,
+    fd: bool,
+    rtr: bool,
+    len: u8,
+    data: [u8; MAX_FD_LEN],
+}
+
+impl CanFrame {
+    /// Create a classic CAN data frame, or `None` if the ID or length is
+    /// invalid.
+    pub fn new(id: u32, extended: bool, data: &[u8]) -> Option<Self> {
+        if data.len() > MAX_CLASSIC_LEN {
+            return None;
+        }
+        Self::with_data(id, extended, false, data)
+    }
+
+    /// Create a CAN FD data frame, or `None` if the ID or length is invalid.
+    ///
+    /// The length must be one a data length code can express: 0 to 8, 12,
+    /// 16, 20, 24, 32, 48 or 64 bytes.
+    pub fn new_fd(id: u32, extended: bool, data: &[u8]) -> Option<Self> {
+        if !fd_len_valid(data.len()) {
+            return None;
+        }
+        Self::with_data(id, extended, true, data)
+    }
+
+    /// Create a classic CAN remote frame requesting `len` bytes, or `None`
+    /// if the ID or length is invalid.
+    pub fn new_remote(id: u32, extended: bool, len: usize) -> Option<Self> {
+        if len > MAX_CLASSIC_LEN || !id_valid(id, extended) {
+            return None;
+        }
+        Some(CanFrame {
+            id,
+            extended,
+            fd: false,
+            rtr: true,
+            len: len as u8,
+            data: [0; MAX_FD_LEN],
+        })
+    }
+
+    fn with_data(id: u32, extended: bool, fd: bool, data: &[u8]) -> Option<Self> {
+        if !id_valid(id, extended) {
+            return None;
+        }
+        let mut frame = CanFrame {
+            id,
+            extended,
+            fd,
+            rtr: false,
+            len: data.len() as u8,
+            data: [0; MAX_FD_LEN],
+        };
+        frame.data[..data.len()].copy_from_slice(data);
+        Some(frame)
+    }
+
+    /// The identifier of the frame.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Whether the frame uses a 29-bit extended identifier.
+    pub fn is_extended(&self) -> bool {
+        self.extended
+    }
+
+    /// Whether the frame is a CAN FD frame.
+    pub fn is_fd(&self) -> bool {
+        self.fd
+    }
+
+    /// Whether the frame is a remote frame.
+    pub fn is_remote(&self) -> bool {
+        self.rtr
+    }
+
+    /// The length of the data, or the requested length for a remote frame.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Whether the frame carries no data.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The data length code of the frame.
+    pub fn dlc(&self) -> usize {
+        match self.len {
+            0..=8 => self.len as usize,
+            9..=12 => 9,
+            13..=16 => 10,
+            17..=20 => 11,
+            21..=24 => 12,
+            25..=32 => 13,
+            33..=48 => 14,
+            _ => 15,
+        }
+    }
+
+    /// The data of the frame, empty for a remote frame.
+    pub fn data(&self) -> &[u8] {
+        if self.rtr {
+            &[]
+        } else {
+            &self.data[..self.len as usize]
+        }
+    }
+}
+
+/// Whether a CAN FD frame can carry exactly `len` bytes of data.
+fn fd_len_valid(len: usize) -> bool {
+    matches!(len, 0..=8 | 12 | 16 | 20 | 24 | 32 | 48 | 64)
+}
+
+/// Whether the identifier fits in a standard or extended identifier.
+fn id_valid(id: u32, extended: bool) -> bool {
+    if extended {
+        id <= MAX_EXTENDED_ID
+    } else {
+        id <= MAX_STANDARD_ID
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct Config {
+    /// The status of the controller.
+    status: ReadOnly<Le16>,
+}
+
+/// The controller is in the bus off state.
+const STATUS_CTRL_BUSOFF: u16 = 1 << 0;
+
+bitflags! {
+    struct Features: u64 {
+        /// The device supports classic CAN frames.
+        const CAN_CLASSIC           = 1 << 0;
+        /// The device supports CAN FD frames.
+        const CAN_FD                = 1 << 1;
+        /// The device acknowledges TX only once the frame is on the bus.
+        const LATE_TX_ACK           = 1 << 2;
+        /// The device supports remote frames.
+        const RTR_FRAMES            = 1 << 3;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+bitflags! {
+    struct FrameFlags: u32 {
+        const EXTENDED = 1 << 1;
+        const FD = 1 << 2;
+        const RTR = 1 << 3;
+    }
+}
+
+/// The header of a TX or RX message.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct FrameHeader {
+    msg_type: u16,
+    length: u16,
+    reserved_classic_dlc: u8,
+    padding: u8,
+    reserved_xl_priority: u16,
+    flags: u32,
+    can_id: u32,
+}
+
+/// A TX or RX message.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    header: FrameHeader,
+    sdu: [u8; MAX_FD_LEN],
+}
+
+impl Frame {
+    fn from_can_frame(msg_type: u16, frame: &CanFrame) -> Frame {
+        let mut flags = FrameFlags::empty();
+        flags.set(FrameFlags::EXTENDED, frame.extended);
+        flags.set(FrameFlags::FD, frame.fd);
+        flags.set(FrameFlags::RTR, frame.rtr);
+        Frame {
+            header: FrameHeader {
+                msg_type,
+                length: frame.len as u16,
+                flags: flags.bits(),
+                can_id: frame.id,
+                ..FrameHeader::default()
+            },
+            sdu: frame.data,
+        }
+    }
+
+    /// Parse a frame received from the device.
+    fn parse(&self) -> Result<CanFrame> {
+        let flags = FrameFlags::from_bits_truncate(self.header.flags);
+        let len = self.header.length as usize;
+        if self.header.msg_type != MSG_RX || len > MAX_FD_LEN {
+            return Err(Error::IoError);
+        }
+        let extended = flags.contains(FrameFlags::EXTENDED);
+        let frame = if flags.contains(FrameFlags::RTR) {
+            CanFrame::new_remote(self.header.can_id, extended, len)
+        } else if flags.contains(FrameFlags::FD) {
+            CanFrame::new_fd(self.header.can_id, extended, &self.sdu[..len])
+        } else {
+            CanFrame::new(self.header.can_id, extended, &self.sdu[..len])
+        };
+        frame.ok_or(Error::IoError)
+    }
+}
+
+unsafe impl AsBuf for Frame {}
+
+/// A driver-owned buffer of a frame queued without blocking.
+#[repr(C)]
+struct TxSlot {
+    frame: Frame,
+    /// The result written by the device.
+    result: u8,
+}
+
+const MSG_TX: u16 = 0x0001;
+const MSG_RX: u16 = 0x0101;
+const MSG_SET_CTRL_MODE_START: u16 = 0x0201;
+const MSG_SET_CTRL_MODE_STOP: u16 = 0x0202;
+
+const RESULT_OK: u8 = 0;
+const RESULT_NOT_OK: u8 = 1;
+
+/// The maximum number of acceptance filters.
+const MAX_FILTERS: usize = 8;
+
+const MAX_CLASSIC_LEN: usize = 8;
+const MAX_FD_LEN: usize = 64;
+const MAX_STANDARD_ID: u32 = 0x7ff;
+const MAX_EXTENDED_ID: u32 = 0x1fff_ffff;
+
+const QUEUE_TX: usize = 0;
+const QUEUE_RX: usize = 1;
+const QUEUE_CONTROL: usize = 2;
+
+// a parameter that can change
+const QUEUE_SIZE: u16 = 16;
+/// The number of frames which can be queued without blocking, each taking a
+/// chain of two descriptors.
+const TX_SLOTS: usize = QUEUE_SIZE as usize / 2;
+
+#[cfg(feature = "embedded-can")]
+mod embedded {
+    use super::*;
+    use embedded_can::{ExtendedId, Id, StandardId};
+
+    impl embedded_can::Frame for CanFrame {
+        fn new(id: impl Into<Id>, data: &[u8]) -> Option<Self> {
+            match id.into() {
+                Id::Standard(id) => CanFrame::new(id.as_raw() as u32, false, data),
+                Id::Extended(id) => CanFrame::new(id.as_raw(), true, data),
+            }
+        }
+
+        fn new_remote(id: impl Into<Id>, dlc: usize) -> Option<Self> {
+            match id.into() {
+                Id::Standard(id) => CanFrame::new_remote(id.as_raw() as u32, false, dlc),
+                Id::Extended(id) => CanFrame::new_remote(id.as_raw(), true, dlc),
+            }
+        }
+
+        fn is_extended(&self) -> bool {
+            self.extended
+        }
+
+        fn is_remote_frame(&self) -> bool {
+            self.rtr
+        }
+
+        fn id(&self) -> Id {
+            // the identifier has been validated on construction
+            if self.extended {
+                Id::Extended(ExtendedId::new(self.id).unwrap_or(ExtendedId::ZERO))
+            } else {
+                Id::Standard(StandardId::new(self.id as u16).unwrap_or(StandardId::ZERO))
+            }
+        }
+
+        fn dlc(&self) -> usize {
+            CanFrame::dlc(self)
+        }
+
+        fn data(&self) -> &[u8] {
+            CanFrame::data(self)
+        }
+    }
+
+    impl embedded_can::Error for Error {
+        fn kind(&self) -> embedded_can::ErrorKind {
+            embedded_can::ErrorKind::Other
+        }
+    }
+
+    impl embedded_can::blocking::Can for VirtIOCan<'_> {
+        type Frame = CanFrame;
+        type Error = Error;
+
+        fn transmit(&mut self, frame: &CanFrame) -> Result {
+            self.send(frame)
+        }
+
+        fn receive(&mut self) -> Result<CanFrame> {
+            loop {
+                if let Some(frame) = self.recv()? {
+                    return Ok(frame);
+                }
+                wait();
+            }
+        }
+    }
+
+    impl embedded_can::nb::Can for VirtIOCan<'_> {
+        type Frame = CanFrame;
+        type Error = Error;
+
+        /// Queue the frame without waiting for the device to send it.
+        ///
+        /// The buffers of the frames the device has processed are reclaimed
+        /// first, ignoring their results, as the trait has no way to report
+        /// them.
+        fn transmit(&mut self, frame: &CanFrame) -> nb::Result<Option<CanFrame>, Error> {
+            self.handle_sent(|_, _| {})?;
+            match self.queue_send(frame) {
+                Ok(_) => Ok(None),
+                Err(Error::NotReady) => Err(nb::Error::WouldBlock),
+                Err(err) => Err(nb::Error::Other(err)),
+            }
+        }
+
+        fn receive(&mut self) -> nb::Result<CanFrame, Error> {
+            self.recv()?.ok_or(nb::Error::WouldBlock)
+        }
+    }
+}
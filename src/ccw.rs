@@ -0,0 +1,454 @@
+use super::*;
+use crate::hal::{ccw_notify, ccw_start, DMA};
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+/// The control unit type of virtio devices, whose model is their device type.
+const VIRTIO_CU_TYPE: u16 = 0x3832;
+
+/// The feature which the driver of a device of revision 1 or later must
+/// accept.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// The highest revision of the transport which the driver supports.
+const MAX_REVISION: u16 = 2;
+
+/// The number of queues whose descriptor tables the transport keeps track
+/// of.
+const MAX_QUEUES: usize = 32;
+
+// the channel commands of virtio devices
+const CCW_CMD_SET_VQ: u8 = 0x13;
+const CCW_CMD_VDEV_RESET: u8 = 0x33;
+const CCW_CMD_SET_IND: u8 = 0x43;
+const CCW_CMD_SET_CONF_IND: u8 = 0x53;
+const CCW_CMD_READ_FEAT: u8 = 0x12;
+const CCW_CMD_WRITE_FEAT: u8 = 0x11;
+const CCW_CMD_READ_CONF: u8 = 0x22;
+const CCW_CMD_WRITE_CONF: u8 = 0x21;
+const CCW_CMD_WRITE_STATUS: u8 = 0x31;
+const CCW_CMD_READ_VQ_CONF: u8 = 0x32;
+const CCW_CMD_SET_VIRTIO_REV: u8 = 0x83;
+const CCW_CMD_READ_STATUS: u8 = 0x72;
+const CCW_CMD_SENSE_ID: u8 = 0xe4;
+
+/// The CCW flag suppressing the incorrect length indication, so that the
+/// device may transfer less data than the CCW has room for.
+const CCW_FLAG_SLI: u8 = 0x20;
+
+// the layout of the page of a transport, which the device accesses
+const CCW_OFFSET: usize = 0;
+const INDICATORS_OFFSET: usize = 8;
+const CONFIG_INDICATORS_OFFSET: usize = 16;
+const DATA_OFFSET: usize = 64;
+const DATA_SIZE: usize = PAGE_SIZE - DATA_OFFSET;
+
+/// The transport of a virtio device on a subchannel of the channel
+/// subsystem of s390x machines, which the driver drives with channel
+/// commands rather than registers.
+///
+/// The channel programs are started through the HAL (`virtio_ccw_start`),
+/// which issues `START SUBCHANNEL` and waits for the subchannel to complete
+/// them, and the queues are notified with `DIAGNOSE 0x500` through the HAL
+/// as well (`virtio_ccw_notify`). The device signals used buffers and
+/// configuration changes with classic indicators, which
+/// [`ack_interrupt_status`](Transport::ack_interrupt_status) reads on the
+/// I/O interrupt of the subchannel.
+///
+/// ```ignore
+/// for schid in subchannels_with_cu_type(0x3832) {
+///     let transport = unsafe { CcwTransport::new(schid) }?;
+///     let device = probe(LEAKED.store(transport))?;
+/// }
+/// ```
+///
+/// Ref: virtio 4.3 Virtio Over Channel I/O
+pub struct CcwTransport {
+    schid: u32,
+    device_type: DeviceType,
+    /// The revision of the transport negotiated with the device.
+    revision: u16,
+    /// The page holding the channel programs, their data and the
+    /// indicators, which must be below 2 GiB as the CCWs address it with 31
+    /// bits.
+    page: DMA,
+    /// Whether a channel program is using the page.
+    busy: AtomicBool,
+    /// The device status, which devices of revisions before 2 cannot report.
+    status: AtomicU8,
+    /// The size of the device configuration.
+    config_size: usize,
+    /// The physical addresses of the descriptor tables of the queues, as they
+    /// are set up.
+    queues: [AtomicUsize; MAX_QUEUES],
+}
+
+impl CcwTransport {
+    /// Create the transport of the virtio device on subchannel `schid`,
+    /// which is the subsystem identification word of the subchannel, and
+    /// negotiate the highest revision of the transport both support.
+    ///
+    /// Fails with [`Error::InvalidParam`] if the device is not a virtio
+    /// device, with [`Error::IoError`] if the subchannel does not complete
+    /// the channel programs, and with [`Error::DmaError`] if the HAL
+    /// allocates the page of the transport above 2 GiB.
+    ///
+    /// # Safety
+    ///
+    /// The subchannel must be enabled, with its I/O interrupts delivered to
+    /// the HAL, and the device must not be accessed through other
+    /// transports.
+    pub unsafe fn new(schid: u32) -> Result<Self> {
+        let page = DMA::new(1)?;
+        if page.paddr() + PAGE_SIZE > 1 << 31 {
+            warn!("CCW page at {:#x} is above 2 GiB", page.paddr());
+            return Err(Error::DmaError);
+        }
+        let mut transport = CcwTransport {
+            schid,
+            device_type: DeviceType::Invalid,
+            revision: 0,
+            page,
+            busy: AtomicBool::new(false),
+            status: AtomicU8::new(0),
+            config_size: 0,
+            queues: core::array::from_fn(|_| AtomicUsize::new(0)),
+        };
+
+        let mut sense_id = [0; 7];
+        transport.command(CCW_CMD_SENSE_ID, CCW_FLAG_SLI, &mut sense_id)?;
+        let cu_type = u16::from_be_bytes([sense_id[1], sense_id[2]]);
+        if sense_id[0] != 0xff || cu_type != VIRTIO_CU_TYPE {
+            warn!(
+                "Subchannel {:#x} has control unit type {:#x}",
+                schid, cu_type
+            );
+            return Err(Error::InvalidParam);
+        }
+        transport.device_type = DeviceType::from_id(sense_id[3] as u32);
+        if transport.device_type == DeviceType::Invalid {
+            return Err(Error::InvalidParam);
+        }
+
+        transport.command(CCW_CMD_VDEV_RESET, 0, &mut [])?;
+        // try each revision from the highest, as devices reject those they
+        // do not support
+        transport.revision = (1..=MAX_REVISION)
+            .rev()
+            .find(|&revision| transport.set_revision(revision).is_ok())
+            .unwrap_or(0);
+
+        let mut config = [0; DATA_SIZE];
+        transport.config_size = transport.command(CCW_CMD_READ_CONF, CCW_FLAG_SLI, &mut config)?;
+        debug!(
+            "Virtio CCW device {:?} on subchannel {:#x}, revision {}, {} bytes of config",
+            transport.device_type, schid, transport.revision, transport.config_size
+        );
+        Ok(transport)
+    }
+
+    /// The subchannel of the device.
+    pub fn schid(&self) -> u32 {
+        self.schid
+    }
+
+    /// The revision of the transport negotiated with the device, where 0 is
+    /// the legacy interface.
+    pub fn revision(&self) -> u16 {
+        self.revision
+    }
+
+    /// Ask the device to use `revision` of the transport.
+    fn set_revision(&self, revision: u16) -> Result {
+        let mut data = [0; 4];
+        data[..2].copy_from_slice(&revision.to_be_bytes());
+        self.command(CCW_CMD_SET_VIRTIO_REV, 0, &mut data)?;
+        Ok(())
+    }
+
+    /// Run the channel command `command` with `data`, which the device
+    /// reads, or overwrites with the data it transfers, and return the
+    /// number of bytes transferred.
+    ///
+    /// Fails with [`Error::IoError`] if the device does not complete the
+    /// command, e.g. as it rejects it.
+    fn command(&self, command: u8, flags: u8, data: &mut [u8]) -> Result<usize> {
+        if data.len() > DATA_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        while self
+            .busy
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        let page = self.page.vaddr() as *mut u8;
+        let data_paddr = (self.page.paddr() + DATA_OFFSET) as u32;
+        let mut ccw = [0; 8];
+        ccw[0] = command;
+        ccw[1] = flags;
+        ccw[2..4].copy_from_slice(&(data.len() as u16).to_be_bytes());
+        ccw[4..].copy_from_slice(&data_paddr.to_be_bytes());
+        unsafe {
+            ptr::copy_nonoverlapping(ccw.as_ptr(), page.add(CCW_OFFSET), ccw.len());
+            ptr::copy_nonoverlapping(data.as_ptr(), page.add(DATA_OFFSET), data.len());
+        }
+        let residual = ccw_start(self.schid, self.page.paddr() + CCW_OFFSET);
+        unsafe {
+            ptr::copy_nonoverlapping(page.add(DATA_OFFSET), data.as_mut_ptr(), data.len());
+        }
+        self.busy.store(false, Ordering::Release);
+        match residual {
+            Some(residual) if residual <= data.len() => Ok(data.len() - residual),
+            _ => {
+                debug!(
+                    "Channel command {:#x} failed on subchannel {:#x}",
+                    command, self.schid
+                );
+                Err(Error::IoError)
+            }
+        }
+    }
+
+    /// Read the device configuration up to the end of the field of `width`
+    /// bytes at `offset`, which the device transfers from its start.
+    fn read_config_prefix(&self, offset: usize, width: usize) -> [u8; DATA_SIZE] {
+        let mut config = [0; DATA_SIZE];
+        if let Err(err) = self.command(CCW_CMD_READ_CONF, 0, &mut config[..offset + width]) {
+            warn!("Failed to read config at {:#x}: {:?}", offset, err);
+        }
+        config
+    }
+
+    /// The classic indicator at `offset` in the page of the transport.
+    fn indicator(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*((self.page.vaddr() + offset) as *const AtomicU64) }
+    }
+
+    /// Register the classic indicators with the device.
+    fn set_indicators(&self) -> Result {
+        for (command, offset) in [
+            (CCW_CMD_SET_IND, INDICATORS_OFFSET),
+            (CCW_CMD_SET_CONF_IND, CONFIG_INDICATORS_OFFSET),
+        ] {
+            self.indicator(offset).store(0, Ordering::Relaxed);
+            let mut address = ((self.page.paddr() + offset) as u64).to_be_bytes();
+            self.command(command, 0, &mut address)?;
+        }
+        Ok(())
+    }
+}
+
+impl Transport for CcwTransport {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        // the legacy interface only has the first 32 feature bits
+        let words = if self.revision == 0 { 1 } else { 2 };
+        let mut features = 0;
+        for index in 0..words {
+            let mut desc = [0; 5];
+            desc[4] = index;
+            match self.command(CCW_CMD_READ_FEAT, 0, &mut desc) {
+                Ok(_) => {
+                    let word = u32::from_le_bytes([desc[0], desc[1], desc[2], desc[3]]);
+                    features |= (word as u64) << (32 * index);
+                }
+                Err(err) => warn!("Failed to read feature word {}: {:?}", index, err),
+            }
+        }
+        features
+    }
+
+    /// Write the features the driver accepts, with `VIRTIO_F_VERSION_1`,
+    /// which the driver of a device of revision 1 or later must accept.
+    fn write_driver_features(&mut self, driver_features: u64) {
+        let (words, driver_features) = if self.revision == 0 {
+            (1, driver_features)
+        } else {
+            (2, driver_features | VIRTIO_F_VERSION_1)
+        };
+        for index in 0..words {
+            let mut desc = [0; 5];
+            desc[..4].copy_from_slice(&((driver_features >> (32 * index)) as u32).to_le_bytes());
+            desc[4] = index;
+            if let Err(err) = self.command(CCW_CMD_WRITE_FEAT, 0, &mut desc) {
+                warn!("Failed to write feature word {}: {:?}", index, err);
+            }
+        }
+    }
+
+    fn max_queue_size(&mut self, queue: u32) -> u32 {
+        let mut config = [0; 4];
+        config[..2].copy_from_slice(&(queue as u16).to_be_bytes());
+        match self.command(CCW_CMD_READ_VQ_CONF, 0, &mut config) {
+            Ok(_) => u16::from_be_bytes([config[2], config[3]]) as u32,
+            Err(_) => 0,
+        }
+    }
+
+    fn notify(&self, queue: u32) {
+        ccw_notify(self.schid, queue as u16);
+    }
+
+    /// The device status, which devices of revision 2 or later report, and
+    /// which is the status last written to others.
+    fn status(&self) -> DeviceStatus {
+        let status = if self.revision >= 2 {
+            let mut status = [0];
+            match self.command(CCW_CMD_READ_STATUS, 0, &mut status) {
+                Ok(_) => status[0],
+                Err(_) => self.status.load(Ordering::Acquire),
+            }
+        } else {
+            self.status.load(Ordering::Acquire)
+        };
+        DeviceStatus::from_bits_truncate(status as u32)
+    }
+
+    /// Write the device status, or reset the device with `VDEV_RESET` if it
+    /// is empty.
+    ///
+    /// The device fails the command if it does not accept the status, e.g.
+    /// FEATURES_OK with features it rejects, so that the status is left
+    /// unchanged.
+    fn set_status(&self, status: DeviceStatus) {
+        if status.is_empty() {
+            match self.command(CCW_CMD_VDEV_RESET, 0, &mut []) {
+                Ok(_) => {
+                    self.status.store(0, Ordering::Release);
+                    for queue in self.queues.iter() {
+                        queue.store(0, Ordering::Relaxed);
+                    }
+                }
+                Err(err) => warn!("Failed to reset device: {:?}", err),
+            }
+            return;
+        }
+        let mut data = [status.bits() as u8];
+        match self.command(CCW_CMD_WRITE_STATUS, 0, &mut data) {
+            Ok(_) => self.status.store(status.bits() as u8, Ordering::Release),
+            Err(err) => warn!("Device did not accept status {:?}: {:?}", status, err),
+        }
+    }
+
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) {
+        // the legacy interface has pages of 4 KiB, and the later ones address
+        // the queues by their physical addresses
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u32,
+        size: u32,
+        descriptors: usize,
+        driver_area: usize,
+        device_area: usize,
+    ) {
+        let mut info = [0; 32];
+        let len = if self.revision == 0 {
+            // the legacy interface finds the rings from the descriptor table,
+            // in the layout of 4 KiB pages
+            info[0..8].copy_from_slice(&(descriptors as u64).to_be_bytes());
+            info[8..12].copy_from_slice(&(PAGE_SIZE as u32).to_be_bytes());
+            info[12..14].copy_from_slice(&(queue as u16).to_be_bytes());
+            info[14..16].copy_from_slice(&(size as u16).to_be_bytes());
+            16
+        } else {
+            info[0..8].copy_from_slice(&(descriptors as u64).to_be_bytes());
+            info[12..14].copy_from_slice(&(queue as u16).to_be_bytes());
+            info[14..16].copy_from_slice(&(size as u16).to_be_bytes());
+            info[16..24].copy_from_slice(&(driver_area as u64).to_be_bytes());
+            info[24..32].copy_from_slice(&(device_area as u64).to_be_bytes());
+            32
+        };
+        match self.command(CCW_CMD_SET_VQ, 0, &mut info[..len]) {
+            Ok(_) => {
+                if let Some(slot) = self.queues.get(queue as usize) {
+                    slot.store(descriptors, Ordering::Relaxed);
+                }
+            }
+            Err(err) => warn!("Failed to set up queue {}: {:?}", queue, err),
+        }
+    }
+
+    fn queue_unset(&mut self, queue: u32) {
+        self.queue_set(queue, 0, 0, 0, 0);
+    }
+
+    fn queue_descriptors(&mut self, queue: u32) -> usize {
+        self.queues
+            .get(queue as usize)
+            .map_or(0, |slot| slot.load(Ordering::Relaxed))
+    }
+
+    fn ack_interrupt_status(&self) -> InterruptStatus {
+        let mut status = InterruptStatus::empty();
+        if self.indicator(INDICATORS_OFFSET).swap(0, Ordering::AcqRel) != 0 {
+            status |= InterruptStatus::USED_BUFFER;
+        }
+        if self
+            .indicator(CONFIG_INDICATORS_OFFSET)
+            .swap(0, Ordering::AcqRel)
+            != 0
+        {
+            status |= InterruptStatus::CONFIG_CHANGE;
+        }
+        status
+    }
+
+    /// The device configuration is only transferred by channel commands, so
+    /// this is null, and the drivers access it through
+    /// `read_config`, which transfers it with `READ_CONF`.
+    fn config_space(&self) -> *mut u64 {
+        ptr::null_mut()
+    }
+
+    fn config_space_size(&self) -> usize {
+        self.config_size
+    }
+
+    unsafe fn config_read(&self, offset: usize, width: usize) -> u32 {
+        let config = self.read_config_prefix(offset, width);
+        let mut bytes = [0; 4];
+        bytes[..width].copy_from_slice(&config[offset..offset + width]);
+        match width {
+            1 => bytes[0] as u32,
+            2 => u16::from_ne_bytes([bytes[0], bytes[1]]) as u32,
+            _ => u32::from_ne_bytes(bytes),
+        }
+    }
+
+    /// Write the field of `width` bytes at `offset`, by writing the device
+    /// configuration from its start, as the device transfers it.
+    unsafe fn config_write(&self, offset: usize, width: usize, value: u32) {
+        let mut config = self.read_config_prefix(offset, width);
+        let field = &mut config[offset..offset + width];
+        match width {
+            1 => field.copy_from_slice(&[value as u8]),
+            2 => field.copy_from_slice(&(value as u16).to_ne_bytes()),
+            _ => field.copy_from_slice(&value.to_ne_bytes()),
+        }
+        if let Err(err) = self.command(CCW_CMD_WRITE_CONF, 0, &mut config[..offset + width]) {
+            warn!("Failed to write config at {:#x}: {:?}", offset, err);
+        }
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.revision == 0
+    }
+
+    /// Register the indicators, which the device forgets when it is reset,
+    /// and finish initializing the device.
+    fn finish_init(&mut self) {
+        if let Err(err) = self.set_indicators() {
+            warn!("Failed to register indicators: {:?}", err);
+        }
+        self.add_status(DeviceStatus::DRIVER_OK);
+        debug!("Device {:?} is ready", self.device_type());
+    }
+}
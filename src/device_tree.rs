@@ -0,0 +1,128 @@
+use super::*;
+
+/// The compatible string of virtio-mmio nodes.
+const VIRTIO_MMIO_COMPATIBLE: &[u8] = b"virtio,mmio";
+
+/// A node of a device tree, with the properties which virtio-mmio nodes
+/// have, as decoded by the device tree parser of the caller.
+#[derive(Debug, Clone, Copy)]
+pub struct DtNode<'a> {
+    /// The `compatible` property, a list of NUL-terminated strings.
+    pub compatible: &'a [u8],
+    /// The physical address of the first region of the `reg` property.
+    pub address: u64,
+    /// The size of the first region of the `reg` property.
+    pub size: u64,
+    /// The interrupt of the node, as the interrupt controller of the caller
+    /// numbers it, e.g. the SPI number of a GIC interrupt plus 32.
+    pub irq: usize,
+}
+
+impl DtNode<'_> {
+    /// Whether the node is compatible with `compatible`.
+    pub fn is_compatible(&self, compatible: &[u8]) -> bool {
+        self.compatible
+            .split(|&c| c == 0)
+            .any(|name| name == compatible)
+    }
+}
+
+/// Find the virtio-mmio devices among the device tree `nodes`, e.g. those
+/// the kernel walks to find its devices, and return their headers with their
+/// interrupts, in the order of the nodes.
+///
+/// The registers of the `virtio,mmio` nodes are mapped with `phys_to_virt`
+/// of the HAL. Nodes whose registers lack the magic value, or are of a
+/// version other than the legacy one and the new one, are skipped with a
+/// warning, and placeholder devices, e.g. the unused transports of QEMU
+/// machines, are skipped silently.
+///
+/// ```ignore
+/// let nodes = fdt.all_nodes().filter_map(|node| {
+///     let reg = node.reg()?.next()?;
+///     Some(DtNode {
+///         compatible: node.property("compatible")?.value,
+///         address: reg.starting_address as u64,
+///         size: reg.size? as u64,
+///         irq: node.interrupts()?.next()?,
+///     })
+/// });
+/// for (header, irq) in unsafe { virtio_mmio_devices(nodes) } {
+///     manager.add(irq, header)?;
+/// }
+/// ```
+///
+/// # Safety
+///
+/// The registers of the nodes must be mapped at the addresses which the HAL
+/// translates them to with `phys_to_virt`, and the devices must not be
+/// accessed through other headers.
+pub unsafe fn virtio_mmio_devices<'a, I>(
+    nodes: I,
+) -> impl Iterator<Item = (&'static mut VirtIOHeader, usize)> + 'a
+where
+    I: IntoIterator<Item = DtNode<'a>>,
+    I::IntoIter: 'a,
+{
+    nodes
+        .into_iter()
+        .filter(|node| node.is_compatible(VIRTIO_MMIO_COMPATIBLE))
+        .filter_map(|node| {
+            let header = unsafe { VirtIOHeader::from_region(node.address, node.size) }?;
+            debug!(
+                "virtio-mmio device {:?} at {:#x}, interrupt {}",
+                header.device_type(),
+                node.address,
+                node.irq
+            );
+            Some((header, node.irq))
+        })
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    extern crate std;
+
+    use super::*;
+    use crate::header::tests::fake_mmio_device;
+    use std::vec::Vec;
+
+    fn node(compatible: &[u8], address: usize, size: u64, irq: usize) -> DtNode<'_> {
+        DtNode {
+            compatible,
+            address: address as u64,
+            size,
+            irq,
+        }
+    }
+
+    #[test]
+    fn matches_any_compatible_string() {
+        let node = node(b"vendor,other\0virtio,mmio\0", 0, 0, 0);
+        assert!(node.is_compatible(b"virtio,mmio"));
+        assert!(!node.is_compatible(b"virtio"));
+    }
+
+    #[test]
+    fn finds_virtio_mmio_devices() {
+        let legacy = fake_mmio_device(1, DeviceType::Block);
+        let modern = fake_mmio_device(2, DeviceType::Console);
+        let placeholder = fake_mmio_device(2, DeviceType::Invalid);
+        let unknown = fake_mmio_device(3, DeviceType::Block);
+        let nodes = [
+            node(b"virtio,mmio\0", legacy.paddr(), 0x200, 48),
+            node(b"arm,pl011\0", modern.paddr(), 0x200, 49),
+            node(b"virtio,mmio\0", placeholder.paddr(), 0x200, 50),
+            node(b"virtio,mmio\0", unknown.paddr(), 0x200, 51),
+            // too small for the registers
+            node(b"virtio,mmio\0", modern.paddr(), 0x80, 52),
+            node(b"virtio,mmio\0", modern.paddr(), 0x200, 53),
+        ];
+        let found: Vec<_> = unsafe { virtio_mmio_devices(nodes) }
+            .map(|(header, irq)| (header.device_type(), irq))
+            .collect();
+        assert_eq!(found, [(DeviceType::Block, 48), (DeviceType::Console, 53)]);
+    }
+}
@@ -0,0 +1,181 @@
+use super::*;
+use core::fmt;
+use core::mem::{self, size_of};
+use core::ptr::NonNull;
+use core::slice;
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+
+/// Which side of a [`DmaBuf`] writes it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BufferDirection {
+    /// The driver fills the buffer for the device to read, e.g. a packet to
+    /// send.
+    DriverToDevice,
+    /// The device fills the buffer for the driver to read, e.g. a received
+    /// packet.
+    DeviceToDriver,
+    /// Both sides read and write the buffer.
+    Both,
+}
+
+/// A reference-counted buffer in DMA memory, which drivers hand to the device
+/// without copying it.
+///
+/// Clones share the same memory, which is freed when the last one is
+/// dropped, so the buffer can be passed on after the driver is done with
+/// it, e.g. from the network driver to a protocol stack. While a queue holds
+/// the buffer for the device, it keeps a clone, so the memory stays
+/// allocated even if the driver is dropped first.
+///
+/// The contents can only be borrowed while the device does not write them,
+/// and mutably borrowed only through the only handle of a buffer which is
+/// not in a queue.
+pub struct DmaBuf {
+    shared: NonNull<Shared>,
+}
+
+/// The bookkeeping of a buffer, kept in front of its data in the same pages.
+#[repr(C)]
+struct Shared {
+    refs: AtomicUsize,
+    in_flight: AtomicBool,
+    direction: BufferDirection,
+    paddr: usize,
+    pages: usize,
+    len: usize,
+}
+
+/// The offset of the data from the start of the pages.
+const DATA_OFFSET: usize = 64;
+
+const _: () = assert!(size_of::<Shared>() <= DATA_OFFSET);
+
+// SAFETY: The bookkeeping is only changed atomically, and the contents are
+// only borrowed as `DmaBuf` allows, like with an `Arc<[u8]>`.
+unsafe impl Send for DmaBuf {}
+unsafe impl Sync for DmaBuf {}
+
+impl DmaBuf {
+    /// Allocate a zeroed buffer of `len` bytes written as `direction` says.
+    pub fn new(len: usize, direction: BufferDirection) -> Result<Self> {
+        if len == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let pages = pages(DATA_OFFSET + len);
+        let dma = DMA::new(pages)?;
+        let vaddr = dma.vaddr();
+        unsafe {
+            core::ptr::write_bytes(vaddr as *mut u8, 0, pages * PAGE_SIZE);
+            (vaddr as *mut Shared).write(Shared {
+                refs: AtomicUsize::new(1),
+                in_flight: AtomicBool::new(false),
+                direction,
+                paddr: dma.paddr(),
+                pages,
+                len,
+            });
+        }
+        // freed by the last handle
+        mem::forget(dma);
+        Ok(DmaBuf {
+            shared: unsafe { NonNull::new_unchecked(vaddr as *mut Shared) },
+        })
+    }
+
+    fn shared(&self) -> &Shared {
+        unsafe { self.shared.as_ref() }
+    }
+
+    /// The length of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.shared().len
+    }
+
+    /// Whether the buffer is empty, which it never is.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Which side writes the buffer.
+    pub fn direction(&self) -> BufferDirection {
+        self.shared().direction
+    }
+
+    /// The physical address of the data.
+    pub fn paddr(&self) -> usize {
+        self.shared().paddr + DATA_OFFSET
+    }
+
+    /// Whether the buffer is in a queue, waiting for the device to use it.
+    pub fn in_flight(&self) -> bool {
+        self.shared().in_flight.load(Ordering::Acquire)
+    }
+
+    /// The contents of the buffer, or `None` while the device may write
+    /// them.
+    pub fn as_slice(&self) -> Option<&[u8]> {
+        if self.in_flight() && self.direction() != BufferDirection::DriverToDevice {
+            return None;
+        }
+        Some(unsafe { slice::from_raw_parts(self.data(), self.len()) })
+    }
+
+    /// The contents of the buffer for writing, or `None` if the buffer is
+    /// shared with another handle or in a queue.
+    pub fn as_mut_slice(&mut self) -> Option<&mut [u8]> {
+        if self.shared().refs.load(Ordering::Acquire) != 1 || self.in_flight() {
+            return None;
+        }
+        Some(unsafe { slice::from_raw_parts_mut(self.data(), self.len()) })
+    }
+
+    fn data(&self) -> *mut u8 {
+        (self.shared.as_ptr() as usize + DATA_OFFSET) as *mut u8
+    }
+
+    /// Mark the buffer as handed to the device, failing with
+    /// [`Error::AlreadyUsed`] if it already is.
+    pub(crate) fn start_use(&self) -> Result {
+        self.shared()
+            .in_flight
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| ())
+            .map_err(|_| Error::AlreadyUsed)
+    }
+
+    /// Mark the buffer as given back by the device.
+    pub(crate) fn end_use(&self) {
+        self.shared().in_flight.store(false, Ordering::Release);
+    }
+}
+
+impl Clone for DmaBuf {
+    fn clone(&self) -> Self {
+        self.shared().refs.fetch_add(1, Ordering::Relaxed);
+        DmaBuf {
+            shared: self.shared,
+        }
+    }
+}
+
+impl Drop for DmaBuf {
+    fn drop(&mut self) {
+        if self.shared().refs.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        fence(Ordering::Acquire);
+        let (paddr, pages) = (self.shared().paddr, self.shared().pages);
+        drop(unsafe { DMA::from_raw(paddr, pages) });
+    }
+}
+
+impl fmt::Debug for DmaBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DmaBuf")
+            .field("paddr", &self.paddr())
+            .field("len", &self.len())
+            .field("direction", &self.direction())
+            .field("in_flight", &self.in_flight())
+            .finish()
+    }
+}
@@ -0,0 +1,84 @@
+use super::*;
+
+/// The interface shared by all device drivers.
+///
+/// It lets a kernel keep devices of different types in one table, e.g. as
+/// `&mut dyn Driver`, and handle their interrupts uniformly.
+pub trait Driver {
+    /// The type of the device.
+    fn device_type(&self) -> DeviceType;
+
+    /// Acknowledge interrupt, and return its causes, which are empty if
+    /// the device did not raise it, e.g. on a line shared with other
+    /// devices.
+    fn ack_interrupt_status(&mut self) -> InterruptStatus;
+
+    /// Acknowledge interrupt, and return whether there was one.
+    fn ack_interrupt(&mut self) -> bool {
+        !self.ack_interrupt_status().is_empty()
+    }
+
+    /// Handle an interrupt of the device: acknowledge it, and let the driver
+    /// process its causes, e.g. collect the requests the device completed
+    /// for the tasks polling for them. Returns the causes, which are empty if
+    /// the device did not raise it.
+    fn handle_interrupt(&mut self) -> InterruptStatus {
+        self.ack_interrupt_status()
+    }
+
+    /// The features negotiated with the device.
+    fn negotiated_features(&self) -> u64;
+
+    /// Ask the device to interrupt when it uses buffers, which it does by
+    /// default.
+    fn enable_notifications(&mut self);
+
+    /// Ask the device not to interrupt when it uses buffers, e.g. while the
+    /// driver polls the device.
+    ///
+    /// This is only a hint, so the device may still interrupt.
+    fn disable_notifications(&mut self);
+
+    /// Report the metrics of the driver, such as the statistics of each of
+    /// its queues, to `f`.
+    fn metrics(&self, f: &mut dyn FnMut(Metric));
+    /// Whether the device needs to be reset after an error, which it
+    /// signals with [`InterruptStatus::CONFIG_CHANGE`], so check it then,
+    /// or poll it, e.g. when a request times out.
+    fn needs_reset(&self) -> bool;
+
+    /// Recover the device after it set `DEVICE_NEEDS_RESET`: reset it,
+    /// negotiate the same features again, set up its queues and finish
+    /// initializing it, as `resume` does after `suspend`.
+    ///
+    /// The requests in flight are lost, so they are reported to `lost`
+    /// first, and the tasks polling for them get [`Error::DeviceReset`].
+    /// The buffers the driver posts itself, e.g. to receive events, are
+    /// posted again rather than reported, and the state the device forgets
+    /// on reset, e.g. the parameters of sound streams, must be set again.
+    fn recover(&mut self, lost: &mut dyn FnMut(LostRequest)) -> Result;
+
+    /// Fail the requests the driver blocks on with [`Error::Timeout`] if the
+    /// device does not complete them within `ticks` of the HAL clock, or
+    /// block until it does with `None`, as by default.
+    ///
+    /// The device is reset on timeout so that it stops using the buffers of
+    /// the request, and has to be [recovered](Self::recover) before it is
+    /// used again.
+    #[cfg(feature = "time-hal")]
+    fn set_timeout(&mut self, ticks: Option<u64>);
+}
+
+/// A request lost when its device was reset, see [`Driver::recover`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct LostRequest {
+    /// The queue of the request, named as in the metrics of the driver.
+    pub queue: &'static str,
+    /// The token of the request, as returned when it was queued, e.g. by
+    /// `VirtIOBlk::read_block_nb`, to match it to the request of the caller.
+    ///
+    /// The blocking methods of the drivers only leave a request in flight
+    /// when they fail, e.g. with [`Error::Timeout`], so the tokens of their
+    /// requests are reported too, but match no request still waited for.
+    pub token: u16,
+}
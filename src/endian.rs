@@ -0,0 +1,51 @@
+//! Little-endian integer types.
+//!
+//! Virtio structures shared with the device are little-endian regardless of
+//! the endianness of the guest. Fields of these types are stored in
+//! little-endian byte order, and converted to and from the native order on
+//! access.
+
+use core::fmt;
+
+macro_rules! le_type {
+    ($name:ident, $ty:ty) => {
+        #[doc = concat!("A little-endian `", stringify!($ty), "`.")]
+        #[repr(transparent)]
+        #[derive(Clone, Copy, Default, PartialEq, Eq)]
+        pub struct $name($ty);
+
+        impl $name {
+            /// Convert from the native byte order.
+            pub const fn new(value: $ty) -> Self {
+                $name(value.to_le())
+            }
+
+            /// Convert to the native byte order.
+            pub const fn get(self) -> $ty {
+                <$ty>::from_le(self.0)
+            }
+        }
+
+        impl From<$ty> for $name {
+            fn from(value: $ty) -> Self {
+                $name::new(value)
+            }
+        }
+
+        impl From<$name> for $ty {
+            fn from(value: $name) -> Self {
+                value.get()
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::Debug::fmt(&self.get(), f)
+            }
+        }
+    };
+}
+
+le_type!(Le16, u16);
+le_type!(Le32, u32);
+le_type!(Le64, u64);
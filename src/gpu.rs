@@ -1,9 +1,8 @@
 use super::*;
 use crate::queue::VirtQueue;
+use crate::volatile::{ReadOnly, Volatile, WriteOnly};
 use bitflags::*;
-use core::sync::atomic::spin_loop_hint;
-use log::*;
-use volatile::{ReadOnly, Volatile, WriteOnly};
+use core::mem::offset_of;
 
 /// A virtio based graphics adapter.
 ///
@@ -13,7 +12,7 @@ use volatile::{ReadOnly, Volatile, WriteOnly};
 /// In 2D mode the virtio-gpu device provides support for ARGB Hardware cursors
 /// and multiple scanouts (aka heads).
 pub struct VirtIOGpu<'a> {
-    header: &'static mut VirtIOHeader,
+    header: &'static mut dyn Transport,
     rect: Rect,
     /// DMA area of frame buffer.
     frame_buffer_dma: Option<DMA>,
@@ -27,30 +26,38 @@ pub struct VirtIOGpu<'a> {
     queue_buf_send: &'a mut [u8],
     /// Recv buffer for queue.
     queue_buf_recv: &'a mut [u8],
+    features: Features,
 }
 
 impl VirtIOGpu<'_> {
     /// Create a new VirtIO-Gpu driver.
-    pub fn new(header: &'static mut VirtIOHeader) -> Result<Self> {
-        header.begin_init(|features| {
-            let features = Features::from_bits_truncate(features);
-            info!("Device features {:?}", features);
-            let supported_features = Features::empty();
-            (features & supported_features).bits()
-        });
+    pub fn new(header: &'static mut dyn Transport) -> Result<Self> {
+        Self::from_init(DeviceInit::new(header))
+    }
+
+    /// Create a new VirtIO-Gpu driver, initializing the device as customized
+    /// by `init`.
+    pub fn from_init(init: DeviceInit<Acknowledged>) -> Result<Self> {
+        let features = Features::from_bits_truncate(init.device_features());
+        info!("Device features {:?}", features);
+        let supported_features = Features::empty();
+        let mut init = init.negotiate(supported_features.bits())?;
 
         // read configuration space
-        let config = unsafe { &mut *(header.config_space() as *mut Config) };
-        info!("Config: {:?}", config);
+        let num_scanouts = init
+            .read_config::<Le32>(offset_of!(Config, num_scanouts))?
+            .get();
+        info!("{} scanouts", num_scanouts);
 
-        let control_queue = VirtQueue::new(header, QUEUE_TRANSMIT, 2)?;
-        let cursor_queue = VirtQueue::new(header, QUEUE_CURSOR, 2)?;
+        let control_queue = init.queue(QUEUE_TRANSMIT, 2)?;
+        let cursor_queue = init.queue(QUEUE_CURSOR, 2)?;
 
         let queue_buf_dma = DMA::new(2)?;
         let queue_buf_send = unsafe { &mut queue_buf_dma.as_buf()[..PAGE_SIZE] };
         let queue_buf_recv = unsafe { &mut queue_buf_dma.as_buf()[PAGE_SIZE..] };
 
-        header.finish_init();
+        let negotiated = init.features();
+        let header = init.finish();
 
         Ok(VirtIOGpu {
             header,
@@ -61,6 +68,7 @@ impl VirtIOGpu<'_> {
             queue_buf_dma,
             queue_buf_send,
             queue_buf_recv,
+            features: Features::from_bits_truncate(negotiated),
         })
     }
 
@@ -69,6 +77,47 @@ impl VirtIOGpu<'_> {
         self.header.ack_interrupt()
     }
 
+    /// Check whether the displays changed, e.g. after the device signals
+    /// [`InterruptStatus::CONFIG_CHANGE`], and clear the pending event.
+    pub fn config_change(&mut self) -> Option<ConfigChange> {
+        let events = self
+            .header
+            .read_config::<Le32>(offset_of!(Config, events_read))
+            .ok()?
+            .get();
+        if events & EVENT_DISPLAY == 0 {
+            return None;
+        }
+        self.header
+            .write_config::<Le32>(offset_of!(Config, events_clear), EVENT_DISPLAY.into())
+            .ok()?;
+        Some(ConfigChange::Display)
+    }
+
+    /// Suspend the device, e.g. before the guest enters S3.
+    ///
+    /// The device is reset so that it stops using the queues, which are set
+    /// up again by [`resume`](Self::resume). The frame buffer is kept.
+    pub fn suspend(&mut self) -> Result {
+        self.header.reset();
+        Ok(())
+    }
+
+    /// Resume the device after [`suspend`](Self::suspend), negotiating the
+    /// same features, setting up the queues again and showing the frame
+    /// buffer if it was set up.
+    pub fn resume(&mut self) -> Result {
+        self.header.begin_reinit(self.features.bits())?;
+        self.control_queue.reinit(self.header);
+        self.cursor_queue.reinit(self.header);
+        self.header.finish_init();
+        if let Some(frame_buffer_dma) = &self.frame_buffer_dma {
+            let paddr = frame_buffer_dma.paddr();
+            self.attach_framebuffer(paddr)?;
+        }
+        Ok(())
+    }
+
     /// Get the resolution (width, height).
     pub fn resolution(&self) -> (u32, u32) {
         (self.rect.width, self.rect.height)
@@ -83,27 +132,36 @@ impl VirtIOGpu<'_> {
         info!("=> {:?}", display_info);
         self.rect = display_info.rect;
 
+        // alloc continuous pages for the frame buffer
+        let size = frame_buffer_size(&self.rect).ok_or(Error::IoError)?;
+        let frame_buffer_dma = DMA::new(pages(size as usize))?;
+        self.attach_framebuffer(frame_buffer_dma.paddr())?;
+
+        let buf = unsafe { frame_buffer_dma.as_buf() };
+        self.frame_buffer_dma = Some(frame_buffer_dma);
+        Ok(buf)
+    }
+
+    /// Create the resource of the frame buffer at `paddr`, which fits the
+    /// current resolution, and map it to the screen.
+    fn attach_framebuffer(&mut self, paddr: usize) -> Result {
         // create resource 2d
         let rsp: CtrlHeader = self.request(ResourceCreate2D {
             header: CtrlHeader::with_type(Command::ResourceCreate2d),
             resource_id: RESOURCE_ID,
             format: Format::B8G8R8A8UNORM,
-            width: display_info.rect.width,
-            height: display_info.rect.height,
+            width: self.rect.width,
+            height: self.rect.height,
         })?;
         rsp.check_type(Command::OkNodata)?;
 
-        // alloc continuous pages for the frame buffer
-        let size = display_info.rect.width * display_info.rect.height * 4;
-        let frame_buffer_dma = DMA::new(pages(size as usize))?;
-
         // resource_attach_backing
         let rsp: CtrlHeader = self.request(ResourceAttachBacking {
             header: CtrlHeader::with_type(Command::ResourceAttachBacking),
             resource_id: RESOURCE_ID,
             nr_entries: 1,
-            addr: frame_buffer_dma.paddr() as u64,
-            length: size,
+            addr: paddr as u64,
+            length: frame_buffer_size(&self.rect).ok_or(Error::IoError)?,
             padding: 0,
         })?;
         rsp.check_type(Command::OkNodata)?;
@@ -111,15 +169,11 @@ impl VirtIOGpu<'_> {
         // map frame buffer to screen
         let rsp: CtrlHeader = self.request(SetScanout {
             header: CtrlHeader::with_type(Command::SetScanout),
-            rect: display_info.rect,
+            rect: self.rect,
             scanout_id: 0,
             resource_id: RESOURCE_ID,
         })?;
-        rsp.check_type(Command::OkNodata)?;
-
-        let buf = unsafe { frame_buffer_dma.as_buf() };
-        self.frame_buffer_dma = Some(frame_buffer_dma);
-        Ok(buf)
+        rsp.check_type(Command::OkNodata)
     }
 
     /// Flush framebuffer to screen.
@@ -151,30 +205,83 @@ impl VirtIOGpu<'_> {
         unsafe {
             (self.queue_buf_send.as_mut_ptr() as *mut Req).write(req);
         }
-        self.control_queue
-            .add(&[self.queue_buf_send], &[self.queue_buf_recv])?;
-        self.header.notify(QUEUE_TRANSMIT as u32);
-        while !self.control_queue.can_pop() {
-            spin_loop_hint();
-        }
-        self.control_queue.pop_used()?;
+        self.control_queue.add_notify_wait_pop(
+            self.header,
+            &[self.queue_buf_send],
+            &[self.queue_buf_recv],
+        )?;
         Ok(unsafe { (self.queue_buf_recv.as_ptr() as *const Rsp).read() })
     }
 }
 
+impl Drop for VirtIOGpu<'_> {
+    fn drop(&mut self) {
+        // stop the device from using the queues, so that they are freed
+        self.header.reset();
+        self.control_queue.unset(self.header);
+        self.cursor_queue.unset(self.header);
+    }
+}
+
+impl Driver for VirtIOGpu<'_> {
+    fn device_type(&self) -> DeviceType {
+        self.header.device_type()
+    }
+
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
+        self.header.ack_interrupt_status()
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.features.bits()
+    }
+
+    fn enable_notifications(&mut self) {
+        self.control_queue.set_used_notifications(true);
+        self.cursor_queue.set_used_notifications(true);
+    }
+
+    fn disable_notifications(&mut self) {
+        self.control_queue.set_used_notifications(false);
+        self.cursor_queue.set_used_notifications(false);
+    }
+
+    fn metrics(&self, f: &mut dyn FnMut(Metric)) {
+        self.control_queue.metrics().report("controlq", f);
+        self.cursor_queue.metrics().report("cursorq", f);
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.header.needs_reset()
+    }
+
+    fn recover(&mut self, lost: &mut dyn FnMut(LostRequest)) -> Result {
+        self.control_queue.report_lost("controlq", lost);
+        self.cursor_queue.report_lost("cursorq", lost);
+        self.header.reset();
+        self.resume()
+    }
+
+    #[cfg(feature = "time-hal")]
+    fn set_timeout(&mut self, ticks: Option<u64>) {
+        self.control_queue.set_timeout(ticks);
+        self.cursor_queue.set_timeout(ticks);
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 struct Config {
     /// Signals pending events to the driver。
-    events_read: ReadOnly<u32>,
+    events_read: ReadOnly<Le32>,
 
     /// Clears pending events in the device.
-    events_clear: WriteOnly<u32>,
+    events_clear: WriteOnly<Le32>,
 
     /// Specifies the maximum number of scanouts supported by the device.
     ///
     /// Minimum value is 1, maximum value is 16.
-    num_scanouts: Volatile<u32>,
+    num_scanouts: Volatile<Le32>,
 }
 
 /// Display configuration has changed.
@@ -206,7 +313,7 @@ bitflags! {
 }
 
 #[repr(u32)]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Command {
     GetDisplayInfo = 0x100,
     ResourceCreate2d = 0x101,
@@ -239,7 +346,8 @@ const GPU_FLAG_FENCE: u32 = 1 << 0;
 #[repr(C)]
 #[derive(Debug)]
 struct CtrlHeader {
-    hdr_type: Command,
+    /// A `Command`, kept raw as responses come from the device.
+    hdr_type: u32,
     flags: u32,
     fence_id: u64,
     ctx_id: u32,
@@ -249,7 +357,7 @@ struct CtrlHeader {
 impl CtrlHeader {
     fn with_type(hdr_type: Command) -> CtrlHeader {
         CtrlHeader {
-            hdr_type,
+            hdr_type: hdr_type as u32,
             flags: 0,
             fence_id: 0,
             ctx_id: 0,
@@ -259,14 +367,24 @@ impl CtrlHeader {
 
     /// Return error if the type is not same as expected.
     fn check_type(&self, expected: Command) -> Result {
-        if self.hdr_type == expected {
+        if self.hdr_type == expected as u32 {
             Ok(())
         } else {
-            Err(Error::IoError)
+            warn!(
+                "Unexpected response {:#x}, expected {:?}",
+                self.hdr_type, expected
+            );
+            Err(Error::GpuResponse(self.hdr_type))
         }
     }
 }
 
+/// The size of a B8G8R8A8 frame buffer covering `rect`, or `None` if it
+/// overflows.
+fn frame_buffer_size(rect: &Rect) -> Option<u32> {
+    rect.width.checked_mul(rect.height)?.checked_mul(4)
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Default)]
 struct Rect {
@@ -12,6 +12,7 @@ impl DMA {
     pub fn new(pages: usize) -> Result<Self> {
         let paddr = unsafe { virtio_dma_alloc(pages) };
         if paddr == 0 {
+            error!("Failed to allocate {} DMA pages", pages);
             return Err(Error::DmaError);
         }
         Ok(DMA {
@@ -20,6 +21,19 @@ impl DMA {
         })
     }
 
+    /// Take ownership of `pages` pages at `paddr` allocated by the HAL.
+    ///
+    /// # Safety
+    ///
+    /// The pages must have been allocated with `virtio_dma_alloc`, and not be
+    /// owned by another `DMA`.
+    pub unsafe fn from_raw(paddr: usize, pages: usize) -> Self {
+        DMA {
+            paddr: paddr as u32,
+            pages: pages as u32,
+        }
+    }
+
     pub fn paddr(&self) -> usize {
         self.paddr as usize
     }
@@ -42,7 +56,9 @@ impl DMA {
 impl Drop for DMA {
     fn drop(&mut self) {
         let err = unsafe { virtio_dma_dealloc(self.paddr as usize, self.pages as usize) };
-        assert_eq!(err, 0, "failed to deallocate DMA");
+        if err != 0 {
+            error!("failed to deallocate DMA at {:#x}", self.paddr);
+        }
     }
 }
 
@@ -54,6 +70,111 @@ pub fn virt_to_phys(vaddr: VirtAddr) -> PhysAddr {
     unsafe { virtio_virt_to_phys(vaddr) }
 }
 
+/// An interrupt handler registered with `virtio_irq_register`, which the
+/// HAL calls with the `data` it was registered with.
+#[cfg(feature = "irq-hal")]
+pub type IrqHandlerFn = unsafe extern "C" fn(data: usize);
+
+/// Register `handler` to be called with `data` on interrupt line `irq`.
+#[cfg(feature = "irq-hal")]
+pub fn register_irq(irq: usize, handler: IrqHandlerFn, data: usize) -> Result {
+    match unsafe { virtio_irq_register(irq, handler, data) } {
+        0 => Ok(()),
+        err => {
+            error!("Failed to register handler for IRQ {}: {}", irq, err);
+            Err(Error::IoError)
+        }
+    }
+}
+
+/// Unregister the handler registered with `data` on interrupt line `irq`.
+#[cfg(feature = "irq-hal")]
+pub fn unregister_irq(irq: usize, data: usize) -> Result {
+    match unsafe { virtio_irq_unregister(irq, data) } {
+        0 => Ok(()),
+        err => {
+            error!("Failed to unregister handler for IRQ {}: {}", irq, err);
+            Err(Error::IoError)
+        }
+    }
+}
+
+/// Wait a little for a device, e.g. until the next interrupt.
+///
+/// Without the `wait-hal` feature, this only hints the CPU that it spins.
+pub fn wait() {
+    #[cfg(feature = "wait-hal")]
+    unsafe {
+        virtio_wait();
+    }
+    #[cfg(not(feature = "wait-hal"))]
+    core::hint::spin_loop();
+}
+
+/// The current time in ticks of the HAL clock.
+#[cfg(feature = "time-hal")]
+pub fn ticks() -> u64 {
+    unsafe { virtio_ticks() }
+}
+
+/// Read the I/O port `port` with an access of `width` bytes, 1, 2 or 4,
+/// e.g. with `inb`, `inw` or `inl` on x86.
+#[cfg(feature = "port-io-hal")]
+pub fn port_read(port: u16, width: usize) -> u32 {
+    unsafe { virtio_port_read(port, width as u8) }
+}
+
+/// Write `value` to the I/O port `port` with an access of `width` bytes,
+/// e.g. with `outb`, `outw` or `outl` on x86.
+#[cfg(feature = "port-io-hal")]
+pub fn port_write(port: u16, width: usize, value: u32) {
+    unsafe { virtio_port_write(port, width as u8, value) }
+}
+
+/// Run the channel program at `ccw` on subchannel `schid`, e.g. with
+/// `START SUBCHANNEL`, and wait until it completes, returning the residual
+/// count of its last CCW, or `None` if the subchannel reports an error.
+#[cfg(feature = "ccw-hal")]
+pub fn ccw_start(schid: u32, ccw: PhysAddr) -> Option<usize> {
+    let residual = unsafe { virtio_ccw_start(schid, ccw) };
+    (residual >= 0).then_some(residual as usize)
+}
+
+/// Notify queue `queue` of the device on subchannel `schid`, e.g. with
+/// `DIAGNOSE 0x500` subcode 3.
+#[cfg(feature = "ccw-hal")]
+pub fn ccw_notify(schid: u32, queue: u16) {
+    unsafe { virtio_ccw_notify(schid, queue) }
+}
+
+#[cfg(feature = "irq-hal")]
+extern "C" {
+    fn virtio_irq_register(irq: usize, handler: IrqHandlerFn, data: usize) -> i32;
+    fn virtio_irq_unregister(irq: usize, data: usize) -> i32;
+}
+
+#[cfg(feature = "time-hal")]
+extern "C" {
+    fn virtio_ticks() -> u64;
+}
+
+#[cfg(feature = "wait-hal")]
+extern "C" {
+    fn virtio_wait();
+}
+
+#[cfg(feature = "port-io-hal")]
+extern "C" {
+    fn virtio_port_read(port: u16, width: u8) -> u32;
+    fn virtio_port_write(port: u16, width: u8, value: u32);
+}
+
+#[cfg(feature = "ccw-hal")]
+extern "C" {
+    fn virtio_ccw_start(schid: u32, ccw: PhysAddr) -> i32;
+    fn virtio_ccw_notify(schid: u32, queue: u16);
+}
+
 extern "C" {
     fn virtio_dma_alloc(pages: usize) -> PhysAddr;
     fn virtio_dma_dealloc(paddr: PhysAddr, pages: usize) -> i32;
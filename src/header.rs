@@ -1,41 +1,44 @@
+use crate::endian::*;
+use crate::transport::{DeviceStatus, InterruptStatus, SharedMemoryRegion, Transport};
+use crate::volatile::{ReadOnly, Volatile, WriteOnly};
 use crate::PAGE_SIZE;
-use bitflags::*;
-use volatile::{ReadOnly, Volatile, WriteOnly};
+use core::hint::spin_loop;
 
-/// MMIO Device Legacy Register Interface.
+/// MMIO Device Register Interface, of the legacy interface (version 1) or
+/// the new one (version 2).
 ///
-/// Ref: 4.2.4 Legacy interface
+/// Ref: 4.2.2 MMIO Device Register Layout, 4.2.4 Legacy interface
 #[repr(C)]
 #[derive(Debug)]
 pub struct VirtIOHeader {
     /// Magic value
-    magic: ReadOnly<u32>,
+    magic: ReadOnly<Le32>,
 
     /// Device version number
     ///
-    /// Legacy device returns value 0x1.
-    version: ReadOnly<u32>,
+    /// Legacy device returns value 0x1, others 0x2.
+    version: ReadOnly<Le32>,
 
     /// Virtio Subsystem Device ID
-    device_id: ReadOnly<u32>,
+    device_id: ReadOnly<Le32>,
 
     /// Virtio Subsystem Vendor ID
-    vendor_id: ReadOnly<u32>,
+    vendor_id: ReadOnly<Le32>,
 
     /// Flags representing features the device supports
-    device_features: ReadOnly<u32>,
+    device_features: ReadOnly<Le32>,
 
     /// Device (host) features word selection
-    device_features_sel: WriteOnly<u32>,
+    device_features_sel: WriteOnly<Le32>,
 
     /// Reserved
-    __r1: [ReadOnly<u32>; 2],
+    __r1: [ReadOnly<Le32>; 2],
 
     /// Flags representing device features understood and activated by the driver
-    driver_features: WriteOnly<u32>,
+    driver_features: WriteOnly<Le32>,
 
     /// Activated (guest) features word selection
-    driver_features_sel: WriteOnly<u32>,
+    driver_features_sel: WriteOnly<Le32>,
 
     /// Guest page size
     ///
@@ -43,17 +46,17 @@ pub struct VirtIOHeader {
     /// initialization, before any queues are used. This value should be a
     /// power of 2 and is used by the device to calculate the Guest address
     /// of the first queue page (see QueuePFN).
-    guest_page_size: WriteOnly<u32>,
+    guest_page_size: WriteOnly<Le32>,
 
     /// Reserved
-    __r2: ReadOnly<u32>,
+    __r2: ReadOnly<Le32>,
 
     /// Virtual queue index
     ///
     /// Writing to this register selects the virtual queue that the following
     /// operations on the QueueNumMax, QueueNum, QueueAlign and QueuePFN
     /// registers apply to. The index number of the first queue is zero (0x0).
-    queue_sel: WriteOnly<u32>,
+    queue_sel: WriteOnly<Le32>,
 
     /// Maximum virtual queue size
     ///
@@ -62,21 +65,21 @@ pub struct VirtIOHeader {
     /// This applies to the queue selected by writing to QueueSel and is
     /// allowed only when QueuePFN is set to zero (0x0), so when the queue is
     /// not actively used.
-    queue_num_max: ReadOnly<u32>,
+    queue_num_max: ReadOnly<Le32>,
 
     /// Virtual queue size
     ///
     /// Queue size is the number of elements in the queue. Writing to this
     /// register notifies the device what size of the queue the driver will use.
     /// This applies to the queue selected by writing to QueueSel.
-    queue_num: WriteOnly<u32>,
+    queue_num: WriteOnly<Le32>,
 
     /// Used Ring alignment in the virtual queue
     ///
     /// Writing to this register notifies the device about alignment boundary
     /// of the Used Ring in bytes. This value should be a power of 2 and
     /// applies to the queue selected by writing to QueueSel.
-    queue_align: WriteOnly<u32>,
+    queue_align: WriteOnly<Le32>,
 
     /// Guest physical page number of the virtual queue
     ///
@@ -89,28 +92,28 @@ pub struct VirtIOHeader {
     /// number of the queue, therefore a value other than zero (0x0) means that
     /// the queue is in use. Both read and write accesses apply to the queue
     /// selected by writing to QueueSel.
-    queue_pfn: Volatile<u32>,
+    queue_pfn: Volatile<Le32>,
 
-    /// new interface only
-    queue_ready: Volatile<u32>,
+    /// Whether the device may use the selected queue, new interface only
+    queue_ready: Volatile<Le32>,
 
     /// Reserved
-    __r3: [ReadOnly<u32>; 2],
+    __r3: [ReadOnly<Le32>; 2],
 
     /// Queue notifier
-    queue_notify: WriteOnly<u32>,
+    queue_notify: WriteOnly<Le32>,
 
     /// Reserved
-    __r4: [ReadOnly<u32>; 3],
+    __r4: [ReadOnly<Le32>; 3],
 
     /// Interrupt status
-    interrupt_status: ReadOnly<u32>,
+    interrupt_status: ReadOnly<Le32>,
 
     /// Interrupt acknowledge
-    interrupt_ack: WriteOnly<u32>,
+    interrupt_ack: WriteOnly<Le32>,
 
     /// Reserved
-    __r5: [ReadOnly<u32>; 2],
+    __r5: [ReadOnly<Le32>; 2],
 
     /// Device status
     ///
@@ -119,191 +122,570 @@ pub struct VirtIOHeader {
     /// indicating the OS/driver progress. Writing zero (0x0) to this register
     /// triggers a device reset. The device sets QueuePFN to zero (0x0) for
     /// all queues in the device. Also see 3.1 Device Initialization.
-    status: Volatile<DeviceStatus>,
+    status: Volatile<Le32>,
 
     /// Reserved
-    __r6: [ReadOnly<u32>; 3],
+    __r6: [ReadOnly<Le32>; 3],
 
     // new interface only since here
-    queue_desc_low: WriteOnly<u32>,
-    queue_desc_high: WriteOnly<u32>,
+    /// The physical address of the descriptor table of the selected queue,
+    /// in two halves
+    queue_desc_low: Volatile<Le32>,
+    queue_desc_high: Volatile<Le32>,
 
     /// Reserved
-    __r7: [ReadOnly<u32>; 2],
+    __r7: [ReadOnly<Le32>; 2],
 
-    queue_avail_low: WriteOnly<u32>,
-    queue_avail_high: WriteOnly<u32>,
+    /// The physical address of the driver area, in two halves
+    queue_avail_low: WriteOnly<Le32>,
+    queue_avail_high: WriteOnly<Le32>,
 
     /// Reserved
-    __r8: [ReadOnly<u32>; 2],
+    __r8: [ReadOnly<Le32>; 2],
 
-    queue_used_low: WriteOnly<u32>,
-    queue_used_high: WriteOnly<u32>,
+    /// The physical address of the device area, in two halves
+    queue_used_low: WriteOnly<Le32>,
+    queue_used_high: WriteOnly<Le32>,
 
     /// Reserved
-    __r9: [ReadOnly<u32>; 21],
+    __r9: [ReadOnly<Le32>; 1],
 
-    config_generation: ReadOnly<u32>,
+    /// Shared memory region id selection, new interface only
+    shm_sel: WriteOnly<Le32>,
+
+    /// The length of the selected shared memory region, which is all ones
+    /// if it does not exist, in two halves
+    shm_len_low: ReadOnly<Le32>,
+    shm_len_high: ReadOnly<Le32>,
+
+    /// The physical address of the selected shared memory region, in two
+    /// halves
+    shm_base_low: ReadOnly<Le32>,
+    shm_base_high: ReadOnly<Le32>,
+
+    /// Reserved
+    __r10: [ReadOnly<Le32>; 15],
+
+    /// Configuration atomicity value, new interface only
+    config_generation: ReadOnly<Le32>,
 }
 
 impl VirtIOHeader {
     /// Verify a valid header.
     pub fn verify(&self) -> bool {
-        self.magic.read() == 0x7472_6976 && self.version.read() == 1 && self.device_id.read() != 0
+        self.magic.read().get() == MAGIC_VALUE
+            && matches!(self.version.read().get(), 1 | 2)
+            && self.device_id.read().get() != 0
     }
 
-    /// Get the device type.
-    pub fn device_type(&self) -> DeviceType {
-        match self.device_id.read() {
-            x @ 1..=13 | x @ 16..=24 => unsafe { core::mem::transmute(x as u8) },
-            _ => DeviceType::Invalid,
-        }
+    /// Whether the device has the new interface, version 2, rather than the
+    /// legacy one.
+    fn is_modern(&self) -> bool {
+        self.version.read().get() >= 2
     }
 
     /// Get the vendor ID.
     pub fn vendor_id(&self) -> u32 {
-        self.vendor_id.read()
+        self.vendor_id.read().get()
     }
 
-    /// Begin initializing the device.
+    /// The header of the virtio-mmio device in the region of `size` bytes
+    /// at the physical `address`, mapped with `phys_to_virt` of the HAL, or
+    /// `None` if the region holds no device.
     ///
-    /// Ref: virtio 3.1.1 Device Initialization
-    pub fn begin_init(&mut self, negotiate_features: impl FnOnce(u64) -> u64) {
-        self.status.write(DeviceStatus::ACKNOWLEDGE);
-        self.status.write(DeviceStatus::DRIVER);
-
-        let features = self.read_device_features();
-        self.write_driver_features(negotiate_features(features));
-        self.status.write(DeviceStatus::FEATURES_OK);
-
-        self.guest_page_size.write(PAGE_SIZE as u32);
+    /// Regions without the magic value, or of a version other than the
+    /// legacy one or the new one, are skipped with a warning, and placeholder devices, e.g.
+    /// the unused transports of QEMU machines, silently.
+    ///
+    /// # Safety
+    ///
+    /// The region must be mapped at the address which the HAL translates it
+    /// to, and the device must not be accessed through another header.
+    pub(crate) unsafe fn from_region(address: u64, size: u64) -> Option<&'static mut Self> {
+        if size < CONFIG_SPACE_OFFSET as u64 {
+            warn!(
+                "virtio-mmio region at {:#x} of {:#x} bytes is too small",
+                address, size
+            );
+            return None;
+        }
+        let vaddr = crate::hal::phys_to_virt(address as usize);
+        let header = (vaddr as *mut VirtIOHeader).as_mut()?;
+        let magic = header.magic.read().get();
+        if magic != MAGIC_VALUE {
+            warn!(
+                "No virtio-mmio registers at {:#x}, magic value {:#x}",
+                address, magic
+            );
+            return None;
+        }
+        let version = header.version.read().get();
+        if !matches!(version, 1 | 2) {
+            warn!(
+                "Unsupported version {} of virtio-mmio device at {:#x}",
+                version, address
+            );
+            return None;
+        }
+        // placeholder devices have device ID 0
+        if header.device_id.read().get() == 0 {
+            trace!("No virtio-mmio device at {:#x}", address);
+            return None;
+        }
+        Some(header)
     }
+}
 
-    /// Finish initializing the device.
-    pub fn finish_init(&mut self) {
-        self.status.write(DeviceStatus::DRIVER_OK);
+impl Transport for VirtIOHeader {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::from_id(self.device_id.read().get())
     }
 
-    /// Read device features.
     fn read_device_features(&mut self) -> u64 {
-        self.device_features_sel.write(0); // device features [0, 32)
-        let mut device_features_bits = self.device_features.read().into();
-        self.device_features_sel.write(1); // device features [32, 64)
-        device_features_bits += (self.device_features.read() as u64) << 32;
+        self.device_features_sel.write(0.into()); // device features [0, 32)
+        let mut device_features_bits = self.device_features.read().get().into();
+        self.device_features_sel.write(1.into()); // device features [32, 64)
+        device_features_bits += (self.device_features.read().get() as u64) << 32;
         device_features_bits
     }
 
-    /// Write device features.
+    /// Write the features the driver accepts, with `VIRTIO_F_VERSION_1` for
+    /// devices of the new interface, whose drivers must accept it.
     fn write_driver_features(&mut self, driver_features: u64) {
-        self.driver_features_sel.write(0); // driver features [0, 32)
-        self.driver_features.write(driver_features as u32);
-        self.driver_features_sel.write(1); // driver features [32, 64)
-        self.driver_features.write((driver_features >> 32) as u32);
+        let driver_features = if self.is_modern() {
+            driver_features | VIRTIO_F_VERSION_1
+        } else {
+            driver_features
+        };
+        self.driver_features_sel.write(0.into()); // driver features [0, 32)
+        self.driver_features.write((driver_features as u32).into());
+        self.driver_features_sel.write(1.into()); // driver features [32, 64)
+        self.driver_features
+            .write(((driver_features >> 32) as u32).into());
     }
 
-    /// Set queue.
-    pub fn queue_set(&mut self, queue: u32, size: u32, align: u32, pfn: u32) {
-        self.queue_sel.write(queue);
-        self.queue_num.write(size);
-        self.queue_align.write(align);
-        self.queue_pfn.write(pfn);
+    fn max_queue_size(&mut self, queue: u32) -> u32 {
+        self.queue_sel.write(queue.into());
+        self.queue_num_max.read().get()
     }
 
-    /// Get guest physical page number of the virtual queue.
-    pub fn queue_physical_page_number(&mut self, queue: u32) -> u32 {
-        self.queue_sel.write(queue);
-        self.queue_pfn.read()
+    fn notify(&self, queue: u32) {
+        self.queue_notify.write(queue.into());
     }
 
-    /// Whether the queue is in used.
-    pub fn queue_used(&mut self, queue: u32) -> bool {
-        self.queue_physical_page_number(queue) != 0
+    fn status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_truncate(self.status.read().get())
     }
 
-    /// Get the max size of queue.
-    pub fn max_queue_size(&self) -> u32 {
-        self.queue_num_max.read()
+    fn set_status(&self, status: DeviceStatus) {
+        self.status.write(status.bits().into());
     }
 
-    /// Notify device.
-    pub fn notify(&mut self, queue: u32) {
-        self.queue_notify.write(queue);
+    fn is_legacy(&self) -> bool {
+        !self.is_modern()
     }
 
-    /// Acknowledge interrupt and return true if success.
-    pub fn ack_interrupt(&mut self) -> bool {
-        let interrupt = self.interrupt_status.read();
-        if interrupt != 0 {
-            self.interrupt_ack.write(interrupt);
-            true
-        } else {
-            false
+    /// Set the size of the guest pages, which only the legacy interface has.
+    fn set_guest_page_size(&mut self, guest_page_size: u32) {
+        if !self.is_modern() {
+            self.guest_page_size.write(guest_page_size.into());
         }
     }
 
-    /// Get the pointer to config space (at offset 0x100)
-    pub fn config_space(&self) -> *mut u64 {
-        (self as *const _ as usize + CONFIG_SPACE_OFFSET) as _
+    fn queue_set(
+        &mut self,
+        queue: u32,
+        size: u32,
+        descriptors: usize,
+        driver_area: usize,
+        device_area: usize,
+    ) {
+        if self.is_modern() {
+            self.queue_sel.write(queue.into());
+            self.queue_num.write(size.into());
+            self.queue_desc_low.write((descriptors as u32).into());
+            self.queue_desc_high
+                .write(((descriptors as u64 >> 32) as u32).into());
+            self.queue_avail_low.write((driver_area as u32).into());
+            self.queue_avail_high
+                .write(((driver_area as u64 >> 32) as u32).into());
+            self.queue_used_low.write((device_area as u32).into());
+            self.queue_used_high
+                .write(((device_area as u64 >> 32) as u32).into());
+            self.queue_ready.write(1.into());
+            return;
+        }
+        // the legacy interface finds the rings from the descriptor table
+        debug_assert_eq!(driver_area, descriptors + size as usize * 16);
+        debug_assert_eq!(device_area % PAGE_SIZE, 0);
+        let pfn = (descriptors / PAGE_SIZE) as u32;
+        self.queue_sel.write(queue.into());
+        self.queue_num.write(size.into());
+        self.queue_align.write((PAGE_SIZE as u32).into());
+        self.queue_pfn.write(pfn.into());
     }
-}
 
-bitflags! {
-    /// The device status field.
-    struct DeviceStatus: u32 {
-        /// Indicates that the guest OS has found the device and recognized it
-        /// as a valid virtio device.
-        const ACKNOWLEDGE = 1;
+    /// Stop the device from using `queue`, by clearing `QueueReady` on the
+    /// new interface, which the device acknowledges by reading 0 from it,
+    /// or the address of the queue on the legacy one.
+    fn queue_unset(&mut self, queue: u32) {
+        self.queue_sel.write(queue.into());
+        if !self.is_modern() {
+            self.queue_pfn.write(0.into());
+            return;
+        }
+        self.queue_ready.write(0.into());
+        for _ in 0..QUEUE_UNSET_SPINS {
+            if self.queue_ready.read().get() == 0 {
+                return;
+            }
+            spin_loop();
+        }
+        warn!("Resetting the device to stop it using queue {}", queue);
+        self.reset();
+    }
+
+    /// The address of the descriptor table of `queue`, which the new
+    /// interface reads back from `QueueDescLow` and `QueueDescHigh` while
+    /// the queue is ready.
+    fn queue_descriptors(&mut self, queue: u32) -> usize {
+        self.queue_sel.write(queue.into());
+        if !self.is_modern() {
+            return self.queue_pfn.read().get() as usize * PAGE_SIZE;
+        }
+        if self.queue_ready.read().get() == 0 {
+            return 0;
+        }
+        let low = self.queue_desc_low.read().get() as u64;
+        let high = self.queue_desc_high.read().get() as u64;
+        ((high << 32) | low) as usize
+    }
 
-        /// Indicates that the guest OS knows how to drive the device.
-        const DRIVER = 2;
+    fn ack_interrupt_status(&self) -> InterruptStatus {
+        let interrupt = self.interrupt_status.read().get();
+        if interrupt != 0 {
+            self.interrupt_ack.write(interrupt.into());
+        }
+        InterruptStatus::from_bits_truncate(interrupt)
+    }
 
-        /// Indicates that something went wrong in the guest, and it has given
-        /// up on the device. This could be an internal error, or the driver
-        /// didn’t like the device for some reason, or even a fatal error
-        /// during device operation.
-        const FAILED = 128;
+    /// Get the pointer to config space (at offset 0x100)
+    fn config_space(&self) -> *mut u64 {
+        (self as *const _ as usize + CONFIG_SPACE_OFFSET) as _
+    }
 
-        /// Indicates that the driver has acknowledged all the features it
-        /// understands, and feature negotiation is complete.
-        const FEATURES_OK = 8;
+    /// The shared memory region `id`, which the legacy interface lacks, as
+    /// its registers only exist in the new one.
+    fn shared_memory_region(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        if !self.is_modern() {
+            return None;
+        }
+        self.shm_sel.write((id as u32).into());
+        let len =
+            ((self.shm_len_high.read().get() as u64) << 32) | self.shm_len_low.read().get() as u64;
+        if len == u64::MAX {
+            return None;
+        }
+        let paddr = ((self.shm_base_high.read().get() as u64) << 32)
+            | self.shm_base_low.read().get() as u64;
+        Some(SharedMemoryRegion { paddr, len })
+    }
 
-        /// Indicates that the driver is set up and ready to drive the device.
-        const DRIVER_OK = 4;
+    fn config_generation(&self) -> u32 {
+        if self.is_modern() {
+            self.config_generation.read().get()
+        } else {
+            0
+        }
+    }
 
-        /// Indicates that the device has experienced an error from which it
-        /// can’t recover.
-        const DEVICE_NEEDS_RESET = 64;
+    /// The size of the config space, up to the end of the 0x200 bytes which
+    /// the registers of virtio-mmio devices take.
+    fn config_space_size(&self) -> usize {
+        REGION_SIZE - CONFIG_SPACE_OFFSET
     }
 }
 
 const CONFIG_SPACE_OFFSET: usize = 0x100;
 
+/// Whether the device complies with virtio 1.x, which the drivers of
+/// devices of the new interface must accept.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// How many times to poll `QueueReady` for the device to stop using a queue
+/// before resetting it instead.
+const QUEUE_UNSET_SPINS: usize = 1 << 20;
+
+/// The size of the registers of virtio-mmio devices, with their config space.
+const REGION_SIZE: usize = 0x200;
+
+/// The magic value of the registers, "virt" in little endian.
+const MAGIC_VALUE: u32 = 0x7472_6976;
+
 /// Types of virtio devices.
-#[repr(u8)]
-#[derive(Debug, Eq, PartialEq)]
+///
+/// Ref: virtio 1.3, 5 Device Types
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 #[allow(missing_docs)]
 pub enum DeviceType {
-    Invalid = 0,
-    Network = 1,
-    Block = 2,
-    Console = 3,
-    EntropySource = 4,
-    MemoryBallooning = 5,
-    IoMemory = 6,
-    Rpmsg = 7,
-    ScsiHost = 8,
-    _9P = 9,
-    Mac80211 = 10,
-    RprocSerial = 11,
-    VirtioCAIF = 12,
-    MemoryBalloon = 13,
-    GPU = 16,
-    Timer = 17,
-    Input = 18,
-    Socket = 19,
-    Crypto = 20,
-    SignalDistributionModule = 21,
-    Pstore = 22,
-    IOMMU = 23,
-    Memory = 24,
+    Invalid,
+    Network,
+    Block,
+    Console,
+    EntropySource,
+    MemoryBallooning,
+    IoMemory,
+    Rpmsg,
+    ScsiHost,
+    _9P,
+    Mac80211,
+    RprocSerial,
+    VirtioCAIF,
+    MemoryBalloon,
+    GPU,
+    Timer,
+    Input,
+    Socket,
+    Crypto,
+    SignalDistributionModule,
+    Pstore,
+    IOMMU,
+    Memory,
+    Sound,
+    FileSystem,
+    Pmem,
+    Rpmb,
+    Mac80211Hwsim,
+    VideoEncoder,
+    VideoDecoder,
+    Scmi,
+    NitroSecureModule,
+    I2c,
+    Watchdog,
+    Can,
+    ParameterServer,
+    AudioPolicy,
+    Bluetooth,
+    Gpio,
+    Rdma,
+    Camera,
+    Ism,
+    Spi,
+    /// The wayland device of crosvm, which is not in the spec.
+    Wl,
+    /// A device ID which is not known to this crate.
+    Unknown(u32),
+}
+
+impl DeviceType {
+    /// The type of the device with virtio device ID `id`, which is
+    /// [`Invalid`](Self::Invalid) for 0.
+    pub fn from_id(id: u32) -> DeviceType {
+        match id {
+            0 => DeviceType::Invalid,
+            1 => DeviceType::Network,
+            2 => DeviceType::Block,
+            3 => DeviceType::Console,
+            4 => DeviceType::EntropySource,
+            5 => DeviceType::MemoryBallooning,
+            6 => DeviceType::IoMemory,
+            7 => DeviceType::Rpmsg,
+            8 => DeviceType::ScsiHost,
+            9 => DeviceType::_9P,
+            10 => DeviceType::Mac80211,
+            11 => DeviceType::RprocSerial,
+            12 => DeviceType::VirtioCAIF,
+            13 => DeviceType::MemoryBalloon,
+            16 => DeviceType::GPU,
+            17 => DeviceType::Timer,
+            18 => DeviceType::Input,
+            19 => DeviceType::Socket,
+            20 => DeviceType::Crypto,
+            21 => DeviceType::SignalDistributionModule,
+            22 => DeviceType::Pstore,
+            23 => DeviceType::IOMMU,
+            24 => DeviceType::Memory,
+            25 => DeviceType::Sound,
+            26 => DeviceType::FileSystem,
+            27 => DeviceType::Pmem,
+            28 => DeviceType::Rpmb,
+            29 => DeviceType::Mac80211Hwsim,
+            30 => DeviceType::VideoEncoder,
+            31 => DeviceType::VideoDecoder,
+            32 => DeviceType::Scmi,
+            33 => DeviceType::NitroSecureModule,
+            34 => DeviceType::I2c,
+            35 => DeviceType::Watchdog,
+            36 => DeviceType::Can,
+            38 => DeviceType::ParameterServer,
+            39 => DeviceType::AudioPolicy,
+            40 => DeviceType::Bluetooth,
+            41 => DeviceType::Gpio,
+            42 => DeviceType::Rdma,
+            43 => DeviceType::Camera,
+            44 => DeviceType::Ism,
+            45 => DeviceType::Spi,
+            63 => DeviceType::Wl,
+            id => DeviceType::Unknown(id),
+        }
+    }
+
+    /// The virtio device ID of the type.
+    pub fn id(self) -> u32 {
+        match self {
+            DeviceType::Invalid => 0,
+            DeviceType::Network => 1,
+            DeviceType::Block => 2,
+            DeviceType::Console => 3,
+            DeviceType::EntropySource => 4,
+            DeviceType::MemoryBallooning => 5,
+            DeviceType::IoMemory => 6,
+            DeviceType::Rpmsg => 7,
+            DeviceType::ScsiHost => 8,
+            DeviceType::_9P => 9,
+            DeviceType::Mac80211 => 10,
+            DeviceType::RprocSerial => 11,
+            DeviceType::VirtioCAIF => 12,
+            DeviceType::MemoryBalloon => 13,
+            DeviceType::GPU => 16,
+            DeviceType::Timer => 17,
+            DeviceType::Input => 18,
+            DeviceType::Socket => 19,
+            DeviceType::Crypto => 20,
+            DeviceType::SignalDistributionModule => 21,
+            DeviceType::Pstore => 22,
+            DeviceType::IOMMU => 23,
+            DeviceType::Memory => 24,
+            DeviceType::Sound => 25,
+            DeviceType::FileSystem => 26,
+            DeviceType::Pmem => 27,
+            DeviceType::Rpmb => 28,
+            DeviceType::Mac80211Hwsim => 29,
+            DeviceType::VideoEncoder => 30,
+            DeviceType::VideoDecoder => 31,
+            DeviceType::Scmi => 32,
+            DeviceType::NitroSecureModule => 33,
+            DeviceType::I2c => 34,
+            DeviceType::Watchdog => 35,
+            DeviceType::Can => 36,
+            DeviceType::ParameterServer => 38,
+            DeviceType::AudioPolicy => 39,
+            DeviceType::Bluetooth => 40,
+            DeviceType::Gpio => 41,
+            DeviceType::Rdma => 42,
+            DeviceType::Camera => 43,
+            DeviceType::Ism => 44,
+            DeviceType::Spi => 45,
+            DeviceType::Wl => 63,
+            DeviceType::Unknown(id) => id,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+pub(crate) mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+    use crate::hal::DMA;
+
+    /// A page of registers of a block device of `version`.
+    fn fake_registers(version: u32) -> DMA {
+        fake_mmio_device(version, DeviceType::Block)
+    }
+
+    /// A page of the registers of a virtio-mmio device of `device_type` and
+    /// `version`, whose physical address is that of the page.
+    pub(crate) fn fake_mmio_device(version: u32, device_type: DeviceType) -> DMA {
+        let dma = DMA::new(1).unwrap();
+        let regs = dma.vaddr() as *mut u32;
+        unsafe {
+            regs.write_volatile(MAGIC_VALUE);
+            regs.add(1).write_volatile(version);
+            regs.add(2).write_volatile(device_type.id());
+        }
+        dma
+    }
+
+    fn register(dma: &DMA, offset: usize) -> u32 {
+        unsafe { ((dma.vaddr() + offset) as *const u32).read_volatile() }
+    }
+
+    #[test]
+    fn modern_header_sets_up_queues_by_their_areas() {
+        let dma = fake_registers(2);
+        let header = unsafe { VirtIOHeader::from_region(dma.paddr() as u64, 0x200) }.unwrap();
+        assert!(!header.is_legacy());
+
+        header.write_driver_features(1);
+        // the high half of the features is written last
+        assert_eq!(register(&dma, 0x20), 1);
+        header.set_guest_page_size(PAGE_SIZE as u32);
+        assert_eq!(register(&dma, 0x28), 0);
+
+        let descriptors = 0x1_2345_6000;
+        header.queue_set(0, 16, descriptors, 0x7000, 0x1_0000_8000);
+        assert_eq!(register(&dma, 0x38), 16);
+        assert_eq!(
+            [0x80, 0x84, 0x90, 0x94, 0xa0, 0xa4].map(|offset| register(&dma, offset)),
+            [0x2345_6000, 1, 0x7000, 0, 0x8000, 1]
+        );
+        assert_eq!(register(&dma, 0x44), 1);
+        assert_eq!(register(&dma, 0x40), 0);
+        assert_eq!(header.queue_descriptors(0), descriptors);
+
+        header.queue_unset(0);
+        assert_eq!(register(&dma, 0x44), 0);
+        assert_eq!(header.queue_descriptors(0), 0);
+    }
+
+    #[test]
+    fn legacy_header_sets_up_queues_by_their_page() {
+        let dma = fake_registers(1);
+        let header = unsafe { VirtIOHeader::from_region(dma.paddr() as u64, 0x200) }.unwrap();
+        assert!(header.is_legacy());
+
+        header.write_driver_features(1);
+        assert_eq!(register(&dma, 0x20), 0);
+        let descriptors = 5 * PAGE_SIZE;
+        header.queue_set(0, 16, descriptors, descriptors + 16 * 16, 6 * PAGE_SIZE);
+        assert_eq!(register(&dma, 0x40), 5);
+        assert_eq!(register(&dma, 0x44), 0);
+        assert_eq!(header.queue_descriptors(0), descriptors);
+        header.queue_unset(0);
+        assert_eq!(header.queue_descriptors(0), 0);
+    }
+
+    #[test]
+    fn shared_memory_regions_need_the_new_interface() {
+        let dma = fake_registers(2);
+        let regs = dma.vaddr() as *mut u32;
+        unsafe {
+            // a region of 0x2_0000_0000 bytes at 0x8000_0000
+            regs.add(0xb0 / 4).write_volatile(0);
+            regs.add(0xb4 / 4).write_volatile(2);
+            regs.add(0xb8 / 4).write_volatile(0x8000_0000);
+        }
+        let header = unsafe { VirtIOHeader::from_region(dma.paddr() as u64, 0x200) }.unwrap();
+        let region = header.shared_memory_region(1).unwrap();
+        assert_eq!((region.paddr, region.len), (0x8000_0000, 0x2_0000_0000));
+        assert_eq!(register(&dma, 0xac), 1);
+
+        // the device reports missing regions with a length of all ones
+        unsafe {
+            regs.add(0xb0 / 4).write_volatile(u32::MAX);
+            regs.add(0xb4 / 4).write_volatile(u32::MAX);
+        }
+        assert!(header.shared_memory_region(2).is_none());
+
+        let dma = fake_registers(1);
+        let header = unsafe { VirtIOHeader::from_region(dma.paddr() as u64, 0x200) }.unwrap();
+        assert!(header.shared_memory_region(1).is_none());
+    }
+
+    #[test]
+    fn unknown_versions_are_skipped() {
+        let dma = fake_registers(3);
+        assert!(unsafe { VirtIOHeader::from_region(dma.paddr() as u64, 0x200) }.is_none());
+    }
 }
@@ -0,0 +1,290 @@
+use super::*;
+use crate::queue::VirtQueue;
+use bitflags::*;
+
+/// The virtio mac80211_hwsim device.
+///
+/// It connects the guest's simulated Wi-Fi radios to the wireless medium
+/// simulated on the host, so that Wi-Fi stacks can be exercised without
+/// physical hardware. Frames and their transmission status are exchanged as
+/// the generic netlink messages of the `MAC80211_HWSIM` family.
+pub struct VirtIOHwsim<'a> {
+    header: &'static mut dyn Transport,
+    /// Queue for sending messages to the medium.
+    tx_queue: VirtQueue<'a>,
+    /// Queue for receiving messages from the medium.
+    rx_queue: VirtQueue<'a>,
+    /// DMA area of the RX buffers.
+    rx_buf_dma: DMA,
+    features: Features,
+}
+
+impl VirtIOHwsim<'_> {
+    /// Create a new VirtIO-Hwsim driver.
+    pub fn new(header: &'static mut dyn Transport) -> Result<Self> {
+        Self::from_init(DeviceInit::new(header))
+    }
+
+    /// Create a new VirtIO-Hwsim driver, initializing the device as customized
+    /// by `init`.
+    pub fn from_init(init: DeviceInit<Acknowledged>) -> Result<Self> {
+        let features = Features::from_bits_truncate(init.device_features());
+        info!("Device features {:?}", features);
+        let supported_features = Features::empty();
+        let mut init = init.negotiate(supported_features.bits())?;
+
+        let tx_queue = init.queue(QUEUE_TX, QUEUE_SIZE)?;
+        let mut rx_queue = init.fixed_queue(QUEUE_RX, QUEUE_SIZE)?;
+
+        let rx_buf_dma = DMA::new(pages(RX_BUF_SIZE * QUEUE_SIZE as usize))?;
+        let rx_buf = unsafe { rx_buf_dma.as_buf() };
+        for (i, buf) in rx_buf
+            .chunks_exact_mut(RX_BUF_SIZE)
+            .take(QUEUE_SIZE as usize)
+            .enumerate()
+        {
+            let token = rx_queue.add(&[], &[buf])?;
+            if token != i as u16 {
+                return Err(Error::WrongToken);
+            }
+        }
+
+        let negotiated = init.features();
+        let header = init.finish();
+
+        Ok(VirtIOHwsim {
+            header,
+            tx_queue,
+            rx_queue,
+            rx_buf_dma,
+            features: Features::from_bits_truncate(negotiated),
+        })
+    }
+
+    /// Acknowledge interrupt.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.header.ack_interrupt()
+    }
+
+    /// Suspend the device, e.g. before the guest enters S3.
+    ///
+    /// The device is reset so that it stops using the queues, which are set
+    /// up again by [`resume`](Self::resume). Received messages which have
+    /// not been taken are dropped.
+    pub fn suspend(&mut self) -> Result {
+        self.header.reset();
+        Ok(())
+    }
+
+    /// Resume the device after [`suspend`](Self::suspend), negotiating the
+    /// same features, setting up the queues again and posting the RX
+    /// buffers.
+    ///
+    /// The guest should register as the wireless medium again.
+    pub fn resume(&mut self) -> Result {
+        self.header.begin_reinit(self.features.bits())?;
+        self.tx_queue.reinit(self.header);
+        self.rx_queue.reinit(self.header);
+        let rx_buf = unsafe { self.rx_buf_dma.as_buf() };
+        for buf in rx_buf
+            .chunks_exact_mut(RX_BUF_SIZE)
+            .take(QUEUE_SIZE as usize)
+        {
+            self.rx_queue.add(&[], &[buf])?;
+        }
+        self.header.finish_init();
+        Ok(())
+    }
+
+    /// Send a generic netlink message to the medium, blocking until it is
+    /// consumed.
+    ///
+    /// `msg` is the whole message, starting with the netlink header.
+    pub fn send(&mut self, msg: &[u8]) -> Result {
+        if netlink_len(msg) != Some(msg.len()) {
+            return Err(Error::InvalidParam);
+        }
+        self.tx_queue
+            .add_notify_wait_pop(self.header, &[msg], &[])?;
+        Ok(())
+    }
+
+    /// Whether a received message is pending.
+    pub fn can_recv(&self) -> bool {
+        self.rx_queue.can_pop()
+    }
+
+    /// Receive a generic netlink message from the medium, if any.
+    ///
+    /// The whole message is copied into `buf`. Returns the generic netlink
+    /// command of the message, such as [`HwsimCommand::Frame`] for a frame
+    /// sent to one of the guest's radios, and the length of the message. If
+    /// `buf` is too small, this fails with [`Error::BufferTooSmall`] and the
+    /// message is kept to be received into a larger buffer.
+    pub fn recv(&mut self, buf: &mut [u8]) -> Result<Option<(u8, usize)>> {
+        if !self.rx_queue.can_pop() {
+            return Ok(None);
+        }
+        // look at the message before popping it, to keep it if `buf` is too small
+        let (token, len) = match self.rx_queue.peek_used_with_len() {
+            Ok(used) => used,
+            // pop the invalid element, so that it is not peeked again
+            Err(err) => return self.rx_queue.pop_used().and(Err(err)),
+        };
+        let rx_buf = self.rx_buf(token);
+        let len = (len as usize).min(RX_BUF_SIZE);
+        let msg = &rx_buf[..len];
+        let result = match (netlink_len(msg), genl_command(msg)) {
+            (Some(msg_len), Some(command)) if msg_len <= len => {
+                if buf.len() < msg_len {
+                    Err(Error::BufferTooSmall)
+                } else {
+                    buf[..msg_len].copy_from_slice(&msg[..msg_len]);
+                    Ok(Some((command, msg_len)))
+                }
+            }
+            _ => Err(Error::IoError),
+        };
+        if result == Err(Error::BufferTooSmall) {
+            return result;
+        }
+        self.rx_queue.pop_used()?;
+        // requeue
+        self.rx_queue.add_notify(self.header, &[], &[rx_buf])?;
+        result
+    }
+
+    /// The RX buffer used by the descriptor chain with the token.
+    fn rx_buf(&self, token: u16) -> &'static mut [u8] {
+        let offset = token as usize * RX_BUF_SIZE;
+        unsafe { &mut self.rx_buf_dma.as_buf()[offset..offset + RX_BUF_SIZE] }
+    }
+}
+
+impl Drop for VirtIOHwsim<'_> {
+    fn drop(&mut self) {
+        // stop the device from using the queues, so that they are freed
+        self.header.reset();
+        self.tx_queue.unset(self.header);
+        self.rx_queue.unset(self.header);
+    }
+}
+
+impl Driver for VirtIOHwsim<'_> {
+    fn device_type(&self) -> DeviceType {
+        self.header.device_type()
+    }
+
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
+        self.header.ack_interrupt_status()
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.features.bits()
+    }
+
+    fn enable_notifications(&mut self) {
+        self.tx_queue.set_used_notifications(true);
+        self.rx_queue.set_used_notifications(true);
+    }
+
+    fn disable_notifications(&mut self) {
+        self.tx_queue.set_used_notifications(false);
+        self.rx_queue.set_used_notifications(false);
+    }
+
+    fn metrics(&self, f: &mut dyn FnMut(Metric)) {
+        self.tx_queue.metrics().report("txq", f);
+        self.rx_queue.metrics().report("rxq", f);
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.header.needs_reset()
+    }
+
+    fn recover(&mut self, lost: &mut dyn FnMut(LostRequest)) -> Result {
+        self.tx_queue.report_lost("txq", lost);
+        self.header.reset();
+        self.resume()
+    }
+
+    #[cfg(feature = "time-hal")]
+    fn set_timeout(&mut self, ticks: Option<u64>) {
+        self.tx_queue.set_timeout(ticks);
+        self.rx_queue.set_timeout(ticks);
+    }
+}
+
+/// The generic netlink commands of the `MAC80211_HWSIM` family.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HwsimCommand {
+    /// Register the guest as the wireless medium.
+    Register = 1,
+    /// A frame sent to or from a radio.
+    Frame = 2,
+    /// The transmission status of a frame.
+    TxInfoFrame = 3,
+    /// Create a radio.
+    NewRadio = 4,
+    /// Destroy a radio.
+    DelRadio = 5,
+    /// Query a radio.
+    GetRadio = 6,
+    /// Add a MAC address to a radio.
+    AddMacAddr = 7,
+    /// Remove a MAC address from a radio.
+    DelMacAddr = 8,
+}
+
+/// The length of a netlink message according to its header, or `None` if
+/// the header is truncated or malformed.
+fn netlink_len(msg: &[u8]) -> Option<usize> {
+    let len = msg.get(..4)?;
+    let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    if len < NLMSG_HDR_LEN + GENL_HDR_LEN {
+        return None;
+    }
+    Some(len)
+}
+
+/// The generic netlink command of a message, or `None` if the header is
+/// truncated.
+fn genl_command(msg: &[u8]) -> Option<u8> {
+    msg.get(NLMSG_HDR_LEN).copied()
+}
+
+bitflags! {
+    struct Features: u64 {
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+/// The length of `struct nlmsghdr`.
+const NLMSG_HDR_LEN: usize = 16;
+/// The length of `struct genlmsghdr`.
+const GENL_HDR_LEN: usize = 4;
+
+const QUEUE_TX: usize = 0;
+const QUEUE_RX: usize = 1;
+
+/// The size of an RX buffer, large enough for a full frame and its
+/// attributes.
+const RX_BUF_SIZE: usize = 4096;
+
+// a parameter that can change
+const QUEUE_SIZE: u16 = 8;
@@ -0,0 +1,252 @@
+use super::*;
+use core::marker::PhantomData;
+
+/// The number of queues of a device whose size or MSI-X vector can be
+/// chosen.
+const MAX_QUEUES: usize = 8;
+
+/// The MSI-X vector meaning that no vector is assigned.
+pub const NO_VECTOR: u16 = 0xffff;
+
+/// The feature bit of devices which take queues in the packed layout.
+const VIRTIO_F_RING_PACKED: u64 = 1 << 34;
+
+/// The stage of a [`DeviceInit`] in which the device is acknowledged, and
+/// the features and queues are chosen.
+#[derive(Debug)]
+pub enum Acknowledged {}
+
+/// The stage of a [`DeviceInit`] in which the features are negotiated, and
+/// the driver sets up its queues.
+#[derive(Debug)]
+pub enum FeaturesOk {}
+
+/// The initialization of a device, with its stage as a type parameter so
+/// that the steps happen in the order of the spec.
+///
+/// The caller creates it in the [`Acknowledged`] stage, customizes the
+/// initialization, and hands it to the `from_init` constructor of a driver,
+/// which negotiates the features, sets up its queues and finishes it:
+///
+/// ```ignore
+/// let init = DeviceInit::new(header)
+///     // never negotiate the MAC address, even if the driver supports it
+///     .mask_features(!(1 << 5))
+///     .queue_size(0, 64)?
+///     .queue_size(1, 64)?;
+/// let net = VirtIONet::from_init(init)?;
+/// ```
+///
+/// Ref: virtio 3.1.1 Device Initialization
+#[derive(Debug)]
+pub struct DeviceInit<S> {
+    header: &'static mut dyn Transport,
+    device_features: u64,
+    feature_mask: u64,
+    features: u64,
+    queue_sizes: [Option<u16>; MAX_QUEUES],
+    config_vector: u16,
+    queue_vectors: [u16; MAX_QUEUES],
+    _stage: PhantomData<S>,
+}
+
+impl DeviceInit<Acknowledged> {
+    /// Begin initializing the device with `header` by resetting and
+    /// acknowledging it.
+    pub fn new(header: &'static mut dyn Transport) -> Self {
+        let device_features = header.acknowledge();
+        DeviceInit {
+            header,
+            device_features,
+            feature_mask: u64::MAX,
+            features: 0,
+            queue_sizes: [None; MAX_QUEUES],
+            config_vector: NO_VECTOR,
+            queue_vectors: [NO_VECTOR; MAX_QUEUES],
+            _stage: PhantomData,
+        }
+    }
+
+    /// The type of the device.
+    pub fn device_type(&self) -> DeviceType {
+        self.header.device_type()
+    }
+
+    /// The features offered by the device.
+    pub fn device_features(&self) -> u64 {
+        self.device_features
+    }
+
+    /// Only negotiate the features in `mask`, even if both the device and
+    /// the driver support others.
+    pub fn mask_features(mut self, mask: u64) -> Self {
+        self.feature_mask &= mask;
+        self
+    }
+
+    /// Set up queue `idx` with `size` descriptors instead of the default of
+    /// the driver.
+    ///
+    /// The size must be a power of 2 no larger than the maximum of the
+    /// device, and no larger than 64 without the `alloc` feature, or the
+    /// driver fails to set up the queue. Queues holding a buffer for each
+    /// descriptor, e.g. for received packets, keep the size of their buffers.
+    pub fn queue_size(mut self, idx: usize, size: u16) -> Result<Self> {
+        *self.queue_sizes.get_mut(idx).ok_or(Error::InvalidParam)? = Some(size);
+        Ok(self)
+    }
+
+    /// Assign MSI-X `vector` to configuration changes.
+    ///
+    /// The vectors are assigned when the features are negotiated, and
+    /// those which the transport or the device cannot assign are left
+    /// unassigned with a warning.
+    pub fn config_vector(mut self, vector: u16) -> Self {
+        self.config_vector = vector;
+        self
+    }
+
+    /// Assign MSI-X `vector` to queue `idx`.
+    pub fn queue_vector(mut self, idx: usize, vector: u16) -> Result<Self> {
+        *self.queue_vectors.get_mut(idx).ok_or(Error::InvalidParam)? = vector;
+        Ok(self)
+    }
+
+    /// Negotiate the features offered by the device and `supported` by the
+    /// driver, except those masked by [`mask_features`](Self::mask_features).
+    ///
+    /// Fails with [`Error::FeaturesRejected`] if the device does not accept
+    /// them.
+    pub fn negotiate(mut self, supported: u64) -> Result<DeviceInit<FeaturesOk>> {
+        self.features = self.device_features & supported & self.feature_mask;
+        debug!(
+            "Negotiated features {:#x} of device features {:#x}",
+            self.features, self.device_features
+        );
+        self.header
+            .accept_features(self.features, self.device_features)?;
+        if self.config_vector != NO_VECTOR {
+            if let Err(err) = self.header.set_config_vector(self.config_vector) {
+                warn!(
+                    "Failed to assign MSI-X vector {} to configuration changes: {:?}",
+                    self.config_vector, err
+                );
+                self.config_vector = NO_VECTOR;
+            }
+        }
+        for (idx, vector) in self.queue_vectors.iter_mut().enumerate() {
+            if *vector == NO_VECTOR {
+                continue;
+            }
+            if let Err(err) = self.header.set_queue_vector(idx as u32, *vector) {
+                warn!(
+                    "Failed to assign MSI-X vector {} to queue {}: {:?}",
+                    vector, idx, err
+                );
+                *vector = NO_VECTOR;
+            }
+        }
+        Ok(DeviceInit {
+            header: self.header,
+            device_features: self.device_features,
+            feature_mask: self.feature_mask,
+            features: self.features,
+            queue_sizes: self.queue_sizes,
+            config_vector: self.config_vector,
+            queue_vectors: self.queue_vectors,
+            _stage: PhantomData,
+        })
+    }
+}
+
+impl DeviceInit<FeaturesOk> {
+    /// The negotiated features.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    /// The configuration space of the device, which is null if it is not
+    /// mapped into memory, so prefer [`read_config`](Self::read_config).
+    pub fn config_space(&self) -> *mut u64 {
+        self.header.config_space()
+    }
+
+    /// Read the field of type `T` at `offset` in the configuration space of
+    /// the device, failing with [`Error::InvalidParam`] if it is out of
+    /// bounds or misaligned.
+    pub fn read_config<T: ConfigField>(&self, offset: usize) -> Result<T> {
+        self.header.read_config(offset)
+    }
+
+    /// Read the configuration space of the device with `read`, again until
+    /// the device did not change it concurrently.
+    pub fn read_config_atomic<T>(&self, read: impl FnMut() -> T) -> T {
+        self.header.read_config_atomic(read)
+    }
+
+    /// Set up queue `idx` with the size chosen by the caller, or
+    /// `default_size` descriptors, or the size of the device if the
+    /// transport fixes it.
+    pub(crate) fn queue<'a>(&mut self, idx: usize, default_size: u16) -> Result<VirtQueue<'a>> {
+        let default_size = if self.header.queue_size_fixed() {
+            self.header.max_queue_size(idx as u32) as u16
+        } else {
+            default_size
+        };
+        let size = self
+            .queue_sizes
+            .get(idx)
+            .copied()
+            .flatten()
+            .unwrap_or(default_size);
+        self.new_queue(idx, size)
+    }
+
+    /// Set up queue `idx` with `size` descriptors, whichever size the caller
+    /// chose, for queues whose buffers are allocated for that size.
+    pub(crate) fn fixed_queue<'a>(&mut self, idx: usize, size: u16) -> Result<VirtQueue<'a>> {
+        if let Some(Some(chosen)) = self.queue_sizes.get(idx) {
+            if *chosen != size {
+                warn!(
+                    "Queue {} keeps its size {} instead of {}",
+                    idx, size, chosen
+                );
+            }
+        }
+        self.new_queue(idx, size)
+    }
+
+    /// Set up queue `idx` with `size` descriptors, in the packed layout if
+    /// it was negotiated.
+    ///
+    /// Legacy interfaces only negotiate the first 32 feature bits, so their
+    /// queues are always split.
+    fn new_queue<'a>(&mut self, idx: usize, size: u16) -> Result<VirtQueue<'a>> {
+        if self.features & VIRTIO_F_RING_PACKED != 0 && !self.header.is_legacy() {
+            VirtQueue::new_packed(self.header, idx, size)
+        } else {
+            VirtQueue::new(self.header, idx, size)
+        }
+    }
+
+    /// The MSI-X vector assigned to configuration changes, or [`NO_VECTOR`]
+    /// if none is.
+    ///
+    /// MMIO devices have a single interrupt line, so the vectors only apply
+    /// to transports with MSI-X.
+    pub fn config_vector(&self) -> u16 {
+        self.config_vector
+    }
+
+    /// The MSI-X vector assigned to queue `idx`.
+    pub fn queue_vector(&self, idx: usize) -> u16 {
+        self.queue_vectors.get(idx).copied().unwrap_or(NO_VECTOR)
+    }
+
+    /// Finish initializing the device, handing its header back to the
+    /// driver.
+    pub fn finish(self) -> &'static mut dyn Transport {
+        self.header.finish_init();
+        self.header
+    }
+}
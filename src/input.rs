@@ -1,7 +1,6 @@
 use super::*;
+use crate::volatile::Volatile;
 use bitflags::*;
-use log::*;
-use volatile::Volatile;
 
 /// Virtual human interface devices such as keyboards, mice and tablets.
 ///
@@ -9,41 +8,45 @@ use volatile::Volatile;
 /// Device behavior mirrors that of the evdev layer in Linux,
 /// making pass-through implementations on top of evdev easy.
 pub struct VirtIOInput<'a> {
-    header: &'static mut VirtIOHeader,
+    header: &'static mut dyn Transport,
     event_queue: VirtQueue<'a>,
     status_queue: VirtQueue<'a>,
     event_buf: &'a mut [Event],
     x: i32,
     y: i32,
+    features: Feature,
 }
 
 impl<'a> VirtIOInput<'a> {
     /// Create a new VirtIO-Input driver.
-    pub fn new(header: &'static mut VirtIOHeader, event_buf: &'a mut [u64]) -> Result<Self> {
+    pub fn new(header: &'static mut dyn Transport, event_buf: &'a mut [u64]) -> Result<Self> {
+        Self::from_init(DeviceInit::new(header), event_buf)
+    }
+
+    /// Create a new VirtIO-Input driver, initializing the device as
+    /// customized by `init`.
+    pub fn from_init(init: DeviceInit<Acknowledged>, event_buf: &'a mut [u64]) -> Result<Self> {
         if event_buf.len() < QUEUE_SIZE {
             return Err(Error::BufferTooSmall);
         }
         let event_buf: &mut [Event] = unsafe { core::mem::transmute(event_buf) };
-        header.begin_init(|features| {
-            let features = Feature::from_bits_truncate(features);
-            info!("Device features: {:?}", features);
-            // negotiate these flags only
-            let supported_features = Feature::empty();
-            (features & supported_features).bits()
-        });
-
-        // read configuration space
-        let config = unsafe { &mut *(header.config_space() as *mut Config) };
-        info!("Config: {:?}", config);
-
-        let mut event_queue = VirtQueue::new(header, QUEUE_EVENT, QUEUE_SIZE as u16)?;
-        let status_queue = VirtQueue::new(header, QUEUE_STATUS, QUEUE_SIZE as u16)?;
+        let features = Feature::from_bits_truncate(init.device_features());
+        info!("Device features: {:?}", features);
+        // negotiate these flags only
+        let supported_features = Feature::empty();
+        let mut init = init.negotiate(supported_features.bits())?;
+
+        let mut event_queue = init.fixed_queue(QUEUE_EVENT, QUEUE_SIZE as u16)?;
+        let status_queue = init.queue(QUEUE_STATUS, QUEUE_SIZE as u16)?;
         for (i, event) in event_buf.iter_mut().enumerate() {
             let token = event_queue.add(&[], &[event.as_buf_mut()])?;
             assert_eq!(token, i as u16);
         }
 
-        header.finish_init();
+        let negotiated = init.features();
+        let header = init.finish();
+        // hand the event buffers to the device
+        header.notify(QUEUE_EVENT as u32);
 
         Ok(VirtIOInput {
             header,
@@ -52,6 +55,7 @@ impl<'a> VirtIOInput<'a> {
             event_buf,
             x: 0,
             y: 0,
+            features: Feature::from_bits_truncate(negotiated),
         })
     }
 
@@ -61,7 +65,14 @@ impl<'a> VirtIOInput<'a> {
         if !ack {
             return Ok(false);
         }
-        while let Ok((token, _)) = self.event_queue.pop_used() {
+        self.process_events()?;
+        Ok(true)
+    }
+
+    /// Take the events the device has sent, and post their buffers again.
+    fn process_events(&mut self) -> Result {
+        while self.event_queue.can_pop() {
+            let (token, _) = self.event_queue.pop_used()?;
             let event = &mut self.event_buf[token as usize];
             match EventRepr::from(*event) {
                 EventRepr::RelX(dx) => self.x += dx,
@@ -69,9 +80,34 @@ impl<'a> VirtIOInput<'a> {
                 r => warn!("{:?}", r),
             }
             // requeue
+            self.event_queue
+                .add_notify(self.header, &[], &[event.as_buf_mut()])?;
+        }
+        Ok(())
+    }
+
+    /// Suspend the device, e.g. before the guest enters S3.
+    ///
+    /// The device is reset so that it stops using the queues, which are set
+    /// up again by [`resume`](Self::resume). Pending events are dropped.
+    pub fn suspend(&mut self) -> Result {
+        self.header.reset();
+        Ok(())
+    }
+
+    /// Resume the device after [`suspend`](Self::suspend), negotiating the
+    /// same features, setting up the queues again and posting the event
+    /// buffers.
+    pub fn resume(&mut self) -> Result {
+        self.header.begin_reinit(self.features.bits())?;
+        self.event_queue.reinit(self.header);
+        self.status_queue.reinit(self.header);
+        for event in self.event_buf.iter_mut() {
             self.event_queue.add(&[], &[event.as_buf_mut()])?;
         }
-        Ok(true)
+        self.header.finish_init();
+        self.header.notify(QUEUE_EVENT as u32);
+        Ok(())
     }
 
     /// Get the coordinate of mouse.
@@ -80,6 +116,67 @@ impl<'a> VirtIOInput<'a> {
     }
 }
 
+impl Drop for VirtIOInput<'_> {
+    fn drop(&mut self) {
+        // stop the device from using the queues, so that they are freed
+        self.header.reset();
+        self.event_queue.unset(self.header);
+        self.status_queue.unset(self.header);
+    }
+}
+
+impl Driver for VirtIOInput<'_> {
+    fn device_type(&self) -> DeviceType {
+        self.header.device_type()
+    }
+
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
+        let status = self.header.ack_interrupt_status();
+        if status.contains(InterruptStatus::USED_BUFFER) {
+            // an error requeuing the events still means there was an interrupt
+            if let Err(err) = self.process_events() {
+                warn!("Failed to process input events: {:?}", err);
+            }
+        }
+        status
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.features.bits()
+    }
+
+    fn enable_notifications(&mut self) {
+        self.event_queue.set_used_notifications(true);
+        self.status_queue.set_used_notifications(true);
+    }
+
+    fn disable_notifications(&mut self) {
+        self.event_queue.set_used_notifications(false);
+        self.status_queue.set_used_notifications(false);
+    }
+
+    fn metrics(&self, f: &mut dyn FnMut(Metric)) {
+        self.event_queue.metrics().report("eventq", f);
+        self.status_queue.metrics().report("statusq", f);
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.header.needs_reset()
+    }
+
+    fn recover(&mut self, lost: &mut dyn FnMut(LostRequest)) -> Result {
+        self.status_queue.report_lost("statusq", lost);
+        self.header.reset();
+        self.resume()
+    }
+
+    #[cfg(feature = "time-hal")]
+    fn set_timeout(&mut self, ticks: Option<u64>) {
+        self.event_queue.set_timeout(ticks);
+        self.status_queue.set_timeout(ticks);
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug)]
 enum Cfg {
@@ -0,0 +1,151 @@
+use super::*;
+use core::ptr::NonNull;
+use core::task::Waker;
+
+/// Dispatches device interrupts to the tasks waiting on them.
+///
+/// Each device is registered under an ID chosen by the caller, typically its
+/// interrupt line. Tasks waiting for a device to use buffers in one of its
+/// queues, or for its configuration to change, register a [`Waker`] which is
+/// woken by [`IrqDispatcher::handle_interrupt`] when the device raises an
+/// interrupt for that cause.
+///
+/// `DEVICES` is the maximum number of registered devices and `QUEUES` the
+/// maximum number of queues per device.
+pub struct IrqDispatcher<const DEVICES: usize, const QUEUES: usize> {
+    devices: [Option<IrqDevice<QUEUES>>; DEVICES],
+}
+
+/// A device registered with the dispatcher.
+struct IrqDevice<const QUEUES: usize> {
+    id: usize,
+    header: NonNull<dyn Transport>,
+    queue_wakers: [Option<Waker>; QUEUES],
+    config_waker: Option<Waker>,
+}
+
+// SAFETY: The header pointers are only dereferenced through `&mut self`, and
+// `register` requires them to stay valid, so the dispatcher can be moved to
+// and shared with other cores like a `&mut dyn Transport`.
+unsafe impl<const DEVICES: usize, const QUEUES: usize> Send for IrqDispatcher<DEVICES, QUEUES> {}
+unsafe impl<const DEVICES: usize, const QUEUES: usize> Sync for IrqDispatcher<DEVICES, QUEUES> {}
+
+impl<const DEVICES: usize, const QUEUES: usize> Default for IrqDispatcher<DEVICES, QUEUES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const DEVICES: usize, const QUEUES: usize> IrqDispatcher<DEVICES, QUEUES> {
+    /// Create an empty dispatcher.
+    pub fn new() -> Self {
+        IrqDispatcher {
+            devices: core::array::from_fn(|_| None),
+        }
+    }
+
+    /// Register the device with `header` under `device_id`.
+    ///
+    /// # Safety
+    ///
+    /// `header` must point to the header of a device, which must stay mapped
+    /// until the device is unregistered. The dispatcher acknowledges the
+    /// interrupts of the device, so its driver should not do so as well.
+    pub unsafe fn register(&mut self, device_id: usize, header: *mut dyn Transport) -> Result {
+        let header = NonNull::new(header).ok_or(Error::InvalidParam)?;
+        if self.device_index(device_id).is_some() {
+            return Err(Error::AlreadyUsed);
+        }
+        let slot = self.devices.iter_mut().find(|device| device.is_none());
+        let slot = slot.ok_or(Error::BufferTooSmall)?;
+        *slot = Some(IrqDevice {
+            id: device_id,
+            header,
+            queue_wakers: core::array::from_fn(|_| None),
+            config_waker: None,
+        });
+        Ok(())
+    }
+
+    /// Unregister the device with `device_id`, dropping its wakers.
+    pub fn unregister(&mut self, device_id: usize) -> Result {
+        let index = self.device_index(device_id).ok_or(Error::InvalidParam)?;
+        self.devices[index] = None;
+        Ok(())
+    }
+
+    /// Wake `waker` when the device with `device_id` uses a buffer in
+    /// `queue`.
+    ///
+    /// The waker is woken once, and replaces any waker previously registered
+    /// for the queue.
+    pub fn register_queue_waker(&mut self, device_id: usize, queue: usize, waker: Waker) -> Result {
+        let device = self.device_mut(device_id)?;
+        let slot = device
+            .queue_wakers
+            .get_mut(queue)
+            .ok_or(Error::InvalidParam)?;
+        *slot = Some(waker);
+        Ok(())
+    }
+
+    /// Wake `waker` when the configuration of the device with `device_id`
+    /// changes.
+    ///
+    /// The waker is woken once, and replaces any waker previously registered
+    /// for configuration changes.
+    pub fn register_config_waker(&mut self, device_id: usize, waker: Waker) -> Result {
+        self.device_mut(device_id)?.config_waker = Some(waker);
+        Ok(())
+    }
+
+    /// Handle an interrupt raised by the device with `device_id`.
+    ///
+    /// Acknowledges the interrupt and wakes the wakers registered for its
+    /// causes. As the interrupt status does not tell which queue the device
+    /// used, all queue wakers of the device are woken on a used buffer
+    /// notification. Returns the causes of the interrupt, which are empty if
+    /// the device did not raise it.
+    pub fn handle_interrupt(&mut self, device_id: usize) -> Result<InterruptStatus> {
+        let device = self.device_mut(device_id)?;
+        let status = unsafe { device.header.as_ref().ack_interrupt_status() };
+        if status.contains(InterruptStatus::USED_BUFFER) {
+            for waker in device.queue_wakers.iter_mut().filter_map(Option::take) {
+                waker.wake();
+            }
+        }
+        if status.contains(InterruptStatus::CONFIG_CHANGE) {
+            if let Some(waker) = device.config_waker.take() {
+                waker.wake();
+            }
+        }
+        Ok(status)
+    }
+
+    /// Handle a shared interrupt line by polling all registered devices.
+    ///
+    /// Returns whether any device raised the interrupt.
+    pub fn handle_shared_interrupt(&mut self) -> bool {
+        let mut handled = false;
+        for index in 0..DEVICES {
+            if let Some(id) = self.devices[index].as_ref().map(|device| device.id) {
+                handled |= matches!(self.handle_interrupt(id), Ok(status) if !status.is_empty());
+            }
+        }
+        handled
+    }
+
+    fn device_index(&self, device_id: usize) -> Option<usize> {
+        self.devices
+            .iter()
+            .position(|device| matches!(device, Some(device) if device.id == device_id))
+    }
+
+    fn device_mut(&mut self, device_id: usize) -> Result<&mut IrqDevice<QUEUES>> {
+        self.devices
+            .iter_mut()
+            .flatten()
+            .find(|device| device.id == device_id)
+            .ok_or(Error::InvalidParam)
+    }
+}
@@ -0,0 +1,141 @@
+use super::*;
+use core::cell::UnsafeCell;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+/// Handles the interrupts of a device through the interrupt registration of
+/// the HAL, so that tasks can wait for the device instead of polling it.
+///
+/// Once registered for the interrupt line of a device, the handler
+/// acknowledges each interrupt of the device, records its causes and wakes
+/// the task waiting in [`poll_interrupt`](Self::poll_interrupt). It lives in
+/// static storage, as the HAL keeps a pointer to it:
+///
+/// ```ignore
+/// static BLK_IRQ: IrqHandler = IrqHandler::new();
+///
+/// unsafe { BLK_IRQ.register(irq, header as *mut dyn Transport)? };
+/// let blk = VirtIOBlk::new(header)?;
+/// ```
+///
+/// The HAL must implement `virtio_irq_register` and `virtio_irq_unregister`,
+/// which exist with the `irq-hal` feature:
+///
+/// ```ignore
+/// #[no_mangle]
+/// extern "C" fn virtio_irq_register(irq: usize, handler: IrqHandlerFn, data: usize) -> i32;
+/// #[no_mangle]
+/// extern "C" fn virtio_irq_unregister(irq: usize, data: usize) -> i32;
+/// ```
+pub struct IrqHandler {
+    /// Whether the handler is [`FREE`], being registered or [`REGISTERED`].
+    state: AtomicU8,
+    /// The device, which is only written while the handler is being
+    /// registered, and read while it is registered.
+    header: UnsafeCell<Option<NonNull<dyn Transport>>>,
+    irq: AtomicUsize,
+    /// The causes of the interrupts not yet taken.
+    status: AtomicU32,
+    waker: WakerCell,
+}
+
+/// The states of an [`IrqHandler`].
+const FREE: u8 = 0;
+const REGISTERING: u8 = 1;
+const REGISTERED: u8 = 2;
+
+// SAFETY: `header` is only written by the single caller of `register` which
+// moved the handler out of `FREE`, and the device is only accessed through
+// the `&self` methods of the transport, which may be called concurrently.
+unsafe impl Send for IrqHandler {}
+unsafe impl Sync for IrqHandler {}
+
+impl IrqHandler {
+    /// Create a handler which is not registered.
+    pub const fn new() -> Self {
+        IrqHandler {
+            state: AtomicU8::new(FREE),
+            header: UnsafeCell::new(None),
+            irq: AtomicUsize::new(0),
+            status: AtomicU32::new(0),
+            waker: WakerCell::new(),
+        }
+    }
+
+    /// Register the handler with the HAL for interrupt line `irq` of the
+    /// device with `header`.
+    ///
+    /// # Safety
+    ///
+    /// `header` must point to the header of a device, which must stay mapped
+    /// until the handler is unregistered. The handler acknowledges the
+    /// interrupts of the device, so its driver should not do so as well.
+    pub unsafe fn register(&'static self, irq: usize, header: *mut dyn Transport) -> Result {
+        let header = NonNull::new(header).ok_or(Error::InvalidParam)?;
+        if self
+            .state
+            .compare_exchange(FREE, REGISTERING, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return Err(Error::AlreadyUsed);
+        }
+        *self.header.get() = Some(header);
+        self.irq.store(irq, Ordering::Release);
+        self.status.store(0, Ordering::Release);
+        self.state.store(REGISTERED, Ordering::Release);
+        if let Err(err) = register_irq(irq, Self::handle, self as *const Self as usize) {
+            self.state.store(FREE, Ordering::Release);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Unregister the handler from the HAL.
+    pub fn unregister(&'static self) -> Result {
+        if self.state.load(Ordering::Acquire) != REGISTERED {
+            return Err(Error::NotReady);
+        }
+        let irq = self.irq.load(Ordering::Acquire);
+        unregister_irq(irq, self as *const Self as usize)?;
+        self.state.store(FREE, Ordering::Release);
+        Ok(())
+    }
+
+    /// Take the causes of the interrupts since they were last taken.
+    pub fn take_status(&self) -> InterruptStatus {
+        InterruptStatus::from_bits_truncate(self.status.swap(0, Ordering::AcqRel))
+    }
+
+    /// Wait for an interrupt, returning its causes once the device raised
+    /// one.
+    pub fn poll_interrupt(&self, cx: &mut Context<'_>) -> Poll<InterruptStatus> {
+        self.waker.poll_with(cx, || {
+            let status = self.take_status();
+            (!status.is_empty()).then_some(status)
+        })
+    }
+
+    /// Called by the HAL on an interrupt, with the handler as `data`.
+    unsafe extern "C" fn handle(data: usize) {
+        let this = &*(data as *const Self);
+        if this.state.load(Ordering::Acquire) != REGISTERED {
+            return;
+        }
+        let header = match *this.header.get() {
+            Some(header) => header,
+            None => return,
+        };
+        let status = header.as_ref().ack_interrupt_status();
+        if !status.is_empty() {
+            this.status.fetch_or(status.bits(), Ordering::AcqRel);
+            this.waker.wake();
+        }
+    }
+}
+
+impl Default for IrqHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
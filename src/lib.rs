@@ -2,26 +2,127 @@
 
 #![no_std]
 #![deny(unused_must_use, missing_docs)]
+// errors from the device are reported to the caller rather than panicking
+#![deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::todo,
+    clippy::unimplemented
+)]
 #![allow(clippy::identity_op)]
+#![allow(clippy::upper_case_acronyms)]
 #![allow(dead_code)]
 
-// #[macro_use]
-extern crate log;
+#[cfg(feature = "alloc")]
+extern crate alloc;
 
+#[macro_use]
+mod logging;
+
+mod acpi;
+#[cfg(feature = "blk")]
 mod blk;
+#[cfg(feature = "bluetooth")]
+mod bluetooth;
+#[cfg(feature = "can")]
+mod can;
+#[cfg(feature = "ccw-hal")]
+mod ccw;
+mod device_tree;
+mod dmabuf;
+mod driver;
+mod endian;
+#[cfg(feature = "gpu")]
 mod gpu;
 mod hal;
 mod header;
+#[cfg(feature = "hwsim")]
+mod hwsim;
+mod init;
+#[cfg(feature = "input")]
 mod input;
+mod irq;
+#[cfg(feature = "irq-hal")]
+mod irq_hal;
+mod manager;
+mod metrics;
+#[cfg(feature = "net")]
 mod net;
+mod pci;
+#[cfg(feature = "pmem")]
+mod pmem;
+mod pool;
+mod probe;
 mod queue;
+#[cfg(feature = "scmi")]
+mod scmi;
+#[cfg(feature = "sound")]
+mod sound;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod transport;
+#[cfg(feature = "video")]
+mod video;
+mod volatile;
+mod wait;
+#[cfg(feature = "wl")]
+mod wl;
 
-pub use self::blk::VirtIOBlk;
+pub use self::acpi::{acpi_virtio_mmio_devices, AcpiDevice};
+#[cfg(feature = "blk")]
+pub use self::blk::{BlkFuture, BlkReq, BlkResp, BlkState, VirtIOBlk};
+#[cfg(feature = "bluetooth")]
+pub use self::bluetooth::{HciPacketType, VirtIOBluetooth};
+#[cfg(feature = "can")]
+pub use self::can::{BusState, CanFilter, CanFrame, VirtIOCan};
+#[cfg(feature = "ccw-hal")]
+pub use self::ccw::CcwTransport;
+pub use self::device_tree::{virtio_mmio_devices, DtNode};
+pub use self::dmabuf::{BufferDirection, DmaBuf};
+pub use self::driver::{Driver, LostRequest};
+pub use self::endian::{Le16, Le32, Le64};
+#[cfg(feature = "gpu")]
 pub use self::gpu::VirtIOGpu;
+#[cfg(feature = "irq-hal")]
+pub use self::hal::IrqHandlerFn;
 pub use self::header::*;
+#[cfg(feature = "hwsim")]
+pub use self::hwsim::{HwsimCommand, VirtIOHwsim};
+pub use self::init::{Acknowledged, DeviceInit, FeaturesOk, NO_VECTOR};
+#[cfg(feature = "input")]
 pub use self::input::VirtIOInput;
-pub use self::net::VirtIONet;
+pub use self::irq::IrqDispatcher;
+#[cfg(feature = "irq-hal")]
+pub use self::irq_hal::IrqHandler;
+pub use self::manager::{AddError, DeviceManager};
+pub use self::metrics::{Metric, MetricKind, QueueMetrics};
+#[cfg(feature = "net")]
+pub use self::net::{NetState, VirtIONet, VirtIONetRx, VirtIONetTx};
+pub use self::pci::{PciAddress, PciRoot, PciTransport};
+#[cfg(feature = "pmem")]
+pub use self::pmem::VirtIOPmem;
+pub use self::pool::DmaPool;
+pub use self::probe::{probe, DeviceKind};
 use self::queue::VirtQueue;
+#[cfg(feature = "scmi")]
+pub use self::scmi::{ScmiEvent, ScmiProtocol, VirtIOScmi};
+#[cfg(feature = "sound")]
+pub use self::sound::{
+    ChmapInfo, Direction, JackFeatures, JackInfo, PcmFeatures, PcmFormat, PcmInfo, PcmParameters,
+    PcmRate, PeriodElapsed, SoundEvent, VirtIOSound,
+};
+pub use self::transport::{
+    ConfigField, DeviceStatus, InterruptStatus, SharedMemoryRegion, Transport,
+};
+#[cfg(feature = "video")]
+pub use self::video::{
+    BufferFlags, Crop, DequeuedBuffer, MemEntry, PlaneFormat, QueueType, VideoControl, VideoEvent,
+    VideoFormat, VideoParams, VirtIOVideo,
+};
+pub use self::wait::{TokenWakers, Wait, WaitQueue, WakerCell};
+#[cfg(feature = "wl")]
+pub use self::wl::{VfdFlags, VfdInfo, VirtIOWl, WlEvent};
 use core::mem::size_of;
 use hal::*;
 
@@ -36,7 +137,8 @@ pub type Result<T = ()> = core::result::Result<T, Error>;
 // }
 
 /// The error type of VirtIO drivers.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
 pub enum Error {
     /// The buffer is too small.
     BufferTooSmall,
@@ -50,8 +152,78 @@ pub enum Error {
     DmaError,
     /// I/O Error
     IoError,
+    /// The block device failed a request with the status.
+    BlkStatus(u8),
+    /// The GPU sent a response of an unexpected type.
+    GpuResponse(u32),
+    /// The sound device failed a request with the status code.
+    SoundStatus(u32),
+    /// The SCMI platform failed a command with the status.
+    ScmiStatus(i32),
+    /// The device did not use the buffers in time.
+    Timeout,
+    /// A chain has more buffers than the queue takes.
+    ChainTooLong,
+    /// The device did not set FEATURES_OK, rejecting the features of the
+    /// driver: those it does not offer, or all of them if it rejected their
+    /// combination.
+    FeaturesRejected(u64),
+    /// The device was reset before it used the buffers of the request.
+    DeviceReset,
+    /// The device used a descriptor chain which was not in flight, e.g. one
+    /// it used already.
+    WrongToken,
+}
+
+/// A change of the configuration of a device, reported by the
+/// `config_change` method of its driver.
+///
+/// Devices signal changes with [`InterruptStatus::CONFIG_CHANGE`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ConfigChange {
+    /// The capacity of a block device changed to the number of 512-byte
+    /// sectors.
+    Capacity(u64),
+    /// The link of a network card went up or down.
+    Link {
+        /// Whether the link is up.
+        up: bool,
+    },
+    /// The displays of a GPU changed, so its display info should be queried
+    /// again.
+    Display,
+    /// The state of the bus of a CAN controller changed.
+    #[cfg(feature = "can")]
+    BusState(BusState),
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Error::BufferTooSmall => write!(f, "buffer too small"),
+            Error::NotReady => write!(f, "device not ready"),
+            Error::AlreadyUsed => write!(f, "queue already in use"),
+            Error::InvalidParam => write!(f, "invalid parameter"),
+            Error::DmaError => write!(f, "failed to allocate DMA memory"),
+            Error::IoError => write!(f, "I/O error"),
+            Error::BlkStatus(status) => write!(f, "block request failed with status {}", status),
+            Error::GpuResponse(type_) => write!(f, "unexpected GPU response {:#x}", type_),
+            Error::SoundStatus(code) => write!(f, "sound request failed with status {:#x}", code),
+            Error::ScmiStatus(status) => write!(f, "SCMI command failed with status {}", status),
+            Error::Timeout => write!(f, "timed out waiting for the device"),
+            Error::ChainTooLong => write!(f, "descriptor chain too long"),
+            Error::FeaturesRejected(features) => {
+                write!(f, "device rejected features {:#x}", features)
+            }
+            Error::DeviceReset => write!(f, "device reset before completing the request"),
+            Error::WrongToken => write!(f, "device used a chain not in flight"),
+        }
+    }
 }
 
+impl core::error::Error for Error {}
+
 /// Align `size` up to a page.
 fn align_up(size: usize) -> usize {
     (size + PAGE_SIZE) & !(PAGE_SIZE - 1)
@@ -59,10 +231,15 @@ fn align_up(size: usize) -> usize {
 
 /// Pages of `size`.
 fn pages(size: usize) -> usize {
-    (size + PAGE_SIZE - 1) / PAGE_SIZE
+    size.div_ceil(PAGE_SIZE)
 }
 
 /// Convert a struct into buffer.
+///
+/// # Safety
+///
+/// The implementor must be a `#[repr(C)]` plain-old-data type which is valid
+/// for any bit pattern.
 unsafe trait AsBuf: Sized {
     fn as_buf(&self) -> &[u8] {
         unsafe { core::slice::from_raw_parts(self as *const _ as _, size_of::<Self>()) }
@@ -71,3 +248,50 @@ unsafe trait AsBuf: Sized {
         unsafe { core::slice::from_raw_parts_mut(self as *mut _ as _, size_of::<Self>()) }
     }
 }
+
+// The drivers own their queues and DMA memory, and only touch them through
+// `&mut self`, so they can be moved to and shared between cores. Callers
+// serialize access to a driver with whatever lock fits their kernel.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<VirtQueue>();
+    #[cfg(feature = "blk")]
+    assert_send_sync::<VirtIOBlk>();
+    #[cfg(feature = "bluetooth")]
+    assert_send_sync::<VirtIOBluetooth>();
+    #[cfg(feature = "can")]
+    assert_send_sync::<VirtIOCan>();
+    #[cfg(feature = "gpu")]
+    assert_send_sync::<VirtIOGpu>();
+    #[cfg(feature = "hwsim")]
+    assert_send_sync::<VirtIOHwsim>();
+    #[cfg(feature = "input")]
+    assert_send_sync::<VirtIOInput>();
+    #[cfg(feature = "net")]
+    assert_send_sync::<VirtIONet>();
+    #[cfg(feature = "net")]
+    assert_send_sync::<VirtIONetRx>();
+    #[cfg(feature = "net")]
+    assert_send_sync::<VirtIONetTx>();
+    #[cfg(feature = "pmem")]
+    assert_send_sync::<VirtIOPmem>();
+    #[cfg(feature = "scmi")]
+    assert_send_sync::<VirtIOScmi>();
+    #[cfg(feature = "sound")]
+    assert_send_sync::<VirtIOSound>();
+    #[cfg(feature = "video")]
+    assert_send_sync::<VirtIOVideo>();
+    #[cfg(feature = "wl")]
+    assert_send_sync::<VirtIOWl>();
+    assert_send_sync::<DeviceKind>();
+    assert_send_sync::<IrqDispatcher<1, 1>>();
+    assert_send_sync::<DeviceManager<1>>();
+    assert_send_sync::<DmaPool<1>>();
+    assert_send_sync::<DmaBuf>();
+    #[cfg(feature = "irq-hal")]
+    assert_send_sync::<IrqHandler>();
+    assert_send_sync::<WakerCell>();
+    assert_send_sync::<TokenWakers<1>>();
+    assert_send_sync::<WaitQueue>();
+    assert_send_sync::<Wait>();
+};
@@ -0,0 +1,31 @@
+//! Logging macros which forward to the `log` crate if the `log` feature is
+//! enabled, and compile to nothing otherwise.
+
+macro_rules! log {
+    ($level:ident, $($arg:tt)+) => {{
+        #[cfg(feature = "log")]
+        ::log::$level!($($arg)+);
+        #[cfg(not(feature = "log"))]
+        let _ = format_args!($($arg)+);
+    }};
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { log!(error, $($arg)+) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { log!(warn, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { log!(info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { log!(debug, $($arg)+) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => { log!(trace, $($arg)+) };
+}
@@ -0,0 +1,165 @@
+use super::*;
+
+/// Owns the probed devices and routes platform interrupts to them.
+///
+/// Each device is added with the interrupt line it is wired to, which may be
+/// shared with other devices, and is then identified by the index returned
+/// by [`DeviceManager::add`].
+///
+/// `DEVICES` is the maximum number of devices.
+pub struct DeviceManager<'a, const DEVICES: usize> {
+    devices: [Option<ManagedDevice<'a>>; DEVICES],
+}
+
+/// A device owned by the manager.
+struct ManagedDevice<'a> {
+    irq: usize,
+    device: DeviceKind<'a>,
+}
+
+impl<const DEVICES: usize> Default for DeviceManager<'_, DEVICES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, const DEVICES: usize> DeviceManager<'a, DEVICES> {
+    /// Create an empty manager.
+    pub fn new() -> Self {
+        DeviceManager {
+            devices: core::array::from_fn(|_| None),
+        }
+    }
+
+    /// Probe the device with `header`, wired to interrupt line `irq`, and
+    /// add it to the manager.
+    ///
+    /// Returns the index of the device, or hands the header back if the
+    /// manager is full or probing the device failed.
+    pub fn add(
+        &mut self,
+        irq: usize,
+        header: &'static mut dyn Transport,
+    ) -> core::result::Result<usize, AddError> {
+        let Some(index) = self.devices.iter().position(Option::is_none) else {
+            return Err(AddError::Full(header));
+        };
+        let header: *mut dyn Transport = header;
+        // SAFETY: The driver which `probe` creates from the header is
+        // dropped when it fails, so the header is no longer borrowed then.
+        match probe(unsafe { &mut *header }) {
+            Ok(device) => {
+                self.devices[index] = Some(ManagedDevice { irq, device });
+                Ok(index)
+            }
+            Err(err) => Err(AddError::Probe(err, unsafe { &mut *header })),
+        }
+    }
+
+    /// Remove the device at `index` from the manager, handing it back.
+    pub fn remove(&mut self, index: usize) -> Option<DeviceKind<'a>> {
+        let device = self.devices.get_mut(index)?.take()?;
+        Some(device.device)
+    }
+
+    /// The device at `index`.
+    pub fn get(&mut self, index: usize) -> Option<&mut DeviceKind<'a>> {
+        let device = self.devices.get_mut(index)?.as_mut()?;
+        Some(&mut device.device)
+    }
+
+    /// The `n`th device of `device_type`, in the order they were added.
+    pub fn find(&mut self, device_type: DeviceType, n: usize) -> Option<&mut DeviceKind<'a>> {
+        self.devices
+            .iter_mut()
+            .flatten()
+            .map(|device| &mut device.device)
+            .filter(|device| device.device_type() == device_type)
+            .nth(n)
+    }
+
+    /// The number of devices.
+    pub fn len(&self) -> usize {
+        self.devices.iter().flatten().count()
+    }
+
+    /// Whether there are no devices.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Handle an interrupt on line `irq`.
+    ///
+    /// Dispatches the interrupt to the driver of each device wired to the
+    /// line through [`Driver::handle_interrupt`], and calls `f` with the
+    /// index of each device which raised it and its causes, so that the
+    /// caller can process the rest, e.g. its configuration changes. Returns
+    /// whether any device raised it.
+    ///
+    /// A device which signals that it needs to be reset is
+    /// [recovered](Driver::recover) before `f` is called, and the requests
+    /// it lost are reported to `lost` with its index.
+    pub fn handle_irq(
+        &mut self,
+        irq: usize,
+        mut f: impl FnMut(usize, &mut DeviceKind<'a>, InterruptStatus),
+        mut lost: impl FnMut(usize, LostRequest),
+    ) -> bool {
+        let mut handled = false;
+        for (index, slot) in self.devices.iter_mut().enumerate() {
+            let device = match slot {
+                Some(device) if device.irq == irq => &mut device.device,
+                _ => continue,
+            };
+            let status = match &mut *device {
+                DeviceKind::Other(_, header) => header.ack_interrupt_status(),
+                device => device
+                    .as_driver()
+                    .map_or(InterruptStatus::empty(), |driver| driver.handle_interrupt()),
+            };
+            if status.contains(InterruptStatus::CONFIG_CHANGE) {
+                match &mut *device {
+                    DeviceKind::Other(_, header) => {
+                        if header.needs_reset() {
+                            warn!("device {} needs to be reset, but has no driver", index);
+                        }
+                    }
+                    device => {
+                        if let Some(driver) = device.as_driver().filter(|d| d.needs_reset()) {
+                            warn!("device {} needs to be reset, recovering it", index);
+                            if let Err(err) = driver.recover(&mut |request| lost(index, request)) {
+                                error!("failed to recover device {}: {:?}", index, err);
+                            }
+                        }
+                    }
+                }
+            }
+            if !status.is_empty() {
+                f(index, device, status);
+                handled = true;
+            }
+        }
+        if !handled {
+            trace!("no device raised interrupt {}", irq);
+        }
+        handled
+    }
+}
+
+/// Why [`DeviceManager::add`] failed, handing back the header of the device.
+#[derive(Debug)]
+pub enum AddError {
+    /// The manager already holds as many devices as it can.
+    Full(&'static mut dyn Transport),
+    /// Probing the device failed with the error.
+    Probe(Error, &'static mut dyn Transport),
+}
+
+impl AddError {
+    /// The header of the device which was not added.
+    pub fn into_header(self) -> &'static mut dyn Transport {
+        match self {
+            AddError::Full(header) | AddError::Probe(_, header) => header,
+        }
+    }
+}
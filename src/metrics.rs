@@ -0,0 +1,66 @@
+//! Counters and gauges published by the queues and drivers.
+
+/// Whether a metric only ever increases, or goes up and down.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MetricKind {
+    /// A value which only increases, e.g. the number of bytes transferred.
+    Counter,
+    /// A value which goes up and down, e.g. the number of buffers in a queue.
+    Gauge,
+}
+
+/// A value published by a driver, see [`Driver::metrics`](crate::Driver::metrics).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Metric {
+    /// What the metric belongs to, e.g. the name of a queue.
+    pub source: &'static str,
+    /// The name of the metric.
+    pub name: &'static str,
+    /// Whether it is a counter or a gauge.
+    pub kind: MetricKind,
+    /// The value when the metric was read.
+    pub value: u64,
+}
+
+/// A snapshot of the statistics of a queue.
+///
+/// The counters start at 0 when the queue is created or restored, and are
+/// kept when it is set up again.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct QueueMetrics {
+    /// The number of descriptors currently in use.
+    pub depth: u16,
+    /// The number of descriptor chains made available to the device.
+    pub added: u64,
+    /// The number of descriptor chains used by the device.
+    pub used: u64,
+    /// The number of bytes in the buffers made available for the device to
+    /// read.
+    pub bytes_out: u64,
+    /// The number of bytes the device reported writing to used buffers.
+    pub bytes_in: u64,
+    /// The number of invalid elements in the used ring.
+    pub errors: u64,
+}
+
+impl QueueMetrics {
+    /// Report each statistic to `f` as a metric of `source`.
+    pub fn report(&self, source: &'static str, f: &mut dyn FnMut(Metric)) {
+        let metrics = [
+            ("depth", MetricKind::Gauge, self.depth as u64),
+            ("added", MetricKind::Counter, self.added),
+            ("used", MetricKind::Counter, self.used),
+            ("bytes_out", MetricKind::Counter, self.bytes_out),
+            ("bytes_in", MetricKind::Counter, self.bytes_in),
+            ("errors", MetricKind::Counter, self.errors),
+        ];
+        for (name, kind, value) in metrics {
+            f(Metric {
+                source,
+                name,
+                kind,
+                value,
+            });
+        }
+    }
+}
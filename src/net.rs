@@ -1,10 +1,11 @@
 use core::mem::{size_of, MaybeUninit};
 
 use super::*;
+use crate::queue::{QueueBuf, QueueState};
+use crate::volatile::{ReadOnly, Volatile};
 use bitflags::*;
-use core::sync::atomic::spin_loop_hint;
-use log::*;
-use volatile::{ReadOnly, Volatile};
+use core::mem::offset_of;
+use core::slice;
 
 /// The virtio network device is a virtual ethernet card.
 ///
@@ -14,45 +15,168 @@ use volatile::{ReadOnly, Volatile};
 /// outgoing packets are enqueued into another for transmission in that order.
 /// A third command queue is used to control advanced filtering features.
 pub struct VirtIONet<'a> {
-    header: &'static mut VirtIOHeader,
+    header: &'static mut dyn Transport,
     mac: EthernetAddress,
     recv_queue: VirtQueue<'a>,
     send_queue: VirtQueue<'a>,
+    features: Features,
+    /// The status when it was last read.
+    status: Status,
 }
 
 impl VirtIONet<'_> {
     /// Create a new VirtIO-Net driver.
-    pub fn new(header: &'static mut VirtIOHeader) -> Result<Self> {
-        header.begin_init(|features| {
-            let features = Features::from_bits_truncate(features);
-            info!("Device features {:?}", features);
-            let supported_features = Features::MAC | Features::STATUS;
-            (features & supported_features).bits()
-        });
+    pub fn new(header: &'static mut dyn Transport) -> Result<Self> {
+        Self::from_init(DeviceInit::new(header))
+    }
+
+    /// Create a new VirtIO-Net driver, initializing the device as customized
+    /// by `init`.
+    pub fn from_init(init: DeviceInit<Acknowledged>) -> Result<Self> {
+        let features = Features::from_bits_truncate(init.device_features());
+        info!("Device features {:?}", features);
+        let supported_features = Features::MAC
+            | Features::STATUS
+            | Features::RING_INDIRECT_DESC
+            | Features::VERSION_1
+            | Features::ORDER_PLATFORM
+            | Features::RING_PACKED
+            | Features::RING_RESET;
+        let mut init = init.negotiate(supported_features.bits())?;
         // read configuration space
-        let config = unsafe { &mut *(header.config_space() as *mut Config) };
-        let mac = config.mac.read();
-        debug!("Got MAC={:?}, status={:?}", mac, config.status.read());
+        let (mac, status) = init.read_config_atomic(|| {
+            Ok::<_, Error>((
+                init.read_config::<EthernetAddress>(offset_of!(Config, mac))?,
+                init.read_config::<Le16>(offset_of!(Config, status))?,
+            ))
+        })?;
+        let status = Status::from_bits_truncate(status.get());
+        debug!("Got MAC={:?}, status={:?}", mac, status);
 
+        let features = Features::from_bits_truncate(init.features());
         let queue_num = 2; // for simplicity
-        let recv_queue = VirtQueue::new(header, QUEUE_RECEIVE, queue_num)?;
-        let send_queue = VirtQueue::new(header, QUEUE_TRANSMIT, queue_num)?;
+        let mut recv_queue = init.queue(QUEUE_RECEIVE, queue_num)?;
+        let mut send_queue = init.queue(QUEUE_TRANSMIT, queue_num)?;
+        if features.contains(Features::RING_INDIRECT_DESC) {
+            recv_queue.enable_indirect()?;
+            send_queue.enable_indirect()?;
+        }
+        if features.contains(Features::ORDER_PLATFORM) {
+            recv_queue.set_order_platform(true);
+            send_queue.set_order_platform(true);
+        }
 
-        header.finish_init();
+        let header = init.finish();
 
         Ok(VirtIONet {
             header,
             mac,
             recv_queue,
             send_queue,
+            features,
+            status,
         })
     }
 
+    /// Re-attach to the device with the state saved by [`save`](Self::save),
+    /// after the VM is restored from a snapshot.
+    ///
+    /// The device is expected to be restored by the VMM, so it is not
+    /// initialized again.
+    ///
+    /// # Safety
+    ///
+    /// The memory of the saved driver must be intact, still allocated from
+    /// the HAL, and not used by any other driver.
+    pub unsafe fn restore(header: &'static mut dyn Transport, state: &NetState) -> Result<Self> {
+        if header.device_type() != DeviceType::Network {
+            return Err(Error::InvalidParam);
+        }
+        let features = Features::from_bits_truncate(state.features);
+        let mut recv_queue = VirtQueue::restore(header, &state.recv_queue)?;
+        let mut send_queue = VirtQueue::restore(header, &state.send_queue)?;
+        if features.contains(Features::RING_INDIRECT_DESC) {
+            recv_queue.enable_indirect()?;
+            send_queue.enable_indirect()?;
+        }
+        if features.contains(Features::ORDER_PLATFORM) {
+            recv_queue.set_order_platform(true);
+            send_queue.set_order_platform(true);
+        }
+        Ok(VirtIONet {
+            header,
+            mac: state.mac,
+            recv_queue,
+            send_queue,
+            features,
+            status: Status::from_bits_truncate(state.status),
+        })
+    }
+
+    /// Save the state of the driver, e.g. with a snapshot of the VM.
+    pub fn save(&self) -> NetState {
+        NetState {
+            features: self.features.bits(),
+            mac: self.mac,
+            status: self.status.bits(),
+            recv_queue: self.recv_queue.save(),
+            send_queue: self.send_queue.save(),
+        }
+    }
+
     /// Acknowledge interrupt.
     pub fn ack_interrupt(&mut self) -> bool {
         self.header.ack_interrupt()
     }
 
+    /// Check whether the link went up or down since it was last checked,
+    /// e.g. after the device signals [`InterruptStatus::CONFIG_CHANGE`](crate::InterruptStatus::CONFIG_CHANGE).
+    pub fn config_change(&mut self) -> Option<ConfigChange> {
+        if !self.features.contains(Features::STATUS) {
+            return None;
+        }
+        let status = self
+            .header
+            .read_config::<Le16>(offset_of!(Config, status))
+            .ok()?;
+        let status = Status::from_bits_truncate(status.get());
+        let up = status.contains(Status::LINK_UP);
+        if up == self.status.contains(Status::LINK_UP) {
+            return None;
+        }
+        self.status = status;
+        Some(ConfigChange::Link { up })
+    }
+
+    /// Suspend the device, e.g. before the guest enters S3.
+    ///
+    /// The device is reset so that it stops using the queues, which are set
+    /// up again by [`resume`](Self::resume).
+    pub fn suspend(&mut self) -> Result {
+        self.header.reset();
+        Ok(())
+    }
+
+    /// Resume the device after [`suspend`](Self::suspend), negotiating the
+    /// same features and setting up the queues again.
+    pub fn resume(&mut self) -> Result {
+        self.header.begin_reinit(self.features.bits())?;
+        self.recv_queue.reinit(self.header);
+        self.send_queue.reinit(self.header);
+        self.header.finish_init();
+        Ok(())
+    }
+
+    /// Reset the receive queue alone, without resetting the device, e.g. to
+    /// receive into buffers of another size, discarding the buffers posted
+    /// to it by receives which timed out.
+    ///
+    /// Fails with [`Error::InvalidParam`] unless the device supports
+    /// resetting queues one by one.
+    pub fn reset_recv_queue(&mut self) -> Result {
+        self.recv_queue.reset(self.header)
+    }
+
     /// Get MAC address.
     pub fn mac(&self) -> EthernetAddress {
         self.mac
@@ -70,29 +194,379 @@ impl VirtIONet<'_> {
 
     /// Receive a packet.
     pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let mut header = MaybeUninit::<Header>::uninit();
-        let header_buf = unsafe { (*header.as_mut_ptr()).as_buf_mut() };
-        self.recv_queue.add(&[], &[header_buf, buf])?;
-        self.header.notify(QUEUE_RECEIVE as u32);
-        while !self.recv_queue.can_pop() {
-            spin_loop_hint();
-        }
+        recv(
+            &mut self.recv_queue,
+            &*self.header,
+            header_len(self.features),
+            buf,
+        )
+    }
+
+    /// Receive a packet into `buf`, which need not be initialized.
+    ///
+    /// Returns the part of `buf` holding the packet.
+    pub fn recv_uninit<'b>(&mut self, buf: &'b mut [MaybeUninit<u8>]) -> Result<&'b mut [u8]> {
+        recv_uninit(
+            &mut self.recv_queue,
+            &*self.header,
+            header_len(self.features),
+            buf,
+        )
+    }
+
+    /// Send a packet.
+    pub fn send(&mut self, buf: &[u8]) -> Result {
+        send(
+            &mut self.send_queue,
+            &*self.header,
+            header_len(self.features),
+            buf,
+        )
+    }
+
+    /// Receive a packet into `buf` without copying it, returning its length.
+    pub fn recv_dma(&mut self, buf: &DmaBuf) -> Result<usize> {
+        recv_dma(
+            &mut self.recv_queue,
+            &*self.header,
+            header_len(self.features),
+            buf,
+        )
+    }
+
+    /// Send the packet in `buf` without copying it.
+    pub fn send_dma(&mut self, buf: &DmaBuf) -> Result {
+        send_dma(
+            &mut self.send_queue,
+            &*self.header,
+            header_len(self.features),
+            buf,
+        )
+    }
+}
+
+impl Drop for VirtIONet<'_> {
+    fn drop(&mut self) {
+        // stop the device from using the queues, so that they are freed
+        self.header.reset();
+        self.recv_queue.unset(self.header);
+        self.send_queue.unset(self.header);
+    }
+}
+
+impl Driver for VirtIONet<'_> {
+    fn device_type(&self) -> DeviceType {
+        self.header.device_type()
+    }
+
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
+        self.header.ack_interrupt_status()
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.features.bits()
+    }
+
+    fn enable_notifications(&mut self) {
+        self.recv_queue.set_used_notifications(true);
+        self.send_queue.set_used_notifications(true);
+    }
+
+    fn disable_notifications(&mut self) {
+        self.recv_queue.set_used_notifications(false);
+        self.send_queue.set_used_notifications(false);
+    }
+
+    fn metrics(&self, f: &mut dyn FnMut(Metric)) {
+        self.recv_queue.metrics().report("receiveq", f);
+        self.send_queue.metrics().report("transmitq", f);
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.header.needs_reset()
+    }
+
+    fn recover(&mut self, lost: &mut dyn FnMut(LostRequest)) -> Result {
+        self.recv_queue.report_lost("receiveq", lost);
+        self.send_queue.report_lost("transmitq", lost);
+        self.header.reset();
+        self.resume()
+    }
+
+    #[cfg(feature = "time-hal")]
+    fn set_timeout(&mut self, ticks: Option<u64>) {
+        self.recv_queue.set_timeout(ticks);
+        self.send_queue.set_timeout(ticks);
+    }
+}
+
+impl<'a> VirtIONet<'a> {
+    /// Split the driver into a receive half and a transmit half, each
+    /// borrowing its queue, so that packets can be received and sent
+    /// concurrently on different cores or tasks.
+    ///
+    /// The driver keeps the device, which it resets when it is dropped after
+    /// the halves. The halves do not acknowledge interrupts, so register the
+    /// header with an [`IrqDispatcher`] to handle them while the driver is
+    /// split.
+    pub fn split(&mut self) -> (VirtIONetRx<'_, 'a>, VirtIONetTx<'_, 'a>) {
+        let header = &*self.header;
+        let rx = VirtIONetRx {
+            header,
+            header_len: header_len(self.features),
+            queue: &mut self.recv_queue,
+        };
+        let tx = VirtIONetTx {
+            header,
+            header_len: header_len(self.features),
+            mac: self.mac,
+            queue: &mut self.send_queue,
+        };
+        (rx, tx)
+    }
+}
+
+/// The receive half of a [`VirtIONet`], created by [`VirtIONet::split`].
+pub struct VirtIONetRx<'n, 'a> {
+    /// The header of the device, shared with the transmit half to notify the
+    /// device.
+    header: &'n dyn Transport,
+    /// The length of the header of each packet.
+    header_len: usize,
+    queue: &'n mut VirtQueue<'a>,
+}
+
+impl VirtIONetRx<'_, '_> {
+    /// Whether can receive packet.
+    pub fn can_recv(&self) -> bool {
+        self.queue.can_pop()
+    }
 
-        let (_, len) = self.recv_queue.pop_used()?;
-        // let header = unsafe { header.assume_init() };
-        Ok(len as usize - size_of::<Header>())
+    /// A snapshot of the statistics of the receive queue.
+    pub fn metrics(&self) -> QueueMetrics {
+        self.queue.metrics()
+    }
+
+    /// Receive a packet.
+    pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        recv(self.queue, self.header, self.header_len, buf)
+    }
+
+    /// Receive a packet into `buf`, which need not be initialized.
+    ///
+    /// Returns the part of `buf` holding the packet.
+    pub fn recv_uninit<'b>(&mut self, buf: &'b mut [MaybeUninit<u8>]) -> Result<&'b mut [u8]> {
+        recv_uninit(self.queue, self.header, self.header_len, buf)
+    }
+
+    /// Receive a packet into `buf` without copying it, returning its length.
+    pub fn recv_dma(&mut self, buf: &DmaBuf) -> Result<usize> {
+        recv_dma(self.queue, self.header, self.header_len, buf)
+    }
+}
+
+/// The transmit half of a [`VirtIONet`], created by [`VirtIONet::split`].
+pub struct VirtIONetTx<'n, 'a> {
+    /// The header of the device, shared with the receive half to notify the
+    /// device.
+    header: &'n dyn Transport,
+    /// The length of the header of each packet.
+    header_len: usize,
+    mac: EthernetAddress,
+    queue: &'n mut VirtQueue<'a>,
+}
+
+impl VirtIONetTx<'_, '_> {
+    /// Get MAC address.
+    pub fn mac(&self) -> EthernetAddress {
+        self.mac
+    }
+
+    /// Whether can send packet.
+    pub fn can_send(&self) -> bool {
+        self.queue.available_desc() >= 2
+    }
+
+    /// A snapshot of the statistics of the transmit queue.
+    pub fn metrics(&self) -> QueueMetrics {
+        self.queue.metrics()
     }
 
     /// Send a packet.
     pub fn send(&mut self, buf: &[u8]) -> Result {
-        let header = unsafe { MaybeUninit::<Header>::zeroed().assume_init() };
-        self.send_queue.add(&[header.as_buf(), buf], &[])?;
-        self.header.notify(QUEUE_TRANSMIT as u32);
-        while !self.send_queue.can_pop() {
-            spin_loop_hint();
+        send(self.queue, self.header, self.header_len, buf)
+    }
+
+    /// Send the packet in `buf` without copying it.
+    pub fn send_dma(&mut self, buf: &DmaBuf) -> Result {
+        send_dma(self.queue, self.header, self.header_len, buf)
+    }
+}
+
+/// The length of the header of each packet, which has `num_buffers` with
+/// `VIRTIO_F_VERSION_1`.
+fn header_len(features: Features) -> usize {
+    if features.contains(Features::VERSION_1) {
+        size_of::<Header>()
+    } else {
+        size_of::<Header>() - size_of::<Le16>()
+    }
+}
+
+/// Receive a packet through the receive queue, blocking until it arrives.
+fn recv(
+    queue: &mut VirtQueue,
+    transport: &dyn Transport,
+    header_len: usize,
+    buf: &mut [u8],
+) -> Result<usize> {
+    // the device only writes bytes, so `buf` stays initialized
+    let buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+    recv_uninit(queue, transport, header_len, buf).map(|packet| packet.len())
+}
+
+/// Receive a packet into a buffer which need not be initialized, returning
+/// the initialized part holding the packet.
+fn recv_uninit<'b>(
+    queue: &mut VirtQueue,
+    transport: &dyn Transport,
+    header_len: usize,
+    buf: &'b mut [MaybeUninit<u8>],
+) -> Result<&'b mut [u8]> {
+    let mut header = MaybeUninit::<Header>::uninit();
+    // the buffers are handed to the device by their addresses, as no
+    // references may be formed to their bytes before it writes them
+    let bufs = [
+        (
+            virt_to_phys(header.as_mut_ptr() as usize),
+            header_len,
+            BufferDirection::DeviceToDriver,
+        ),
+        (
+            virt_to_phys(buf.as_mut_ptr() as usize),
+            buf.len(),
+            BufferDirection::DeviceToDriver,
+        ),
+    ];
+    // SAFETY: The buffers are not accessed until the device used them, or
+    // `wait_for` failed and reset the device.
+    let token = unsafe { queue.add_premapped(&bufs)? };
+    if queue.should_notify() {
+        transport.notify(QUEUE_RECEIVE as u32);
+    }
+    let len = queue.wait_for(transport, token)?;
+    let len = (len as usize)
+        .checked_sub(header_len)
+        .filter(|&len| len <= buf.len())
+        .ok_or(Error::IoError)?;
+    // the device has written the first `len` bytes
+    Ok(unsafe { slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, len) })
+}
+
+/// Send a packet through the transmit queue, blocking until it is consumed.
+fn send(queue: &mut VirtQueue, transport: &dyn Transport, header_len: usize, buf: &[u8]) -> Result {
+    let header = unsafe { MaybeUninit::<Header>::zeroed().assume_init() };
+    let token = queue.add(&[&header.as_buf()[..header_len], buf], &[])?;
+    if queue.should_notify() {
+        transport.notify(QUEUE_TRANSMIT as u32);
+    }
+    queue.wait_for(transport, token)?;
+    Ok(())
+}
+
+/// Receive a packet into a DMA buffer, blocking until it arrives.
+fn recv_dma(
+    queue: &mut VirtQueue,
+    transport: &dyn Transport,
+    header_len: usize,
+    buf: &DmaBuf,
+) -> Result<usize> {
+    let mut header = MaybeUninit::<Header>::uninit();
+    let header_buf = unsafe { (*header.as_mut_ptr()).as_buf_mut() };
+    let token = queue.add_parts(
+        &[],
+        &[
+            QueueBuf::SliceMut(&mut header_buf[..header_len]),
+            QueueBuf::Dma(buf),
+        ],
+    )?;
+    if queue.should_notify() {
+        transport.notify(QUEUE_RECEIVE as u32);
+    }
+    let len = queue.wait_for(transport, token)?;
+    (len as usize)
+        .checked_sub(header_len)
+        .filter(|&len| len <= buf.len())
+        .ok_or(Error::IoError)
+}
+
+/// Send the packet in a DMA buffer, blocking until it is consumed.
+fn send_dma(
+    queue: &mut VirtQueue,
+    transport: &dyn Transport,
+    header_len: usize,
+    buf: &DmaBuf,
+) -> Result {
+    let header = unsafe { MaybeUninit::<Header>::zeroed().assume_init() };
+    let token = queue.add_parts(
+        &[
+            QueueBuf::Slice(&header.as_buf()[..header_len]),
+            QueueBuf::Dma(buf),
+        ],
+        &[],
+    )?;
+    if queue.should_notify() {
+        transport.notify(QUEUE_TRANSMIT as u32);
+    }
+    queue.wait_for(transport, token)?;
+    Ok(())
+}
+
+/// The state of a [`VirtIONet`], saved to restore the driver after the VM is
+/// restored from a snapshot.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct NetState {
+    features: u64,
+    mac: EthernetAddress,
+    status: u16,
+    recv_queue: QueueState,
+    send_queue: QueueState,
+}
+
+impl NetState {
+    /// The length of the serialized state.
+    pub const SIZE: usize = 16 + 2 * QueueState::SIZE;
+
+    /// Serialize the state as little-endian fields.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        const RECV: usize = 16 + QueueState::SIZE;
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..8].copy_from_slice(&self.features.to_le_bytes());
+        bytes[8..14].copy_from_slice(&self.mac);
+        bytes[14..16].copy_from_slice(&self.status.to_le_bytes());
+        bytes[16..RECV].copy_from_slice(&self.recv_queue.to_bytes());
+        bytes[RECV..].copy_from_slice(&self.send_queue.to_bytes());
+        bytes
+    }
+
+    /// Deserialize a state serialized by [`to_bytes`](Self::to_bytes).
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        const RECV: usize = 16 + QueueState::SIZE;
+        let mut features = [0; 8];
+        let mut mac = [0; 6];
+        let mut recv_queue = [0; QueueState::SIZE];
+        let mut send_queue = [0; QueueState::SIZE];
+        features.copy_from_slice(&bytes[0..8]);
+        mac.copy_from_slice(&bytes[8..14]);
+        recv_queue.copy_from_slice(&bytes[16..RECV]);
+        send_queue.copy_from_slice(&bytes[RECV..]);
+        NetState {
+            features: u64::from_le_bytes(features),
+            mac,
+            status: u16::from_le_bytes([bytes[14], bytes[15]]),
+            recv_queue: QueueState::from_bytes(&recv_queue),
+            send_queue: QueueState::from_bytes(&send_queue),
         }
-        self.send_queue.pop_used()?;
-        Ok(())
     }
 }
 
@@ -154,6 +628,13 @@ bitflags! {
         const RING_INDIRECT_DESC = 1 << 28;
         const RING_EVENT_IDX = 1 << 29;
         const VERSION_1 = 1 << 32; // legacy
+        /// Queues are in the packed layout.
+        const RING_PACKED = 1 << 34;
+        /// Memory accesses are ordered as the platform describes, e.g. for
+        /// hardware devices.
+        const ORDER_PLATFORM = 1 << 36;
+        /// Queues can be reset one by one.
+        const RING_RESET = 1 << 40;
     }
 }
 
@@ -164,18 +645,11 @@ bitflags! {
     }
 }
 
-bitflags! {
-    struct InterruptStatus : u32 {
-        const USED_RING_UPDATE = 1 << 0;
-        const CONFIGURATION_CHANGE = 1 << 1;
-    }
-}
-
 #[repr(C)]
 #[derive(Debug)]
 struct Config {
     mac: ReadOnly<EthernetAddress>,
-    status: ReadOnly<Status>,
+    status: ReadOnly<Le16>,
 }
 
 type EthernetAddress = [u8; 6];
@@ -186,10 +660,12 @@ type EthernetAddress = [u8; 6];
 struct Header {
     flags: Volatile<Flags>,
     gso_type: Volatile<GsoType>,
-    hdr_len: Volatile<u16>, // cannot rely on this
-    gso_size: Volatile<u16>,
-    csum_start: Volatile<u16>,
-    csum_offset: Volatile<u16>,
+    hdr_len: Volatile<Le16>, // cannot rely on this
+    gso_size: Volatile<Le16>,
+    csum_start: Volatile<Le16>,
+    csum_offset: Volatile<Le16>,
+    /// Only in the header of devices complying with virtio 1.x.
+    num_buffers: Volatile<Le16>,
     // payload starts from here
 }
 
@@ -0,0 +1,1789 @@
+use super::*;
+use crate::transport::{read_mapped, write_mapped};
+use crate::volatile::{ReadOnly, Volatile, WriteOnly};
+use core::hint::spin_loop;
+use core::mem::offset_of;
+use core::ops::RangeInclusive;
+use core::ptr::{self, NonNull};
+
+/// The PCI vendor ID of virtio devices.
+const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+
+/// The PCI device IDs of transitional devices, whose device type is their
+/// subsystem device ID.
+const TRANSITIONAL_DEVICE_IDS: RangeInclusive<u16> = 0x1000..=0x103f;
+
+/// The PCI device ID of modern devices is this plus their device type.
+const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+
+// offsets in the configuration space of a PCI function
+const PCI_VENDOR_ID: usize = 0x00;
+const PCI_DEVICE_ID: usize = 0x02;
+const PCI_COMMAND: usize = 0x04;
+const PCI_STATUS: usize = 0x06;
+const PCI_HEADER_TYPE: usize = 0x0e;
+const PCI_BAR0: usize = 0x10;
+const PCI_SUBSYSTEM_ID: usize = 0x2e;
+const PCI_CAPABILITIES: usize = 0x34;
+
+/// The command register bits enabling I/O and memory BARs, and DMA.
+const PCI_COMMAND_IO: u16 = 1 << 0;
+const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+const PCI_COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+/// The status register bit meaning the function has a capability list.
+const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+
+/// The ID of vendor-specific capabilities, which virtio uses.
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+
+/// The ID of the MSI-X capability.
+const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+/// The message control register bits of the MSI-X capability enabling
+/// MSI-X, and masking all of its vectors.
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+
+/// The vector control bit of an MSI-X table entry masking the vector.
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+// the types of virtio capabilities
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+const VIRTIO_PCI_CAP_SHARED_MEMORY_CFG: u8 = 8;
+
+/// The size of the pages by which the legacy interface addresses the queues.
+const LEGACY_PAGE_SIZE: usize = 4096;
+
+/// The number of queues of a modern device whose notification addresses
+/// the transport keeps.
+const MAX_NOTIFY_QUEUES: usize = 64;
+
+/// The feature bit of devices complying with virtio 1.x, which drivers of
+/// modern devices must accept.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// The feature bit of devices which reset their queues one by one through
+/// `queue_reset`.
+const VIRTIO_F_RING_RESET: u64 = 1 << 40;
+
+/// How many times to check whether the device finished resetting a queue
+/// before giving up on it.
+const QUEUE_RESET_SPINS: usize = 1 << 20;
+
+/// The common configuration structure of a modern PCI device.
+///
+/// Ref: 4.1.4.3 Common configuration structure layout
+#[repr(C)]
+#[derive(Debug)]
+struct CommonCfg {
+    /// Device (host) features word selection
+    device_feature_select: Volatile<Le32>,
+    /// The word of the features the device supports selected by
+    /// `device_feature_select`
+    device_feature: ReadOnly<Le32>,
+    /// Activated (guest) features word selection
+    driver_feature_select: Volatile<Le32>,
+    /// The word of the features accepted by the driver selected by
+    /// `driver_feature_select`
+    driver_feature: Volatile<Le32>,
+    /// The MSI-X vector of configuration changes
+    config_msix_vector: Volatile<Le16>,
+    /// The number of queues of the device
+    num_queues: ReadOnly<Le16>,
+    /// Device status, see 2.1 Device Status Field
+    device_status: Volatile<u8>,
+    /// Configuration atomicity value
+    config_generation: ReadOnly<u8>,
+
+    /// Virtual queue index, which the following fields apply to
+    queue_select: Volatile<Le16>,
+    /// Virtual queue size, which reads the maximum size after reset
+    queue_size: Volatile<Le16>,
+    /// The MSI-X vector of the queue
+    queue_msix_vector: Volatile<Le16>,
+    /// Whether the device may use the queue
+    queue_enable: Volatile<Le16>,
+    /// The offset of the notification address of the queue
+    queue_notify_off: ReadOnly<Le16>,
+    /// The physical address of the descriptor table, in two halves
+    queue_desc_low: Volatile<Le32>,
+    queue_desc_high: Volatile<Le32>,
+    /// The physical address of the driver area, in two halves
+    queue_driver_low: Volatile<Le32>,
+    queue_driver_high: Volatile<Le32>,
+    /// The physical address of the device area, in two halves
+    queue_device_low: Volatile<Le32>,
+    queue_device_high: Volatile<Le32>,
+}
+
+/// The fields which virtio 1.2 appends to the common configuration
+/// structure, which older devices lack.
+#[repr(C)]
+#[derive(Debug)]
+struct QueueResetCfg {
+    /// The data a driver with `VIRTIO_F_NOTIFICATION_DATA` notifies the
+    /// queue with
+    queue_notify_data: ReadOnly<Le16>,
+    /// Written 1 to reset the queue, and reads 1 until it is reset
+    queue_reset: Volatile<Le16>,
+}
+
+/// The registers of a legacy device at the start of its I/O BAR0, which the
+/// device configuration follows.
+///
+/// Ref: 4.1.4.10 Legacy Interfaces: A Note on PCI Device Layout
+#[repr(C)]
+#[derive(Debug)]
+struct LegacyHeader {
+    /// The features the device supports
+    host_features: ReadOnly<Le32>,
+    /// The features accepted by the driver
+    guest_features: Volatile<Le32>,
+    /// Guest physical page number of the selected virtual queue
+    queue_pfn: Volatile<Le32>,
+    /// The size of the selected virtual queue, which the driver must use
+    queue_size: ReadOnly<Le16>,
+    /// Virtual queue index, which `queue_pfn` and `queue_size` apply to
+    queue_select: Volatile<Le16>,
+    /// Queue notifier
+    queue_notify: WriteOnly<Le16>,
+    /// Device status
+    device_status: Volatile<u8>,
+    /// Interrupt status, which is cleared when it is read
+    isr_status: ReadOnly<u8>,
+}
+
+/// The MSI-X vector registers of a legacy device, which follow its other
+/// registers while MSI-X is enabled, moving the device configuration to
+/// offset 0x18.
+#[repr(C)]
+#[derive(Debug)]
+struct LegacyMsix {
+    /// The MSI-X vector of configuration changes
+    config_vector: Volatile<Le16>,
+    /// The MSI-X vector of the selected virtual queue
+    queue_vector: Volatile<Le16>,
+}
+
+/// An entry of the MSI-X table.
+///
+/// Ref: PCI Local Bus Specification 3.0, 6.8.2.6 MSI-X Table
+#[repr(C)]
+#[derive(Debug)]
+struct MsixEntry {
+    message_address_low: Volatile<Le32>,
+    message_address_high: Volatile<Le32>,
+    message_data: Volatile<Le32>,
+    vector_control: Volatile<Le32>,
+}
+
+/// The transport of a virtio PCI device, through the structures which the
+/// capabilities of a modern device point to in its memory BARs, or the
+/// registers of a legacy device in its I/O BAR0, e.g. for transitional
+/// devices which lack the capabilities.
+///
+/// Ref: 4.1 Virtio Over PCI Bus
+#[derive(Debug)]
+pub struct PciTransport {
+    device_type: DeviceType,
+    interface: Interface,
+    config: ConfigSpace,
+    msix: Option<Msix>,
+    /// The offsets of the notification addresses of the queues of a modern
+    /// device in its notification structure, as they are set up.
+    notify_offsets: [u32; MAX_NOTIFY_QUEUES],
+}
+
+/// The MSI-X capability of a [`PciTransport`].
+#[derive(Debug)]
+struct Msix {
+    /// The offset of the capability in the configuration space.
+    offset: usize,
+    /// The MSI-X table, in a memory BAR.
+    table: NonNull<MsixEntry>,
+    /// The number of entries of the table.
+    size: u16,
+}
+
+/// The interface of a [`PciTransport`] to its device.
+#[derive(Debug)]
+enum Interface {
+    /// The structures of a virtio 1.x device.
+    Modern {
+        common_cfg: NonNull<CommonCfg>,
+        /// The fields after the common configuration, if the structure is
+        /// long enough to hold them.
+        queue_reset: Option<NonNull<QueueResetCfg>>,
+        /// The start of the notification structure.
+        notify_region: NonNull<u8>,
+        /// The length of the notification structure.
+        notify_len: usize,
+        /// The multiplier of the notification offsets of the queues.
+        notify_off_multiplier: u32,
+        /// The ISR status byte, which is cleared when it is read.
+        isr_status: NonNull<ReadOnly<u8>>,
+        /// The device configuration structure, which some devices lack.
+        config_space: Option<NonNull<u64>>,
+        /// The length of the device configuration structure.
+        config_len: usize,
+    },
+    /// The registers of a legacy device.
+    Legacy {
+        regs: LegacyRegs,
+        /// The size of I/O BAR0, which the registers and the device
+        /// configuration take.
+        bar_size: usize,
+    },
+}
+
+/// How a [`PciTransport`] accesses the I/O space of the PCI bus, for the
+/// legacy interface.
+#[derive(Debug, Clone, Copy)]
+enum IoSpace {
+    /// Through the virtual address at which it is mapped into memory.
+    Window(usize),
+    /// With port I/O through the HAL.
+    #[cfg(feature = "port-io-hal")]
+    Ports,
+}
+
+impl IoSpace {
+    /// The registers of a legacy device whose I/O BAR0 is at `port`.
+    fn legacy_regs(self, port: u32) -> Option<LegacyRegs> {
+        match self {
+            IoSpace::Window(io_window) => {
+                NonNull::new((io_window + port as usize) as *mut u8).map(LegacyRegs::Mapped)
+            }
+            #[cfg(feature = "port-io-hal")]
+            IoSpace::Ports => (port <= u16::MAX as u32).then_some(LegacyRegs::Ports(port as u16)),
+        }
+    }
+}
+
+/// The registers of a legacy device in its I/O BAR0, in the layout of a
+/// [`LegacyHeader`] followed by the device configuration.
+#[derive(Debug, Clone, Copy)]
+enum LegacyRegs {
+    /// Mapped into memory through the I/O window of the bus.
+    Mapped(NonNull<u8>),
+    /// Accessed with port I/O through the HAL, from this port on.
+    #[cfg(feature = "port-io-hal")]
+    Ports(u16),
+}
+
+impl LegacyRegs {
+    /// Read the register of `width` bytes, 1, 2 or 4, at `offset`.
+    fn read(self, offset: usize, width: usize) -> u32 {
+        match self {
+            LegacyRegs::Mapped(base) => unsafe { read_mapped(base.as_ptr().add(offset), width) },
+            #[cfg(feature = "port-io-hal")]
+            LegacyRegs::Ports(port) => crate::hal::port_read(port + offset as u16, width),
+        }
+    }
+
+    /// Write the register of `width` bytes at `offset`.
+    fn write(self, offset: usize, width: usize, value: u32) {
+        match self {
+            LegacyRegs::Mapped(base) => unsafe {
+                write_mapped(base.as_ptr().add(offset), width, value)
+            },
+            #[cfg(feature = "port-io-hal")]
+            LegacyRegs::Ports(port) => crate::hal::port_write(port + offset as u16, width, value),
+        }
+    }
+
+    fn read_u8(self, offset: usize) -> u8 {
+        self.read(offset, 1) as u8
+    }
+
+    fn read_u16(self, offset: usize) -> u16 {
+        u16::from_le(self.read(offset, 2) as u16)
+    }
+
+    fn read_u32(self, offset: usize) -> u32 {
+        u32::from_le(self.read(offset, 4))
+    }
+
+    fn write_u8(self, offset: usize, value: u8) {
+        self.write(offset, 1, value as u32)
+    }
+
+    fn write_u16(self, offset: usize, value: u16) {
+        self.write(offset, 2, value.to_le() as u32)
+    }
+
+    fn write_u32(self, offset: usize, value: u32) {
+        self.write(offset, 4, value.to_le())
+    }
+}
+
+// SAFETY: The structures of the device are only accessed with volatile reads
+// and writes, through `&mut self` but for the registers which the methods of
+// `Transport` taking `&self` may access concurrently.
+unsafe impl Send for PciTransport {}
+unsafe impl Sync for PciTransport {}
+
+impl PciTransport {
+    /// Create the transport of the virtio device with the configuration
+    /// space at `config`, enabling its memory BARs and bus mastering.
+    ///
+    /// Fails with [`Error::InvalidParam`] if the function is not a virtio
+    /// device, or lacks the capabilities of a modern device. Use
+    /// [`with_io_window`](Self::with_io_window) to fall back to the legacy
+    /// interface.
+    ///
+    /// # Safety
+    ///
+    /// `config` must point to the configuration space of a PCI function
+    /// mapped into memory, e.g. its 4 KiB window in an ECAM region. Its
+    /// memory BARs must be assigned, and mapped at the addresses which the
+    /// HAL translates them to with `phys_to_virt`, until the transport is
+    /// dropped.
+    pub unsafe fn new(config: NonNull<u8>) -> Result<Self> {
+        Self::probe(ConfigSpace(config), None)
+    }
+
+    /// Create the transport of the virtio device with the configuration
+    /// space at `config` as [`new`](Self::new) does, falling back to the
+    /// legacy interface of transitional devices which lack the capabilities
+    /// of a modern device.
+    ///
+    /// The legacy registers are in I/O BAR0, which is accessed through
+    /// `io_window`, the virtual address at which the I/O space of the PCI
+    /// bus is mapped into memory, e.g. by the host bridge of ARM and RISC-V
+    /// machines.
+    ///
+    /// # Safety
+    ///
+    /// As for [`new`](Self::new), and `io_window` must map the I/O space
+    /// holding BAR0 until the transport is dropped.
+    pub unsafe fn with_io_window(config: NonNull<u8>, io_window: usize) -> Result<Self> {
+        Self::probe(ConfigSpace(config), Some(IoSpace::Window(io_window)))
+    }
+
+    /// Create the transport of the virtio device with the configuration
+    /// space at `config` as [`with_io_window`](Self::with_io_window) does,
+    /// but accessing the legacy registers in I/O BAR0 with port I/O through
+    /// the HAL (`virtio_port_read` and `virtio_port_write`), e.g. with the
+    /// `in` and `out` instructions of x86, before the I/O space is mapped
+    /// into memory, or where it never is.
+    ///
+    /// The device configuration of a legacy device is then not mapped into
+    /// memory, so [`config_space`](Transport::config_space) is null, and
+    /// the drivers access it through
+    /// [`read_config`](../trait.Transport.html#method.read_config).
+    ///
+    /// # Safety
+    ///
+    /// As for [`new`](Self::new), and the HAL must access the I/O ports of
+    /// BAR0 until the transport is dropped.
+    #[cfg(feature = "port-io-hal")]
+    pub unsafe fn with_io_ports(config: NonNull<u8>) -> Result<Self> {
+        Self::probe(ConfigSpace(config), Some(IoSpace::Ports))
+    }
+
+    unsafe fn probe(config: ConfigSpace, io_space: Option<IoSpace>) -> Result<Self> {
+        if config.read_u16(PCI_VENDOR_ID) != VIRTIO_VENDOR_ID {
+            return Err(Error::InvalidParam);
+        }
+        let device_id = config.read_u16(PCI_DEVICE_ID);
+        let transitional = TRANSITIONAL_DEVICE_IDS.contains(&device_id);
+        let device_type = if transitional {
+            DeviceType::from_id(config.read_u16(PCI_SUBSYSTEM_ID) as u32)
+        } else if device_id >= MODERN_DEVICE_ID_BASE {
+            DeviceType::from_id((device_id - MODERN_DEVICE_ID_BASE) as u32)
+        } else {
+            DeviceType::Invalid
+        };
+        if device_type == DeviceType::Invalid {
+            warn!("Unknown virtio PCI device ID {:#x}", device_id);
+            return Err(Error::InvalidParam);
+        }
+
+        let interface = match (config.modern_interface(), io_space) {
+            (Some(interface), _) => interface,
+            (None, Some(io_space)) if transitional => {
+                let port = config.io_bar_address(0).ok_or(Error::InvalidParam)?;
+                Interface::Legacy {
+                    regs: io_space.legacy_regs(port).ok_or(Error::InvalidParam)?,
+                    bar_size: config.io_bar_size(0) as usize,
+                }
+            }
+            (None, _) => {
+                warn!("Virtio PCI device lacks the capabilities of a modern device");
+                return Err(Error::InvalidParam);
+            }
+        };
+
+        let msix = config.msix();
+        let command = config.read_u16(PCI_COMMAND);
+        config.write_u16(
+            PCI_COMMAND,
+            command | PCI_COMMAND_IO | PCI_COMMAND_MEMORY | PCI_COMMAND_BUS_MASTER,
+        );
+        debug!(
+            "Virtio PCI device {:?} with {} interface",
+            device_type,
+            match interface {
+                Interface::Modern { .. } => "modern",
+                Interface::Legacy { .. } => "legacy",
+            }
+        );
+        Ok(PciTransport {
+            device_type,
+            interface,
+            config,
+            msix,
+            notify_offsets: [0; MAX_NOTIFY_QUEUES],
+        })
+    }
+
+    /// The number of entries of the MSI-X table, which the vectors assigned
+    /// to the device index, or 0 if the device lacks MSI-X.
+    pub fn msix_table_size(&self) -> u16 {
+        self.msix.as_ref().map_or(0, |msix| msix.size)
+    }
+
+    /// Program entry `vector` of the MSI-X table to signal an interrupt by
+    /// writing `data` to the physical `address`, as the interrupt controller
+    /// defines, and unmask it.
+    ///
+    /// Fails with [`Error::InvalidParam`] if the table lacks the entry.
+    pub fn set_msix_entry(&mut self, vector: u16, address: u64, data: u32) -> Result {
+        let msix = self.msix.as_ref().ok_or(Error::InvalidParam)?;
+        if vector >= msix.size {
+            return Err(Error::InvalidParam);
+        }
+        let entry = unsafe { &*msix.table.as_ptr().add(vector as usize) };
+        entry.vector_control.write(MSIX_ENTRY_MASKED.into());
+        entry.message_address_low.write((address as u32).into());
+        entry
+            .message_address_high
+            .write(((address >> 32) as u32).into());
+        entry.message_data.write(data.into());
+        entry.vector_control.write(0.into());
+        Ok(())
+    }
+
+    /// Enable or disable MSI-X, which replaces the interrupt line of the
+    /// device while it is enabled.
+    ///
+    /// Enable it before initializing the device, as it moves the device
+    /// configuration of legacy devices, and the vectors are only assigned
+    /// while it is enabled. Fails with [`Error::InvalidParam`] if the device
+    /// lacks MSI-X.
+    pub fn enable_msix(&mut self, enabled: bool) -> Result {
+        let msix = self.msix.as_ref().ok_or(Error::InvalidParam)?;
+        let control = self.config.read_u16(msix.offset + 2) & !MSIX_FUNCTION_MASK;
+        let control = if enabled {
+            control | MSIX_ENABLE
+        } else {
+            control & !MSIX_ENABLE
+        };
+        self.config.write_u16(msix.offset + 2, control);
+        Ok(())
+    }
+
+    /// Whether MSI-X is enabled.
+    pub fn msix_enabled(&self) -> bool {
+        self.msix
+            .as_ref()
+            .is_some_and(|msix| self.config.read_u16(msix.offset + 2) & MSIX_ENABLE != 0)
+    }
+
+    /// The offset of the device configuration of a legacy device in its
+    /// registers, which moves while MSI-X is enabled.
+    fn legacy_config_offset(&self) -> usize {
+        if self.msix_enabled() {
+            size_of::<LegacyHeader>() + size_of::<LegacyMsix>()
+        } else {
+            size_of::<LegacyHeader>()
+        }
+    }
+}
+
+/// Write `vector` to the MSI-X vector register `reg`, and check that the
+/// device assigned it.
+fn assign_vector(reg: &Volatile<Le16>, vector: u16) -> Result {
+    reg.write(vector.into());
+    check_vector(reg.read().get(), vector)
+}
+
+/// Check that the device assigned `vector`, as it reads back [`NO_VECTOR`]
+/// from the vector register if it could not.
+fn check_vector(assigned: u16, vector: u16) -> Result {
+    if assigned != vector {
+        warn!(
+            "Device assigned MSI-X vector {:#x} instead of {:#x}",
+            assigned, vector
+        );
+        return Err(Error::IoError);
+    }
+    Ok(())
+}
+
+impl Transport for PciTransport {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        match self.interface {
+            Interface::Modern { common_cfg, .. } => {
+                let cfg = unsafe { common_cfg.as_ref() };
+                cfg.device_feature_select.write(0.into()); // device features [0, 32)
+                let mut device_features_bits = cfg.device_feature.read().get() as u64;
+                cfg.device_feature_select.write(1.into()); // device features [32, 64)
+                device_features_bits |= (cfg.device_feature.read().get() as u64) << 32;
+                device_features_bits
+            }
+            // the legacy interface only has the first 32 feature bits
+            Interface::Legacy { regs, .. } => {
+                regs.read_u32(offset_of!(LegacyHeader, host_features)) as u64
+            }
+        }
+    }
+
+    /// Write the features the driver accepts, with `VIRTIO_F_VERSION_1`,
+    /// which the driver of a modern device must accept.
+    fn write_driver_features(&mut self, driver_features: u64) {
+        match self.interface {
+            Interface::Modern { common_cfg, .. } => {
+                let driver_features = driver_features | VIRTIO_F_VERSION_1;
+                let cfg = unsafe { common_cfg.as_ref() };
+                cfg.driver_feature_select.write(0.into()); // driver features [0, 32)
+                cfg.driver_feature.write((driver_features as u32).into());
+                cfg.driver_feature_select.write(1.into()); // driver features [32, 64)
+                cfg.driver_feature
+                    .write(((driver_features >> 32) as u32).into());
+            }
+            Interface::Legacy { regs, .. } => {
+                regs.write_u32(
+                    offset_of!(LegacyHeader, guest_features),
+                    driver_features as u32,
+                );
+            }
+        }
+    }
+
+    fn max_queue_size(&mut self, queue: u32) -> u32 {
+        match self.interface {
+            Interface::Modern { common_cfg, .. } => {
+                let cfg = unsafe { common_cfg.as_ref() };
+                cfg.queue_select.write((queue as u16).into());
+                cfg.queue_size.read().get() as u32
+            }
+            Interface::Legacy { regs, .. } => {
+                regs.write_u16(offset_of!(LegacyHeader, queue_select), queue as u16);
+                regs.read_u16(offset_of!(LegacyHeader, queue_size)) as u32
+            }
+        }
+    }
+
+    /// Notify the device of new buffers in `queue`, for modern devices at
+    /// the notification address of the queue, which `queue_set` found from
+    /// its `queue_notify_off`.
+    fn notify(&self, queue: u32) {
+        match self.interface {
+            Interface::Modern { notify_region, .. } => {
+                let offset = self
+                    .notify_offsets
+                    .get(queue as usize)
+                    .copied()
+                    .unwrap_or(0);
+                let notify = unsafe { notify_region.as_ptr().add(offset as usize) };
+                let notify = unsafe { &*(notify as *const WriteOnly<Le16>) };
+                notify.write((queue as u16).into())
+            }
+            Interface::Legacy { regs, .. } => {
+                regs.write_u16(offset_of!(LegacyHeader, queue_notify), queue as u16)
+            }
+        }
+    }
+
+    fn status(&self) -> DeviceStatus {
+        let status = match self.interface {
+            Interface::Modern { common_cfg, .. } => {
+                unsafe { common_cfg.as_ref() }.device_status.read()
+            }
+            Interface::Legacy { regs, .. } => regs.read_u8(offset_of!(LegacyHeader, device_status)),
+        };
+        DeviceStatus::from_bits_truncate(status as u32)
+    }
+
+    fn set_status(&self, status: DeviceStatus) {
+        let status = status.bits() as u8;
+        match self.interface {
+            Interface::Modern { common_cfg, .. } => {
+                unsafe { common_cfg.as_ref() }.device_status.write(status)
+            }
+            Interface::Legacy { regs, .. } => {
+                regs.write_u8(offset_of!(LegacyHeader, device_status), status)
+            }
+        }
+    }
+
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) {
+        // the legacy interface has pages of 4 KiB, and the modern one
+        // addresses the queues by their physical addresses
+    }
+
+    fn config_space_size(&self) -> usize {
+        match self.interface {
+            Interface::Modern { config_len, .. } => config_len,
+            Interface::Legacy { bar_size, .. } => {
+                bar_size.saturating_sub(self.legacy_config_offset())
+            }
+        }
+    }
+
+    fn shared_memory_region(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        match self.interface {
+            Interface::Modern { .. } => self.config.shared_memory_region(id),
+            Interface::Legacy { .. } => None,
+        }
+    }
+
+    fn config_generation(&self) -> u32 {
+        match self.interface {
+            Interface::Modern { common_cfg, .. } => {
+                unsafe { common_cfg.as_ref() }.config_generation.read() as u32
+            }
+            Interface::Legacy { .. } => 0,
+        }
+    }
+
+    /// Whether the transport uses the legacy interface of the device.
+    fn is_legacy(&self) -> bool {
+        matches!(self.interface, Interface::Legacy { .. })
+    }
+
+    fn queue_size_fixed(&self) -> bool {
+        self.is_legacy()
+    }
+
+    fn set_config_vector(&mut self, vector: u16) -> Result {
+        if !self.msix_enabled() {
+            return match vector {
+                NO_VECTOR => Ok(()),
+                _ => Err(Error::InvalidParam),
+            };
+        }
+        match self.interface {
+            Interface::Modern { common_cfg, .. } => {
+                assign_vector(&unsafe { common_cfg.as_ref() }.config_msix_vector, vector)
+            }
+            Interface::Legacy { regs, .. } => {
+                let reg = size_of::<LegacyHeader>() + offset_of!(LegacyMsix, config_vector);
+                regs.write_u16(reg, vector);
+                check_vector(regs.read_u16(reg), vector)
+            }
+        }
+    }
+
+    fn set_queue_vector(&mut self, queue: u32, vector: u16) -> Result {
+        if !self.msix_enabled() {
+            return match vector {
+                NO_VECTOR => Ok(()),
+                _ => Err(Error::InvalidParam),
+            };
+        }
+        match self.interface {
+            Interface::Modern { common_cfg, .. } => {
+                let cfg = unsafe { common_cfg.as_ref() };
+                cfg.queue_select.write((queue as u16).into());
+                assign_vector(&cfg.queue_msix_vector, vector)
+            }
+            Interface::Legacy { regs, .. } => {
+                regs.write_u16(offset_of!(LegacyHeader, queue_select), queue as u16);
+                let reg = size_of::<LegacyHeader>() + offset_of!(LegacyMsix, queue_vector);
+                regs.write_u16(reg, vector);
+                check_vector(regs.read_u16(reg), vector)
+            }
+        }
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u32,
+        size: u32,
+        descriptors: usize,
+        driver_area: usize,
+        device_area: usize,
+    ) {
+        match self.interface {
+            Interface::Modern {
+                common_cfg,
+                notify_len,
+                notify_off_multiplier,
+                ..
+            } => {
+                let cfg = unsafe { common_cfg.as_ref() };
+                cfg.queue_select.write((queue as u16).into());
+                let notify_off = cfg.queue_notify_off.read().get() as u32;
+                let offset = notify_off
+                    .checked_mul(notify_off_multiplier)
+                    .filter(|&offset| offset as usize + size_of::<Le16>() <= notify_len);
+                match self.notify_offsets.get_mut(queue as usize) {
+                    Some(slot) => {
+                        *slot = offset.unwrap_or_else(|| {
+                            warn!(
+                                "Notification offset {} of queue {} is out of the notification structure",
+                                notify_off, queue
+                            );
+                            0
+                        })
+                    }
+                    None => warn!(
+                        "Queue {} is notified at the start of the notification structure",
+                        queue
+                    ),
+                }
+                cfg.queue_size.write((size as u16).into());
+                cfg.queue_desc_low.write((descriptors as u32).into());
+                cfg.queue_desc_high
+                    .write(((descriptors as u64 >> 32) as u32).into());
+                cfg.queue_driver_low.write((driver_area as u32).into());
+                cfg.queue_driver_high
+                    .write(((driver_area as u64 >> 32) as u32).into());
+                cfg.queue_device_low.write((device_area as u32).into());
+                cfg.queue_device_high
+                    .write(((device_area as u64 >> 32) as u32).into());
+                cfg.queue_enable.write(1.into());
+            }
+            Interface::Legacy { regs, .. } => {
+                // the legacy interface finds the rings from the descriptor
+                // table, in the layout of 4 KiB pages
+                debug_assert_eq!(driver_area, descriptors + size as usize * 16);
+                debug_assert_eq!(device_area % LEGACY_PAGE_SIZE, 0);
+                regs.write_u16(offset_of!(LegacyHeader, queue_select), queue as u16);
+                regs.write_u32(
+                    offset_of!(LegacyHeader, queue_pfn),
+                    (descriptors / LEGACY_PAGE_SIZE) as u32,
+                );
+            }
+        }
+    }
+
+    /// Stop the device from using `queue`.
+    ///
+    /// A modern device which negotiated `VIRTIO_F_RING_RESET` resets the
+    /// queue. The driver may not disable a queue of other modern devices, so
+    /// they are reset as a whole, while legacy devices are told the address
+    /// of the queue is cleared.
+    fn queue_unset(&mut self, queue: u32) {
+        if self.queue_reset(queue).is_ok() {
+            return;
+        }
+        match self.interface {
+            Interface::Modern { .. } => {
+                warn!("Resetting the device to stop it using queue {}", queue);
+                self.reset();
+            }
+            Interface::Legacy { regs, .. } => {
+                regs.write_u16(offset_of!(LegacyHeader, queue_select), queue as u16);
+                regs.write_u32(offset_of!(LegacyHeader, queue_pfn), 0);
+            }
+        }
+    }
+
+    fn queue_reset(&mut self, queue: u32) -> Result {
+        let Interface::Modern {
+            common_cfg,
+            queue_reset: Some(reset_cfg),
+            ..
+        } = self.interface
+        else {
+            return Err(Error::InvalidParam);
+        };
+        let cfg = unsafe { common_cfg.as_ref() };
+        cfg.driver_feature_select.write(1.into()); // driver features [32, 64)
+        if (cfg.driver_feature.read().get() as u64) << 32 & VIRTIO_F_RING_RESET == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let reset_cfg = unsafe { reset_cfg.as_ref() };
+        cfg.queue_select.write((queue as u16).into());
+        reset_cfg.queue_reset.write(1.into());
+        // the device presents 1 until the queue is reset, and then 0 in both
+        // queue_reset and queue_enable
+        for _ in 0..QUEUE_RESET_SPINS {
+            if reset_cfg.queue_reset.read().get() == 0 && cfg.queue_enable.read().get() == 0 {
+                return Ok(());
+            }
+            spin_loop();
+        }
+        warn!("Queue {} did not reset", queue);
+        Err(Error::Timeout)
+    }
+
+    fn queue_descriptors(&mut self, queue: u32) -> usize {
+        match self.interface {
+            Interface::Modern { common_cfg, .. } => {
+                let cfg = unsafe { common_cfg.as_ref() };
+                cfg.queue_select.write((queue as u16).into());
+                if cfg.queue_enable.read().get() == 0 {
+                    return 0;
+                }
+                let low = cfg.queue_desc_low.read().get() as u64;
+                let high = cfg.queue_desc_high.read().get() as u64;
+                ((high << 32) | low) as usize
+            }
+            Interface::Legacy { regs, .. } => {
+                regs.write_u16(offset_of!(LegacyHeader, queue_select), queue as u16);
+                regs.read_u32(offset_of!(LegacyHeader, queue_pfn)) as usize * LEGACY_PAGE_SIZE
+            }
+        }
+    }
+
+    fn ack_interrupt_status(&self) -> InterruptStatus {
+        // reading the ISR status clears it
+        let isr = match self.interface {
+            Interface::Modern { isr_status, .. } => unsafe { isr_status.as_ref() }.read(),
+            Interface::Legacy { regs, .. } => regs.read_u8(offset_of!(LegacyHeader, isr_status)),
+        };
+        InterruptStatus::from_bits_truncate(isr as u32)
+    }
+
+    /// Get the pointer to the configuration space of the device, which is
+    /// null if a modern device has none, or a legacy device is accessed with
+    /// port I/O, and follows the registers of a legacy device, including its
+    /// MSI-X vector registers while MSI-X is enabled.
+    fn config_space(&self) -> *mut u64 {
+        match self.interface {
+            Interface::Modern { config_space, .. } => {
+                config_space.map_or(ptr::null_mut(), |config| config.as_ptr())
+            }
+            Interface::Legacy {
+                regs: LegacyRegs::Mapped(base),
+                ..
+            } => unsafe { base.as_ptr().add(self.legacy_config_offset()) as *mut u64 },
+            #[cfg(feature = "port-io-hal")]
+            Interface::Legacy {
+                regs: LegacyRegs::Ports(_),
+                ..
+            } => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn config_read(&self, offset: usize, width: usize) -> u32 {
+        match self.interface {
+            Interface::Modern { .. } => unsafe {
+                read_mapped((self.config_space() as *const u8).add(offset), width)
+            },
+            Interface::Legacy { regs, .. } => {
+                regs.read(self.legacy_config_offset() + offset, width)
+            }
+        }
+    }
+
+    unsafe fn config_write(&self, offset: usize, width: usize, value: u32) {
+        match self.interface {
+            Interface::Modern { .. } => unsafe {
+                write_mapped((self.config_space() as *mut u8).add(offset), width, value)
+            },
+            Interface::Legacy { regs, .. } => {
+                regs.write(self.legacy_config_offset() + offset, width, value)
+            }
+        }
+    }
+}
+
+/// The address of a PCI function.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PciAddress {
+    /// The bus number.
+    pub bus: u8,
+    /// The device number on the bus, below 32.
+    pub device: u8,
+    /// The function number of the device, below 8.
+    pub function: u8,
+}
+
+/// The PCI buses behind an ECAM (enhanced configuration access mechanism)
+/// region, which maps the configuration space of each function into memory,
+/// e.g. as described by the ACPI MCFG table or the `pci-host-ecam-generic`
+/// node of a device tree.
+///
+/// ```ignore
+/// let root = unsafe { PciRoot::new(ecam, 0..=255) };
+/// for (address, transport) in root.virtio_devices() {
+///     let transport: &'static mut PciTransport = LEAKED.store(transport);
+///     let device = probe(transport)?;
+/// }
+/// ```
+#[derive(Debug)]
+pub struct PciRoot {
+    ecam: NonNull<u8>,
+    buses: RangeInclusive<u8>,
+    io_space: Option<IoSpace>,
+}
+
+impl PciRoot {
+    /// Scan `buses` of the ECAM region at `ecam`, which is the virtual
+    /// address of the configuration space of bus 0.
+    ///
+    /// # Safety
+    ///
+    /// `ecam` must map the configuration space of `buses`, and the memory
+    /// BARs of their functions must be assigned and mapped as
+    /// [`PciTransport::new`] requires. The virtio devices on them must not be
+    /// accessed through other transports.
+    pub unsafe fn new(ecam: NonNull<u8>, buses: RangeInclusive<u8>) -> Self {
+        PciRoot {
+            ecam,
+            buses,
+            io_space: None,
+        }
+    }
+
+    /// Fall back to the legacy interface of transitional devices, through
+    /// the I/O space of the buses mapped at `io_window`, as
+    /// [`PciTransport::with_io_window`] does.
+    ///
+    /// # Safety
+    ///
+    /// `io_window` must map the I/O space of the buses.
+    pub unsafe fn with_io_window(mut self, io_window: usize) -> Self {
+        self.io_space = Some(IoSpace::Window(io_window));
+        self
+    }
+
+    /// Fall back to the legacy interface of transitional devices, with port
+    /// I/O through the HAL, as [`PciTransport::with_io_ports`] does.
+    ///
+    /// # Safety
+    ///
+    /// The HAL must access the I/O ports of the buses.
+    #[cfg(feature = "port-io-hal")]
+    pub unsafe fn with_io_ports(mut self) -> Self {
+        self.io_space = Some(IoSpace::Ports);
+        self
+    }
+
+    /// The transports of the virtio devices on the buses, with their
+    /// addresses, in the order of their addresses.
+    ///
+    /// Functions which are virtio devices but whose transport cannot be
+    /// created, e.g. as their BARs are unassigned, are skipped with a
+    /// warning.
+    pub fn virtio_devices(self) -> impl Iterator<Item = (PciAddress, PciTransport)> {
+        let PciRoot {
+            ecam,
+            buses,
+            io_space,
+        } = self;
+        buses
+            .flat_map(|bus| (0..32).map(move |device| (bus, device)))
+            .flat_map(move |(bus, device)| {
+                let address = PciAddress {
+                    bus,
+                    device,
+                    function: 0,
+                };
+                // the other functions only exist on multi-function devices
+                let functions = match address.config(ecam) {
+                    Some(config) if config.read_u8(PCI_HEADER_TYPE) & 0x80 != 0 => 8,
+                    Some(_) => 1,
+                    None => 0,
+                };
+                (0..functions).map(move |function| PciAddress {
+                    function,
+                    ..address
+                })
+            })
+            .filter_map(move |address| {
+                let config = address.config(ecam)?;
+                if config.read_u16(PCI_VENDOR_ID) != VIRTIO_VENDOR_ID {
+                    return None;
+                }
+                match unsafe { PciTransport::probe(config, io_space) } {
+                    Ok(transport) => Some((address, transport)),
+                    Err(err) => {
+                        warn!("Skipped virtio device at {:?}: {:?}", address, err);
+                        None
+                    }
+                }
+            })
+    }
+}
+
+impl PciAddress {
+    /// The configuration space of the function in the ECAM region at
+    /// `ecam`, or `None` if the function is absent.
+    fn config(self, ecam: NonNull<u8>) -> Option<ConfigSpace> {
+        let offset = ((self.bus as usize) << 20)
+            | ((self.device as usize) << 15)
+            | ((self.function as usize) << 12);
+        let config = ConfigSpace(unsafe { NonNull::new_unchecked(ecam.as_ptr().add(offset)) });
+        (config.read_u16(PCI_VENDOR_ID) != 0xffff).then_some(config)
+    }
+}
+
+/// The configuration space of a PCI function, mapped into memory.
+#[derive(Debug, Clone, Copy)]
+struct ConfigSpace(NonNull<u8>);
+
+impl ConfigSpace {
+    fn read_u8(&self, offset: usize) -> u8 {
+        unsafe { self.0.as_ptr().add(offset).read_volatile() }
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        let reg = unsafe { self.0.as_ptr().add(offset) as *const Le16 };
+        unsafe { reg.read_volatile() }.get()
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        let reg = unsafe { self.0.as_ptr().add(offset) as *const Le32 };
+        unsafe { reg.read_volatile() }.get()
+    }
+
+    fn write_u16(&self, offset: usize, value: u16) {
+        let reg = unsafe { self.0.as_ptr().add(offset) as *mut Le16 };
+        unsafe { reg.write_volatile(value.into()) }
+    }
+
+    fn write_u32(&self, offset: usize, value: u32) {
+        let reg = unsafe { self.0.as_ptr().add(offset) as *mut Le32 };
+        unsafe { reg.write_volatile(value.into()) }
+    }
+
+    /// The capabilities of the function, in the order of its list.
+    fn capabilities(self) -> impl Iterator<Item = Capability> {
+        let mut next = if self.read_u16(PCI_STATUS) & PCI_STATUS_CAP_LIST != 0 {
+            self.read_u8(PCI_CAPABILITIES) & !0x3
+        } else {
+            0
+        };
+        // bound the walk, in case the list of a broken device loops
+        (0..48).map_while(move |_| {
+            if next == 0 {
+                return None;
+            }
+            let offset = next as usize;
+            next = self.read_u8(offset + 1) & !0x3;
+            Some(Capability {
+                id: self.read_u8(offset),
+                offset,
+            })
+        })
+    }
+
+    /// The structures of a modern device, which its capabilities point to,
+    /// or `None` if it lacks any of those the driver needs.
+    fn modern_interface(self) -> Option<Interface> {
+        let mut common_cfg = None;
+        let mut notify = None;
+        let mut isr_status = None;
+        let mut config_space = None;
+        for cap in self.capabilities() {
+            if cap.id != PCI_CAP_ID_VNDR {
+                continue;
+            }
+            // the driver uses the first structure of each type it can map
+            let cfg_type = self.read_u8(cap.offset + 3);
+            let region = || self.region(cap.offset);
+            match cfg_type {
+                VIRTIO_PCI_CAP_COMMON_CFG if common_cfg.is_none() => {
+                    common_cfg = region().filter(|r| r.len >= size_of::<CommonCfg>());
+                }
+                VIRTIO_PCI_CAP_NOTIFY_CFG if notify.is_none() => {
+                    let multiplier = self.read_u32(cap.offset + 16);
+                    notify = region()
+                        .filter(|r| r.len >= size_of::<Le16>())
+                        .map(|r| (r, multiplier));
+                }
+                VIRTIO_PCI_CAP_ISR_CFG if isr_status.is_none() => {
+                    isr_status = region().filter(|r| r.len >= 1);
+                }
+                VIRTIO_PCI_CAP_DEVICE_CFG if config_space.is_none() => {
+                    config_space = region();
+                }
+                _ => {}
+            }
+        }
+        let (notify, notify_off_multiplier) = notify?;
+        let common_cfg = common_cfg?;
+        let queue_reset = (common_cfg.len >= size_of::<CommonCfg>() + size_of::<QueueResetCfg>())
+            .then(|| unsafe { common_cfg.ptr.add(size_of::<CommonCfg>()) }.cast());
+        Some(Interface::Modern {
+            common_cfg: common_cfg.ptr.cast(),
+            queue_reset,
+            notify_region: notify.ptr,
+            notify_len: notify.len,
+            notify_off_multiplier,
+            isr_status: isr_status?.ptr.cast(),
+            config_space: config_space.as_ref().map(|region| region.ptr.cast()),
+            config_len: config_space.map_or(0, |region| region.len),
+        })
+    }
+
+    /// The MSI-X capability of the function, or `None` if it lacks one, or
+    /// its table is not in an assigned memory BAR.
+    fn msix(self) -> Option<Msix> {
+        let cap = self.capabilities().find(|cap| cap.id == PCI_CAP_ID_MSIX)?;
+        let control = self.read_u16(cap.offset + 2);
+        let table = self.read_u32(cap.offset + 4);
+        let base = self.bar_address((table & 0x7) as u8)?;
+        let vaddr = phys_to_virt(base.checked_add((table & !0x7) as u64)? as usize);
+        Some(Msix {
+            offset: cap.offset,
+            table: NonNull::new(vaddr as *mut MsixEntry)?,
+            size: (control & 0x7ff) + 1,
+        })
+    }
+
+    /// The shared memory region `id`, which a capability of its own points
+    /// to, with a 64-bit offset and length.
+    ///
+    /// Ref: 4.1.4.7 Shared memory capability
+    fn shared_memory_region(self, id: u8) -> Option<SharedMemoryRegion> {
+        let cap = self.capabilities().find(|cap| {
+            cap.id == PCI_CAP_ID_VNDR
+                && self.read_u8(cap.offset + 3) == VIRTIO_PCI_CAP_SHARED_MEMORY_CFG
+                && self.read_u8(cap.offset + 5) == id
+        })?;
+        let base = self.bar_address(self.read_u8(cap.offset + 4))?;
+        let offset =
+            ((self.read_u32(cap.offset + 16) as u64) << 32) | self.read_u32(cap.offset + 8) as u64;
+        let len =
+            ((self.read_u32(cap.offset + 20) as u64) << 32) | self.read_u32(cap.offset + 12) as u64;
+        Some(SharedMemoryRegion {
+            paddr: base.checked_add(offset)?,
+            len,
+        })
+    }
+
+    /// The memory region which the virtio capability at `offset` points to,
+    /// or `None` if it is in an I/O or unassigned BAR.
+    ///
+    /// Ref: 4.1.4 Virtio Structure PCI Capabilities
+    fn region(&self, offset: usize) -> Option<Region> {
+        let bar = self.read_u8(offset + 4);
+        let start = self.read_u32(offset + 8) as u64;
+        let len = self.read_u32(offset + 12) as usize;
+        let base = self.bar_address(bar)?;
+        let vaddr = phys_to_virt(base.checked_add(start)? as usize);
+        Some(Region {
+            ptr: NonNull::new(vaddr as *mut u8)?,
+            len,
+        })
+    }
+
+    /// The physical address of memory BAR `bar`, or `None` if it is an I/O
+    /// BAR or unassigned.
+    fn bar_address(&self, bar: u8) -> Option<u64> {
+        if bar > 5 {
+            return None;
+        }
+        let reg = PCI_BAR0 + bar as usize * 4;
+        let low = self.read_u32(reg);
+        if low & 1 != 0 {
+            return None;
+        }
+        let mut address = (low & !0xf) as u64;
+        // a 64-bit BAR takes the next register as well
+        if (low >> 1) & 0x3 == 0x2 {
+            if bar == 5 {
+                return None;
+            }
+            address |= (self.read_u32(reg + 4) as u64) << 32;
+        }
+        (address != 0).then_some(address)
+    }
+
+    /// The size of I/O BAR `bar`, found by writing all ones to it, with I/O
+    /// decoding disabled meanwhile.
+    fn io_bar_size(&self, bar: u8) -> u32 {
+        let reg = PCI_BAR0 + bar as usize * 4;
+        let command = self.read_u16(PCI_COMMAND);
+        self.write_u16(PCI_COMMAND, command & !PCI_COMMAND_IO);
+        let value = self.read_u32(reg);
+        self.write_u32(reg, !0);
+        let mask = self.read_u32(reg) & !0x3;
+        self.write_u32(reg, value);
+        self.write_u16(PCI_COMMAND, command);
+        // I/O BARs decode at most the low 16 bits
+        (!mask).wrapping_add(1) & 0xffff
+    }
+
+    /// The port of I/O BAR `bar`, or `None` if it is a memory BAR or
+    /// unassigned.
+    fn io_bar_address(&self, bar: u8) -> Option<u32> {
+        if bar > 5 {
+            return None;
+        }
+        let value = self.read_u32(PCI_BAR0 + bar as usize * 4);
+        let port = value & !0x3;
+        (value & 1 != 0 && port != 0).then_some(port)
+    }
+}
+
+/// A capability of a PCI function.
+struct Capability {
+    id: u8,
+    offset: usize,
+}
+
+/// A structure of a device in one of its BARs.
+struct Region {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    extern crate std;
+
+    use super::*;
+    use crate::hal::DMA;
+    use std::vec::Vec;
+
+    /// The offsets of the structures of a fake modern device in its BAR0.
+    const COMMON_CFG: usize = 0x000;
+    const ISR: usize = 0x100;
+    const DEVICE_CFG: usize = 0x200;
+    const NOTIFY: usize = 0x300;
+    const NOTIFY_OFF_MULTIPLIER: u32 = 4;
+    const MSIX_TABLE: usize = 0x800;
+
+    /// The configuration space of a fake PCI function, which lists its
+    /// capabilities one after another from offset 0x40.
+    struct FakeFunction {
+        config: ConfigSpace,
+        last_cap: Option<usize>,
+        next_cap: usize,
+    }
+
+    impl FakeFunction {
+        /// A function with `vendor` and `device` IDs, whose configuration
+        /// space is the zeroed memory at `config`.
+        fn new(config: usize, vendor: u16, device: u16) -> Self {
+            let config = ConfigSpace(NonNull::new(config as *mut u8).unwrap());
+            config.write_u16(PCI_VENDOR_ID, vendor);
+            config.write_u16(PCI_DEVICE_ID, device);
+            FakeFunction {
+                config,
+                last_cap: None,
+                next_cap: 0x40,
+            }
+        }
+
+        /// A modern device of `device_type`, with its structures at the
+        /// offsets above of its BAR0 at the physical address `bar`.
+        fn modern(config: usize, device_type: DeviceType, bar: usize) -> Self {
+            let device = MODERN_DEVICE_ID_BASE + device_type.id() as u16;
+            let mut function = Self::new(config, VIRTIO_VENDOR_ID, device);
+            function.config.write_u32(PCI_BAR0, bar as u32);
+            function.add_modern_caps();
+            function
+        }
+
+        /// Add the capabilities of the structures of a modern device, at the
+        /// offsets above of BAR0.
+        fn add_modern_caps(&mut self) {
+            let multiplier = NOTIFY_OFF_MULTIPLIER.to_le_bytes();
+            self.add_virtio_cap(VIRTIO_PCI_CAP_COMMON_CFG, 0, COMMON_CFG, 0x40, &[]);
+            self.add_virtio_cap(VIRTIO_PCI_CAP_NOTIFY_CFG, 0, NOTIFY, 0x100, &multiplier);
+            self.add_virtio_cap(VIRTIO_PCI_CAP_ISR_CFG, 0, ISR, 1, &[]);
+            self.add_virtio_cap(VIRTIO_PCI_CAP_DEVICE_CFG, 0, DEVICE_CFG, 0x10, &[]);
+        }
+
+        /// A transitional block device without the capabilities of a modern
+        /// device, whose I/O BAR0 is at `port`.
+        fn transitional(config: usize, port: u32) -> Self {
+            let function = Self::new(config, VIRTIO_VENDOR_ID, 0x1001);
+            function
+                .config
+                .write_u16(PCI_SUBSYSTEM_ID, DeviceType::Block.id() as u16);
+            function.config.write_u32(PCI_BAR0, port | 1);
+            function
+        }
+
+        /// Add an MSI-X capability with a table of `size` entries at
+        /// [`MSIX_TABLE`] of `bar`, which starts with its function masked.
+        fn add_msix_cap(&mut self, bar: u8, size: u16) -> usize {
+            let control = MSIX_FUNCTION_MASK | (size - 1);
+            let mut body = Vec::from(control.to_le_bytes());
+            body.extend_from_slice(&(MSIX_TABLE as u32 | bar as u32).to_le_bytes());
+            body.extend_from_slice(&0u32.to_le_bytes());
+            self.add_cap(PCI_CAP_ID_MSIX, &body)
+        }
+
+        fn ptr(&self) -> NonNull<u8> {
+            self.config.0
+        }
+
+        fn write_u8(&self, offset: usize, value: u8) {
+            unsafe { self.config.0.as_ptr().add(offset).write_volatile(value) }
+        }
+
+        /// Add a capability `id` whose bytes after the ID and the next
+        /// pointer are `body`, and return its offset.
+        fn add_cap(&mut self, id: u8, body: &[u8]) -> usize {
+            let offset = self.next_cap;
+            self.write_u8(offset, id);
+            for (i, &byte) in body.iter().enumerate() {
+                self.write_u8(offset + 2 + i, byte);
+            }
+            match self.last_cap {
+                Some(last) => self.write_u8(last + 1, offset as u8),
+                None => {
+                    self.write_u8(PCI_CAPABILITIES, offset as u8);
+                    self.config.write_u16(PCI_STATUS, PCI_STATUS_CAP_LIST);
+                }
+            }
+            self.last_cap = Some(offset);
+            self.next_cap = (offset + 2 + body.len() + 3) & !3;
+            offset
+        }
+
+        /// Add a virtio capability of `cfg_type` for `len` bytes at `offset`
+        /// of `bar`, followed by `extra`.
+        fn add_virtio_cap(
+            &mut self,
+            cfg_type: u8,
+            bar: u8,
+            offset: usize,
+            len: usize,
+            extra: &[u8],
+        ) -> usize {
+            let mut body = Vec::from([(16 + extra.len()) as u8, cfg_type, bar, 0, 0, 0]);
+            body.extend_from_slice(&(offset as u32).to_le_bytes());
+            body.extend_from_slice(&(len as u32).to_le_bytes());
+            body.extend_from_slice(extra);
+            self.add_cap(PCI_CAP_ID_VNDR, &body)
+        }
+    }
+
+    /// The field at `offset` of the common configuration in `bar`.
+    fn common_cfg<T>(bar: &DMA, offset: usize) -> *mut T {
+        (bar.vaddr() + COMMON_CFG + offset) as *mut T
+    }
+
+    #[test]
+    fn modern_device_from_its_capabilities() {
+        let (config, bar) = (DMA::new(1).unwrap(), DMA::new(1).unwrap());
+        let function = FakeFunction::modern(config.vaddr(), DeviceType::Block, bar.paddr());
+        unsafe {
+            common_cfg::<u32>(&bar, offset_of!(CommonCfg, device_feature)).write_volatile(3);
+            ((bar.vaddr() + ISR) as *mut u8).write_volatile(1);
+        }
+
+        let mut transport = unsafe { PciTransport::new(function.ptr()) }.unwrap();
+        assert_eq!(transport.device_type(), DeviceType::Block);
+        assert!(!transport.is_legacy());
+        assert!(!transport.queue_size_fixed());
+        assert_eq!(
+            function.config.read_u16(PCI_COMMAND),
+            PCI_COMMAND_IO | PCI_COMMAND_MEMORY | PCI_COMMAND_BUS_MASTER
+        );
+        assert_eq!(transport.config_space() as usize, bar.vaddr() + DEVICE_CFG);
+        assert_eq!(transport.config_space_size(), 0x10);
+
+        // both words of the features read the one fake register
+        assert_eq!(transport.read_device_features(), 3 << 32 | 3);
+        transport.write_driver_features(1);
+        let (select, feature) = unsafe {
+            (
+                common_cfg::<u32>(&bar, offset_of!(CommonCfg, driver_feature_select))
+                    .read_volatile(),
+                common_cfg::<u32>(&bar, offset_of!(CommonCfg, driver_feature)).read_volatile(),
+            )
+        };
+        // the high word, with VIRTIO_F_VERSION_1, is written last
+        assert_eq!((select, feature), (1, 1));
+
+        transport.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+        let status =
+            unsafe { common_cfg::<u8>(&bar, offset_of!(CommonCfg, device_status)).read_volatile() };
+        assert_eq!(status, 3);
+        assert_eq!(
+            transport.ack_interrupt_status(),
+            InterruptStatus::USED_BUFFER
+        );
+    }
+
+    #[test]
+    fn queues_are_set_up_and_notified_at_their_offsets() {
+        let (config, bar) = (DMA::new(1).unwrap(), DMA::new(1).unwrap());
+        let function = FakeFunction::modern(config.vaddr(), DeviceType::Network, bar.paddr());
+        let mut transport = unsafe { PciTransport::new(function.ptr()) }.unwrap();
+        let notify_off = common_cfg::<u16>(&bar, offset_of!(CommonCfg, queue_notify_off));
+
+        unsafe { notify_off.write_volatile(3) };
+        transport.queue_set(1, 16, 0x1_2345_6000, 0x7000, 0x1_0000_8000);
+        let fields = [
+            offset_of!(CommonCfg, queue_desc_low),
+            offset_of!(CommonCfg, queue_desc_high),
+            offset_of!(CommonCfg, queue_driver_low),
+            offset_of!(CommonCfg, queue_driver_high),
+            offset_of!(CommonCfg, queue_device_low),
+            offset_of!(CommonCfg, queue_device_high),
+        ];
+        assert_eq!(
+            fields.map(|offset| unsafe { common_cfg::<u32>(&bar, offset).read_volatile() }),
+            [0x2345_6000, 1, 0x7000, 0, 0x8000, 1]
+        );
+        let queue_fields = [
+            offset_of!(CommonCfg, queue_select),
+            offset_of!(CommonCfg, queue_size),
+            offset_of!(CommonCfg, queue_enable),
+        ];
+        assert_eq!(
+            queue_fields.map(|offset| unsafe { common_cfg::<u16>(&bar, offset).read_volatile() }),
+            [1, 16, 1]
+        );
+        assert_eq!(transport.queue_descriptors(1), 0x1_2345_6000);
+        transport.notify(1);
+        let notified = |offset: usize| unsafe {
+            ((bar.vaddr() + NOTIFY + offset) as *const u16).read_volatile()
+        };
+        assert_eq!(notified(3 * NOTIFY_OFF_MULTIPLIER as usize), 1);
+
+        // an offset out of the notification structure falls back to its start
+        unsafe { notify_off.write_volatile(0x100) };
+        transport.queue_set(2, 16, 0x1000, 0x2000, 0x3000);
+        transport.notify(2);
+        assert_eq!(notified(0), 2);
+    }
+
+    #[test]
+    fn queues_are_reset_only_with_ring_reset() {
+        let (config, bar) = (DMA::new(1).unwrap(), DMA::new(1).unwrap());
+        let function = FakeFunction::modern(config.vaddr(), DeviceType::Block, bar.paddr());
+        let mut transport = unsafe { PciTransport::new(function.ptr()) }.unwrap();
+
+        transport.write_driver_features(0);
+        assert_eq!(transport.queue_reset(0), Err(Error::InvalidParam));
+        transport.write_driver_features(VIRTIO_F_RING_RESET);
+        // the fake device never completes the reset
+        assert_eq!(transport.queue_reset(0), Err(Error::Timeout));
+        let reset = size_of::<CommonCfg>() + offset_of!(QueueResetCfg, queue_reset);
+        assert_eq!(unsafe { common_cfg::<u16>(&bar, reset).read_volatile() }, 1);
+    }
+
+    #[test]
+    fn first_structure_the_driver_can_map_is_used() {
+        let (config, bar) = (DMA::new(1).unwrap(), DMA::new(1).unwrap());
+        let mut function = FakeFunction::new(config.vaddr(), VIRTIO_VENDOR_ID, 0x1043);
+        function.config.write_u32(PCI_BAR0, bar.paddr() as u32);
+        // BAR2 is unassigned
+        function.add_virtio_cap(VIRTIO_PCI_CAP_COMMON_CFG, 2, 0, 0x40, &[]);
+        // too short for the common configuration
+        function.add_virtio_cap(VIRTIO_PCI_CAP_COMMON_CFG, 0, 0x800, 0x10, &[]);
+        assert_eq!(
+            unsafe { PciTransport::new(function.ptr()) }.err(),
+            Some(Error::InvalidParam)
+        );
+
+        function.add_modern_caps();
+        // a list which loops is walked a bounded number of times
+        let first = function.config.read_u8(PCI_CAPABILITIES);
+        function.write_u8(function.last_cap.unwrap() + 1, first);
+        let transport = unsafe { PciTransport::new(function.ptr()) }.unwrap();
+        assert_eq!(transport.device_type(), DeviceType::Console);
+        transport.set_status(DeviceStatus::FAILED);
+        let status = offset_of!(CommonCfg, device_status);
+        assert_eq!(
+            unsafe { common_cfg::<u8>(&bar, status).read_volatile() },
+            0x80
+        );
+        assert_eq!(
+            unsafe { ((bar.vaddr() + 0x800 + status) as *const u8).read_volatile() },
+            0
+        );
+    }
+
+    #[test]
+    fn other_functions_are_rejected() {
+        let config = DMA::new(1).unwrap();
+        for (vendor, device) in [
+            (0x8086, 0x1042),
+            (VIRTIO_VENDOR_ID, 0x0042),
+            (VIRTIO_VENDOR_ID, MODERN_DEVICE_ID_BASE),
+            // transitional, but without an I/O window for its legacy interface
+            (VIRTIO_VENDOR_ID, 0x1001),
+        ] {
+            let function = FakeFunction::new(config.vaddr(), vendor, device);
+            function
+                .config
+                .write_u16(PCI_SUBSYSTEM_ID, DeviceType::Block.id() as u16);
+            assert_eq!(
+                unsafe { PciTransport::new(function.ptr()) }.err(),
+                Some(Error::InvalidParam)
+            );
+        }
+    }
+
+    /// The legacy register at `offset` of the I/O BAR0 at `port`, in the I/O
+    /// space mapped at `io`.
+    fn legacy_reg<T>(io: &DMA, port: u32, offset: usize) -> *mut T {
+        (io.vaddr() + port as usize + offset) as *mut T
+    }
+
+    #[test]
+    fn transitional_device_falls_back_to_its_legacy_registers() {
+        let (config, io) = (DMA::new(1).unwrap(), DMA::new(1).unwrap());
+        let port = 0x40;
+        let function = FakeFunction::transitional(config.vaddr(), port);
+        unsafe {
+            legacy_reg::<u32>(&io, port, offset_of!(LegacyHeader, host_features))
+                .write_volatile(0x1234_5678);
+            legacy_reg::<u16>(&io, port, offset_of!(LegacyHeader, queue_size)).write_volatile(128);
+            legacy_reg::<u8>(&io, port, offset_of!(LegacyHeader, isr_status)).write_volatile(2);
+            legacy_reg::<u8>(&io, port, size_of::<LegacyHeader>()).write_volatile(0xab);
+        }
+        let reg = |offset: usize| unsafe { legacy_reg::<u32>(&io, port, offset).read_volatile() };
+
+        let mut transport =
+            unsafe { PciTransport::with_io_window(function.ptr(), io.vaddr()) }.unwrap();
+        assert_eq!(transport.device_type(), DeviceType::Block);
+        assert!(transport.is_legacy());
+        assert!(transport.queue_size_fixed());
+        // sizing BAR0 restores it
+        assert_eq!(function.config.read_u32(PCI_BAR0), port | 1);
+
+        assert_eq!(transport.read_device_features(), 0x1234_5678);
+        // the legacy interface only has the first 32 feature bits
+        transport.write_driver_features(VIRTIO_F_VERSION_1 | 5);
+        assert_eq!(reg(offset_of!(LegacyHeader, guest_features)), 5);
+
+        assert_eq!(transport.max_queue_size(1), 128);
+        transport.queue_set(1, 128, 0x5000, 0x5800, 0x7000);
+        assert_eq!(reg(offset_of!(LegacyHeader, queue_pfn)), 5);
+        assert_eq!(transport.queue_descriptors(1), 0x5000);
+        transport.notify(1);
+        let notify = legacy_reg::<u16>(&io, port, offset_of!(LegacyHeader, queue_notify));
+        assert_eq!(unsafe { notify.read_volatile() }, 1);
+        transport.queue_unset(1);
+        assert_eq!(reg(offset_of!(LegacyHeader, queue_pfn)), 0);
+
+        transport.set_status(DeviceStatus::ACKNOWLEDGE);
+        let status = legacy_reg::<u8>(&io, port, offset_of!(LegacyHeader, device_status));
+        assert_eq!(unsafe { status.read_volatile() }, 1);
+        assert_eq!(
+            transport.ack_interrupt_status(),
+            InterruptStatus::CONFIG_CHANGE
+        );
+        // the device configuration follows the registers
+        assert_eq!(
+            transport.config_space() as usize,
+            io.vaddr() + port as usize + size_of::<LegacyHeader>()
+        );
+        assert_eq!(unsafe { transport.config_read(0, 1) }, 0xab);
+    }
+
+    #[test]
+    fn legacy_registers_need_an_assigned_io_bar() {
+        let (config, io) = (DMA::new(1).unwrap(), DMA::new(1).unwrap());
+        // unassigned, and a memory BAR
+        for bar in [1, 0x1000] {
+            let function = FakeFunction::transitional(config.vaddr(), 0);
+            function.config.write_u32(PCI_BAR0, bar);
+            assert_eq!(
+                unsafe { PciTransport::with_io_window(function.ptr(), io.vaddr()) }.err(),
+                Some(Error::InvalidParam)
+            );
+        }
+    }
+
+    #[cfg(feature = "port-io-hal")]
+    #[test]
+    fn legacy_registers_are_accessed_with_port_io() {
+        use crate::testing::io_ports;
+
+        let config = DMA::new(1).unwrap();
+        let port = 0x2000;
+        let function = FakeFunction::transitional(config.vaddr(), port as u32);
+        {
+            let mut ports = io_ports();
+            ports[port..port + 4].copy_from_slice(&0x8765_4321u32.to_le_bytes());
+            ports[port + size_of::<LegacyHeader>()] = 0xcd;
+        }
+
+        let mut transport = unsafe { PciTransport::with_io_ports(function.ptr()) }.unwrap();
+        assert!(transport.is_legacy());
+        assert_eq!(transport.read_device_features(), 0x8765_4321);
+        transport.set_status(DeviceStatus::DRIVER);
+        transport.notify(3);
+        // the device configuration is not mapped into memory
+        assert!(transport.config_space().is_null());
+        assert_eq!(unsafe { transport.config_read(0, 1) }, 0xcd);
+
+        let ports = io_ports();
+        let status = port + offset_of!(LegacyHeader, device_status);
+        assert_eq!(ports[status], DeviceStatus::DRIVER.bits() as u8);
+        let notify = port + offset_of!(LegacyHeader, queue_notify);
+        assert_eq!(ports[notify..notify + 2], [3, 0]);
+    }
+
+    /// The address of function `function` of `device` on `bus` of the ECAM
+    /// region at `ecam`.
+    fn ecam_function(ecam: &DMA, bus: u8, device: u8, function: u8) -> usize {
+        ecam.vaddr() + ((bus as usize) << 20 | (device as usize) << 15 | (function as usize) << 12)
+    }
+
+    #[test]
+    fn root_finds_the_virtio_functions_on_its_buses() {
+        let ecam = DMA::new(2 << 8).unwrap();
+        let (bar, io) = (DMA::new(1).unwrap(), DMA::new(1).unwrap());
+        // all functions are absent but those laid out below
+        for function in 0..2 * 32 * 8 {
+            FakeFunction::new(ecam.vaddr() + (function << 12), 0xffff, 0xffff);
+        }
+        let at = |bus, device, function| ecam_function(&ecam, bus, device, function);
+        FakeFunction::new(at(0, 0, 0), 0x8086, 0x29c0);
+        FakeFunction::modern(at(0, 1, 0), DeviceType::Block, bar.paddr());
+        // not a multi-function device, so its other functions are not scanned
+        FakeFunction::modern(at(0, 1, 1), DeviceType::Block, bar.paddr());
+        let multi_function = FakeFunction::new(at(0, 3, 0), 0x8086, 0x1234);
+        multi_function.write_u8(PCI_HEADER_TYPE, 0x80);
+        FakeFunction::modern(at(0, 3, 2), DeviceType::Console, bar.paddr());
+        // lacking its capabilities
+        FakeFunction::new(at(0, 4, 0), VIRTIO_VENDOR_ID, 0x1042);
+        FakeFunction::transitional(at(0, 5, 0), 0x40);
+        FakeFunction::modern(at(1, 0, 0), DeviceType::Network, bar.paddr());
+
+        let ecam_ptr = NonNull::new(ecam.vaddr() as *mut u8).unwrap();
+        let found = |root: PciRoot| {
+            root.virtio_devices()
+                .map(|(address, transport)| {
+                    let PciAddress {
+                        bus,
+                        device,
+                        function,
+                    } = address;
+                    ((bus, device, function), transport.device_type())
+                })
+                .collect::<Vec<_>>()
+        };
+        let modern = [
+            ((0, 1, 0), DeviceType::Block),
+            ((0, 3, 2), DeviceType::Console),
+            ((1, 0, 0), DeviceType::Network),
+        ];
+        assert_eq!(found(unsafe { PciRoot::new(ecam_ptr, 0..=1) }), modern);
+        assert_eq!(found(unsafe { PciRoot::new(ecam_ptr, 0..=0) }), modern[..2]);
+        let root = unsafe { PciRoot::new(ecam_ptr, 0..=1).with_io_window(io.vaddr()) };
+        assert_eq!(
+            found(root),
+            [
+                modern[0],
+                modern[1],
+                ((0, 5, 0), DeviceType::Block),
+                modern[2]
+            ]
+        );
+    }
+
+    #[test]
+    fn msix_entries_are_programmed_and_unmasked() {
+        let (config, bar) = (DMA::new(1).unwrap(), DMA::new(1).unwrap());
+        let mut function = FakeFunction::modern(config.vaddr(), DeviceType::Block, bar.paddr());
+        let cap = function.add_msix_cap(0, 4);
+        let table = (bar.vaddr() + MSIX_TABLE) as *mut u32;
+        for entry in 0..4 {
+            unsafe { table.add(entry * 4 + 3).write_volatile(MSIX_ENTRY_MASKED) };
+        }
+        let mut transport = unsafe { PciTransport::new(function.ptr()) }.unwrap();
+        assert_eq!(transport.msix_table_size(), 4);
+        assert!(!transport.msix_enabled());
+
+        transport.set_msix_entry(2, 0x1_fee0_0000, 0x41).unwrap();
+        let entry = |entry: usize| {
+            [0, 1, 2, 3].map(|i| unsafe { table.add(entry * 4 + i).read_volatile() })
+        };
+        assert_eq!(entry(2), [0xfee0_0000, 1, 0x41, 0]);
+        assert_eq!(entry(1), [0, 0, 0, MSIX_ENTRY_MASKED]);
+        assert_eq!(
+            transport.set_msix_entry(4, 0xfee0_0000, 0),
+            Err(Error::InvalidParam)
+        );
+
+        transport.enable_msix(true).unwrap();
+        assert!(transport.msix_enabled());
+        // the function is no longer masked
+        assert_eq!(function.config.read_u16(cap + 2), MSIX_ENABLE | 3);
+        transport.enable_msix(false).unwrap();
+        assert!(!transport.msix_enabled());
+        assert_eq!(function.config.read_u16(cap + 2), 3);
+    }
+
+    #[test]
+    fn msix_needs_its_capability_and_table() {
+        let (config, bar) = (DMA::new(1).unwrap(), DMA::new(1).unwrap());
+        let mut function = FakeFunction::modern(config.vaddr(), DeviceType::Block, bar.paddr());
+        let mut transport = unsafe { PciTransport::new(function.ptr()) }.unwrap();
+        assert_eq!(transport.msix_table_size(), 0);
+        assert_eq!(transport.enable_msix(true), Err(Error::InvalidParam));
+        assert_eq!(transport.set_msix_entry(0, 0, 0), Err(Error::InvalidParam));
+        assert!(!transport.msix_enabled());
+
+        // the table is in the unassigned BAR1
+        function.add_msix_cap(1, 4);
+        let transport = unsafe { PciTransport::new(function.ptr()) }.unwrap();
+        assert_eq!(transport.msix_table_size(), 0);
+    }
+
+    #[test]
+    fn vectors_are_assigned_while_msix_is_enabled() {
+        let (config, bar) = (DMA::new(1).unwrap(), DMA::new(1).unwrap());
+        let mut function = FakeFunction::modern(config.vaddr(), DeviceType::Block, bar.paddr());
+        function.add_msix_cap(0, 4);
+        let mut transport = unsafe { PciTransport::new(function.ptr()) }.unwrap();
+        let vector = |field: usize| unsafe { common_cfg::<u16>(&bar, field).read_volatile() };
+
+        // the interrupt line needs no vectors
+        assert_eq!(transport.set_config_vector(NO_VECTOR), Ok(()));
+        assert_eq!(transport.set_config_vector(1), Err(Error::InvalidParam));
+        assert_eq!(transport.set_queue_vector(0, 1), Err(Error::InvalidParam));
+
+        transport.enable_msix(true).unwrap();
+        transport.set_config_vector(1).unwrap();
+        transport.set_queue_vector(2, 3).unwrap();
+        assert_eq!(vector(offset_of!(CommonCfg, config_msix_vector)), 1);
+        assert_eq!(vector(offset_of!(CommonCfg, queue_select)), 2);
+        assert_eq!(vector(offset_of!(CommonCfg, queue_msix_vector)), 3);
+    }
+
+    #[test]
+    fn legacy_configuration_moves_while_msix_is_enabled() {
+        let (config, bar, io) = (
+            DMA::new(1).unwrap(),
+            DMA::new(1).unwrap(),
+            DMA::new(1).unwrap(),
+        );
+        let port = 0x40;
+        let mut function = FakeFunction::transitional(config.vaddr(), port);
+        function.config.write_u32(PCI_BAR0 + 4, bar.paddr() as u32);
+        function.add_msix_cap(1, 2);
+        let mut transport =
+            unsafe { PciTransport::with_io_window(function.ptr(), io.vaddr()) }.unwrap();
+        assert!(transport.is_legacy());
+        let regs = io.vaddr() + port as usize;
+        assert_eq!(
+            transport.config_space() as usize,
+            regs + size_of::<LegacyHeader>()
+        );
+
+        transport.enable_msix(true).unwrap();
+        assert_eq!(
+            transport.config_space() as usize,
+            regs + size_of::<LegacyHeader>() + size_of::<LegacyMsix>()
+        );
+        transport.set_config_vector(2).unwrap();
+        transport.set_queue_vector(1, 1).unwrap();
+        let vectors =
+            unsafe { ((regs + size_of::<LegacyHeader>()) as *const [u16; 2]).read_volatile() };
+        assert_eq!(vectors, [2, 1]);
+        let queue_select = legacy_reg::<u16>(&io, port, offset_of!(LegacyHeader, queue_select));
+        assert_eq!(unsafe { queue_select.read_volatile() }, 1);
+    }
+}
@@ -0,0 +1,207 @@
+use super::*;
+use crate::queue::VirtQueue;
+use crate::volatile::ReadOnly;
+use bitflags::*;
+use core::mem::offset_of;
+
+/// The virtio persistent memory device.
+///
+/// The device exposes a range of guest physical memory backed by persistent
+/// storage on the host, which the guest can access directly. Writes to the
+/// range are only guaranteed to be durable after a flush request has been
+/// completed by the device.
+pub struct VirtIOPmem<'a> {
+    header: &'static mut dyn Transport,
+    queue: VirtQueue<'a>,
+    start: u64,
+    size: u64,
+    features: Features,
+}
+
+impl VirtIOPmem<'_> {
+    /// Create a new VirtIO-Pmem driver.
+    pub fn new(header: &'static mut dyn Transport) -> Result<Self> {
+        Self::from_init(DeviceInit::new(header))
+    }
+
+    /// Create a new VirtIO-Pmem driver, initializing the device as customized
+    /// by `init`.
+    pub fn from_init(init: DeviceInit<Acknowledged>) -> Result<Self> {
+        let features = Features::from_bits_truncate(init.device_features());
+        info!("Device features {:?}", features);
+        let supported_features = Features::empty();
+        let mut init = init.negotiate(supported_features.bits())?;
+
+        // read configuration space
+        let (start, size) = init.read_config_atomic(|| {
+            Ok::<_, Error>((
+                init.read_config::<Le64>(offset_of!(Config, start))?.get(),
+                init.read_config::<Le64>(offset_of!(Config, size))?.get(),
+            ))
+        })?;
+        info!("start={:#x}, size={:#x}", start, size);
+
+        let queue = init.queue(QUEUE_REQUEST, 2)?;
+        let negotiated = init.features();
+        let header = init.finish();
+
+        Ok(VirtIOPmem {
+            start,
+            size,
+            header,
+            queue,
+            features: Features::from_bits_truncate(negotiated),
+        })
+    }
+
+    /// Acknowledge interrupt.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.header.ack_interrupt()
+    }
+
+    /// Suspend the device, e.g. before the guest enters S3.
+    ///
+    /// The device is reset so that it stops using the queue, which is set up
+    /// again by [`resume`](Self::resume). The persistent memory itself is
+    /// kept by the host.
+    pub fn suspend(&mut self) -> Result {
+        self.header.reset();
+        Ok(())
+    }
+
+    /// Resume the device after [`suspend`](Self::suspend), negotiating the
+    /// same features and setting up the queue again.
+    pub fn resume(&mut self) -> Result {
+        self.header.begin_reinit(self.features.bits())?;
+        self.queue.reinit(self.header);
+        self.header.finish_init();
+        Ok(())
+    }
+
+    /// The guest physical address of the persistent memory range.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// The size of the persistent memory range in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Flush all previous writes to the persistent memory range, blocking
+    /// until they are durable.
+    pub fn flush(&mut self) -> Result {
+        let req = PmemReq {
+            type_: ReqType::Flush,
+        };
+        let mut resp = PmemResp { ret: u32::MAX };
+        self.queue
+            .add_notify_wait_pop(self.header, &[req.as_buf()], &[resp.as_buf_mut()])?;
+        match resp.ret {
+            0 => Ok(()),
+            _ => Err(Error::IoError),
+        }
+    }
+}
+
+impl Drop for VirtIOPmem<'_> {
+    fn drop(&mut self) {
+        // stop the device from using the queues, so that they are freed
+        self.header.reset();
+        self.queue.unset(self.header);
+    }
+}
+
+impl Driver for VirtIOPmem<'_> {
+    fn device_type(&self) -> DeviceType {
+        self.header.device_type()
+    }
+
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
+        self.header.ack_interrupt_status()
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.features.bits()
+    }
+
+    fn enable_notifications(&mut self) {
+        self.queue.set_used_notifications(true);
+    }
+
+    fn disable_notifications(&mut self) {
+        self.queue.set_used_notifications(false);
+    }
+
+    fn metrics(&self, f: &mut dyn FnMut(Metric)) {
+        self.queue.metrics().report("requestq", f);
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.header.needs_reset()
+    }
+
+    fn recover(&mut self, lost: &mut dyn FnMut(LostRequest)) -> Result {
+        self.queue.report_lost("requestq", lost);
+        self.header.reset();
+        self.resume()
+    }
+
+    #[cfg(feature = "time-hal")]
+    fn set_timeout(&mut self, ticks: Option<u64>) {
+        self.queue.set_timeout(ticks);
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct Config {
+    /// The start address of the persistent memory range.
+    start: ReadOnly<Le64>,
+    /// The size of the persistent memory range.
+    size: ReadOnly<Le64>,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct PmemReq {
+    type_: ReqType,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct PmemResp {
+    /// Zero on success, non-zero on failure.
+    ret: u32,
+}
+
+#[repr(u32)]
+#[derive(Debug)]
+enum ReqType {
+    Flush = 0,
+}
+
+bitflags! {
+    struct Features: u64 {
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+unsafe impl AsBuf for PmemReq {}
+unsafe impl AsBuf for PmemResp {}
+
+const QUEUE_REQUEST: usize = 0;
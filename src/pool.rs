@@ -0,0 +1,163 @@
+//! DMA memory in static storage.
+
+use super::*;
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A pool of `PAGES` pages of DMA memory in static storage.
+///
+/// The drivers allocate their queues and buffers only through the HAL, so
+/// backing `virtio_dma_alloc` and `virtio_dma_dealloc` with a pool lets them
+/// run without a heap, e.g. in early boot stages:
+///
+/// ```ignore
+/// static POOL: DmaPool<64> = DmaPool::new();
+///
+/// #[no_mangle]
+/// extern "C" fn virtio_dma_alloc(pages: usize) -> usize {
+///     POOL.alloc(pages)
+/// }
+///
+/// #[no_mangle]
+/// extern "C" fn virtio_dma_dealloc(paddr: usize, pages: usize) -> i32 {
+///     POOL.dealloc(paddr, pages)
+/// }
+/// ```
+///
+/// The pool must live in memory which the device can access, and
+/// `virtio_virt_to_phys` and `virtio_phys_to_virt` must translate its
+/// addresses.
+pub struct DmaPool<const PAGES: usize> {
+    lock: AtomicBool,
+    /// Whether each page is allocated.
+    used: UnsafeCell<[bool; PAGES]>,
+    pages: UnsafeCell<[Page; PAGES]>,
+}
+
+// SAFETY: `used` and the allocation of `pages` are only accessed while
+// holding `lock`.
+unsafe impl<const PAGES: usize> Sync for DmaPool<PAGES> {}
+
+impl<const PAGES: usize> DmaPool<PAGES> {
+    /// Create a pool with all pages free.
+    pub const fn new() -> Self {
+        const ZERO: Page = Page([0; PAGE_SIZE]);
+        DmaPool {
+            lock: AtomicBool::new(false),
+            used: UnsafeCell::new([false; PAGES]),
+            pages: UnsafeCell::new([ZERO; PAGES]),
+        }
+    }
+
+    /// Allocate `pages` contiguous zeroed pages.
+    ///
+    /// Returns the physical address of the first page, or 0 if there are not
+    /// enough contiguous free pages.
+    pub fn alloc(&self, pages: usize) -> usize {
+        if pages == 0 {
+            return 0;
+        }
+        self.lock();
+        let used = unsafe { &mut *self.used.get() };
+        let mut paddr = 0;
+        let mut start = 0;
+        while start + pages <= PAGES {
+            match used[start..start + pages].iter().rposition(|&used| used) {
+                // skip past the last allocated page of the range
+                Some(i) => start += i + 1,
+                None => {
+                    used[start..start + pages].fill(true);
+                    let first = unsafe { (self.pages.get() as *mut Page).add(start) };
+                    unsafe { ptr::write_bytes(first, 0, pages) };
+                    paddr = virt_to_phys(first as usize);
+                    break;
+                }
+            }
+        }
+        self.unlock();
+        if paddr == 0 {
+            warn!("DMA pool has no {} contiguous free pages", pages);
+        }
+        paddr
+    }
+
+    /// Free `pages` pages allocated by [`alloc`](Self::alloc) at `paddr`.
+    ///
+    /// Returns 0 on success, or -1 if the pages were not allocated from the
+    /// pool.
+    pub fn dealloc(&self, paddr: usize, pages: usize) -> i32 {
+        let base = self.pages.get() as usize;
+        let vaddr = phys_to_virt(paddr);
+        if vaddr < base || !(vaddr - base).is_multiple_of(PAGE_SIZE) {
+            return -1;
+        }
+        let start = (vaddr - base) / PAGE_SIZE;
+        if pages == 0 || start + pages > PAGES {
+            return -1;
+        }
+        self.lock();
+        let used = unsafe { &mut *self.used.get() };
+        let result = if used[start..start + pages].iter().all(|&used| used) {
+            used[start..start + pages].fill(false);
+            0
+        } else {
+            -1
+        };
+        self.unlock();
+        result
+    }
+
+    fn lock(&self) {
+        while self
+            .lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+    }
+
+    fn unlock(&self) {
+        self.lock.store(false, Ordering::Release);
+    }
+}
+
+impl<const PAGES: usize> Default for DmaPool<PAGES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[repr(C, align(4096))]
+struct Page([u8; PAGE_SIZE]);
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocates_contiguous_zeroed_pages() {
+        static POOL: DmaPool<4> = DmaPool::new();
+        assert_eq!(POOL.alloc(0), 0);
+        let first = POOL.alloc(2);
+        assert_ne!(first, 0);
+        let second = POOL.alloc(1);
+        assert_eq!(second, first + 2 * PAGE_SIZE);
+        assert_eq!(POOL.alloc(2), 0);
+
+        unsafe { ptr::write_bytes(phys_to_virt(first) as *mut u8, 0xff, 2 * PAGE_SIZE) };
+        assert_eq!(POOL.dealloc(first, 2), 0);
+        assert_eq!(POOL.dealloc(first, 2), -1);
+        assert_eq!(POOL.alloc(2), first);
+        let page = unsafe { &*(phys_to_virt(first) as *const [u8; 2 * PAGE_SIZE]) };
+        assert!(page.iter().all(|&byte| byte == 0));
+
+        // pages outside the pool, or not at a page boundary
+        assert_eq!(POOL.dealloc(second + PAGE_SIZE * 4, 1), -1);
+        assert_eq!(POOL.dealloc(second + 1, 1), -1);
+        assert_eq!(POOL.dealloc(second, 2), -1);
+        assert_eq!(POOL.dealloc(second, 1), 0);
+    }
+}
@@ -0,0 +1,150 @@
+use super::*;
+use core::convert::Infallible;
+use core::marker::PhantomData;
+
+/// A driver constructed by [`probe`] for the type of device it found.
+///
+/// Only the variants of the drivers enabled by Cargo features exist.
+#[allow(clippy::large_enum_variant)]
+pub enum DeviceKind<'a> {
+    /// A block device.
+    #[cfg(feature = "blk")]
+    Blk(VirtIOBlk<'a>),
+    /// A network card.
+    #[cfg(feature = "net")]
+    Net(VirtIONet<'a>),
+    /// A GPU.
+    #[cfg(feature = "gpu")]
+    Gpu(VirtIOGpu<'a>),
+    /// A sound card.
+    #[cfg(feature = "sound")]
+    Sound(VirtIOSound<'a>),
+    /// A persistent memory device.
+    #[cfg(feature = "pmem")]
+    Pmem(VirtIOPmem<'a>),
+    /// A video encoder or decoder.
+    #[cfg(feature = "video")]
+    Video(VirtIOVideo<'a>),
+    /// A CAN controller.
+    #[cfg(feature = "can")]
+    Can(VirtIOCan<'a>),
+    /// A Bluetooth controller.
+    #[cfg(feature = "bluetooth")]
+    Bluetooth(VirtIOBluetooth<'a>),
+    /// A crosvm Wayland device.
+    #[cfg(feature = "wl")]
+    Wl(VirtIOWl<'a>),
+    /// An SCMI device.
+    #[cfg(feature = "scmi")]
+    Scmi(VirtIOScmi<'a>),
+    /// A mac80211_hwsim device.
+    #[cfg(feature = "hwsim")]
+    Hwsim(VirtIOHwsim<'a>),
+    /// A device without an enabled driver, or whose driver needs more than the header
+    /// to be constructed, like `VirtIOInput`.
+    ///
+    /// The header is handed back so that the caller can set it up itself.
+    Other(DeviceType, &'static mut dyn Transport),
+    /// Keeps the lifetime used when no driver is enabled. Never constructed.
+    #[doc(hidden)]
+    _Unused(Infallible, PhantomData<&'a ()>),
+}
+
+impl<'a> DeviceKind<'a> {
+    /// The type of the device.
+    pub fn device_type(&self) -> DeviceType {
+        match self {
+            #[cfg(feature = "blk")]
+            DeviceKind::Blk(driver) => driver.device_type(),
+            #[cfg(feature = "net")]
+            DeviceKind::Net(driver) => driver.device_type(),
+            #[cfg(feature = "gpu")]
+            DeviceKind::Gpu(driver) => driver.device_type(),
+            #[cfg(feature = "sound")]
+            DeviceKind::Sound(driver) => driver.device_type(),
+            #[cfg(feature = "pmem")]
+            DeviceKind::Pmem(driver) => driver.device_type(),
+            #[cfg(feature = "video")]
+            DeviceKind::Video(driver) => driver.device_type(),
+            #[cfg(feature = "can")]
+            DeviceKind::Can(driver) => driver.device_type(),
+            #[cfg(feature = "bluetooth")]
+            DeviceKind::Bluetooth(driver) => driver.device_type(),
+            #[cfg(feature = "wl")]
+            DeviceKind::Wl(driver) => driver.device_type(),
+            #[cfg(feature = "scmi")]
+            DeviceKind::Scmi(driver) => driver.device_type(),
+            #[cfg(feature = "hwsim")]
+            DeviceKind::Hwsim(driver) => driver.device_type(),
+            DeviceKind::Other(device_type, _) => *device_type,
+            DeviceKind::_Unused(never, _) => match *never {},
+        }
+    }
+
+    /// The driver of the device, or `None` for [`DeviceKind::Other`].
+    pub fn as_driver(&mut self) -> Option<&mut (dyn Driver + 'a)> {
+        match self {
+            #[cfg(feature = "blk")]
+            DeviceKind::Blk(driver) => Some(driver),
+            #[cfg(feature = "net")]
+            DeviceKind::Net(driver) => Some(driver),
+            #[cfg(feature = "gpu")]
+            DeviceKind::Gpu(driver) => Some(driver),
+            #[cfg(feature = "sound")]
+            DeviceKind::Sound(driver) => Some(driver),
+            #[cfg(feature = "pmem")]
+            DeviceKind::Pmem(driver) => Some(driver),
+            #[cfg(feature = "video")]
+            DeviceKind::Video(driver) => Some(driver),
+            #[cfg(feature = "can")]
+            DeviceKind::Can(driver) => Some(driver),
+            #[cfg(feature = "bluetooth")]
+            DeviceKind::Bluetooth(driver) => Some(driver),
+            #[cfg(feature = "wl")]
+            DeviceKind::Wl(driver) => Some(driver),
+            #[cfg(feature = "scmi")]
+            DeviceKind::Scmi(driver) => Some(driver),
+            #[cfg(feature = "hwsim")]
+            DeviceKind::Hwsim(driver) => Some(driver),
+            DeviceKind::Other(..) => None,
+            DeviceKind::_Unused(never, _) => match *never {},
+        }
+    }
+}
+
+/// Read the type of the device with `header` and construct its driver.
+pub fn probe<'a>(header: &'static mut dyn Transport) -> Result<DeviceKind<'a>> {
+    let device_type = header.device_type();
+    if device_type == DeviceType::Invalid {
+        return Err(Error::InvalidParam);
+    }
+    info!("Detected virtio device of type {:?}", device_type);
+    let kind = match device_type {
+        #[cfg(feature = "blk")]
+        DeviceType::Block => DeviceKind::Blk(VirtIOBlk::new(header)?),
+        #[cfg(feature = "net")]
+        DeviceType::Network => DeviceKind::Net(VirtIONet::new(header)?),
+        #[cfg(feature = "gpu")]
+        DeviceType::GPU => DeviceKind::Gpu(VirtIOGpu::new(header)?),
+        #[cfg(feature = "sound")]
+        DeviceType::Sound => DeviceKind::Sound(VirtIOSound::new(header)?),
+        #[cfg(feature = "pmem")]
+        DeviceType::Pmem => DeviceKind::Pmem(VirtIOPmem::new(header)?),
+        #[cfg(feature = "video")]
+        DeviceType::VideoEncoder | DeviceType::VideoDecoder => {
+            DeviceKind::Video(VirtIOVideo::new(header)?)
+        }
+        #[cfg(feature = "can")]
+        DeviceType::Can => DeviceKind::Can(VirtIOCan::new(header)?),
+        #[cfg(feature = "bluetooth")]
+        DeviceType::Bluetooth => DeviceKind::Bluetooth(VirtIOBluetooth::new(header)?),
+        #[cfg(feature = "wl")]
+        DeviceType::Wl => DeviceKind::Wl(VirtIOWl::new(header)?),
+        #[cfg(feature = "scmi")]
+        DeviceType::Scmi => DeviceKind::Scmi(VirtIOScmi::new(header)?),
+        #[cfg(feature = "hwsim")]
+        DeviceType::Mac80211Hwsim => DeviceKind::Hwsim(VirtIOHwsim::new(header)?),
+        device_type => DeviceKind::Other(device_type, header),
+    };
+    Ok(kind)
+}
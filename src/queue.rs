@@ -1,26 +1,41 @@
-use core::mem::size_of;
+use core::future::Future;
+use core::marker::PhantomData;
+use core::mem::{self, size_of, take, ManuallyDrop};
+use core::pin::Pin;
+use core::ptr;
 use core::slice;
 use core::sync::atomic::{fence, Ordering};
+use core::task::{Context, Poll, Waker};
 
 use super::*;
-use crate::header::VirtIOHeader;
+use crate::metrics::QueueMetrics;
 use bitflags::*;
 
-use volatile::Volatile;
+use crate::volatile::{ReadOnly, Volatile};
+
+/// The maximum number of buffers in an indirect descriptor table.
+const MAX_INDIRECT: usize = 16;
+
+/// The maximum number of segments of an [`SgList`].
+const MAX_SEGMENTS: usize = 16;
 
 /// The mechanism for bulk data transport on virtio devices.
 ///
 /// Each device can have zero or more virtqueues.
+///
+/// A queue is `Send` and `Sync`. It is only modified through `&mut self`, so
+/// a queue shared between cores needs a lock, but queues of the same device
+/// can be locked independently of each other.
 #[repr(C)]
 pub struct VirtQueue<'a> {
-    /// DMA guard
-    dma: DMA,
-    /// Descriptor table
-    desc: &'a mut [Descriptor],
-    /// Available ring
-    avail: &'a mut AvailRing,
-    /// Used ring
-    used: &'a mut UsedRing,
+    /// The memory of the descriptor table and rings, which is leaked if the
+    /// queue is dropped while still attached to the device.
+    memory: ManuallyDrop<QueueMemory>,
+    /// Whether the queue is set up on the device, which may then use it
+    /// until it is [unset](Self::unset) or the device is reset.
+    attached: bool,
+    /// The rings, in the layout chosen when the queue was created.
+    rings: Rings<'a>,
 
     /// The index of queue
     queue_idx: u32,
@@ -30,188 +45,2079 @@ pub struct VirtQueue<'a> {
     num_used: u16,
     /// The head desc index of the free list.
     free_head: u16,
+    /// The index of the next slot of the available ring, or in packed queues
+    /// the position of the next descriptor with the wrap counter in
+    /// [`PACKED_WRAP`].
     avail_idx: u16,
+    /// The index of the next element of the used ring to take, or in packed
+    /// queues the position of the next used descriptor with the wrap
+    /// counter in [`PACKED_WRAP`].
     last_used_idx: u16,
+    /// The position and flags of the first chain added to a packed queue
+    /// since it was last published, which make the chains after it
+    /// available at once when they are written.
+    pending_head: Option<(u16, DescFlags)>,
+    /// The maximum number of descriptors in a chain, e.g. as limited by the
+    /// device.
+    max_chain_len: usize,
+    /// The maximum length of the buffer of a descriptor.
+    max_desc_len: u32,
+    /// Whether `VIRTIO_F_ORDER_PLATFORM` was negotiated, so the barriers
+    /// around the rings are those of the platform.
+    order_platform: bool,
+    /// Statistics, where `depth` is not kept up to date.
+    metrics: QueueMetrics,
+    /// The number of chains with a `completed` length.
+    num_completed: u16,
+    /// Whether a wait for the device failed, so it was reset and no chains
+    /// are added until the queue is set up again.
+    broken: bool,
+    /// The ticks of the HAL clock to wait for the device to use a chain.
+    #[cfg(feature = "time-hal")]
+    timeout: Option<u64>,
+    /// The indirect descriptor tables, if enabled.
+    indirect: Option<IndirectTables<'a>>,
+    /// The state of the driver for each descriptor, by descriptor index, or
+    /// by buffer ID in packed queues.
+    states: DescStates,
+}
+
+// SAFETY: The memory of the queue is only accessed through the queue, which
+// is only modified through `&mut self`.
+unsafe impl Send for VirtQueue<'_> {}
+unsafe impl Sync for VirtQueue<'_> {}
+
+/// The state of the driver for a descriptor.
+#[derive(Default)]
+struct DescState {
+    /// The DMA buffer of the descriptor while it is in use.
+    dma_buf: Option<InFlight>,
+    /// The length of the chain with this head used by the device but not
+    /// popped yet, whose descriptors are recycled when it is.
+    completed: Option<u32>,
+    /// The waker of the task polling for the chain with this head.
+    waker: Option<Waker>,
+    /// Whether the chain with this head is in flight.
+    in_flight: bool,
+    /// Whether the chain with this head was in flight when the queue was
+    /// set up again, so its poller is told it was lost.
+    lost: bool,
+    /// The next state in the chain or the free list of a packed queue, whose
+    /// ring does not link descriptors.
+    next: u16,
+    /// The number of descriptors of the chain with this head in a packed
+    /// queue, which the device skips when it uses the chain.
+    chain_len: u16,
+    /// Whether the descriptor is in a chain owned by the device.
+    #[cfg(feature = "validate")]
+    owned: bool,
+    /// The number of bytes the device may write to the chain with this head.
+    #[cfg(feature = "validate")]
+    writable: u64,
+}
+
+/// The states of the descriptors of a queue, which the device does not
+/// access, so they are kept in ordinary memory.
+#[cfg(feature = "alloc")]
+type DescStates = alloc::boxed::Box<[DescState]>;
+
+/// The states of the descriptors of a queue, held inline for queues of up to
+/// `INLINE_STATES` descriptors.
+#[cfg(not(feature = "alloc"))]
+struct DescStates {
+    states: [DescState; INLINE_STATES],
+    len: usize,
+}
+
+/// The largest queue whose descriptor states are held inline.
+#[cfg(not(feature = "alloc"))]
+const INLINE_STATES: usize = 64;
+
+#[cfg(not(feature = "alloc"))]
+impl core::ops::Deref for DescStates {
+    type Target = [DescState];
+
+    fn deref(&self) -> &[DescState] {
+        &self.states[..self.len]
+    }
+}
+
+#[cfg(not(feature = "alloc"))]
+impl core::ops::DerefMut for DescStates {
+    fn deref_mut(&mut self) -> &mut [DescState] {
+        &mut self.states[..self.len]
+    }
+}
+
+/// Create the states of the descriptors of a queue of `size`.
+fn desc_states(size: usize) -> Result<DescStates> {
+    #[cfg(feature = "alloc")]
+    {
+        Ok((0..size).map(|_| DescState::default()).collect())
+    }
+    #[cfg(not(feature = "alloc"))]
+    {
+        if size > INLINE_STATES {
+            warn!(
+                "Queues of more than {} descriptors need the alloc feature",
+                INLINE_STATES
+            );
+            return Err(Error::InvalidParam);
+        }
+        Ok(DescStates {
+            states: core::array::from_fn(|_| DescState::default()),
+            len: size,
+        })
+    }
+}
+
+/// The memory of the descriptor table and rings of a queue.
+enum QueueMemory {
+    /// A single region in the layout of the legacy interfaces, which find
+    /// the rings from the address of the descriptor table.
+    Legacy { dma: DMA, layout: VirtQueueLayout },
+    /// A region for each area, whose addresses modern interfaces take
+    /// separately, so that large queues need no large contiguous region.
+    Separate { desc: DMA, avail: DMA, used: DMA },
+}
+
+impl QueueMemory {
+    /// Allocate the memory of a queue of `size` descriptors, in a single
+    /// region for `legacy` interfaces, which take no `packed` queues.
+    fn new(size: u16, legacy: bool, packed: bool) -> Result<Self> {
+        let layout = VirtQueueLayout::new(size)?;
+        if legacy && !packed {
+            let dma = DMA::new(layout.size / PAGE_SIZE)?;
+            return Ok(QueueMemory::Legacy { dma, layout });
+        }
+        if legacy {
+            warn!("Legacy interfaces take no packed queues");
+            return Err(Error::InvalidParam);
+        }
+        let sizes = AreaSizes::new(size, packed);
+        Ok(QueueMemory::Separate {
+            desc: DMA::new(pages(sizes.desc))?,
+            avail: DMA::new(pages(sizes.avail))?,
+            used: DMA::new(pages(sizes.used))?,
+        })
+    }
+
+    /// Take back the memory of a queue of `size` descriptors at the physical
+    /// addresses `paddrs` of its areas, in the layout of [`new`](Self::new).
+    ///
+    /// # Safety
+    ///
+    /// The areas must have been allocated by [`new`](Self::new), and not be
+    /// owned by another queue.
+    unsafe fn from_raw(size: u16, legacy: bool, packed: bool, paddrs: [usize; 3]) -> Result<Self> {
+        let layout = VirtQueueLayout::new(size)?;
+        let [desc, avail, used] = paddrs;
+        if legacy && packed {
+            return Err(Error::InvalidParam);
+        }
+        if legacy {
+            if !desc.is_multiple_of(PAGE_SIZE)
+                || avail != desc + layout.avail_offset
+                || used != desc + layout.used_offset
+            {
+                return Err(Error::InvalidParam);
+            }
+            let dma = DMA::from_raw(desc, layout.size / PAGE_SIZE);
+            return Ok(QueueMemory::Legacy { dma, layout });
+        }
+        if paddrs.iter().any(|paddr| !paddr.is_multiple_of(PAGE_SIZE)) {
+            return Err(Error::InvalidParam);
+        }
+        let sizes = AreaSizes::new(size, packed);
+        Ok(QueueMemory::Separate {
+            desc: DMA::from_raw(desc, pages(sizes.desc)),
+            avail: DMA::from_raw(avail, pages(sizes.avail)),
+            used: DMA::from_raw(used, pages(sizes.used)),
+        })
+    }
+
+    /// The physical addresses of the descriptor table, the available ring
+    /// and the used ring.
+    fn paddrs(&self) -> [usize; 3] {
+        match self {
+            QueueMemory::Legacy { dma, layout } => [
+                dma.paddr(),
+                dma.paddr() + layout.avail_offset,
+                dma.paddr() + layout.used_offset,
+            ],
+            QueueMemory::Separate { desc, avail, used } => {
+                [desc.paddr(), avail.paddr(), used.paddr()]
+            }
+        }
+    }
+
+    /// The virtual addresses of the areas, as [`paddrs`](Self::paddrs).
+    fn vaddrs(&self) -> [usize; 3] {
+        self.paddrs().map(phys_to_virt)
+    }
 }
 
-impl VirtQueue<'_> {
+/// The sizes of the areas of a queue in bytes: the descriptor table, the
+/// available ring and the used ring of a split queue, or the descriptor ring
+/// and the driver and device event suppression structures of a packed one.
+///
+/// Ref: 2.7 Split Virtqueues, 2.8 Packed Virtqueues
+struct AreaSizes {
+    desc: usize,
+    avail: usize,
+    used: usize,
+}
+
+impl AreaSizes {
+    fn new(queue_size: u16, packed: bool) -> Self {
+        let queue_size = queue_size as usize;
+        if packed {
+            return AreaSizes {
+                desc: size_of::<PackedDescriptor>() * queue_size,
+                avail: size_of::<EventSuppress>(),
+                used: size_of::<EventSuppress>(),
+            };
+        }
+        AreaSizes {
+            desc: size_of::<Descriptor>() * queue_size,
+            avail: size_of::<u16>() * (3 + queue_size),
+            used: size_of::<u16>() * 3 + size_of::<UsedElem>() * queue_size,
+        }
+    }
+}
+
+/// The rings of a queue, in the layout chosen when it is created.
+#[derive(Clone, Copy)]
+enum Rings<'a> {
+    /// A descriptor table, and an available and a used ring of descriptor
+    /// indices.
+    ///
+    /// Ref: 2.7 Split Virtqueues
+    Split {
+        desc: &'a [Descriptor],
+        avail: &'a AvailRing,
+        used: &'a UsedRing,
+    },
+    /// A single ring of descriptors, which the device overwrites as it uses
+    /// the chains, and the structures by which the driver and the device
+    /// suppress notifications.
+    ///
+    /// Ref: 2.8 Packed Virtqueues
+    Packed {
+        desc: &'a [PackedDescriptor],
+        driver_event: &'a EventSuppress,
+        device_event: &'a EventSuppress,
+    },
+}
+
+/// The indirect descriptor tables of a queue, one for each descriptor so
+/// that any descriptor can refer to one.
+struct IndirectTables<'a> {
+    dma: DMA,
+    desc: &'a [Descriptor],
+}
+
+impl<'a> VirtQueue<'a> {
     /// Create a new VirtQueue.
-    pub fn new(header: &mut VirtIOHeader, idx: usize, size: u16) -> Result<Self> {
+    ///
+    /// Without the `alloc` feature, queues of more than 64 descriptors fail
+    /// with [`Error::InvalidParam`].
+    ///
+    /// The queue is to be [unset](Self::unset) through `header` before it is
+    /// dropped, or its memory is leaked.
+    pub fn new(header: &mut dyn Transport, idx: usize, size: u16) -> Result<Self> {
+        Self::create(header, idx, size, false)
+    }
+
+    /// Create a new VirtQueue in the packed layout, for devices with which
+    /// `VIRTIO_F_RING_PACKED` was negotiated.
+    ///
+    /// Fails with [`Error::InvalidParam`] on legacy interfaces, which only
+    /// take split queues, and like [`new`](Self::new) for more than 64
+    /// descriptors without the `alloc` feature.
+    pub fn new_packed(header: &mut dyn Transport, idx: usize, size: u16) -> Result<Self> {
+        Self::create(header, idx, size, true)
+    }
+
+    fn create(header: &mut dyn Transport, idx: usize, size: u16, packed: bool) -> Result<Self> {
         if header.queue_used(idx as u32) {
+            warn!("Queue {} is already in use", idx);
             return Err(Error::AlreadyUsed);
         }
-        if !size.is_power_of_two() || header.max_queue_size() < size as u32 {
+        header.check_queue_size(idx as u32, size as u32)?;
+        let memory = QueueMemory::new(size, header.is_legacy(), packed)?;
+        let mut queue = unsafe { Self::from_memory(memory, idx as u32, size, packed)? };
+        queue.clear();
+
+        queue.set_up(header);
+        debug!(
+            "Queue {} of size {} set up at {:#x}{}",
+            idx,
+            size,
+            queue.memory.paddrs()[0],
+            if packed { ", packed" } else { "" }
+        );
+        Ok(queue)
+    }
+
+    /// Restore a queue from the state saved by [`save`](Self::save), e.g.
+    /// after the VM is restored from a snapshot.
+    ///
+    /// The device is expected to be restored with the queue still set up,
+    /// and this fails with [`Error::NotReady`] if it is not. The chains the
+    /// queue held are in flight again, except in packed queues, which are
+    /// only restored without any.
+    ///
+    /// # Safety
+    ///
+    /// The memory of the saved queue must be intact, still allocated from the
+    /// HAL, and not used by any other queue.
+    pub unsafe fn restore(header: &mut dyn Transport, state: &QueueState) -> Result<Self> {
+        let size = state.queue_size;
+        if !size.is_power_of_two() || state.num_used > size || state.free_head > size {
+            warn!("Invalid state of queue {}", state.queue_idx);
+            return Err(Error::InvalidParam);
+        }
+        // the chains of a packed queue are linked in the states of the
+        // driver, which are not part of the saved state
+        if state.packed && state.num_used != 0 {
+            warn!(
+                "Packed queue {} saved with chains in flight",
+                state.queue_idx
+            );
+            return Err(Error::InvalidParam);
+        }
+        if header.queue_descriptors(state.queue_idx) != state.paddrs[0] as usize {
+            warn!("Queue {} is not set up on the device", state.queue_idx);
+            return Err(Error::NotReady);
+        }
+        let paddrs = state.paddrs.map(|paddr| paddr as usize);
+        let Ok(memory) = QueueMemory::from_raw(size, header.is_legacy(), state.packed, paddrs)
+        else {
+            warn!("Invalid areas of queue {}", state.queue_idx);
+            return Err(Error::InvalidParam);
+        };
+        let mut queue = Self::from_memory(memory, state.queue_idx, size, state.packed)?;
+        queue.attached = true;
+        if state.packed {
+            queue.link_free_states();
+        } else {
+            queue.num_used = state.num_used;
+            queue.free_head = state.free_head;
+            queue.find_in_flight()?;
+        }
+        queue.avail_idx = state.avail_idx;
+        queue.last_used_idx = state.last_used_idx;
+        debug!(
+            "Queue {} of size {} restored at {:#x}",
+            state.queue_idx, size, state.paddrs[0]
+        );
+        Ok(queue)
+    }
+
+    /// Mark the chains of a restored split queue in flight, whose heads are
+    /// the descriptors outside the free list which no other descriptor
+    /// links to.
+    fn find_in_flight(&mut self) -> Result {
+        let Rings::Split { desc, .. } = self.rings else {
+            return Ok(());
+        };
+        for state in self.states.iter_mut() {
+            state.in_flight = true;
+        }
+        let mut index = self.free_head;
+        for _ in 0..self.queue_size - self.num_used {
+            let state = self
+                .states
+                .get_mut(index as usize)
+                .ok_or(Error::InvalidParam)?;
+            if !state.in_flight {
+                return Err(Error::InvalidParam);
+            }
+            state.in_flight = false;
+            index = desc[index as usize].next.read().get();
+        }
+        // a bit for each descriptor of the largest queue
+        let mut linked = [0u64; 32768 / 64];
+        for (i, state) in self.states.iter().enumerate() {
+            let flags = DescFlags::from_bits_truncate(desc[i].flags.read().get());
+            if state.in_flight && flags.contains(DescFlags::NEXT) {
+                let next = desc[i].next.read().get() as usize;
+                if next >= self.states.len() {
+                    return Err(Error::InvalidParam);
+                }
+                linked[next / 64] |= 1 << (next % 64);
+            }
+        }
+        for (i, state) in self.states.iter_mut().enumerate() {
+            state.in_flight &= linked[i / 64] & (1 << (i % 64)) == 0;
+        }
+        #[cfg(feature = "validate")]
+        for head in 0..self.queue_size {
+            if !self.states[head as usize].in_flight {
+                continue;
+            }
+            let mut index = head;
+            let mut writable = 0;
+            for _ in 0..self.num_used {
+                let desc = &desc[index as usize];
+                let flags = DescFlags::from_bits_truncate(desc.flags.read().get());
+                writable += if flags.contains(DescFlags::INDIRECT) {
+                    let table = phys_to_virt(desc.addr.read().get() as usize) as *const Descriptor;
+                    let count = desc.len.read().get() as usize / size_of::<Descriptor>();
+                    let table = unsafe { slice::from_raw_parts(table, count) };
+                    table
+                        .iter()
+                        .filter(|desc| desc.flags.read().get() & DescFlags::WRITE.bits() != 0)
+                        .map(|desc| desc.len.read().get() as u64)
+                        .sum()
+                } else if flags.contains(DescFlags::WRITE) {
+                    desc.len.read().get() as u64
+                } else {
+                    0
+                };
+                self.states[index as usize].owned = true;
+                if !flags.contains(DescFlags::NEXT) {
+                    break;
+                }
+                index = desc.next.read().get();
+            }
+            self.states[head as usize].writable = writable;
+        }
+        Ok(())
+    }
+
+    /// Create a queue in `memory`, which holds the areas of a queue of
+    /// `size` in the split or `packed` layout.
+    unsafe fn from_memory(memory: QueueMemory, idx: u32, size: u16, packed: bool) -> Result<Self> {
+        let size = size as usize;
+        let [desc, avail, used] = memory.vaddrs();
+        let rings = if packed {
+            Rings::Packed {
+                desc: slice::from_raw_parts(desc as *const PackedDescriptor, size),
+                driver_event: &*(avail as *const EventSuppress),
+                device_event: &*(used as *const EventSuppress),
+            }
+        } else {
+            // the rings are as long as the queue, which the metadata of
+            // their pointers carries
+            Rings::Split {
+                desc: slice::from_raw_parts(desc as *const Descriptor, size),
+                avail: &*(ptr::slice_from_raw_parts(avail as *const u16, size) as *const AvailRing),
+                used: &*(ptr::slice_from_raw_parts(used as *const u16, size) as *const UsedRing),
+            }
+        };
+        let states = desc_states(size)?;
+        Ok(VirtQueue {
+            memory: ManuallyDrop::new(memory),
+            attached: false,
+            rings,
+            queue_size: size as u16,
+            queue_idx: idx,
+            num_used: 0,
+            free_head: 0,
+            avail_idx: 0,
+            last_used_idx: 0,
+            pending_head: None,
+            max_chain_len: size,
+            max_desc_len: u32::MAX,
+            order_platform: false,
+            metrics: QueueMetrics::default(),
+            num_completed: 0,
+            broken: false,
+            #[cfg(feature = "time-hal")]
+            timeout: None,
+            indirect: None,
+            states,
+        })
+    }
+
+    /// Place chains of several buffers in indirect descriptor tables, which
+    /// take a single descriptor of the queue each.
+    ///
+    /// The driver must have negotiated `VIRTIO_RING_F_INDIRECT_DESC`. Chains
+    /// longer than an indirect table or the queue, or holding a [`DmaBuf`],
+    /// are still added directly.
+    pub fn enable_indirect(&mut self) -> Result {
+        if self.indirect.is_some() {
+            return Ok(());
+        }
+        let len = self.queue_size as usize * MAX_INDIRECT;
+        let dma = DMA::new(pages(len * size_of::<Descriptor>()))?;
+        let desc = unsafe { slice::from_raw_parts(dma.vaddr() as *const Descriptor, len) };
+        self.indirect = Some(IndirectTables { dma, desc });
+        Ok(())
+    }
+
+    /// Limit the chains added to `len` descriptors, e.g. to the number of
+    /// segments the device takes, so that longer ones fail with
+    /// [`Error::ChainTooLong`]. The limit is at most the size of the queue.
+    pub fn set_max_chain_len(&mut self, len: usize) -> Result {
+        if len == 0 {
+            return Err(Error::InvalidParam);
+        }
+        self.max_chain_len = len.min(self.queue_size as usize);
+        Ok(())
+    }
+
+    /// The maximum number of descriptors in a chain, to size scatter-gather
+    /// requests by.
+    pub fn max_chain_len(&self) -> usize {
+        self.max_chain_len
+    }
+
+    /// Limit the buffer of each descriptor to `len` bytes, e.g. to the
+    /// largest segment the device takes. Longer buffers are split over
+    /// several descriptors.
+    pub fn set_max_desc_len(&mut self, len: u32) -> Result {
+        if len == 0 {
+            return Err(Error::InvalidParam);
+        }
+        self.max_desc_len = len;
+        Ok(())
+    }
+
+    /// Fail the waits for the device to use a chain with [`Error::Timeout`]
+    /// once `ticks` of the HAL clock have passed, or wait forever with
+    /// `None`, as by default.
+    #[cfg(feature = "time-hal")]
+    pub fn set_timeout(&mut self, ticks: Option<u64>) {
+        self.timeout = ticks;
+    }
+
+    /// Order the accesses to the rings with the barriers of the platform, for
+    /// devices with which `VIRTIO_F_ORDER_PLATFORM` was negotiated, e.g.
+    /// hardware rather than emulated devices.
+    pub fn set_order_platform(&mut self, enabled: bool) {
+        self.order_platform = enabled;
+    }
+
+    /// The tokens of the chains in flight, which the driver has not popped
+    /// yet.
+    pub fn in_flight(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..self.queue_size).filter(move |&head| self.states[head as usize].in_flight)
+    }
+
+    /// Report the chains [`in_flight`](Self::in_flight) to `f` as requests
+    /// of `queue` lost when the device is reset.
+    pub fn report_lost(&self, queue: &'static str, f: &mut dyn FnMut(LostRequest)) {
+        for token in self.in_flight() {
+            f(LostRequest { queue, token });
+        }
+    }
+
+    /// Save the state of the queue, e.g. with a snapshot of the VM.
+    pub fn save(&self) -> QueueState {
+        QueueState {
+            queue_idx: self.queue_idx,
+            queue_size: self.queue_size,
+            paddrs: self.memory.paddrs().map(|paddr| paddr as u64),
+            packed: matches!(self.rings, Rings::Packed { .. }),
+            num_used: self.num_used,
+            free_head: self.free_head,
+            avail_idx: self.avail_idx,
+            last_used_idx: self.last_used_idx,
+        }
+    }
+
+    /// Reset the queue to its initial state and set it up on the device
+    /// again, e.g. after the device is reset.
+    ///
+    /// Buffers which are still in the queue are discarded, and the tasks
+    /// polling for them are woken to find they were lost, so the driver is
+    /// to report their [`in_flight`](Self::in_flight) tokens first.
+    pub fn reinit(&mut self, header: &mut dyn Transport) {
+        let mut lost = 0;
+        for state in self.states.iter_mut() {
+            let in_flight = state.in_flight;
+            let waker = state.waker.take();
+            *state = DescState::default();
+            state.lost = in_flight;
+            if in_flight {
+                lost += 1;
+            }
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+        if lost != 0 {
+            debug!("Queue {} lost {} chains in flight", self.queue_idx, lost);
+        }
+        self.clear();
+        self.num_completed = 0;
+        self.broken = false;
+
+        self.set_up(header);
+        debug!("Queue {} set up again", self.queue_idx);
+    }
+
+    /// Set the queue up on the device again after the device was reset, e.g.
+    /// on resume from suspend to RAM, keeping the chains in flight.
+    ///
+    /// Unlike [`reinit`](Self::reinit), the chains which the device had not
+    /// used are made available to it again, so their tokens and buffers stay
+    /// valid, and the device is to be notified once it is ready. As the
+    /// device starts over with empty rings, this fails with
+    /// [`Error::NotReady`] while chains it used are left to pop. It fails
+    /// with [`Error::InvalidParam`] for packed queues, as the device
+    /// overwrites the descriptors of a packed ring which it has read.
+    pub fn resume(&mut self, header: &mut dyn Transport) -> Result {
+        let Rings::Split { avail, used, .. } = self.rings else {
+            return Err(Error::InvalidParam);
+        };
+        if self.can_pop() {
+            warn!("Queue {} has used chains left to pop", self.queue_idx);
+            return Err(Error::NotReady);
+        }
+        used.flags.write(0.into());
+        used.idx.write(0.into());
+        self.avail_idx = 0;
+        self.last_used_idx = 0;
+        for head in 0..self.queue_size {
+            if self.states[head as usize].in_flight {
+                avail.ring[self.avail_idx as usize].write(head.into());
+                self.avail_idx += 1;
+            }
+        }
+        self.publish();
+        self.broken = false;
+
+        self.set_up(header);
+        debug!(
+            "Queue {} set up again with {} chains in flight",
+            self.queue_idx, self.avail_idx
+        );
+        Ok(())
+    }
+
+    /// Empty the rings, and link all descriptors into the free list.
+    fn clear(&mut self) {
+        match self.rings {
+            Rings::Split { desc, avail, used } => {
+                for (i, desc) in desc.iter().enumerate() {
+                    desc.addr.write(0.into());
+                    desc.len.write(0.into());
+                    desc.flags.write(0.into());
+                    desc.next.write((i as u16 + 1).into());
+                }
+                avail.flags.write(0.into());
+                avail.idx.write(0.into());
+                used.flags.write(0.into());
+                used.idx.write(0.into());
+                self.avail_idx = 0;
+                self.last_used_idx = 0;
+            }
+            Rings::Packed {
+                desc, driver_event, ..
+            } => {
+                for desc in desc {
+                    desc.addr.write(0.into());
+                    desc.len.write(0.into());
+                    desc.id.write(0.into());
+                    desc.flags.write(0.into());
+                }
+                driver_event.off_wrap.write(0.into());
+                driver_event.flags.write(0.into());
+                self.link_free_states();
+                // both wrap counters start at 1
+                self.avail_idx = PACKED_WRAP;
+                self.last_used_idx = PACKED_WRAP;
+            }
+        }
+        self.num_used = 0;
+        self.free_head = 0;
+        self.pending_head = None;
+    }
+
+    /// Link all states of a packed queue into the free list.
+    fn link_free_states(&mut self) {
+        for (i, state) in self.states.iter_mut().enumerate() {
+            state.next = i as u16 + 1;
+        }
+        self.free_head = 0;
+    }
+
+    /// Reset the queue alone through `header` and set it up again as
+    /// [`reinit`](Self::reinit) does, without resetting the device, e.g. to
+    /// post buffers of another size.
+    ///
+    /// Fails with [`Error::InvalidParam`], leaving the queue as it was,
+    /// unless the device negotiated `VIRTIO_F_RING_RESET`.
+    pub fn reset(&mut self, header: &mut dyn Transport) -> Result {
+        header.queue_reset(self.queue_idx)?;
+        self.reinit(header);
+        Ok(())
+    }
+
+    /// Stop the device from using the queue through `header`, unless the
+    /// device was reset already, so that the memory of the queue is freed
+    /// when it is dropped.
+    pub fn unset(&mut self, header: &mut dyn Transport) {
+        if header.queue_descriptors(self.queue_idx) == self.memory.paddrs()[0] {
+            if self.num_used > 0 {
+                warn!(
+                    "Queue {} unset with {} descriptors in use",
+                    self.queue_idx, self.num_used
+                );
+            }
+            header.queue_unset(self.queue_idx);
+            debug!("Queue {} unset", self.queue_idx);
+        }
+        self.attached = false;
+    }
+
+    /// Set the queue up on the device with the addresses of its rings.
+    fn set_up(&mut self, header: &mut dyn Transport) {
+        let [desc, avail, used] = self.memory.paddrs();
+        header.queue_set(self.queue_idx, self.queue_size as u32, desc, avail, used);
+        self.attached = true;
+    }
+
+    /// Add buffers to the virtqueue, return a token.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_add
+    pub fn add(&mut self, inputs: &[&[u8]], outputs: &[&mut [u8]]) -> Result<u16> {
+        let inputs = inputs.iter().map(|buf| ChainBuf::slice(buf, false));
+        let outputs = outputs.iter().map(|buf| ChainBuf::slice(buf, true));
+        self.add_chain(inputs.chain(outputs))
+    }
+
+    /// Add buffers to the virtqueue as [`add`](Self::add) does, and notify
+    /// the device through `header` if it [asks to be](Self::should_notify),
+    /// return a token.
+    pub fn add_notify(
+        &mut self,
+        header: &mut dyn Transport,
+        inputs: &[&[u8]],
+        outputs: &[&mut [u8]],
+    ) -> Result<u16> {
+        let token = self.add(inputs, outputs)?;
+        if self.should_notify() {
+            header.notify(self.queue_idx);
+        }
+        Ok(token)
+    }
+
+    /// Add buffers to the virtqueue and notify the device as
+    /// [`add_notify`](Self::add_notify) does, then wait for the device to use
+    /// them as [`wait_for`](Self::wait_for) does, return the length it wrote.
+    pub fn add_notify_wait_pop(
+        &mut self,
+        header: &mut dyn Transport,
+        inputs: &[&[u8]],
+        outputs: &[&mut [u8]],
+    ) -> Result<u32> {
+        let token = self.add_notify(header, inputs, outputs)?;
+        self.wait_for(header, token)
+    }
+
+    /// Add buffers to the virtqueue, some of which may be [`DmaBuf`]s, and
+    /// return a token.
+    ///
+    /// The queue holds each `DmaBuf` until the device uses it, and fails with
+    /// [`Error::AlreadyUsed`] if one is already in a queue.
+    pub fn add_parts(&mut self, inputs: &[QueueBuf], outputs: &[QueueBuf]) -> Result<u16> {
+        let readable = |buf: &QueueBuf| match buf {
+            QueueBuf::Dma(dma) => dma.direction() != BufferDirection::DeviceToDriver,
+            _ => true,
+        };
+        let writable = |buf: &QueueBuf| match buf {
+            QueueBuf::Slice(_) => false,
+            QueueBuf::SliceMut(_) => true,
+            QueueBuf::Dma(dma) => dma.direction() != BufferDirection::DriverToDevice,
+        };
+        if !inputs.iter().all(readable) || !outputs.iter().all(writable) {
+            return Err(Error::InvalidParam);
+        }
+        let inputs = inputs.iter().map(|buf| buf.chain_buf(false));
+        let outputs = outputs.iter().map(|buf| buf.chain_buf(true));
+        self.add_chain(inputs.chain(outputs))
+    }
+
+    /// Add buffers given by their physical address, length and direction to
+    /// the virtqueue, return a token.
+    ///
+    /// The addresses are not translated, so buffers mapped once can be added
+    /// again and again. The device writes to the buffers which are not
+    /// [`BufferDirection::DriverToDevice`], which come after the others in the
+    /// chain.
+    ///
+    /// # Safety
+    ///
+    /// The buffers must be valid for the device to access in their direction
+    /// until the chain is used, and not be accessed by the driver meanwhile
+    /// unless the device only reads them.
+    pub unsafe fn add_premapped(
+        &mut self,
+        bufs: &[(usize, usize, BufferDirection)],
+    ) -> Result<u16> {
+        let chain_buf = |&(paddr, len, direction): &(usize, usize, BufferDirection)| ChainBuf {
+            paddr: paddr as u64,
+            len: len as u64,
+            write: direction != BufferDirection::DriverToDevice,
+            dma: None,
+        };
+        let readable = bufs.iter().map(chain_buf).filter(|buf| !buf.write);
+        let writable = bufs.iter().map(chain_buf).filter(|buf| buf.write);
+        self.add_chain(readable.chain(writable))
+    }
+
+    /// Add the segments of `sg` to the virtqueue, return a token.
+    pub fn add_sg(&mut self, sg: SgList) -> Result<u16> {
+        let segments = &sg.segments[..sg.len];
+        let readable = segments.iter().filter(|seg| !seg.write);
+        let writable = segments.iter().filter(|seg| seg.write);
+        self.add_chain(readable.chain(writable).copied())
+    }
+
+    /// Add chains of buffers, each given as `(inputs, outputs)` as to
+    /// [`add`](Self::add), and make them available to the device at once.
+    ///
+    /// The chains are added in order until one does not fit in the queue, and
+    /// their tokens are written to `tokens`, which also bounds the number of
+    /// chains. Returns the number of chains added, or the error of the first
+    /// chain if none could be added.
+    pub fn add_batch<'b>(
+        &mut self,
+        chains: impl IntoIterator<Item = (&'b [&'b [u8]], &'b [&'b mut [u8]])>,
+        tokens: &mut [u16],
+    ) -> Result<usize> {
+        let mut added = 0;
+        for ((inputs, outputs), token) in chains.into_iter().zip(tokens.iter_mut()) {
+            let inputs = inputs.iter().map(|buf| ChainBuf::slice(buf, false));
+            let outputs = outputs.iter().map(|buf| ChainBuf::slice(buf, true));
+            match self.push_chain(inputs.chain(outputs)) {
+                Ok(head) => *token = head,
+                Err(err) if added == 0 => return Err(err),
+                Err(_) => break,
+            }
+            added += 1;
+        }
+        if added > 0 {
+            self.publish();
+        }
+        Ok(added)
+    }
+
+    /// Add the buffers of `bufs` as a descriptor chain.
+    fn add_chain<'b>(&mut self, bufs: impl Iterator<Item = ChainBuf<'b>> + Clone) -> Result<u16> {
+        let head = self.push_chain(bufs)?;
+        self.publish();
+        Ok(head)
+    }
+
+    /// Write the buffers of `bufs` as a descriptor chain into the next slot
+    /// of the available ring, without making it available yet.
+    fn push_chain<'b>(&mut self, bufs: impl Iterator<Item = ChainBuf<'b>> + Clone) -> Result<u16> {
+        // buffers longer than a descriptor takes span several
+        let max_desc_len = self.max_desc_len;
+        let bufs = bufs.flat_map(move |buf| buf.split(max_desc_len));
+        let count = bufs.clone().count();
+        if count == 0 {
             return Err(Error::InvalidParam);
         }
-        let layout = VirtQueueLayout::new(size);
-        // alloc continuous pages
-        let dma = DMA::new(layout.size / PAGE_SIZE)?;
+        if self.broken {
+            warn!(
+                "Queue {} is not set up again since it failed",
+                self.queue_idx
+            );
+            return Err(Error::NotReady);
+        }
+        if count > self.max_chain_len {
+            warn!(
+                "Queue {} takes chains of up to {} buffers, not {}",
+                self.queue_idx, self.max_chain_len, count
+            );
+            return Err(Error::ChainTooLong);
+        }
+        let indirect = self
+            .indirect
+            .as_ref()
+            .filter(|_| {
+                count > 1
+                    && count <= MAX_INDIRECT.min(self.queue_size as usize)
+                    && bufs.clone().all(|buf| buf.dma.is_none())
+            })
+            .map(|tables| (tables.dma.paddr(), tables.desc));
+        let needed = if indirect.is_some() { 1 } else { count };
+        if needed + self.num_used as usize > self.queue_size as usize {
+            trace!("Queue {} is full", self.queue_idx);
+            return Err(Error::BufferTooSmall);
+        }
+        #[cfg(feature = "validate")]
+        self.check_free(needed)?;
+        #[cfg(feature = "validate")]
+        let writable = bufs
+            .clone()
+            .filter(|buf| buf.write)
+            .map(|buf| buf.len)
+            .sum();
+        // take the DMA buffers before changing the queue, as that may fail
+        for (i, buf) in bufs.clone().enumerate() {
+            if let Some(dma) = buf.dma {
+                if let Err(err) = dma.start_use() {
+                    for dma in bufs.clone().take(i).filter_map(|buf| buf.dma) {
+                        dma.end_use();
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        let head = self.free_head;
+        let bytes_out = match self.rings {
+            Rings::Split { desc, .. } => self.write_split_chain(desc, indirect, bufs, count),
+            Rings::Packed { desc, .. } => self.write_packed_chain(desc, indirect, bufs, count),
+        };
+
+        #[cfg(feature = "validate")]
+        {
+            let mut index = head;
+            for _ in 0..needed {
+                self.states[index as usize].owned = true;
+                index = self.next(index);
+            }
+            self.states[head as usize].writable = writable;
+            self.check_free_list()?;
+        }
+
+        match self.rings {
+            Rings::Split { avail, .. } => {
+                let avail_slot = self.avail_idx & (self.queue_size - 1);
+                avail.ring[avail_slot as usize].write(head.into());
+                self.avail_idx = self.avail_idx.wrapping_add(1);
+            }
+            Rings::Packed { .. } => {
+                self.states[head as usize].chain_len = needed as u16;
+                self.avail_idx = packed_advance(self.avail_idx, needed as u16, self.queue_size);
+            }
+        }
+        self.states[head as usize].in_flight = true;
+        self.states[head as usize].lost = false;
+        self.metrics.added += 1;
+        self.metrics.bytes_out += bytes_out;
+        trace!("Queue {} added buffers with token {}", self.queue_idx, head);
+        Ok(head)
+    }
+
+    /// Write the buffers of `bufs`, `count` descriptors, as a chain from the
+    /// head of the free list into the descriptor table `desc` of a split
+    /// queue, or into an indirect table of `indirect`, the physical address
+    /// and descriptors of the tables, return the bytes for the device to
+    /// read.
+    fn write_split_chain<'b>(
+        &mut self,
+        desc: &[Descriptor],
+        indirect: Option<(usize, &[Descriptor])>,
+        bufs: impl Iterator<Item = ChainBuf<'b>>,
+        count: usize,
+    ) -> u64 {
+        let head = self.free_head;
+        let mut bytes_out = 0;
+        if let Some((tables_paddr, tables)) = indirect {
+            // fill in the table of the head, and refer to it from the head
+            let offset = head as usize * MAX_INDIRECT;
+            let table = &tables[offset..offset + count];
+            for (i, buf) in bufs.enumerate() {
+                let desc = &table[i];
+                desc.addr.write(buf.paddr.into());
+                desc.len.write((buf.len as u32).into());
+                let mut flags = if buf.write {
+                    DescFlags::WRITE
+                } else {
+                    bytes_out += buf.len;
+                    DescFlags::empty()
+                };
+                if i + 1 < count {
+                    flags |= DescFlags::NEXT;
+                }
+                desc.flags.write(flags.bits().into());
+                desc.next.write((i as u16 + 1).into());
+            }
+            let paddr = tables_paddr + offset * size_of::<Descriptor>();
+            let desc = &desc[head as usize];
+            desc.addr.write((paddr as u64).into());
+            desc.len
+                .write(((count * size_of::<Descriptor>()) as u32).into());
+            desc.flags.write(DescFlags::INDIRECT.bits().into());
+            self.free_head = desc.next.read().get();
+            self.num_used += 1;
+        } else {
+            // allocate descriptors from free list
+            let mut last = self.free_head;
+            for buf in bufs {
+                let desc = &desc[self.free_head as usize];
+                desc.addr.write(buf.paddr.into());
+                desc.len.write((buf.len as u32).into());
+                let flags = if buf.write {
+                    DescFlags::NEXT | DescFlags::WRITE
+                } else {
+                    bytes_out += buf.len;
+                    DescFlags::NEXT
+                };
+                desc.flags.write(flags.bits().into());
+                self.states[self.free_head as usize].dma_buf =
+                    buf.dma.map(|dma| InFlight(dma.clone()));
+                last = self.free_head;
+                self.free_head = desc.next.read().get();
+            }
+            // set last_elem.next = NULL
+            let desc = &desc[last as usize];
+            let mut flags = DescFlags::from_bits_truncate(desc.flags.read().get());
+            flags.remove(DescFlags::NEXT);
+            desc.flags.write(flags.bits().into());
+            self.num_used += count as u16;
+        }
+        bytes_out
+    }
+
+    /// Write the buffers of `bufs`, `count` descriptors, as a chain into the
+    /// descriptor ring `desc` of a packed queue from its next position, or
+    /// into an indirect table of `indirect` as for
+    /// [`write_split_chain`](Self::write_split_chain), return the bytes for
+    /// the device to read.
+    ///
+    /// The chain takes buffer IDs from the free list as a split chain takes
+    /// descriptors, to hold its DMA buffers, and its head is the ID the
+    /// device uses it with. The flags of its first descriptor, which make it
+    /// available, are written last, when the chain is published if it is
+    /// the first since the last time.
+    fn write_packed_chain<'b>(
+        &mut self,
+        desc: &[PackedDescriptor],
+        indirect: Option<(usize, &[Descriptor])>,
+        bufs: impl Iterator<Item = ChainBuf<'b>>,
+        count: usize,
+    ) -> u64 {
+        let head = self.free_head;
+        let first = (self.avail_idx & !PACKED_WRAP) as usize;
+        let mut bytes_out = 0;
+        let head_flags = if let Some((tables_paddr, tables)) = indirect {
+            // fill in the table of the head in the packed layout, and refer
+            // to it from the descriptor of the ring
+            let offset = head as usize * MAX_INDIRECT;
+            let table = unsafe {
+                slice::from_raw_parts(
+                    (tables.as_ptr() as *const PackedDescriptor).add(offset),
+                    count,
+                )
+            };
+            for (desc, buf) in table.iter().zip(bufs) {
+                desc.addr.write(buf.paddr.into());
+                desc.len.write((buf.len as u32).into());
+                desc.id.write(0.into());
+                let flags = if buf.write {
+                    DescFlags::WRITE
+                } else {
+                    bytes_out += buf.len;
+                    DescFlags::empty()
+                };
+                desc.flags.write(flags.bits().into());
+            }
+            let paddr = tables_paddr + offset * size_of::<PackedDescriptor>();
+            desc[first].addr.write((paddr as u64).into());
+            desc[first]
+                .len
+                .write(((count * size_of::<PackedDescriptor>()) as u32).into());
+            desc[first].id.write(head.into());
+            self.free_head = self.states[head as usize].next;
+            self.num_used += 1;
+            DescFlags::INDIRECT | packed_avail_flags(self.avail_idx)
+        } else {
+            let mut idx = self.avail_idx;
+            let mut head_flags = DescFlags::empty();
+            for (i, buf) in bufs.enumerate() {
+                let desc = &desc[(idx & !PACKED_WRAP) as usize];
+                desc.addr.write(buf.paddr.into());
+                desc.len.write((buf.len as u32).into());
+                desc.id.write(head.into());
+                let mut flags = if buf.write {
+                    DescFlags::WRITE
+                } else {
+                    bytes_out += buf.len;
+                    DescFlags::empty()
+                };
+                if i + 1 < count {
+                    flags |= DescFlags::NEXT;
+                }
+                flags |= packed_avail_flags(idx);
+                if i == 0 {
+                    head_flags = flags;
+                } else {
+                    desc.flags.write(flags.bits().into());
+                }
+                self.states[self.free_head as usize].dma_buf =
+                    buf.dma.map(|dma| InFlight(dma.clone()));
+                self.free_head = self.states[self.free_head as usize].next;
+                idx = packed_advance(idx, 1, self.queue_size);
+            }
+            self.num_used += count as u16;
+            head_flags
+        };
+        // the device stops at the first chain not available yet, so the
+        // chains after it are made available by its flags
+        if self.pending_head.is_none() {
+            self.pending_head = Some((first as u16, head_flags));
+        } else {
+            desc[first].flags.write(head_flags.bits().into());
+        }
+        bytes_out
+    }
+
+    /// Make the chains written to the available ring available to the device.
+    fn publish(&mut self) {
+        write_barrier(self.order_platform);
+
+        match self.rings {
+            // increase head of avail ring
+            Rings::Split { avail, .. } => avail.idx.write(self.avail_idx.into()),
+            Rings::Packed { desc, .. } => {
+                if let Some((first, flags)) = self.pending_head.take() {
+                    desc[first as usize].flags.write(flags.bits().into());
+                }
+            }
+        }
+    }
+
+    /// Ask the device to interrupt when it uses buffers, or not to.
+    ///
+    /// This is only a hint, so the device may still interrupt.
+    pub fn set_used_notifications(&mut self, enabled: bool) {
+        match self.rings {
+            Rings::Split { avail, .. } => {
+                let flags = if enabled {
+                    AvailFlags::empty()
+                } else {
+                    AvailFlags::NO_INTERRUPT
+                };
+                avail.flags.write(flags.bits().into());
+            }
+            Rings::Packed { driver_event, .. } => {
+                let flags = if enabled {
+                    EVENT_FLAGS_ENABLE
+                } else {
+                    EVENT_FLAGS_DISABLE
+                };
+                driver_event.flags.write(flags.into());
+            }
+        }
+    }
+
+    /// Whether the device asks to be notified of the buffers added, which it
+    /// may not while it processes the queue anyway.
+    pub fn should_notify(&self) -> bool {
+        // make the buffers added visible before reading the flags, which
+        // orders a write before a read and so takes a full barrier, and
+        // before writing the doorbell
+        if self.order_platform {
+            platform_barrier();
+        } else {
+            fence(Ordering::SeqCst);
+        }
+        match self.rings {
+            Rings::Split { used, .. } => {
+                let flags = UsedFlags::from_bits_truncate(used.flags.read().get());
+                !flags.contains(UsedFlags::NO_NOTIFY)
+            }
+            Rings::Packed { device_event, .. } => {
+                device_event.flags.read().get() != EVENT_FLAGS_DISABLE
+            }
+        }
+    }
+
+    /// Wait for the device to use the chain with `token`, and pop it, return
+    /// the length it wrote.
+    ///
+    /// The other chains the device uses meanwhile are kept to be popped
+    /// later. If the device uses a chain wrongly, or does not use it within
+    /// the timeout set with `set_timeout`, it is reset through `header` before
+    /// this fails, so that it no longer accesses the buffers of the chain,
+    /// which may be on the stack of the caller. The queue then takes no more
+    /// chains until it is [set up again](Self::reinit).
+    pub fn wait_for(&mut self, header: &dyn Transport, token: u16) -> Result<u32> {
+        if !self
+            .states
+            .get(token as usize)
+            .is_some_and(|state| state.in_flight)
+        {
+            return Err(Error::InvalidParam);
+        }
+        #[cfg(feature = "time-hal")]
+        let deadline = self.timeout.map(|timeout| ticks().saturating_add(timeout));
+        let result = loop {
+            match self.claim(token) {
+                Ok(Some(len)) => break Ok(len),
+                Ok(None) => {}
+                Err(err) => break Err(err),
+            }
+            if !self.has_used() {
+                #[cfg(feature = "time-hal")]
+                if deadline.is_some_and(|deadline| ticks() >= deadline) {
+                    warn!("Queue {} timed out waiting for the device", self.queue_idx);
+                    break Err(Error::Timeout);
+                }
+                wait();
+                continue;
+            }
+            read_barrier(self.order_platform);
+            match self.take_used() {
+                Ok((used, len)) => self.complete(used, len),
+                Err(err) => break Err(err),
+            }
+        };
+        if result.is_err() {
+            self.fail(header);
+        }
+        result
+    }
+
+    /// Wait for the device to use all the chains with `tokens`, and pop them.
+    ///
+    /// Like [`wait_for`](Self::wait_for), the other chains the device uses
+    /// meanwhile are kept to be popped later, and the device is reset through
+    /// `header` if this fails.
+    pub fn wait_for_all(&mut self, header: &dyn Transport, tokens: &[u16]) -> Result {
+        if !tokens.iter().all(|&token| {
+            self.states
+                .get(token as usize)
+                .is_some_and(|state| state.in_flight)
+        }) {
+            return Err(Error::InvalidParam);
+        }
+        #[cfg(feature = "time-hal")]
+        let deadline = self.timeout.map(|timeout| ticks().saturating_add(timeout));
+        let mut pending = tokens.len();
+        let result = loop {
+            let popped = self
+                .pop_used_multiple(pending)
+                .only(tokens)
+                .try_fold(0, |count, used| used.map(|_| count + 1));
+            match popped {
+                Ok(count) => pending -= count,
+                Err(err) => break Err(err),
+            }
+            if pending == 0 {
+                break Ok(());
+            }
+            if !self.has_used() {
+                #[cfg(feature = "time-hal")]
+                if deadline.is_some_and(|deadline| ticks() >= deadline) {
+                    warn!("Queue {} timed out waiting for the device", self.queue_idx);
+                    break Err(Error::Timeout);
+                }
+                wait();
+            }
+        };
+        if result.is_err() {
+            self.fail(header);
+        }
+        result
+    }
+
+    /// Reset the device through `header` after it used the queue wrongly, and
+    /// take no more chains until the queue is set up again.
+    ///
+    /// The tasks polling for chains are woken to find the device was reset.
+    fn fail(&mut self, header: &dyn Transport) {
+        error!("Queue {} failed, resetting the device", self.queue_idx);
+        header.reset();
+        self.broken = true;
+        for waker in self
+            .states
+            .iter_mut()
+            .filter_map(|state| state.waker.take())
+        {
+            waker.wake();
+        }
+    }
+
+    /// Whether there is a used element that can pop.
+    pub fn can_pop(&self) -> bool {
+        self.num_completed != 0 || self.has_used()
+    }
+
+    /// Whether the device used chains which are not taken from the used ring
+    /// yet.
+    fn has_used(&self) -> bool {
+        match self.rings {
+            Rings::Split { used, .. } => self.last_used_idx != used.idx.read().get(),
+            Rings::Packed { desc, .. } => {
+                // the device marks a descriptor used by setting both of its
+                // AVAIL and USED bits to its wrap counter
+                let desc = &desc[(self.last_used_idx & !PACKED_WRAP) as usize];
+                let flags = DescFlags::from_bits_truncate(desc.flags.read().get());
+                let wrap = self.last_used_idx & PACKED_WRAP != 0;
+                flags.contains(DescFlags::AVAIL) == wrap && flags.contains(DescFlags::USED) == wrap
+            }
+        }
+    }
+
+    /// The index of the used ring of a split queue, up to which the chains
+    /// used by the device are taken after a single read barrier, or `None`
+    /// for a packed queue, whose used descriptors are each checked and
+    /// ordered by [`used_before`](Self::used_before).
+    fn used_end(&self) -> Option<u16> {
+        match self.rings {
+            Rings::Split { used, .. } => Some(used.idx.read().get()),
+            Rings::Packed { .. } => None,
+        }
+    }
+
+    /// Whether the device used a chain not taken yet, before the `end` of
+    /// the used ring given by [`used_end`](Self::used_end).
+    fn used_before(&self, end: Option<u16>) -> bool {
+        match end {
+            Some(end) => self.last_used_idx != end,
+            None => {
+                let used = self.has_used();
+                if used {
+                    read_barrier(self.order_platform);
+                }
+                used
+            }
+        }
+    }
+
+    /// A snapshot of the statistics of the queue.
+    pub fn metrics(&self) -> QueueMetrics {
+        QueueMetrics {
+            depth: self.num_used,
+            ..self.metrics
+        }
+    }
+
+    /// The number of free descriptors.
+    pub fn available_desc(&self) -> usize {
+        (self.queue_size - self.num_used) as usize
+    }
+
+    /// Walk the chain in use with `head`, return its last descriptor and its
+    /// number of descriptors.
+    fn chain_end(&self, head: u16) -> Result<(u16, u16)> {
+        if !self
+            .states
+            .get(head as usize)
+            .is_some_and(|state| state.in_flight)
+        {
+            return Err(Error::WrongToken);
+        }
+        let mut len = 0;
+        let mut last = head;
+        loop {
+            if last >= self.queue_size || len >= self.num_used {
+                return Err(Error::IoError);
+            }
+            #[cfg(feature = "validate")]
+            if !self.states[last as usize].owned {
+                error!(
+                    "Queue {} got back descriptor {} not owned by the device",
+                    self.queue_idx, last
+                );
+                return Err(Error::IoError);
+            }
+            len += 1;
+            let more = match self.rings {
+                Rings::Split { desc, .. } => {
+                    let flags =
+                        DescFlags::from_bits_truncate(desc[last as usize].flags.read().get());
+                    flags.contains(DescFlags::NEXT)
+                }
+                Rings::Packed { .. } => len < self.states[head as usize].chain_len,
+            };
+            if !more {
+                break;
+            }
+            last = self.next(last);
+        }
+        Ok((last, len))
+    }
+
+    /// The descriptor after `index` in its chain or the free list, linked in
+    /// the descriptor table of a split queue, or the state after it in a
+    /// packed queue.
+    fn next(&self, index: u16) -> u16 {
+        match self.rings {
+            Rings::Split { desc, .. } => desc[index as usize].next.read().get(),
+            Rings::Packed { .. } => self.states[index as usize].next,
+        }
+    }
+
+    /// Link `next` after `index`, as [`next`](Self::next) finds it.
+    fn set_next(&mut self, index: u16, next: u16) {
+        match self.rings {
+            Rings::Split { desc, .. } => desc[index as usize].next.write(next.into()),
+            Rings::Packed { .. } => self.states[index as usize].next = next,
+        }
+    }
+
+    /// Recycle descriptors in the list specified by head.
+    ///
+    /// This will push all linked descriptors at the front of the free list.
+    fn recycle_descriptors(&mut self, head: u16) -> Result {
+        // walk the whole chain before touching the free list, as the head
+        // comes from the device and may not be a chain in use
+        let (last, len) = self.chain_end(head)?;
+        // give the DMA buffers back
+        let mut index = head;
+        for _ in 0..len {
+            self.states[index as usize].dma_buf = None;
+            #[cfg(feature = "validate")]
+            {
+                self.states[index as usize].owned = false;
+            }
+            index = self.next(index);
+        }
+        self.set_next(last, self.free_head);
+        self.free_head = head;
+        self.num_used -= len;
+        self.states[head as usize].in_flight = false;
+        #[cfg(feature = "validate")]
+        self.check_free_list()?;
+        Ok(())
+    }
 
-        header.queue_set(idx as u32, size as u32, PAGE_SIZE as u32, dma.pfn());
+    /// Check that the next `count` descriptors of the free list are not in
+    /// use.
+    #[cfg(feature = "validate")]
+    fn check_free(&self, count: usize) -> Result {
+        let mut index = self.free_head;
+        for _ in 0..count {
+            if index >= self.queue_size || self.states[index as usize].owned {
+                error!(
+                    "Queue {} would reuse descriptor {} owned by the device",
+                    self.queue_idx, index
+                );
+                return Err(Error::IoError);
+            }
+            index = self.next(index);
+        }
+        Ok(())
+    }
 
-        let desc =
-            unsafe { slice::from_raw_parts_mut(dma.vaddr() as *mut Descriptor, size as usize) };
-        let avail = unsafe { &mut *((dma.vaddr() + layout.avail_offset) as *mut AvailRing) };
-        let used = unsafe { &mut *((dma.vaddr() + layout.used_offset) as *mut UsedRing) };
+    /// Check that the free list holds each descriptor not in use once.
+    #[cfg(feature = "validate")]
+    fn check_free_list(&self) -> Result {
+        // a bit for each descriptor of the largest queue
+        let mut seen = [0u64; 32768 / 64];
+        let mut index = self.free_head;
+        for _ in 0..self.queue_size - self.num_used {
+            let i = index as usize;
+            if index >= self.queue_size
+                || self.states[i].owned
+                || seen[i / 64] & (1 << (i % 64)) != 0
+            {
+                error!(
+                    "Queue {} has a corrupt free list at descriptor {}",
+                    self.queue_idx, index
+                );
+                return Err(Error::IoError);
+            }
+            seen[i / 64] |= 1 << (i % 64);
+            index = self.next(index);
+        }
+        Ok(())
+    }
 
-        // link descriptors together
-        for i in 0..(size - 1) {
-            desc[i as usize].next.write(i + 1);
+    /// Get a token from device used buffers, return (token, len).
+    ///
+    /// The chains are popped in the order the device used them, which may
+    /// differ from the order they were added, so callers with several chains
+    /// in flight should match the returned token to find the completed one.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_get_buf_ctx
+    pub fn pop_used(&mut self) -> Result<(u16, u32)> {
+        if let Some(used) = self.claim_any(None)? {
+            return Ok(used);
+        }
+        if !self.has_used() {
+            return Err(Error::NotReady);
         }
+        read_barrier(self.order_platform);
+        self.pop_one()
+    }
 
-        Ok(VirtQueue {
-            dma,
-            desc,
-            avail,
-            used,
-            queue_size: size,
-            queue_idx: idx as u32,
-            num_used: 0,
-            free_head: 0,
-            avail_idx: 0,
-            last_used_idx: 0,
-        })
+    /// Get the token of the next chain used by the device without popping
+    /// it, as [`peek_used_with_len`](Self::peek_used_with_len) does.
+    pub fn peek_used(&self) -> Result<u16> {
+        self.peek_used_with_len().map(|(token, _)| token)
     }
 
-    /// Add buffers to the virtqueue, return a token.
+    /// Get the next chain [`pop_used`](Self::pop_used) would pop without
+    /// popping it, return (token, len), e.g. to size a copy of it before
+    /// deciding to pop it.
     ///
-    /// Ref: linux virtio_ring.c virtqueue_add
-    pub fn add(&mut self, inputs: &[&[u8]], outputs: &[&mut [u8]]) -> Result<u16> {
-        if inputs.is_empty() && outputs.is_empty() {
-            return Err(Error::InvalidParam);
+    /// This fails as `pop_used` would, but leaves the chain to pop either
+    /// way.
+    pub fn peek_used_with_len(&self) -> Result<(u16, u32)> {
+        // chains used while waiting for others are popped first
+        if self.num_completed != 0 {
+            let completed = (0..self.queue_size)
+                .find_map(|token| Some((token, self.states[token as usize].completed?)));
+            if let Some(used) = completed {
+                return Ok(used);
+            }
         }
-        if inputs.len() + outputs.len() + self.num_used as usize > self.queue_size as usize {
-            return Err(Error::BufferTooSmall);
+        if !self.has_used() {
+            return Err(Error::NotReady);
         }
-
-        // allocate descriptors from free list
-        let head = self.free_head;
-        let mut last = self.free_head;
-        for input in inputs.iter() {
-            let desc = &mut self.desc[self.free_head as usize];
-            desc.set_buf(input);
-            desc.flags.write(DescFlags::NEXT);
-            last = self.free_head;
-            self.free_head = desc.next.read();
-        }
-        for output in outputs.iter() {
-            let desc = &mut self.desc[self.free_head as usize];
-            desc.set_buf(output);
-            desc.flags.write(DescFlags::NEXT | DescFlags::WRITE);
-            last = self.free_head;
-            self.free_head = desc.next.read();
-        }
-        // set last_elem.next = NULL
+        read_barrier(self.order_platform);
+        let (id, len) = match self.rings {
+            Rings::Split { used, .. } => {
+                let elem = &used.ring[(self.last_used_idx & (self.queue_size - 1)) as usize];
+                (elem.id.read().get(), elem.len.read().get())
+            }
+            Rings::Packed { desc, .. } => {
+                let desc = &desc[(self.last_used_idx & !PACKED_WRAP) as usize];
+                (desc.id.read().get() as u32, desc.len.read().get())
+            }
+        };
+        if !self
+            .states
+            .get(id as usize)
+            .is_some_and(|state| state.in_flight && state.completed.is_none())
         {
-            let desc = &mut self.desc[last as usize];
-            let mut flags = desc.flags.read();
-            flags.remove(DescFlags::NEXT);
-            desc.flags.write(flags);
+            return Err(Error::WrongToken);
         }
-        self.num_used += (inputs.len() + outputs.len()) as u16;
+        Ok((id as u16, len))
+    }
 
-        let avail_slot = self.avail_idx & (self.queue_size - 1);
-        self.avail.ring[avail_slot as usize].write(head);
+    /// Pop up to `max` chains used by the device, with a single read barrier
+    /// for all of them, return an iterator of (token, len).
+    ///
+    /// An element of the used ring with an invalid token ends the iteration
+    /// with an error.
+    pub fn pop_used_multiple(&mut self, max: usize) -> PopUsed<'_, 'a> {
+        let end = self.used_end();
+        read_barrier(self.order_platform);
+        PopUsed {
+            queue: self,
+            end,
+            remaining: max,
+            only: None,
+        }
+    }
 
-        // write barrier
-        fence(Ordering::SeqCst);
+    /// Pop the next element of the used ring, which the device has written.
+    fn pop_one(&mut self) -> Result<(u16, u32)> {
+        let (index, len) = self.take_used()?;
+        self.recycle_descriptors(index)?;
+        Ok((index, len))
+    }
 
-        // increase head of avail ring
-        self.avail_idx = self.avail_idx.wrapping_add(1);
-        self.avail.idx.write(self.avail_idx);
-        Ok(head)
+    /// Keep the chain with `token` used by the device with `len` bytes to be
+    /// popped later, waking the task polling for it.
+    fn complete(&mut self, token: u16, len: u32) {
+        let state = &mut self.states[token as usize];
+        state.completed = Some(len);
+        self.num_completed += 1;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
     }
 
-    /// Whether there is a used element that can pop.
-    pub fn can_pop(&self) -> bool {
-        self.last_used_idx != self.used.idx.read()
+    /// Pop a chain the device used already, with one of `tokens` if given,
+    /// recycling its descriptors, return (token, len).
+    fn claim_any(&mut self, tokens: Option<&[u16]>) -> Result<Option<(u16, u32)>> {
+        if self.num_completed == 0 {
+            return Ok(None);
+        }
+        let completed = (0..self.queue_size).find(|&token| {
+            self.states[token as usize].completed.is_some()
+                && tokens.is_none_or(|tokens| tokens.contains(&token))
+        });
+        match completed {
+            Some(token) => Ok(self.claim(token)?.map(|len| (token, len))),
+            None => Ok(None),
+        }
     }
 
-    /// The number of free descriptors.
-    pub fn available_desc(&self) -> usize {
-        (self.queue_size - self.num_used) as usize
+    /// Pop the chain with `token` if the device used it already, recycling
+    /// its descriptors, return its length.
+    fn claim(&mut self, token: u16) -> Result<Option<u32>> {
+        let Some(len) = self.states[token as usize].completed.take() else {
+            return Ok(None);
+        };
+        self.num_completed -= 1;
+        self.recycle_descriptors(token)?;
+        Ok(Some(len))
     }
 
-    /// Recycle descriptors in the list specified by head.
+    /// Take the next element of the used ring, which the device has written,
+    /// and check its chain, return (token, len). The descriptors of the chain
+    /// are left to recycle.
+    fn take_used(&mut self) -> Result<(u16, u32)> {
+        let (id, len) = match self.rings {
+            Rings::Split { used, .. } => {
+                let last_used_slot = self.last_used_idx & (self.queue_size - 1);
+                let elem = &used.ring[last_used_slot as usize];
+                // skip the element even if it is invalid, so that it is not
+                // read again
+                self.last_used_idx = self.last_used_idx.wrapping_add(1);
+                (elem.id.read().get(), elem.len.read().get())
+            }
+            Rings::Packed { desc, .. } => {
+                let desc = &desc[(self.last_used_idx & !PACKED_WRAP) as usize];
+                let id = desc.id.read().get();
+                // skip the descriptors of the chain, which the device used
+                // as a whole, or the descriptor if the chain is invalid
+                let skip = self
+                    .states
+                    .get(id as usize)
+                    .filter(|state| state.in_flight)
+                    .map_or(1, |state| state.chain_len);
+                self.last_used_idx = packed_advance(self.last_used_idx, skip, self.queue_size);
+                (id as u32, desc.len.read().get())
+            }
+        };
+
+        let index = id as u16;
+        if !self
+            .states
+            .get(id as usize)
+            .is_some_and(|state| state.in_flight && state.completed.is_none())
+        {
+            error!("Queue {} used token {} not in flight", self.queue_idx, id);
+            self.metrics.errors += 1;
+            return Err(Error::WrongToken);
+        }
+        if let Err(err) = self.chain_end(index) {
+            error!("Queue {} used invalid chain {}", self.queue_idx, id);
+            self.metrics.errors += 1;
+            return Err(err);
+        }
+        trace!(
+            "Queue {} used buffers with token {}, len {}",
+            self.queue_idx,
+            index,
+            len
+        );
+        #[cfg(feature = "validate")]
+        if len as u64 > self.states[index as usize].writable {
+            error!(
+                "Queue {} used {} bytes of token {} with {} writable bytes",
+                self.queue_idx, len, index, self.states[index as usize].writable
+            );
+            self.metrics.errors += 1;
+            self.recycle_descriptors(index)?;
+            return Err(Error::IoError);
+        }
+        self.metrics.used += 1;
+        self.metrics.bytes_in += len as u64;
+        Ok((index, len))
+    }
+
+    /// Poll for the chain with `token` to be used by the device, returning
+    /// its length once it is, and registering the waker of `cx` to be woken
+    /// by [`on_interrupt`](Self::on_interrupt) until then.
     ///
-    /// This will push all linked descriptors at the front of the free list.
-    fn recycle_descriptors(&mut self, mut head: u16) {
-        let origin_free_head = self.free_head;
-        self.free_head = head;
-        loop {
-            let desc = &mut self.desc[head as usize];
-            let flags = desc.flags.read();
-            self.num_used -= 1;
-            if flags.contains(DescFlags::NEXT) {
-                head = desc.next.read();
-            } else {
-                desc.next.write(origin_free_head);
-                return;
+    /// The other chains used meanwhile are kept for their own pollers, so
+    /// this is not to be mixed with [`pop_used`](Self::pop_used) on the same
+    /// queue. If the device used a chain wrongly, it is reset through
+    /// `header`.
+    pub fn poll_used(
+        &mut self,
+        header: &dyn Transport,
+        cx: &mut Context<'_>,
+        token: u16,
+    ) -> Poll<Result<u32>> {
+        if token >= self.queue_size {
+            return Poll::Ready(Err(Error::InvalidParam));
+        }
+        if take(&mut self.states[token as usize].lost) || self.broken {
+            return Poll::Ready(Err(Error::DeviceReset));
+        }
+        if !self.states[token as usize].in_flight {
+            return Poll::Ready(Err(Error::InvalidParam));
+        }
+        if let Err(err) = self.on_interrupt(header) {
+            return Poll::Ready(Err(err));
+        }
+        match self.claim(token) {
+            Ok(Some(len)) => Poll::Ready(Ok(len)),
+            Ok(None) => {
+                self.states[token as usize].waker = Some(cx.waker().clone());
+                Poll::Pending
             }
+            Err(err) => Poll::Ready(Err(err)),
         }
     }
 
-    /// Get a token from device used buffers, return (token, len).
+    /// Collect the chains used by the device for [`poll_used`](Self::poll_used),
+    /// waking the tasks polling for them, and return how many there were.
     ///
-    /// Ref: linux virtio_ring.c virtqueue_get_buf_ctx
-    pub fn pop_used(&mut self) -> Result<(u16, u32)> {
-        if !self.can_pop() {
-            return Err(Error::NotReady);
+    /// If the device used a chain wrongly, it is reset through `header`, and
+    /// the tasks polling for chains are woken to find it was.
+    pub fn on_interrupt(&mut self, header: &dyn Transport) -> Result<usize> {
+        let mut count = 0;
+        let end = self.used_end();
+        read_barrier(self.order_platform);
+        while self.used_before(end) {
+            match self.take_used() {
+                Ok((token, len)) => self.complete(token, len),
+                Err(err) => {
+                    self.fail(header);
+                    return Err(err);
+                }
+            }
+            count += 1;
         }
-        // read barrier
-        fence(Ordering::SeqCst);
+        Ok(count)
+    }
 
-        let last_used_slot = self.last_used_idx & (self.queue_size - 1);
-        let index = self.used.ring[last_used_slot as usize].id.read() as u16;
-        let len = self.used.ring[last_used_slot as usize].len.read();
+    /// A future for the chain with `token` to be used by the device, which
+    /// resolves to its length as [`poll_used`](Self::poll_used) does.
+    pub fn used<'q>(&'q mut self, header: &'q dyn Transport, token: u16) -> UsedFuture<'q, 'a> {
+        UsedFuture {
+            queue: self,
+            header,
+            token,
+        }
+    }
+}
 
-        self.recycle_descriptors(index);
-        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+impl Drop for VirtQueue<'_> {
+    fn drop(&mut self) {
+        if self.attached {
+            // the device may still use the memory of the queue
+            warn!(
+                "Queue {} dropped while set up on the device, leaking its memory",
+                self.queue_idx
+            );
+            mem::forget(self.indirect.take());
+            return;
+        }
+        unsafe { ManuallyDrop::drop(&mut self.memory) };
+    }
+}
 
-        Ok((index, len))
+/// An iterator of the chains used by the device, as (token, len), created by
+/// [`VirtQueue::pop_used_multiple`].
+pub struct PopUsed<'q, 'a> {
+    queue: &'q mut VirtQueue<'a>,
+    /// The index of the used ring of a split queue when the iterator was
+    /// created.
+    end: Option<u16>,
+    remaining: usize,
+    /// The tokens to pop, if not all; the chains with other tokens are kept
+    /// to be popped later.
+    only: Option<&'q [u16]>,
+}
+
+impl<'q> PopUsed<'q, '_> {
+    /// Pop only the chains with `tokens`.
+    fn only(mut self, tokens: &'q [u16]) -> Self {
+        self.only = Some(tokens);
+        self
+    }
+}
+
+impl Iterator for PopUsed<'_, '_> {
+    type Item = Result<(u16, u32)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let used = match self.queue.claim_any(self.only) {
+            Ok(None) => loop {
+                if !self.queue.used_before(self.end) {
+                    return None;
+                }
+                match self.queue.take_used() {
+                    Ok((token, len)) if !self.only.is_none_or(|only| only.contains(&token)) => {
+                        self.queue.complete(token, len)
+                    }
+                    Ok((token, len)) => {
+                        break self.queue.recycle_descriptors(token).map(|()| (token, len))
+                    }
+                    Err(err) => break Err(err),
+                }
+            },
+            Ok(Some(used)) => Ok(used),
+            Err(err) => Err(err),
+        };
+        // stop at an error, as the queue can no longer be trusted
+        self.remaining = if used.is_ok() { self.remaining - 1 } else { 0 };
+        Some(used)
+    }
+}
+
+/// A future for a chain to be used by the device, created by
+/// [`VirtQueue::used`].
+pub struct UsedFuture<'q, 'a> {
+    queue: &'q mut VirtQueue<'a>,
+    header: &'q dyn Transport,
+    token: u16,
+}
+
+impl Future for UsedFuture<'_, '_> {
+    type Output = Result<u32>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let (header, token) = (self.header, self.token);
+        self.queue.poll_used(header, cx, token)
+    }
+}
+
+/// The state of a [`VirtQueue`], saved to restore the queue after the VM is
+/// restored from a snapshot.
+///
+/// The descriptors and rings themselves are in the queue memory, which is
+/// part of the snapshot. The chains in flight are recorded there too.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct QueueState {
+    queue_idx: u32,
+    queue_size: u16,
+    /// The physical addresses of the descriptor table, the available ring
+    /// and the used ring.
+    paddrs: [u64; 3],
+    /// Whether the queue is in the packed layout.
+    packed: bool,
+    num_used: u16,
+    free_head: u16,
+    avail_idx: u16,
+    last_used_idx: u16,
+}
+
+impl QueueState {
+    /// The length of the serialized state.
+    pub(crate) const SIZE: usize = 39;
+
+    /// Serialize the state as little-endian fields.
+    pub(crate) fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.queue_idx.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.queue_size.to_le_bytes());
+        for (i, paddr) in self.paddrs.iter().enumerate() {
+            bytes[6 + 8 * i..14 + 8 * i].copy_from_slice(&paddr.to_le_bytes());
+        }
+        bytes[30..32].copy_from_slice(&self.num_used.to_le_bytes());
+        bytes[32..34].copy_from_slice(&self.free_head.to_le_bytes());
+        bytes[34..36].copy_from_slice(&self.avail_idx.to_le_bytes());
+        bytes[36..38].copy_from_slice(&self.last_used_idx.to_le_bytes());
+        bytes[38] = self.packed as u8;
+        bytes
+    }
+
+    /// Deserialize a state serialized by [`to_bytes`](Self::to_bytes).
+    pub(crate) fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let paddr_at = |i: usize| {
+            let mut paddr = [0; 8];
+            paddr.copy_from_slice(&bytes[6 + 8 * i..14 + 8 * i]);
+            u64::from_le_bytes(paddr)
+        };
+        QueueState {
+            queue_idx: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            queue_size: u16_at(4),
+            paddrs: [paddr_at(0), paddr_at(1), paddr_at(2)],
+            packed: bytes[38] != 0,
+            num_used: u16_at(30),
+            free_head: u16_at(32),
+            avail_idx: u16_at(34),
+            last_used_idx: u16_at(36),
+        }
+    }
+}
+
+/// Order the writes to the rings before the write of the index which makes
+/// them visible to the device, which takes a [`platform_barrier`] with
+/// `platform`.
+fn write_barrier(platform: bool) {
+    if platform {
+        return platform_barrier();
     }
+    #[cfg(not(feature = "seqcst-fences"))]
+    fence(Ordering::Release);
+    #[cfg(feature = "seqcst-fences")]
+    fence(Ordering::SeqCst);
+}
+
+/// Order the read of the index written by the device before the reads of the
+/// ring elements it covers, which takes a [`platform_barrier`] with
+/// `platform`.
+fn read_barrier(platform: bool) {
+    if platform {
+        return platform_barrier();
+    }
+    #[cfg(not(feature = "seqcst-fences"))]
+    fence(Ordering::Acquire);
+    #[cfg(feature = "seqcst-fences")]
+    fence(Ordering::SeqCst);
+}
+
+/// Order all memory accesses before the barrier before all those after it,
+/// as observed by devices outside the coherency domain of the CPUs too, e.g.
+/// hardware behind an IOMMU, including writes to write-combined doorbells.
+///
+/// Fences between CPUs only order accesses in their domain, so this takes
+/// the system barriers of the architecture, as the `mb()` of Linux.
+fn platform_barrier() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        core::arch::asm!("dsb sy", options(nostack, preserves_flags))
+    };
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    unsafe {
+        core::arch::asm!("fence iorw, iorw", options(nostack, preserves_flags))
+    };
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    unsafe {
+        core::arch::asm!("mfence", options(nostack, preserves_flags))
+    };
+    fence(Ordering::SeqCst);
 }
 
 /// The inner layout of a VirtQueue.
 ///
 /// Ref: 2.6.2 Legacy Interfaces: A Note on Virtqueue Layout
-struct VirtQueueLayout {
-    avail_offset: usize,
-    used_offset: usize,
-    size: usize,
+pub(crate) struct VirtQueueLayout {
+    pub(crate) avail_offset: usize,
+    pub(crate) used_offset: usize,
+    pub(crate) size: usize,
 }
 
 impl VirtQueueLayout {
-    fn new(queue_size: u16) -> Self {
-        assert!(
-            queue_size.is_power_of_two(),
-            "queue size should be a power of 2"
-        );
-        let queue_size = queue_size as usize;
-        let desc = size_of::<Descriptor>() * queue_size;
-        let avail = size_of::<u16>() * (3 + queue_size);
-        let used = size_of::<u16>() * 3 + size_of::<UsedElem>() * queue_size;
-        VirtQueueLayout {
+    /// The layout of a queue of `queue_size` descriptors, failing with
+    /// [`Error::InvalidParam`] unless it is a power of 2.
+    pub(crate) fn new(queue_size: u16) -> Result<Self> {
+        if !queue_size.is_power_of_two() {
+            warn!("Queue size {} is not a power of 2", queue_size);
+            return Err(Error::InvalidParam);
+        }
+        let AreaSizes { desc, avail, used } = AreaSizes::new(queue_size, false);
+        Ok(VirtQueueLayout {
             avail_offset: desc,
             used_offset: align_up(desc + avail),
             size: align_up(desc + avail) + align_up(used),
-        }
+        })
     }
 }
 
 #[repr(C, align(16))]
 #[derive(Debug)]
 struct Descriptor {
-    addr: Volatile<u64>,
-    len: Volatile<u32>,
-    flags: Volatile<DescFlags>,
-    next: Volatile<u16>,
+    addr: Volatile<Le64>,
+    len: Volatile<Le32>,
+    flags: Volatile<Le16>,
+    next: Volatile<Le16>,
+}
+
+/// A buffer to add to a queue with [`VirtQueue::add_parts`].
+pub enum QueueBuf<'b> {
+    /// Memory for the device to read.
+    Slice(&'b [u8]),
+    /// Memory for the device to write.
+    SliceMut(&'b mut [u8]),
+    /// A buffer which the queue holds until the device uses it.
+    Dma(&'b DmaBuf),
+}
+
+impl QueueBuf<'_> {
+    fn chain_buf(&self, write: bool) -> ChainBuf<'_> {
+        match self {
+            QueueBuf::Slice(buf) => ChainBuf::slice(buf, write),
+            QueueBuf::SliceMut(buf) => ChainBuf::slice(buf, write),
+            QueueBuf::Dma(dma) => ChainBuf {
+                paddr: dma.paddr() as u64,
+                len: dma.len() as u64,
+                write,
+                dma: Some(dma),
+            },
+        }
+    }
+}
+
+/// A scatter-gather list of buffers, to add to a queue as a chain with
+/// [`VirtQueue::add_sg`].
+///
+/// Segments for the device to read and to write can be pushed in any order,
+/// e.g. a request header, its payload and a status trailer. As the device
+/// reads a chain before writing to it, the readable segments come first in
+/// the chain, each kind in the order it was pushed.
+pub struct SgList<'b> {
+    segments: [ChainBuf<'b>; MAX_SEGMENTS],
+    len: usize,
+    _bufs: PhantomData<&'b mut [u8]>,
+}
+
+impl<'b> SgList<'b> {
+    /// Create an empty list.
+    pub fn new() -> Self {
+        SgList {
+            segments: [ChainBuf {
+                paddr: 0,
+                len: 0,
+                write: false,
+                dma: None,
+            }; MAX_SEGMENTS],
+            len: 0,
+            _bufs: PhantomData,
+        }
+    }
+
+    /// Push a segment for the device to read.
+    pub fn readable(self, buf: &'b [u8]) -> Result<Self> {
+        self.push(ChainBuf::slice(buf, false))
+    }
+
+    /// Push a segment for the device to write.
+    pub fn writable(self, buf: &'b mut [u8]) -> Result<Self> {
+        self.push(ChainBuf::slice(buf, true))
+    }
+
+    fn push(mut self, segment: ChainBuf<'b>) -> Result<Self> {
+        *self.segments.get_mut(self.len).ok_or(Error::InvalidParam)? = segment;
+        self.len += 1;
+        Ok(self)
+    }
+}
+
+impl Default for SgList<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A buffer of a descriptor chain being added.
+#[derive(Clone, Copy)]
+struct ChainBuf<'b> {
+    paddr: u64,
+    len: u64,
+    write: bool,
+    dma: Option<&'b DmaBuf>,
+}
+
+impl<'b> ChainBuf<'b> {
+    /// Split the buffer into pieces of at most `max_len` bytes, one for each
+    /// descriptor, of which the first holds the DMA buffer if any.
+    fn split(self, max_len: u32) -> impl Iterator<Item = ChainBuf<'b>> + Clone {
+        let max_len = max_len as u64;
+        let pieces = self.len.max(1).div_ceil(max_len);
+        (0..pieces).map(move |i| ChainBuf {
+            paddr: self.paddr + i * max_len,
+            len: (self.len - i * max_len).min(max_len),
+            write: self.write,
+            dma: self.dma.filter(|_| i == 0),
+        })
+    }
+
+    fn slice(buf: &[u8], write: bool) -> Self {
+        ChainBuf {
+            paddr: virt_to_phys(buf.as_ptr() as usize) as u64,
+            len: buf.len() as u64,
+            write,
+            dma: None,
+        }
+    }
 }
 
-impl Descriptor {
-    fn set_buf(&mut self, buf: &[u8]) {
-        self.addr.write(virt_to_phys(buf.as_ptr() as usize) as u64);
-        self.len.write(buf.len() as u32);
+/// A DMA buffer held by a queue, which is given back when this is dropped.
+struct InFlight(DmaBuf);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.end_use();
     }
 }
 
@@ -221,36 +2127,488 @@ bitflags! {
         const NEXT = 1;
         const WRITE = 2;
         const INDIRECT = 4;
+        /// The descriptor of a packed ring is available, if the bit equals
+        /// the wrap counter of the driver.
+        const AVAIL = 1 << 7;
+        /// The descriptor of a packed ring is used, if the bit equals the
+        /// wrap counter of the device.
+        const USED = 1 << 15;
+    }
+}
+
+bitflags! {
+    /// Available ring flags
+    struct AvailFlags: u16 {
+        const NO_INTERRUPT = 1;
+    }
+}
+
+bitflags! {
+    /// Used ring flags
+    struct UsedFlags: u16 {
+        const NO_NOTIFY = 1;
     }
 }
 
 /// The driver uses the available ring to offer buffers to the device:
 /// each ring entry refers to the head of a descriptor chain.
 /// It is only written by the driver and read by the device.
+///
+/// The ring has an entry for each descriptor, and is followed by the unused
+/// `used_event` field.
 #[repr(C)]
 #[derive(Debug)]
 struct AvailRing {
-    flags: Volatile<u16>,
+    flags: Volatile<Le16>,
     /// A driver MUST NOT decrement the idx.
-    idx: Volatile<u16>,
-    ring: [Volatile<u16>; 32], // actual size: queue_size
-    used_event: Volatile<u16>, // unused
+    idx: Volatile<Le16>,
+    ring: [Volatile<Le16>],
 }
 
 /// The used ring is where the device returns buffers once it is done with them:
 /// it is only written to by the device, and read by the driver.
+///
+/// The ring has an entry for each descriptor, and is followed by the unused
+/// `avail_event` field.
 #[repr(C)]
 #[derive(Debug)]
 struct UsedRing {
-    flags: Volatile<u16>,
-    idx: Volatile<u16>,
-    ring: [UsedElem; 32],       // actual size: queue_size
-    avail_event: Volatile<u16>, // unused
+    flags: Volatile<Le16>,
+    idx: Volatile<Le16>,
+    ring: [UsedElem],
 }
 
 #[repr(C)]
 #[derive(Debug)]
 struct UsedElem {
-    id: Volatile<u32>,
-    len: Volatile<u32>,
+    id: ReadOnly<Le32>,
+    len: ReadOnly<Le32>,
+}
+
+/// A descriptor of a packed ring, which the driver makes available with a
+/// chain, and the device overwrites with the buffer ID and written length
+/// of the chain it uses.
+///
+/// Ref: 2.8 Packed Virtqueues
+#[repr(C, align(16))]
+#[derive(Debug)]
+struct PackedDescriptor {
+    addr: Volatile<Le64>,
+    len: Volatile<Le32>,
+    id: Volatile<Le16>,
+    flags: Volatile<Le16>,
+}
+
+/// The structure by which the driver or the device of a packed queue asks
+/// the other not to notify it.
+///
+/// Ref: 2.8 Packed Virtqueues
+#[repr(C)]
+#[derive(Debug)]
+struct EventSuppress {
+    /// The descriptor to be notified of, with `VIRTIO_F_EVENT_IDX`.
+    off_wrap: Volatile<Le16>,
+    flags: Volatile<Le16>,
+}
+
+/// Notify of every chain.
+const EVENT_FLAGS_ENABLE: u16 = 0;
+/// Notify of no chain.
+const EVENT_FLAGS_DISABLE: u16 = 1;
+
+/// The bit of the indices of a packed queue holding the wrap counter, which
+/// flips each time the index wraps around the ring.
+const PACKED_WRAP: u16 = 1 << 15;
+
+/// The flags marking the descriptor at `idx` of a packed ring available:
+/// the AVAIL bit is the wrap counter, and the USED bit its inverse.
+fn packed_avail_flags(idx: u16) -> DescFlags {
+    if idx & PACKED_WRAP != 0 {
+        DescFlags::AVAIL
+    } else {
+        DescFlags::USED
+    }
+}
+
+/// Advance `idx` of a packed ring of `size` descriptors by `count`, flipping
+/// the wrap counter if it wraps around.
+fn packed_advance(idx: u16, count: u16, size: u16) -> u16 {
+    let position = (idx & !PACKED_WRAP) + count;
+    if position >= size {
+        (position - size) | (!idx & PACKED_WRAP)
+    } else {
+        position | (idx & PACKED_WRAP)
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    extern crate std;
+
+    use super::*;
+    use crate::testing::{
+        destroy_fake_device, fake_device, fake_modern_device, read_chain, write_chain, FakeBackend,
+        FakeTransport, Reply, ScriptedDevice,
+    };
+    use std::sync::{Arc, Mutex};
+    use std::vec;
+    use std::vec::Vec;
+
+    const VERSION_1: u64 = 1 << 32;
+    const RING_PACKED: u64 = 1 << 34;
+
+    /// Set up queue 0 of `size` on a fake device answering as `device`, in
+    /// the packed layout if `packed`.
+    fn fake_queue(
+        device: impl FakeBackend + 'static,
+        size: u16,
+        packed: bool,
+    ) -> (&'static mut FakeTransport, VirtQueue<'static>) {
+        let header = if packed {
+            let header = fake_modern_device(device);
+            header.write_driver_features(VERSION_1 | RING_PACKED);
+            header
+        } else {
+            fake_device(device)
+        };
+        let queue = if packed {
+            VirtQueue::new_packed(header, 0, size)
+        } else {
+            VirtQueue::new(header, 0, size)
+        };
+        (header, queue.unwrap())
+    }
+
+    /// The buffers of a chain, as the device sees them: the contents of the
+    /// readable ones and the lengths of the writable ones.
+    type Chain = (Vec<Vec<u8>>, Vec<usize>);
+
+    /// A device which records the chains made available in its queues and
+    /// writes the bytes it read back to the writable buffers.
+    #[derive(Default)]
+    struct Echo {
+        chains: Arc<Mutex<Vec<Chain>>>,
+    }
+
+    impl FakeBackend for Echo {
+        fn device_type(&self) -> DeviceType {
+            DeviceType::Block
+        }
+
+        fn process(
+            &mut self,
+            _queue: u32,
+            inputs: &[&[u8]],
+            outputs: &mut [&mut [u8]],
+        ) -> Option<u32> {
+            let chain = (
+                inputs.iter().map(|input| input.to_vec()).collect(),
+                outputs.iter().map(|output| output.len()).collect(),
+            );
+            self.chains.lock().unwrap().push(chain);
+            Some(write_chain(outputs, &read_chain(inputs)) as u32)
+        }
+    }
+
+    /// The buffers of a chain added with [`VirtQueue::add_batch`].
+    type BatchChain<'b> = (&'b [&'b [u8]], &'b [&'b mut [u8]]);
+
+    /// Unset `queue` and remove its fake device.
+    fn destroy(header: &'static mut FakeTransport, mut queue: VirtQueue) {
+        queue.unset(header);
+        drop(queue);
+        unsafe { destroy_fake_device(header) };
+    }
+
+    #[test]
+    fn peek_used_leaves_chain_to_pop() {
+        for packed in [false, true] {
+            let device = ScriptedDevice::new(DeviceType::Block)
+                .reply(0, Reply::Data(vec![1; 3]))
+                .reply(0, Reply::Data(vec![2; 2]))
+                .reply(0, Reply::Data(vec![3; 1]));
+            let (header, mut queue) = fake_queue(device, 4, packed);
+            assert_eq!(queue.peek_used(), Err(Error::NotReady));
+
+            let (mut first, mut second) = ([0; 4], [0; 4]);
+            let token = queue.add(&[], &[&mut first]).unwrap();
+            header.notify(0);
+            assert_eq!(queue.peek_used_with_len(), Ok((token, 3)));
+            assert_eq!(queue.peek_used(), Ok(token));
+            assert_eq!(queue.pop_used(), Ok((token, 3)));
+            assert_eq!(queue.peek_used(), Err(Error::NotReady));
+
+            // a chain used while waiting for another is peeked first
+            let first_token = queue.add(&[], &[&mut first]).unwrap();
+            let second_token = queue.add(&[], &[&mut second]).unwrap();
+            header.notify(0);
+            assert_eq!(queue.wait_for(header, second_token), Ok(1));
+            assert_eq!(queue.peek_used_with_len(), Ok((first_token, 2)));
+            assert_eq!(queue.pop_used(), Ok((first_token, 2)));
+            assert_eq!(queue.peek_used(), Err(Error::NotReady));
+
+            destroy(header, queue);
+        }
+    }
+
+    #[test]
+    fn indirect_chains_take_one_descriptor() {
+        for packed in [false, true] {
+            let device = Echo::default();
+            let chains = device.chains.clone();
+            let (header, mut queue) = fake_queue(device, 4, packed);
+            queue.enable_indirect().unwrap();
+
+            let mut outputs = [[0; 6], [0; 6]];
+            let [first, second] = &mut outputs;
+            let first = queue
+                .add(&[b"ab", b"cd", b"ef"], &[&mut first[..]])
+                .unwrap();
+            let second = queue
+                .add(&[b"gh", b"ij", b"kl"], &[&mut second[..]])
+                .unwrap();
+            assert_eq!(queue.available_desc(), 2);
+            header.notify(0);
+            assert_eq!(queue.pop_used(), Ok((first, 6)));
+            assert_eq!(queue.pop_used(), Ok((second, 6)));
+            assert_eq!(queue.available_desc(), 4);
+            assert_eq!(outputs, [*b"abcdef", *b"ghijkl"]);
+            assert_eq!(
+                chains.lock().unwrap()[1],
+                (
+                    vec![b"gh".to_vec(), b"ij".to_vec(), b"kl".to_vec()],
+                    vec![6]
+                )
+            );
+
+            // single buffers are added directly, and chains longer than the
+            // queue not at all
+            let mut output = [0; 2];
+            queue.add(&[b"ab"], &[]).unwrap();
+            assert_eq!(queue.available_desc(), 3);
+            assert_eq!(
+                queue.add(&[b"ab", b"cd", b"ef", b"gh"], &[&mut output]),
+                Err(Error::ChainTooLong)
+            );
+
+            destroy(header, queue);
+        }
+    }
+
+    #[test]
+    fn sg_list_puts_readable_segments_first() {
+        for packed in [false, true] {
+            let device = Echo::default();
+            let chains = device.chains.clone();
+            let (header, mut queue) = fake_queue(device, 8, packed);
+
+            let mut data = [0; 4];
+            let mut status = [0; 1];
+            let sg = SgList::new()
+                .readable(b"head")
+                .unwrap()
+                .writable(&mut data)
+                .unwrap()
+                .readable(b"x")
+                .unwrap()
+                .writable(&mut status)
+                .unwrap();
+            let token = queue.add_sg(sg).unwrap();
+            header.notify(0);
+            assert_eq!(queue.pop_used(), Ok((token, 5)));
+            assert_eq!((&data, &status), (b"head", b"x"));
+            assert_eq!(
+                chains.lock().unwrap()[0],
+                (vec![b"head".to_vec(), b"x".to_vec()], vec![4, 1])
+            );
+
+            destroy(header, queue);
+        }
+    }
+
+    #[test]
+    fn sg_list_is_bounded() {
+        let mut sg = SgList::new();
+        for _ in 0..MAX_SEGMENTS {
+            sg = sg.readable(b"x").unwrap();
+        }
+        assert!(matches!(sg.readable(b"x"), Err(Error::InvalidParam)));
+    }
+
+    #[test]
+    fn long_buffers_span_several_descriptors() {
+        for packed in [false, true] {
+            let device = Echo::default();
+            let chains = device.chains.clone();
+            let (header, mut queue) = fake_queue(device, 8, packed);
+            queue.set_max_desc_len(4).unwrap();
+            assert_eq!(queue.set_max_desc_len(0), Err(Error::InvalidParam));
+
+            let mut output = [0; 6];
+            let token = queue.add(&[b"0123456789"], &[&mut output]).unwrap();
+            assert_eq!(queue.available_desc(), 3);
+            header.notify(0);
+            assert_eq!(queue.pop_used(), Ok((token, 6)));
+            assert_eq!(&output, b"012345");
+            assert_eq!(
+                chains.lock().unwrap()[0],
+                (
+                    vec![b"0123".to_vec(), b"4567".to_vec(), b"89".to_vec()],
+                    vec![4, 2]
+                )
+            );
+
+            // the pieces count against the limit of the chain length
+            queue.set_max_chain_len(4).unwrap();
+            assert_eq!(
+                queue.add(&[b"0123456789"], &[&mut output]),
+                Err(Error::ChainTooLong)
+            );
+
+            destroy(header, queue);
+        }
+    }
+
+    #[test]
+    fn add_batch_adds_chains_until_full() {
+        for packed in [false, true] {
+            let device = Echo::default();
+            let chains = device.chains.clone();
+            let (header, mut queue) = fake_queue(device, 4, packed);
+
+            let mut outputs = [[0; 1]; 3];
+            let [first, second, third] = &mut outputs;
+            let (first, second, third) = ([&mut first[..]], [&mut second[..]], [&mut third[..]]);
+            let batch: [BatchChain; 3] = [(&[b"a"], &first), (&[b"b"], &second), (&[b"c"], &third)];
+            let single: BatchChain = (&[b"d"], &[]);
+            let mut tokens = [0; 3];
+            assert_eq!(queue.add_batch(batch, &mut tokens), Ok(2));
+            assert_eq!(queue.available_desc(), 0);
+            assert_eq!(
+                queue.add_batch([single], &mut tokens),
+                Err(Error::BufferTooSmall)
+            );
+
+            header.notify(0);
+            assert_eq!(chains.lock().unwrap().len(), 2);
+            assert_eq!(queue.pop_used(), Ok((tokens[0], 1)));
+            assert_eq!(queue.pop_used(), Ok((tokens[1], 1)));
+            assert_eq!(outputs, [*b"a", *b"b", [0]]);
+
+            // the tokens bound the number of chains
+            assert_eq!(queue.add_batch([single; 2], &mut tokens[..1]), Ok(1));
+            assert_eq!(queue.available_desc(), 3);
+
+            destroy(header, queue);
+        }
+    }
+
+    #[cfg(feature = "validate")]
+    #[test]
+    fn validate_rejects_more_used_bytes_than_writable() {
+        for packed in [false, true] {
+            let device = ScriptedDevice::new(DeviceType::Block)
+                .reply(0, Reply::Len(5))
+                .reply(0, Reply::Len(4));
+            let (header, mut queue) = fake_queue(device, 4, packed);
+
+            let mut output = [0; 4];
+            queue.add(&[b"in"], &[&mut output]).unwrap();
+            header.notify(0);
+            assert_eq!(queue.pop_used(), Err(Error::IoError));
+            // the chain is given back all the same
+            assert_eq!(queue.available_desc(), 4);
+            assert_eq!(queue.in_flight().count(), 0);
+
+            let token = queue.add(&[b"in"], &[&mut output]).unwrap();
+            header.notify(0);
+            assert_eq!(queue.pop_used(), Ok((token, 4)));
+
+            destroy(header, queue);
+        }
+    }
+
+    #[test]
+    fn restore_continues_chains_in_flight() {
+        let device = ScriptedDevice::new(DeviceType::Block)
+            .reply(0, Reply::Data(vec![1; 2]))
+            .reply(0, Reply::Data(vec![2; 3]));
+        let (header, mut queue) = fake_queue(device, 4, false);
+        let mut first = [0; 4];
+        let token = queue.add(&[], &[&mut first]).unwrap();
+        header.notify(0);
+        assert_eq!(queue.pop_used(), Ok((token, 2)));
+        let mut second = [0; 4];
+        let token = queue.add(&[], &[&mut second]).unwrap();
+
+        let state = queue.save();
+        assert_eq!(QueueState::from_bytes(&state.to_bytes()), state);
+        // the queue stays set up on the device, so its memory is leaked
+        drop(queue);
+        let mut queue = unsafe { VirtQueue::restore(header, &state) }.unwrap();
+        assert_eq!(queue.in_flight().collect::<Vec<_>>(), [token]);
+        header.notify(0);
+        assert_eq!(queue.pop_used(), Ok((token, 3)));
+        assert_eq!(second, [2, 2, 2, 0]);
+        assert_eq!(queue.available_desc(), 4);
+
+        // a queue which is not set up on the device any more is not restored
+        let saved = queue.save();
+        queue.unset(header);
+        assert!(matches!(
+            unsafe { VirtQueue::restore(header, &saved) },
+            Err(Error::NotReady)
+        ));
+
+        destroy(header, queue);
+    }
+
+    #[test]
+    fn packed_queues_are_not_restored_with_chains_in_flight() {
+        let device = ScriptedDevice::new(DeviceType::Block);
+        let (header, mut queue) = fake_queue(device, 4, true);
+        let mut output = [0; 4];
+        queue.add(&[], &[&mut output]).unwrap();
+        let state = queue.save();
+        assert!(matches!(
+            unsafe { VirtQueue::restore(header, &state) },
+            Err(Error::InvalidParam)
+        ));
+
+        destroy(header, queue);
+    }
+
+    #[test]
+    fn dma_bufs_are_held_until_used() {
+        let device = ScriptedDevice::new(DeviceType::Block).reply(0, Reply::Data(b"data".to_vec()));
+        let (header, mut queue) = fake_queue(device, 4, false);
+        let mut buf = DmaBuf::new(4, BufferDirection::DeviceToDriver).unwrap();
+        assert_eq!(
+            queue.add_parts(&[QueueBuf::Dma(&buf)], &[]),
+            Err(Error::InvalidParam)
+        );
+
+        let token = queue
+            .add_parts(&[QueueBuf::Slice(b"req")], &[QueueBuf::Dma(&buf)])
+            .unwrap();
+        assert!(buf.in_flight());
+        assert!(buf.as_slice().is_none());
+        assert!(buf.as_mut_slice().is_none());
+        assert_eq!(
+            queue.add_parts(&[], &[QueueBuf::Dma(&buf)]),
+            Err(Error::AlreadyUsed)
+        );
+        // the queue keeps the buffer while the driver drops its handle
+        let shared = buf.clone();
+        drop(buf);
+
+        header.notify(0);
+        assert_eq!(queue.pop_used(), Ok((token, 4)));
+        assert!(!shared.in_flight());
+        assert_eq!(shared.as_slice(), Some(&b"data"[..]));
+
+        destroy(header, queue);
+    }
 }
@@ -0,0 +1,431 @@
+use super::*;
+use crate::queue::VirtQueue;
+use bitflags::*;
+
+/// The virtio SCMI device.
+///
+/// It carries System Control and Management Interface messages between the
+/// guest and an SCMI platform on the host, which lets the guest manage power
+/// domains, clocks, sensors and other resources shared with the host.
+///
+/// Commands are sent through the command queue. If the device supports
+/// platform-to-agent channels, notifications and delayed responses arrive in
+/// buffers posted to the event queue.
+pub struct VirtIOScmi<'a> {
+    header: &'static mut dyn Transport,
+    /// Queue for commands and their responses.
+    cmd_queue: VirtQueue<'a>,
+    /// Queue for notifications and delayed responses.
+    event_queue: Option<VirtQueue<'a>>,
+    /// DMA area of the event buffers.
+    event_buf_dma: Option<DMA>,
+    /// The token of the next command.
+    next_token: u16,
+    features: Features,
+}
+
+impl VirtIOScmi<'_> {
+    /// Create a new VirtIO-SCMI driver.
+    pub fn new(header: &'static mut dyn Transport) -> Result<Self> {
+        Self::from_init(DeviceInit::new(header))
+    }
+
+    /// Create a new VirtIO-SCMI driver, initializing the device as customized
+    /// by `init`.
+    pub fn from_init(init: DeviceInit<Acknowledged>) -> Result<Self> {
+        let features = Features::from_bits_truncate(init.device_features());
+        info!("Device features {:?}", features);
+        let supported_features = Features::P2A_CHANNELS;
+        let mut init = init.negotiate(supported_features.bits())?;
+        let negotiated = Features::from_bits_truncate(init.features());
+
+        let cmd_queue = init.queue(QUEUE_CMD, QUEUE_SIZE)?;
+
+        let (event_queue, event_buf_dma) = if negotiated.contains(Features::P2A_CHANNELS) {
+            let mut event_queue = init.fixed_queue(QUEUE_EVENT, QUEUE_SIZE)?;
+            let event_buf_dma = DMA::new(pages(EVENT_BUF_SIZE * QUEUE_SIZE as usize))?;
+            let event_buf = unsafe { event_buf_dma.as_buf() };
+            for (i, buf) in event_buf
+                .chunks_exact_mut(EVENT_BUF_SIZE)
+                .take(QUEUE_SIZE as usize)
+                .enumerate()
+            {
+                let token = event_queue.add(&[], &[buf])?;
+                if token != i as u16 {
+                    return Err(Error::WrongToken);
+                }
+            }
+            (Some(event_queue), Some(event_buf_dma))
+        } else {
+            (None, None)
+        };
+
+        let header = init.finish();
+
+        Ok(VirtIOScmi {
+            header,
+            cmd_queue,
+            event_queue,
+            event_buf_dma,
+            next_token: 0,
+            features: negotiated,
+        })
+    }
+
+    /// Acknowledge interrupt.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.header.ack_interrupt()
+    }
+
+    /// Suspend the device, e.g. before the guest enters S3.
+    ///
+    /// The device is reset so that it stops using the queues, which are set
+    /// up again by [`resume`](Self::resume). Events which have not been
+    /// taken are dropped.
+    pub fn suspend(&mut self) -> Result {
+        self.header.reset();
+        Ok(())
+    }
+
+    /// Resume the device after [`suspend`](Self::suspend), negotiating the
+    /// same features, setting up the queues again and posting the event
+    /// buffers.
+    ///
+    /// Notifications which were enabled before must be enabled again.
+    pub fn resume(&mut self) -> Result {
+        self.header.begin_reinit(self.features.bits())?;
+        self.cmd_queue.reinit(self.header);
+        if let (Some(event_queue), Some(event_buf_dma)) =
+            (self.event_queue.as_mut(), self.event_buf_dma.as_ref())
+        {
+            event_queue.reinit(self.header);
+            let event_buf = unsafe { event_buf_dma.as_buf() };
+            for buf in event_buf
+                .chunks_exact_mut(EVENT_BUF_SIZE)
+                .take(QUEUE_SIZE as usize)
+            {
+                event_queue.add(&[], &[buf])?;
+            }
+        }
+        self.header.finish_init();
+        Ok(())
+    }
+
+    /// Whether the device sends notifications and delayed responses.
+    pub fn has_events(&self) -> bool {
+        self.event_queue.is_some()
+    }
+
+    /// Send a command to the SCMI platform, blocking until it responds.
+    ///
+    /// `params` are the parameters of the message and the return values
+    /// following the status are copied into `ret`. Returns the length of the
+    /// return values.
+    pub fn command(
+        &mut self,
+        protocol_id: u8,
+        message_id: u8,
+        params: &[u8],
+        ret: &mut [u8],
+    ) -> Result<usize> {
+        let token = self.next_token;
+        self.next_token = (self.next_token + 1) & TOKEN_MASK;
+        let req = MessageHeader::new(protocol_id, message_id, MessageType::Command, token);
+        let mut rsp = Response {
+            header: MessageHeader(0),
+            status: ScmiStatus::GenericError as i32,
+        };
+        let req_buf = req.as_buf();
+        let rsp_buf = rsp.as_buf_mut();
+        let queued = match (params.is_empty(), ret.is_empty()) {
+            (true, true) => self.cmd_queue.add(&[req_buf], &[rsp_buf])?,
+            (true, false) => self.cmd_queue.add(&[req_buf], &[rsp_buf, ret])?,
+            (false, true) => self.cmd_queue.add(&[req_buf, params], &[rsp_buf])?,
+            (false, false) => self.cmd_queue.add(&[req_buf, params], &[rsp_buf, ret])?,
+        };
+        if self.cmd_queue.should_notify() {
+            self.header.notify(QUEUE_CMD as u32);
+        }
+        let len = self.cmd_queue.wait_for(self.header, queued)?;
+        if (len as usize) < size_of::<Response>() || rsp.header.token() != token {
+            return Err(Error::IoError);
+        }
+        ScmiStatus::check(rsp.status)?;
+        Ok((len as usize - size_of::<Response>()).min(ret.len()))
+    }
+
+    /// Query the version of a protocol implemented by the SCMI platform.
+    pub fn protocol_version(&mut self, protocol_id: u8) -> Result<u32> {
+        let mut version = [0u8; 4];
+        self.command(protocol_id, MESSAGE_PROTOCOL_VERSION, &[], &mut version)?;
+        Ok(u32::from_le_bytes(version))
+    }
+
+    /// Get a notification or delayed response sent by the SCMI platform, if
+    /// any.
+    ///
+    /// The payload of the message is copied into `buf`.
+    pub fn pop_event(&mut self, buf: &mut [u8]) -> Result<Option<ScmiEvent>> {
+        let (event_queue, event_buf_dma) =
+            match (self.event_queue.as_mut(), self.event_buf_dma.as_ref()) {
+                (Some(queue), Some(dma)) if queue.can_pop() => (queue, dma),
+                _ => return Ok(None),
+            };
+        let (token, len) = event_queue.pop_used()?;
+        let event_buf = event_buf(event_buf_dma, token);
+        let len = (len as usize).min(EVENT_BUF_SIZE);
+        let result = if len < size_of::<MessageHeader>() {
+            Err(Error::IoError)
+        } else {
+            let header = MessageHeader(u32::from_le_bytes([
+                event_buf[0],
+                event_buf[1],
+                event_buf[2],
+                event_buf[3],
+            ]));
+            let payload = &event_buf[size_of::<MessageHeader>()..len];
+            if buf.len() < payload.len() {
+                Err(Error::BufferTooSmall)
+            } else {
+                buf[..payload.len()].copy_from_slice(payload);
+                Ok(Some(ScmiEvent {
+                    protocol_id: header.protocol_id(),
+                    message_id: header.message_id(),
+                    delayed_response: header.message_type() == MessageType::DelayedResponse as u8,
+                    token: header.token(),
+                    len: payload.len(),
+                }))
+            }
+        };
+        // requeue
+        event_queue.add_notify(self.header, &[], &[event_buf])?;
+        result
+    }
+}
+
+/// The event buffer in `dma` used by the descriptor chain with the token.
+fn event_buf(dma: &DMA, token: u16) -> &'static mut [u8] {
+    let offset = token as usize * EVENT_BUF_SIZE;
+    unsafe { &mut dma.as_buf()[offset..offset + EVENT_BUF_SIZE] }
+}
+
+impl Drop for VirtIOScmi<'_> {
+    fn drop(&mut self) {
+        // stop the device from using the queues, so that they are freed
+        self.header.reset();
+        self.cmd_queue.unset(self.header);
+        if let Some(event_queue) = self.event_queue.as_mut() {
+            event_queue.unset(self.header);
+        }
+    }
+}
+
+impl Driver for VirtIOScmi<'_> {
+    fn device_type(&self) -> DeviceType {
+        self.header.device_type()
+    }
+
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
+        self.header.ack_interrupt_status()
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.features.bits()
+    }
+
+    fn enable_notifications(&mut self) {
+        self.cmd_queue.set_used_notifications(true);
+        if let Some(event_queue) = self.event_queue.as_mut() {
+            event_queue.set_used_notifications(true);
+        }
+    }
+
+    fn disable_notifications(&mut self) {
+        self.cmd_queue.set_used_notifications(false);
+        if let Some(event_queue) = self.event_queue.as_mut() {
+            event_queue.set_used_notifications(false);
+        }
+    }
+
+    fn metrics(&self, f: &mut dyn FnMut(Metric)) {
+        self.cmd_queue.metrics().report("cmdq", f);
+        if let Some(event_queue) = self.event_queue.as_ref() {
+            event_queue.metrics().report("eventq", f);
+        }
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.header.needs_reset()
+    }
+
+    fn recover(&mut self, lost: &mut dyn FnMut(LostRequest)) -> Result {
+        self.cmd_queue.report_lost("cmdq", lost);
+        self.header.reset();
+        self.resume()
+    }
+
+    #[cfg(feature = "time-hal")]
+    fn set_timeout(&mut self, ticks: Option<u64>) {
+        self.cmd_queue.set_timeout(ticks);
+        if let Some(event_queue) = self.event_queue.as_mut() {
+            event_queue.set_timeout(ticks);
+        }
+    }
+}
+
+/// A message sent by the SCMI platform through the event queue.
+#[derive(Debug, Copy, Clone)]
+pub struct ScmiEvent {
+    /// The protocol of the message.
+    pub protocol_id: u8,
+    /// The ID of the message within the protocol.
+    pub message_id: u8,
+    /// Whether this is a delayed response to an asynchronous command rather
+    /// than a notification.
+    pub delayed_response: bool,
+    /// The token of the command a delayed response belongs to.
+    pub token: u16,
+    /// The length of the payload.
+    pub len: usize,
+}
+
+/// The standard SCMI protocols.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ScmiProtocol {
+    /// Discovery of the platform and its protocols.
+    Base = 0x10,
+    /// Power domain management.
+    PowerDomain = 0x11,
+    /// System power management.
+    System = 0x12,
+    /// Performance domain management.
+    Performance = 0x13,
+    /// Clock management.
+    Clock = 0x14,
+    /// Sensor management.
+    Sensor = 0x15,
+    /// Reset domain management.
+    Reset = 0x16,
+    /// Voltage domain management.
+    Voltage = 0x17,
+    /// Power capping and monitoring.
+    PowerCapping = 0x18,
+}
+
+/// The status returned by the SCMI platform.
+#[repr(i32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ScmiStatus {
+    Success = 0,
+    NotSupported = -1,
+    InvalidParameters = -2,
+    Denied = -3,
+    NotFound = -4,
+    OutOfRange = -5,
+    Busy = -6,
+    CommsError = -7,
+    GenericError = -8,
+    HardwareError = -9,
+    ProtocolError = -10,
+}
+
+impl ScmiStatus {
+    fn check(status: i32) -> Result {
+        match status {
+            s if s == ScmiStatus::Success as i32 => Ok(()),
+            s => Err(Error::ScmiStatus(s)),
+        }
+    }
+}
+
+/// The header of an SCMI message.
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone)]
+struct MessageHeader(u32);
+
+impl MessageHeader {
+    fn new(protocol_id: u8, message_id: u8, type_: MessageType, token: u16) -> Self {
+        MessageHeader(
+            message_id as u32
+                | (type_ as u32) << 8
+                | (protocol_id as u32) << 10
+                | ((token & TOKEN_MASK) as u32) << 18,
+        )
+    }
+
+    fn message_id(self) -> u8 {
+        self.0 as u8
+    }
+
+    fn message_type(self) -> u8 {
+        (self.0 >> 8) as u8 & 0x3
+    }
+
+    fn protocol_id(self) -> u8 {
+        (self.0 >> 10) as u8
+    }
+
+    fn token(self) -> u16 {
+        (self.0 >> 18) as u16 & TOKEN_MASK
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum MessageType {
+    Command = 0,
+    DelayedResponse = 2,
+    Notification = 3,
+}
+
+/// Followed by the return values.
+#[repr(C)]
+#[derive(Debug)]
+struct Response {
+    header: MessageHeader,
+    status: i32,
+}
+
+unsafe impl AsBuf for MessageHeader {}
+unsafe impl AsBuf for Response {}
+
+bitflags! {
+    struct Features: u64 {
+        /// The device implements platform-to-agent channels.
+        const P2A_CHANNELS          = 1 << 0;
+        /// The device implements shared memory based channels.
+        const SHARED_MEMORY         = 1 << 1;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+/// The message ID of `PROTOCOL_VERSION`, common to all protocols.
+const MESSAGE_PROTOCOL_VERSION: u8 = 0;
+
+/// Tokens are 10 bits wide.
+const TOKEN_MASK: u16 = 0x3ff;
+
+const QUEUE_CMD: usize = 0;
+const QUEUE_EVENT: usize = 1;
+
+/// The size of an event buffer.
+const EVENT_BUF_SIZE: usize = 512;
+
+// a parameter that can change
+const QUEUE_SIZE: u16 = 8;
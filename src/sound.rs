@@ -0,0 +1,994 @@
+use super::*;
+use crate::queue::VirtQueue;
+use crate::volatile::ReadOnly;
+use bitflags::*;
+use core::mem::offset_of;
+
+/// The virtio sound card device.
+///
+/// The device exposes a number of PCM streams, each of which is either an
+/// output (playback) or an input (capture) stream. Streams are configured
+/// through the control queue, and audio data is transferred in periods
+/// through the TX queue for playback and the RX queue for capture.
+pub struct VirtIOSound<'a> {
+    header: &'static mut dyn Transport,
+    /// Queue for sending control requests.
+    control_queue: VirtQueue<'a>,
+    /// Queue for receiving device notifications.
+    event_queue: VirtQueue<'a>,
+    /// Queue for sending playback periods.
+    tx_queue: VirtQueue<'a>,
+    /// Queue for receiving capture periods.
+    rx_queue: VirtQueue<'a>,
+    /// DMA area of the event buffers.
+    event_buf_dma: DMA,
+    /// Buffers posted to the event queue.
+    event_buf: &'a mut [Event],
+    /// DMA area of the headers and data of queued periods.
+    period_dma: DMA,
+    /// Periods queued to the TX queue.
+    tx_periods: [Option<Period>; MAX_PERIODS],
+    /// Periods queued to the RX queue.
+    rx_periods: [Option<Period>; MAX_PERIODS],
+    jacks: u32,
+    streams: u32,
+    chmaps: u32,
+    features: Features,
+}
+
+impl VirtIOSound<'_> {
+    /// Create a new VirtIO-Sound driver.
+    pub fn new(header: &'static mut dyn Transport) -> Result<Self> {
+        Self::from_init(DeviceInit::new(header))
+    }
+
+    /// Create a new VirtIO-Sound driver, initializing the device as customized
+    /// by `init`.
+    pub fn from_init(init: DeviceInit<Acknowledged>) -> Result<Self> {
+        let features = Features::from_bits_truncate(init.device_features());
+        info!("Device features {:?}", features);
+        let supported_features = Features::empty();
+        let mut init = init.negotiate(supported_features.bits())?;
+
+        // read configuration space
+        let jacks = init.read_config::<Le32>(offset_of!(Config, jacks))?.get();
+        let streams = init.read_config::<Le32>(offset_of!(Config, streams))?.get();
+        let chmaps = init.read_config::<Le32>(offset_of!(Config, chmaps))?.get();
+        info!("jacks={}, streams={}, chmaps={}", jacks, streams, chmaps);
+
+        let control_queue = init.queue(QUEUE_CONTROL, QUEUE_SIZE)?;
+        let mut event_queue = init.fixed_queue(QUEUE_EVENT, QUEUE_SIZE)?;
+        let tx_queue = init.queue(QUEUE_TX, PCM_QUEUE_SIZE)?;
+        let rx_queue = init.queue(QUEUE_RX, PCM_QUEUE_SIZE)?;
+
+        let event_buf_dma = DMA::new(1)?;
+        let event_buf = unsafe {
+            core::slice::from_raw_parts_mut(
+                event_buf_dma.vaddr() as *mut Event,
+                QUEUE_SIZE as usize,
+            )
+        };
+        for (i, event) in event_buf.iter_mut().enumerate() {
+            let token = event_queue.add(&[], &[event.as_buf_mut()])?;
+            if token != i as u16 {
+                return Err(Error::WrongToken);
+            }
+        }
+        // a page of headers followed by a page of data for each period
+        let period_dma = DMA::new(1 + 2 * MAX_PERIODS)?;
+
+        let negotiated = init.features();
+        let header = init.finish();
+
+        Ok(VirtIOSound {
+            jacks,
+            streams,
+            chmaps,
+            header,
+            control_queue,
+            event_queue,
+            tx_queue,
+            rx_queue,
+            event_buf_dma,
+            event_buf,
+            period_dma,
+            tx_periods: [None; MAX_PERIODS],
+            rx_periods: [None; MAX_PERIODS],
+            features: Features::from_bits_truncate(negotiated),
+        })
+    }
+
+    /// Acknowledge interrupt.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.header.ack_interrupt()
+    }
+
+    /// Suspend the device, e.g. before the guest enters S3.
+    ///
+    /// The device is reset so that it stops using the queues, which are set
+    /// up again by [`resume`](Self::resume). Fails with
+    /// [`Error::NotReady`] while periods are queued, so stop the streams and
+    /// [`handle_periods`](Self::handle_periods) first.
+    pub fn suspend(&mut self) -> Result {
+        if self
+            .tx_periods
+            .iter()
+            .chain(&self.rx_periods)
+            .any(Option::is_some)
+        {
+            return Err(Error::NotReady);
+        }
+        self.header.reset();
+        Ok(())
+    }
+
+    /// Resume the device after [`suspend`](Self::suspend), negotiating the
+    /// same features, setting up the queues again and posting the event
+    /// buffers.
+    ///
+    /// The device forgets the parameters of the streams when it is reset, so
+    /// they must be set and prepared again.
+    pub fn resume(&mut self) -> Result {
+        self.header.begin_reinit(self.features.bits())?;
+        self.control_queue.reinit(self.header);
+        self.event_queue.reinit(self.header);
+        self.tx_queue.reinit(self.header);
+        self.rx_queue.reinit(self.header);
+        for event in self.event_buf.iter_mut() {
+            self.event_queue.add(&[], &[event.as_buf_mut()])?;
+        }
+        self.header.finish_init();
+        Ok(())
+    }
+
+    /// The number of available jacks.
+    pub fn jacks(&self) -> u32 {
+        self.jacks
+    }
+
+    /// The number of available PCM streams.
+    pub fn streams(&self) -> u32 {
+        self.streams
+    }
+
+    /// The number of available channel maps.
+    pub fn chmaps(&self) -> u32 {
+        self.chmaps
+    }
+
+    /// Pop a pending event sent by the device, if any.
+    ///
+    /// The event buffer is handed back to the device afterwards.
+    pub fn pop_event(&mut self) -> Result<Option<SoundEvent>> {
+        if !self.event_queue.can_pop() {
+            return Ok(None);
+        }
+        let (token, _) = self.event_queue.pop_used()?;
+        let event = &mut self.event_buf[token as usize];
+        let repr = SoundEvent::from(*event);
+        // requeue
+        self.event_queue
+            .add_notify(self.header, &[], &[event.as_buf_mut()])?;
+        Ok(Some(repr))
+    }
+
+    /// Query the information of a jack.
+    pub fn jack_info(&mut self, jack_id: u32) -> Result<JackInfo> {
+        if jack_id >= self.jacks {
+            return Err(Error::InvalidParam);
+        }
+        let req = QueryInfo {
+            header: Header::with_code(RequestCode::JackInfo),
+            start_id: jack_id,
+            count: 1,
+            size: size_of::<JackInfo>() as u32,
+        };
+        let mut info = JackInfo::default();
+        self.request(req.as_buf(), info.as_buf_mut())?;
+        Ok(info)
+    }
+
+    /// Override the association and sequence of a jack.
+    ///
+    /// Only jacks with [`JackFeatures::REMAP`] support remapping.
+    pub fn jack_remap(&mut self, jack_id: u32, association: u32, sequence: u32) -> Result {
+        if jack_id >= self.jacks {
+            return Err(Error::InvalidParam);
+        }
+        let req = JackRemap {
+            header: Header::with_code(RequestCode::JackRemap),
+            jack_id,
+            association,
+            sequence,
+        };
+        self.request(req.as_buf(), &mut [])
+    }
+
+    /// Query the information of a channel map.
+    pub fn chmap_info(&mut self, chmap_id: u32) -> Result<ChmapInfo> {
+        if chmap_id >= self.chmaps {
+            return Err(Error::InvalidParam);
+        }
+        let req = QueryInfo {
+            header: Header::with_code(RequestCode::ChmapInfo),
+            start_id: chmap_id,
+            count: 1,
+            size: size_of::<ChmapInfo>() as u32,
+        };
+        let mut info = ChmapInfo::default();
+        self.request(req.as_buf(), info.as_buf_mut())?;
+        Ok(info)
+    }
+
+    /// Query the information of a PCM stream.
+    pub fn pcm_info(&mut self, stream_id: u32) -> Result<PcmInfo> {
+        if stream_id >= self.streams {
+            return Err(Error::InvalidParam);
+        }
+        let req = QueryInfo {
+            header: Header::with_code(RequestCode::PcmInfo),
+            start_id: stream_id,
+            count: 1,
+            size: size_of::<PcmInfo>() as u32,
+        };
+        let mut info = PcmInfo::default();
+        self.request(req.as_buf(), info.as_buf_mut())?;
+        Ok(info)
+    }
+
+    /// Set the parameters of a PCM stream.
+    ///
+    /// `buffer_bytes` must be a multiple of `period_bytes`.
+    pub fn pcm_set_params(&mut self, stream_id: u32, params: PcmParameters) -> Result {
+        if params.period_bytes == 0 || !params.buffer_bytes.is_multiple_of(params.period_bytes) {
+            return Err(Error::InvalidParam);
+        }
+        let req = PcmSetParams {
+            header: PcmHeader::new(RequestCode::PcmSetParams, stream_id),
+            buffer_bytes: params.buffer_bytes,
+            period_bytes: params.period_bytes,
+            features: params.features.bits(),
+            channels: params.channels,
+            format: params.format as u8,
+            rate: params.rate as u8,
+            padding: 0,
+        };
+        self.request(req.as_buf(), &mut [])
+    }
+
+    /// Prepare a PCM stream, allocating its resources on the device.
+    pub fn pcm_prepare(&mut self, stream_id: u32) -> Result {
+        self.pcm_command(RequestCode::PcmPrepare, stream_id)
+    }
+
+    /// Release the resources of a PCM stream.
+    pub fn pcm_release(&mut self, stream_id: u32) -> Result {
+        self.pcm_command(RequestCode::PcmRelease, stream_id)
+    }
+
+    /// Start a PCM stream.
+    pub fn pcm_start(&mut self, stream_id: u32) -> Result {
+        self.pcm_command(RequestCode::PcmStart, stream_id)
+    }
+
+    /// Stop a PCM stream.
+    pub fn pcm_stop(&mut self, stream_id: u32) -> Result {
+        self.pcm_command(RequestCode::PcmStop, stream_id)
+    }
+
+    /// Send a period of audio frames to an output stream.
+    ///
+    /// Blocks until the device has consumed the period, and returns the
+    /// latency reported by the device in bytes.
+    pub fn pcm_xfer(&mut self, stream_id: u32, frames: &[u8]) -> Result<u32> {
+        if stream_id >= self.streams {
+            return Err(Error::InvalidParam);
+        }
+        if self.tx_periods.iter().any(Option::is_some) {
+            return Err(Error::NotReady);
+        }
+        let xfer = PcmXfer { stream_id };
+        let mut status = PcmStatus::default();
+        self.tx_queue.add_notify_wait_pop(
+            self.header,
+            &[xfer.as_buf(), frames],
+            &[status.as_buf_mut()],
+        )?;
+        status.status()?;
+        Ok(status.latency_bytes)
+    }
+
+    /// Receive a period of audio frames from an input stream.
+    ///
+    /// Blocks until the device has filled the buffer, and returns the number
+    /// of bytes captured.
+    pub fn pcm_capture(&mut self, stream_id: u32, frames: &mut [u8]) -> Result<usize> {
+        if stream_id >= self.streams {
+            return Err(Error::InvalidParam);
+        }
+        if self.rx_periods.iter().any(Option::is_some) {
+            return Err(Error::NotReady);
+        }
+        let xfer = PcmXfer { stream_id };
+        let mut status = PcmStatus::default();
+        let len = self.rx_queue.add_notify_wait_pop(
+            self.header,
+            &[xfer.as_buf()],
+            &[frames, status.as_buf_mut()],
+        )?;
+        status.status()?;
+        Ok((len as usize).saturating_sub(size_of::<PcmStatus>()))
+    }
+
+    /// Queue a period of audio frames to an output stream without blocking.
+    ///
+    /// The frames are copied into a buffer owned by the driver, so the
+    /// caller's buffer can be refilled right away. When the device has
+    /// consumed the period, it is reported by [`Self::handle_periods`].
+    /// Returns the token of the period.
+    ///
+    /// Blocking transfers with [`Self::pcm_xfer`] fail while periods are
+    /// queued.
+    pub fn pcm_queue_xfer(&mut self, stream_id: u32, frames: &[u8]) -> Result<u16> {
+        if stream_id >= self.streams || frames.len() > PERIOD_BUF_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        let slot = self.tx_periods.iter().position(Option::is_none);
+        let slot = slot.ok_or(Error::NotReady)?;
+        let (xfer, status, data) = self.period_bufs(Direction::Output, slot);
+        xfer.copy_from_slice(PcmXfer { stream_id }.as_buf());
+        status.fill(0);
+        data[..frames.len()].copy_from_slice(frames);
+        let token = self
+            .tx_queue
+            .add(&[xfer, &data[..frames.len()]], &[status])?;
+        self.tx_periods[slot] = Some(Period { token, stream_id });
+        if self.tx_queue.should_notify() {
+            self.header.notify(QUEUE_TX as u32);
+        }
+        Ok(token)
+    }
+
+    /// Queue a buffer of `len` bytes for a period of audio frames from an
+    /// input stream without blocking.
+    ///
+    /// When the device has filled the buffer, the captured frames are passed
+    /// to the callback of [`Self::handle_periods`]. Returns the token of the
+    /// period.
+    ///
+    /// Blocking transfers with [`Self::pcm_capture`] fail while periods are
+    /// queued.
+    pub fn pcm_queue_capture(&mut self, stream_id: u32, len: usize) -> Result<u16> {
+        if stream_id >= self.streams || len > PERIOD_BUF_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        let slot = self.rx_periods.iter().position(Option::is_none);
+        let slot = slot.ok_or(Error::NotReady)?;
+        let (xfer, status, data) = self.period_bufs(Direction::Input, slot);
+        xfer.copy_from_slice(PcmXfer { stream_id }.as_buf());
+        status.fill(0);
+        let token = self.rx_queue.add(&[xfer], &[&mut data[..len], status])?;
+        self.rx_periods[slot] = Some(Period { token, stream_id });
+        if self.rx_queue.should_notify() {
+            self.header.notify(QUEUE_RX as u32);
+        }
+        Ok(token)
+    }
+
+    /// Handle the periods which have elapsed since the last call, calling
+    /// `f` for each of them in the order the device completed them.
+    ///
+    /// For input streams, `f` is also passed the captured frames. This is
+    /// meant to be called after an interrupt, so that the caller can queue
+    /// the next period just in time. Returns the number of elapsed periods.
+    pub fn handle_periods(&mut self, mut f: impl FnMut(PeriodElapsed, &[u8])) -> Result<usize> {
+        let mut count = 0;
+        for direction in [Direction::Output, Direction::Input] {
+            loop {
+                let (queue, periods) = match direction {
+                    Direction::Output => (&mut self.tx_queue, &mut self.tx_periods),
+                    Direction::Input => (&mut self.rx_queue, &mut self.rx_periods),
+                };
+                if !queue.can_pop() {
+                    break;
+                }
+                let (token, len) = queue.pop_used()?;
+                let (slot, period) = periods
+                    .iter_mut()
+                    .enumerate()
+                    .find_map(|(slot, period)| match period {
+                        Some(p) if p.token == token => Some((slot, period.take()?)),
+                        _ => None,
+                    })
+                    .ok_or(Error::IoError)?;
+                let (_, status, data) = self.period_bufs(direction, slot);
+                let status = unsafe { &*(status.as_ptr() as *const PcmStatus) };
+                let frames = match direction {
+                    Direction::Output => &[][..],
+                    Direction::Input => {
+                        let len = (len as usize).saturating_sub(size_of::<PcmStatus>());
+                        &data[..len.min(PERIOD_BUF_SIZE)]
+                    }
+                };
+                let elapsed = PeriodElapsed {
+                    stream_id: period.stream_id,
+                    direction,
+                    token,
+                    latency_bytes: status.latency_bytes,
+                    ok: status.status().is_ok(),
+                };
+                f(elapsed, frames);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// The transfer header, status and data buffers of a queued period.
+    fn period_bufs(
+        &self,
+        direction: Direction,
+        slot: usize,
+    ) -> (&'static mut [u8], &'static mut [u8], &'static mut [u8]) {
+        let index = direction as usize * MAX_PERIODS + slot;
+        let buf = unsafe { self.period_dma.as_buf() };
+        let (headers, data) = buf.split_at_mut(PAGE_SIZE);
+        let header = &mut headers[index * PERIOD_HEADER_SIZE..(index + 1) * PERIOD_HEADER_SIZE];
+        let (xfer, status) = header.split_at_mut(size_of::<PcmXfer>());
+        let data = &mut data[index * PERIOD_BUF_SIZE..(index + 1) * PERIOD_BUF_SIZE];
+        (xfer, &mut status[..size_of::<PcmStatus>()], data)
+    }
+
+    /// Send a PCM request which carries nothing but the stream ID.
+    fn pcm_command(&mut self, code: RequestCode, stream_id: u32) -> Result {
+        if stream_id >= self.streams {
+            return Err(Error::InvalidParam);
+        }
+        let req = PcmHeader::new(code, stream_id);
+        self.request(req.as_buf(), &mut [])
+    }
+
+    /// Send a request through the control queue and block for a response.
+    ///
+    /// The response is a status header optionally followed by `rsp_payload`.
+    fn request(&mut self, req: &[u8], rsp_payload: &mut [u8]) -> Result {
+        let mut rsp = Header::with_code(RequestCode::Unset);
+        let token = if rsp_payload.is_empty() {
+            self.control_queue.add(&[req], &[rsp.as_buf_mut()])?
+        } else {
+            self.control_queue
+                .add(&[req], &[rsp.as_buf_mut(), rsp_payload])?
+        };
+        if self.control_queue.should_notify() {
+            self.header.notify(QUEUE_CONTROL as u32);
+        }
+        self.control_queue.wait_for(self.header, token)?;
+        rsp.status()
+    }
+}
+
+impl Drop for VirtIOSound<'_> {
+    fn drop(&mut self) {
+        // stop the device from using the queues, so that they are freed
+        self.header.reset();
+        self.control_queue.unset(self.header);
+        self.event_queue.unset(self.header);
+        self.tx_queue.unset(self.header);
+        self.rx_queue.unset(self.header);
+    }
+}
+
+impl Driver for VirtIOSound<'_> {
+    fn device_type(&self) -> DeviceType {
+        self.header.device_type()
+    }
+
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
+        self.header.ack_interrupt_status()
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.features.bits()
+    }
+
+    fn enable_notifications(&mut self) {
+        self.control_queue.set_used_notifications(true);
+        self.event_queue.set_used_notifications(true);
+        self.tx_queue.set_used_notifications(true);
+        self.rx_queue.set_used_notifications(true);
+    }
+
+    fn disable_notifications(&mut self) {
+        self.control_queue.set_used_notifications(false);
+        self.event_queue.set_used_notifications(false);
+        self.tx_queue.set_used_notifications(false);
+        self.rx_queue.set_used_notifications(false);
+    }
+
+    fn metrics(&self, f: &mut dyn FnMut(Metric)) {
+        self.control_queue.metrics().report("controlq", f);
+        self.event_queue.metrics().report("eventq", f);
+        self.tx_queue.metrics().report("txq", f);
+        self.rx_queue.metrics().report("rxq", f);
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.header.needs_reset()
+    }
+
+    fn recover(&mut self, lost: &mut dyn FnMut(LostRequest)) -> Result {
+        self.control_queue.report_lost("controlq", lost);
+        self.tx_queue.report_lost("txq", lost);
+        self.rx_queue.report_lost("rxq", lost);
+        self.tx_periods = [None; MAX_PERIODS];
+        self.rx_periods = [None; MAX_PERIODS];
+        self.header.reset();
+        self.resume()
+    }
+
+    #[cfg(feature = "time-hal")]
+    fn set_timeout(&mut self, ticks: Option<u64>) {
+        self.control_queue.set_timeout(ticks);
+        self.event_queue.set_timeout(ticks);
+        self.tx_queue.set_timeout(ticks);
+        self.rx_queue.set_timeout(ticks);
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct Config {
+    /// The total number of all available jacks.
+    jacks: ReadOnly<Le32>,
+    /// The total number of all available PCM streams.
+    streams: ReadOnly<Le32>,
+    /// The total number of all available channel maps.
+    chmaps: ReadOnly<Le32>,
+}
+
+bitflags! {
+    struct Features: u64 {
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum RequestCode {
+    Unset = 0,
+
+    // jack control request types
+    JackInfo = 1,
+    JackRemap = 2,
+
+    // PCM control request types
+    PcmInfo = 0x0100,
+    PcmSetParams = 0x0101,
+    PcmPrepare = 0x0102,
+    PcmRelease = 0x0103,
+    PcmStart = 0x0104,
+    PcmStop = 0x0105,
+
+    // channel map control request types
+    ChmapInfo = 0x0200,
+
+    // jack event types
+    JackConnected = 0x1000,
+    JackDisconnected = 0x1001,
+
+    // PCM event types
+    PcmPeriodElapsed = 0x1100,
+    PcmXrun = 0x1101,
+
+    // common status codes
+    Ok = 0x8000,
+    BadMsg = 0x8001,
+    NotSupp = 0x8002,
+    IoErr = 0x8003,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct Header {
+    /// A [`RequestCode`], kept as a `u32` since the device may write any value.
+    code: u32,
+}
+
+impl Header {
+    fn with_code(code: RequestCode) -> Header {
+        Header { code: code as u32 }
+    }
+
+    /// Return error if the status code is not `Ok`.
+    fn status(&self) -> Result {
+        status(self.code)
+    }
+}
+
+/// Return error if the status code is not `Ok`.
+fn status(code: u32) -> Result {
+    if code == RequestCode::Ok as u32 {
+        Ok(())
+    } else {
+        Err(Error::SoundStatus(code))
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct QueryInfo {
+    header: Header,
+    /// The starting identifier for the item.
+    start_id: u32,
+    /// The number of items for which information is requested.
+    count: u32,
+    /// The size of the structure containing information for one item.
+    size: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct PcmHeader {
+    header: Header,
+    stream_id: u32,
+}
+
+impl PcmHeader {
+    fn new(code: RequestCode, stream_id: u32) -> PcmHeader {
+        PcmHeader {
+            header: Header::with_code(code),
+            stream_id,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct PcmSetParams {
+    header: PcmHeader,
+    buffer_bytes: u32,
+    period_bytes: u32,
+    features: u32,
+    channels: u8,
+    format: u8,
+    rate: u8,
+    padding: u8,
+}
+
+/// The header of a PCM I/O message.
+#[repr(C)]
+#[derive(Debug)]
+struct PcmXfer {
+    stream_id: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct JackRemap {
+    header: Header,
+    jack_id: u32,
+    association: u32,
+    sequence: u32,
+}
+
+/// An event written by the device into the event queue.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Event {
+    code: u32,
+    data: u32,
+}
+
+/// A notification sent by the device.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SoundEvent {
+    /// A jack has been connected.
+    JackConnected(u32),
+    /// A jack has been disconnected.
+    JackDisconnected(u32),
+    /// A hardware buffer period has elapsed on a stream.
+    PcmPeriodElapsed(u32),
+    /// An underflow or overflow occurred on a stream.
+    PcmXrun(u32),
+    /// An event type unknown to the driver, with its code and data.
+    Unknown(u32, u32),
+}
+
+impl From<Event> for SoundEvent {
+    fn from(e: Event) -> Self {
+        const JACK_CONNECTED: u32 = RequestCode::JackConnected as u32;
+        const JACK_DISCONNECTED: u32 = RequestCode::JackDisconnected as u32;
+        const PCM_PERIOD_ELAPSED: u32 = RequestCode::PcmPeriodElapsed as u32;
+        const PCM_XRUN: u32 = RequestCode::PcmXrun as u32;
+        match e.code {
+            JACK_CONNECTED => SoundEvent::JackConnected(e.data),
+            JACK_DISCONNECTED => SoundEvent::JackDisconnected(e.data),
+            PCM_PERIOD_ELAPSED => SoundEvent::PcmPeriodElapsed(e.data),
+            PCM_XRUN => SoundEvent::PcmXrun(e.data),
+            _ => SoundEvent::Unknown(e.code, e.data),
+        }
+    }
+}
+
+/// The status of a PCM I/O message, written by the device.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct PcmStatus {
+    /// A [`RequestCode`], kept as a `u32` since the device may write any value.
+    status: u32,
+    latency_bytes: u32,
+}
+
+impl PcmStatus {
+    /// Return error if the status code is not `Ok`.
+    fn status(&self) -> Result {
+        status(self.status)
+    }
+}
+
+/// Information about a PCM stream.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct PcmInfo {
+    /// Function group node ID for High Definition Audio.
+    pub hda_fn_nid: u32,
+    /// Supported feature bits.
+    pub features: PcmFeatures,
+    /// Supported sample formats, one bit per [`PcmFormat`].
+    pub formats: u64,
+    /// Supported frame rates, one bit per [`PcmRate`].
+    pub rates: u64,
+    direction: u8,
+    /// The minimum number of supported channels.
+    pub channels_min: u8,
+    /// The maximum number of supported channels.
+    pub channels_max: u8,
+    padding: [u8; 5],
+}
+
+impl PcmInfo {
+    /// The direction of data flow.
+    pub fn direction(&self) -> Direction {
+        Direction::from(self.direction)
+    }
+
+    /// Whether the stream supports the sample format.
+    pub fn supports_format(&self, format: PcmFormat) -> bool {
+        self.formats & (1 << format as u8) != 0
+    }
+
+    /// Whether the stream supports the frame rate.
+    pub fn supports_rate(&self, rate: PcmRate) -> bool {
+        self.rates & (1 << rate as u8) != 0
+    }
+}
+
+/// Information about a jack.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct JackInfo {
+    /// Function group node ID for High Definition Audio.
+    pub hda_fn_nid: u32,
+    /// Supported feature bits.
+    pub features: JackFeatures,
+    /// The pin default configuration from the HDA specification.
+    pub hda_reg_defconf: u32,
+    /// The pin capabilities from the HDA specification.
+    pub hda_reg_caps: u32,
+    connected: u8,
+    padding: [u8; 7],
+}
+
+impl JackInfo {
+    /// Whether the jack is currently connected.
+    pub fn connected(&self) -> bool {
+        self.connected != 0
+    }
+}
+
+bitflags! {
+    /// Supported jack features.
+    #[derive(Default)]
+    pub struct JackFeatures: u32 {
+        /// Supports jack remapping.
+        const REMAP = 1 << 0;
+    }
+}
+
+/// Information about a channel map.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct ChmapInfo {
+    /// Function group node ID for High Definition Audio.
+    pub hda_fn_nid: u32,
+    direction: u8,
+    channels: u8,
+    positions: [u8; MAX_CHANNELS],
+}
+
+impl ChmapInfo {
+    /// The direction of the streams using this channel map.
+    pub fn direction(&self) -> Direction {
+        Direction::from(self.direction)
+    }
+
+    /// The channel positions, one `VIRTIO_SND_CHMAP_*` value per channel.
+    pub fn positions(&self) -> &[u8] {
+        let channels = (self.channels as usize).min(MAX_CHANNELS);
+        &self.positions[..channels]
+    }
+}
+
+/// Parameters to set on a PCM stream.
+#[derive(Debug, Copy, Clone)]
+pub struct PcmParameters {
+    /// The size of the hardware buffer in bytes.
+    pub buffer_bytes: u32,
+    /// The size of one period in bytes.
+    pub period_bytes: u32,
+    /// Selected feature bits.
+    pub features: PcmFeatures,
+    /// The number of channels.
+    pub channels: u8,
+    /// The sample format.
+    pub format: PcmFormat,
+    /// The frame rate.
+    pub rate: PcmRate,
+}
+
+bitflags! {
+    /// Supported PCM stream features.
+    #[derive(Default)]
+    pub struct PcmFeatures: u32 {
+        /// Supports sharing a host memory with a guest.
+        const SHMEM_HOST = 1 << 0;
+        /// Supports sharing a guest memory with a host.
+        const SHMEM_GUEST = 1 << 1;
+        /// Supports polling mode for message-based transport.
+        const MSG_POLLING = 1 << 2;
+        /// Supports elapsed period notifications for shared memory transport.
+        const EVT_SHMEM_PERIODS = 1 << 3;
+        /// Supports underrun/overrun notifications.
+        const EVT_XRUNS = 1 << 4;
+    }
+}
+
+/// A period which has been consumed or filled by the device.
+#[derive(Debug, Copy, Clone)]
+pub struct PeriodElapsed {
+    /// The stream the period belongs to.
+    pub stream_id: u32,
+    /// Whether the period was played back or captured.
+    pub direction: Direction,
+    /// The token returned when the period was queued.
+    pub token: u16,
+    /// The latency reported by the device in bytes.
+    pub latency_bytes: u32,
+    /// Whether the device processed the period successfully.
+    pub ok: bool,
+}
+
+/// A period queued to the TX or RX queue.
+#[derive(Debug, Copy, Clone)]
+struct Period {
+    token: u16,
+    stream_id: u32,
+}
+
+/// The direction of data flow of a stream.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Direction {
+    /// Playback.
+    Output = 0,
+    /// Capture.
+    Input = 1,
+}
+
+impl From<u8> for Direction {
+    fn from(direction: u8) -> Self {
+        if direction == Direction::Input as u8 {
+            Direction::Input
+        } else {
+            Direction::Output
+        }
+    }
+}
+
+/// PCM sample formats.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[allow(missing_docs)]
+pub enum PcmFormat {
+    ImaAdpcm = 0,
+    MuLaw = 1,
+    ALaw = 2,
+    S8 = 3,
+    U8 = 4,
+    S16 = 5,
+    U16 = 6,
+    S18_3 = 7,
+    U18_3 = 8,
+    S20_3 = 9,
+    U20_3 = 10,
+    S24_3 = 11,
+    U24_3 = 12,
+    S20 = 13,
+    U20 = 14,
+    S24 = 15,
+    U24 = 16,
+    S32 = 17,
+    U32 = 18,
+    Float = 19,
+    Float64 = 20,
+    DsdU8 = 21,
+    DsdU16 = 22,
+    DsdU32 = 23,
+    Iec958Subframe = 24,
+}
+
+/// PCM frame rates.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[allow(missing_docs)]
+pub enum PcmRate {
+    Rate5512 = 0,
+    Rate8000 = 1,
+    Rate11025 = 2,
+    Rate16000 = 3,
+    Rate22050 = 4,
+    Rate32000 = 5,
+    Rate44100 = 6,
+    Rate48000 = 7,
+    Rate64000 = 8,
+    Rate88200 = 9,
+    Rate96000 = 10,
+    Rate176400 = 11,
+    Rate192000 = 12,
+    Rate384000 = 13,
+}
+
+unsafe impl AsBuf for Header {}
+unsafe impl AsBuf for QueryInfo {}
+unsafe impl AsBuf for PcmHeader {}
+unsafe impl AsBuf for PcmSetParams {}
+unsafe impl AsBuf for PcmXfer {}
+unsafe impl AsBuf for PcmStatus {}
+unsafe impl AsBuf for PcmInfo {}
+unsafe impl AsBuf for JackRemap {}
+unsafe impl AsBuf for JackInfo {}
+unsafe impl AsBuf for ChmapInfo {}
+unsafe impl AsBuf for Event {}
+
+const QUEUE_CONTROL: usize = 0;
+const QUEUE_EVENT: usize = 1;
+const QUEUE_TX: usize = 2;
+const QUEUE_RX: usize = 3;
+
+/// The maximum number of channels in a channel map.
+const MAX_CHANNELS: usize = 18;
+
+// a parameter that can change
+const QUEUE_SIZE: u16 = 8;
+const PCM_QUEUE_SIZE: u16 = 16;
+
+/// The maximum number of periods queued in each direction.
+const MAX_PERIODS: usize = 4;
+/// The size of the buffer of a queued period.
+const PERIOD_BUF_SIZE: usize = PAGE_SIZE;
+/// The space for the transfer header and status of a queued period.
+const PERIOD_HEADER_SIZE: usize = 16;
@@ -0,0 +1,1109 @@
+//! Fake devices for testing code which uses the drivers without a hypervisor.
+//!
+//! Enabling the `testing` feature links this module's implementation of the
+//! HAL functions, which allocates DMA memory from the host heap, so it must
+//! not be combined with another HAL.
+//!
+//! [`fake_device`] creates the [`FakeTransport`] of a fake device, which
+//! behaves as a legacy MMIO device, and [`fake_modern_device`] one which
+//! behaves as a virtio 1.x device. When a driver notifies the device, the
+//! chains made available in its queues are passed to a [`FakeBackend`], and
+//! the used rings are filled in before `notify` returns. [`FakeBlk`] and
+//! [`FakeNet`] are backends for a block device and a loopback network card,
+//! and a [`ScriptedDevice`] answers as scripted.
+//! The fake devices record the [`accesses`] of the driver, which
+//! [`conformance`] checks against the spec.
+//!
+//! With the `irq-hal` feature, the interrupt handlers registered through the
+//! HAL are called by `raise_irq`. With the `port-io-hal` feature, the I/O
+//! ports are plain memory, which `io_ports` exposes to fake legacy PCI
+//! devices. With the `ccw-hal` feature, the channel programs of a subchannel
+//! are run by the handler `set_ccw_device` installs for it.
+
+// a test double may panic when it is misused
+#![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+
+extern crate std;
+
+pub mod conformance;
+
+use super::*;
+use crate::queue::VirtQueueLayout;
+use core::convert::TryInto;
+#[cfg(feature = "time-hal")]
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::boxed::Box;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::vec;
+use std::vec::Vec;
+
+/// The device side of a fake device.
+pub trait FakeBackend: Send {
+    /// The type of the device.
+    fn device_type(&self) -> DeviceType;
+
+    /// The content of the config space.
+    fn config(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// The features offered by the device.
+    fn features(&self) -> u64 {
+        0
+    }
+
+    /// Handle a descriptor chain made available in `queue`.
+    ///
+    /// `inputs` are the buffers readable by the device and `outputs` the
+    /// buffers writable by it. Returns the number of bytes written, or `None`
+    /// to leave the chain available until the device is notified again.
+    fn process(&mut self, queue: u32, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Option<u32>;
+
+    /// The ID the device reports in the used ring for the chain with `head`.
+    ///
+    /// A misbehaving device can be simulated by returning another ID.
+    fn used_id(&mut self, _queue: u32, head: u16) -> u32 {
+        head as u32
+    }
+}
+
+/// The transport of a fake device, which passes the accesses of the driver
+/// to the device side state of the device.
+///
+/// It behaves as a legacy MMIO device, whose queues are addressed by their
+/// page frame numbers, or as a modern device, which takes the addresses of
+/// the areas of each queue separately.
+#[derive(Debug)]
+pub struct FakeTransport {
+    device_type: DeviceType,
+    legacy: bool,
+}
+
+/// Create the transport of a fake legacy device served by `backend`.
+pub fn fake_device(backend: impl FakeBackend + 'static) -> &'static mut FakeTransport {
+    create_fake_device(backend, true)
+}
+
+/// Create the transport of a fake modern device served by `backend`, which
+/// checks that the driver sets `FEATURES_OK`, and takes the areas of each
+/// queue wherever the driver allocated them.
+pub fn fake_modern_device(backend: impl FakeBackend + 'static) -> &'static mut FakeTransport {
+    create_fake_device(backend, false)
+}
+
+fn create_fake_device(
+    backend: impl FakeBackend + 'static,
+    legacy: bool,
+) -> &'static mut FakeTransport {
+    let mut config = backend.config();
+    config.resize(config.len().max(CONFIG_SPACE_SIZE), 0);
+    let transport = Box::leak(Box::new(FakeTransport {
+        device_type: backend.device_type(),
+        legacy,
+    }));
+    DEVICES.lock().unwrap().push(FakeState {
+        transport: transport as *const _ as usize,
+        features: backend.features(),
+        driver_features: 0,
+        status: 0,
+        interrupt: 0,
+        config,
+        queues: Vec::new(),
+        accesses: Vec::new(),
+        backend: Box::new(backend),
+    });
+    transport
+}
+
+/// Remove a fake device created by [`fake_device`], freeing its transport.
+///
+/// # Safety
+///
+/// `transport` must have been returned by [`fake_device`], and the driver
+/// using it must have been dropped.
+pub unsafe fn destroy_fake_device(transport: *mut FakeTransport) {
+    let addr = transport as usize;
+    DEVICES
+        .lock()
+        .unwrap()
+        .retain(|device| device.transport != addr);
+    drop(Box::from_raw(transport));
+}
+
+/// Make a fake device signal that it needs to be reset, as after an error,
+/// by setting `DEVICE_NEEDS_RESET` and raising a configuration change.
+pub fn set_needs_reset(transport: *const FakeTransport) {
+    with_device(transport, |device| {
+        device.status |= DeviceStatus::DEVICE_NEEDS_RESET.bits();
+        device.interrupt |= InterruptStatus::CONFIG_CHANGE.bits();
+    });
+}
+
+/// An access of the driver to a fake device, as recorded by the device.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Access {
+    /// The driver reset the device.
+    Reset,
+    /// The driver wrote the device status.
+    Status(u32),
+    /// The driver wrote the features it negotiated.
+    DriverFeatures(u64),
+    /// The driver set up a queue, or removed it if `pfn` is 0.
+    QueueSet {
+        /// The index of the queue.
+        queue: u32,
+        /// The number of descriptors.
+        size: u32,
+        /// The page frame number of the descriptor table of the queue.
+        pfn: u32,
+    },
+    /// The driver reset a queue alone, which it then sets up again.
+    QueueReset(u32),
+    /// The driver notified the device of new buffers in a queue.
+    Notify(u32),
+    /// The driver made a malformed descriptor chain available in a queue,
+    /// which the device skipped.
+    BadChain {
+        /// The index of the queue.
+        queue: u32,
+        /// The head of the chain.
+        head: u16,
+    },
+    /// The driver made more chains available in a queue than it has
+    /// descriptors.
+    AvailOverflow(u32),
+}
+
+/// The accesses of the driver to the fake device with `transport`, in the
+/// order they happened since the device was created.
+pub fn accesses(transport: *const FakeTransport) -> Vec<Access> {
+    let devices = DEVICES.lock().unwrap();
+    devices
+        .iter()
+        .find(|device| device.transport == transport as usize)
+        .map(|device| device.accesses.clone())
+        .unwrap_or_default()
+}
+
+/// Copy `data` across the writable buffers of a chain, returning the number
+/// of bytes copied.
+pub fn write_chain(outputs: &mut [&mut [u8]], data: &[u8]) -> usize {
+    let mut copied = 0;
+    for output in outputs.iter_mut() {
+        let len = output.len().min(data.len() - copied);
+        output[..len].copy_from_slice(&data[copied..copied + len]);
+        copied += len;
+    }
+    copied
+}
+
+/// Concatenate the readable buffers of a chain.
+pub fn read_chain(inputs: &[&[u8]]) -> Vec<u8> {
+    inputs.concat()
+}
+
+/// A fake block device backed by a disk image in memory.
+pub struct FakeBlk {
+    disk: Arc<Mutex<Vec<u8>>>,
+    features: u64,
+}
+
+impl FakeBlk {
+    /// Create a block device with a zeroed disk image of `sectors` sectors.
+    pub fn new(sectors: usize) -> Self {
+        Self::with_image(vec![0; sectors * SECTOR_SIZE])
+    }
+
+    /// Create a block device with the disk image `image`.
+    pub fn with_image(image: Vec<u8>) -> Self {
+        assert_eq!(image.len() % SECTOR_SIZE, 0);
+        FakeBlk {
+            disk: Arc::new(Mutex::new(image)),
+            features: RING_INDIRECT_DESC,
+        }
+    }
+
+    /// Offer `features` besides indirect descriptors, e.g. `1 << 34` for
+    /// packed queues.
+    pub fn with_features(mut self, features: u64) -> Self {
+        self.features |= features;
+        self
+    }
+
+    /// The disk image, shared with the device.
+    pub fn disk(&self) -> Arc<Mutex<Vec<u8>>> {
+        self.disk.clone()
+    }
+}
+
+impl FakeBackend for FakeBlk {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let sectors = (self.disk.lock().unwrap().len() / SECTOR_SIZE) as u64;
+        sectors.to_le_bytes().to_vec()
+    }
+
+    fn process(&mut self, _queue: u32, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Option<u32> {
+        let request = read_chain(inputs);
+        let (status, data) = outputs.split_last_mut()?;
+        let type_ = u32::from_le_bytes(request[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(request[8..16].try_into().unwrap()) as usize;
+        let payload = &request[16..];
+        let mut disk = self.disk.lock().unwrap();
+        let offset = sector * SECTOR_SIZE;
+        let (result, written) = match type_ {
+            BLK_T_IN => {
+                let len: usize = data.iter().map(|buf| buf.len()).sum();
+                match disk.get(offset..offset + len) {
+                    Some(sectors) => (BLK_S_OK, write_chain(data, sectors)),
+                    None => (BLK_S_IOERR, 0),
+                }
+            }
+            BLK_T_OUT => match disk.get_mut(offset..offset + payload.len()) {
+                Some(sectors) => {
+                    sectors.copy_from_slice(payload);
+                    (BLK_S_OK, 0)
+                }
+                None => (BLK_S_IOERR, 0),
+            },
+            _ => (BLK_S_UNSUPP, 0),
+        };
+        status[0] = result;
+        Some(written as u32 + 1)
+    }
+}
+
+/// A fake network card which loops sent packets back to the guest.
+pub struct FakeNet {
+    mac: [u8; 6],
+    rx: Arc<Mutex<VecDeque<Vec<u8>>>>,
+}
+
+impl FakeNet {
+    /// Create a network card with the MAC address `mac`.
+    pub fn new(mac: [u8; 6]) -> Self {
+        FakeNet {
+            mac,
+            rx: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// The packets waiting to be received by the guest, shared with the
+    /// device. Packets pushed to it are received as if they came from the
+    /// network.
+    pub fn rx_packets(&self) -> Arc<Mutex<VecDeque<Vec<u8>>>> {
+        self.rx.clone()
+    }
+}
+
+impl FakeBackend for FakeNet {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Network
+    }
+
+    fn features(&self) -> u64 {
+        NET_F_MAC | NET_F_STATUS | RING_INDIRECT_DESC
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let mut config = self.mac.to_vec();
+        // status: link up
+        config.extend_from_slice(&1u16.to_le_bytes());
+        config
+    }
+
+    fn process(&mut self, queue: u32, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Option<u32> {
+        match queue {
+            NET_QUEUE_RECEIVE => {
+                let packet = self.rx.lock().unwrap().pop_front()?;
+                let mut buf = vec![0; NET_HDR_SIZE];
+                buf.extend_from_slice(&packet);
+                Some(write_chain(outputs, &buf) as u32)
+            }
+            NET_QUEUE_TRANSMIT => {
+                let packet = read_chain(inputs);
+                let packet = packet.get(NET_HDR_SIZE..).unwrap_or_default();
+                self.rx.lock().unwrap().push_back(packet.to_vec());
+                Some(0)
+            }
+            _ => Some(0),
+        }
+    }
+}
+
+/// How a [`ScriptedDevice`] answers a descriptor chain.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Reply {
+    /// Write `data` across the writable buffers and report the bytes
+    /// written.
+    Data(Vec<u8>),
+    /// Report `len` bytes written without writing them, even if the buffers
+    /// are smaller.
+    Len(u32),
+    /// Report the chain used with the descriptor ID `id` instead of its head.
+    BadId(u32),
+}
+
+/// A fake device which answers the chains made available in each queue as
+/// scripted, e.g. to check how a driver handles a misbehaving device.
+///
+/// Chains without a scripted reply stay available.
+pub struct ScriptedDevice {
+    device_type: DeviceType,
+    features: u64,
+    config: Vec<u8>,
+    replies: VecDeque<(u32, Reply)>,
+    bad_id: Option<u32>,
+}
+
+impl ScriptedDevice {
+    /// Create a device of `device_type` offering no features, with an empty
+    /// config space.
+    pub fn new(device_type: DeviceType) -> Self {
+        ScriptedDevice {
+            device_type,
+            features: 0,
+            config: Vec::new(),
+            replies: VecDeque::new(),
+            bad_id: None,
+        }
+    }
+
+    /// Offer `features`.
+    pub fn with_features(mut self, features: u64) -> Self {
+        self.features = features;
+        self
+    }
+
+    /// Fill the config space with `config`.
+    pub fn with_config(mut self, config: Vec<u8>) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Answer the next chain made available in `queue` with `reply`, after
+    /// the replies scripted before.
+    pub fn reply(mut self, queue: u32, reply: Reply) -> Self {
+        self.replies.push_back((queue, reply));
+        self
+    }
+}
+
+impl FakeBackend for ScriptedDevice {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn config(&self) -> Vec<u8> {
+        self.config.clone()
+    }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn process(&mut self, queue: u32, _inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Option<u32> {
+        let index = self.replies.iter().position(|&(q, _)| q == queue)?;
+        let (_, reply) = self.replies.remove(index)?;
+        match reply {
+            Reply::Data(data) => Some(write_chain(outputs, &data) as u32),
+            Reply::Len(len) => Some(len),
+            Reply::BadId(id) => {
+                self.bad_id = Some(id);
+                Some(0)
+            }
+        }
+    }
+
+    fn used_id(&mut self, _queue: u32, head: u16) -> u32 {
+        self.bad_id.take().unwrap_or(head as u32)
+    }
+}
+
+impl Transport for FakeTransport {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        with_device(self, |device| device.features)
+    }
+
+    /// Record the features of the driver, of which legacy devices only take
+    /// the first 32.
+    fn write_driver_features(&mut self, driver_features: u64) {
+        let legacy = self.legacy;
+        with_device(self, |device| {
+            device.driver_features = if legacy {
+                driver_features & u32::MAX as u64
+            } else {
+                driver_features
+            };
+            device
+                .accesses
+                .push(Access::DriverFeatures(driver_features))
+        });
+    }
+
+    fn max_queue_size(&mut self, _queue: u32) -> u32 {
+        QUEUE_NUM_MAX
+    }
+
+    /// Let the device process the chains made available by the driver.
+    fn notify(&self, queue: u32) {
+        with_device(self, |device| {
+            device.accesses.push(Access::Notify(queue));
+            let mut used = false;
+            loop {
+                let mut progress = false;
+                for queue in device.queues.iter_mut() {
+                    while let Some(()) =
+                        queue.process_one(device.backend.as_mut(), &mut device.accesses)
+                    {
+                        progress = true;
+                    }
+                }
+                if !progress {
+                    break;
+                }
+                used = true;
+            }
+            if used {
+                device.interrupt |= InterruptStatus::USED_BUFFER.bits();
+            }
+        });
+    }
+
+    fn status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_truncate(with_device(self, |device| device.status))
+    }
+
+    /// Write the status, forgetting the features and queues if the driver
+    /// resets the device.
+    fn set_status(&self, status: DeviceStatus) {
+        with_device(self, |device| {
+            device.status = status.bits();
+            if status.is_empty() {
+                device.accesses.push(Access::Reset);
+                device.driver_features = 0;
+                device.queues.clear();
+            } else {
+                device.accesses.push(Access::Status(status.bits()));
+            }
+        });
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.legacy
+    }
+
+    fn set_guest_page_size(&mut self, guest_page_size: u32) {
+        assert_eq!(guest_page_size as usize, PAGE_SIZE);
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u32,
+        size: u32,
+        descriptors: usize,
+        driver_area: usize,
+        device_area: usize,
+    ) {
+        if self.legacy {
+            // legacy devices find the rings from the descriptor table
+            let layout = VirtQueueLayout::new(size as u16).unwrap();
+            assert_eq!(driver_area, descriptors + layout.avail_offset);
+            assert_eq!(device_area, descriptors + layout.used_offset);
+        }
+        let areas = [descriptors, driver_area, device_area];
+        with_device(self, |device| device.set_queue(queue, size, areas));
+    }
+
+    fn queue_unset(&mut self, queue: u32) {
+        with_device(self, |device| device.set_queue(queue, 0, [0; 3]));
+    }
+
+    /// Reset `queue` if the driver negotiated `VIRTIO_F_RING_RESET`, which
+    /// only modern devices offer.
+    fn queue_reset(&mut self, queue: u32) -> Result {
+        let legacy = self.legacy;
+        with_device(self, |device| {
+            if legacy || device.driver_features & RING_RESET == 0 {
+                return Err(Error::InvalidParam);
+            }
+            device.accesses.push(Access::QueueReset(queue));
+            device.queues.retain(|q| q.idx != queue);
+            Ok(())
+        })
+    }
+
+    fn queue_descriptors(&mut self, queue: u32) -> usize {
+        with_device(self, |device| {
+            let queue = device.queues.iter().find(|q| q.idx == queue);
+            queue.map_or(0, |q| q.areas[0])
+        })
+    }
+
+    fn ack_interrupt_status(&self) -> InterruptStatus {
+        let interrupt = with_device(self, |device| core::mem::take(&mut device.interrupt));
+        InterruptStatus::from_bits_truncate(interrupt)
+    }
+
+    /// The config space is not mapped into memory, so that the driver only
+    /// accesses it through the transport.
+    fn config_space(&self) -> *mut u64 {
+        core::ptr::null_mut()
+    }
+
+    fn config_space_size(&self) -> usize {
+        with_device(self, |device| device.config.len())
+    }
+
+    unsafe fn config_read(&self, offset: usize, width: usize) -> u32 {
+        with_device(self, |device| {
+            let mut bytes = [0; 4];
+            bytes[..width].copy_from_slice(&device.config[offset..offset + width]);
+            match width {
+                1 => bytes[0] as u32,
+                2 => u16::from_ne_bytes([bytes[0], bytes[1]]) as u32,
+                _ => u32::from_ne_bytes(bytes),
+            }
+        })
+    }
+
+    unsafe fn config_write(&self, offset: usize, width: usize, value: u32) {
+        with_device(self, |device| {
+            let bytes = match width {
+                1 => vec![value as u8],
+                2 => (value as u16).to_ne_bytes().to_vec(),
+                _ => value.to_ne_bytes().to_vec(),
+            };
+            device.config[offset..offset + width].copy_from_slice(&bytes);
+        });
+    }
+}
+
+/// Run `f` on the device side state of the fake device with `transport`.
+fn with_device<T>(transport: *const FakeTransport, f: impl FnOnce(&mut FakeState) -> T) -> T {
+    let mut devices = DEVICES.lock().unwrap();
+    let device = devices
+        .iter_mut()
+        .find(|device| device.transport == transport as usize)
+        .expect("the fake device was destroyed");
+    f(device)
+}
+
+/// The device side state of a fake device.
+struct FakeState {
+    /// The address of the transport of the device.
+    transport: usize,
+    features: u64,
+    driver_features: u64,
+    status: u32,
+    /// The causes of the interrupt which the driver has not acknowledged.
+    interrupt: u32,
+    config: Vec<u8>,
+    queues: Vec<FakeQueue>,
+    accesses: Vec<Access>,
+    backend: Box<dyn FakeBackend>,
+}
+
+impl FakeState {
+    /// Set up a queue with the physical addresses of its areas, or remove it
+    /// if they are 0.
+    fn set_queue(&mut self, queue: u32, size: u32, areas: [usize; 3]) {
+        let pfn = (areas[0] / PAGE_SIZE) as u32;
+        self.accesses.push(Access::QueueSet { queue, size, pfn });
+        self.queues.retain(|q| q.idx != queue);
+        if pfn != 0 {
+            let packed = self.driver_features & RING_PACKED != 0;
+            self.queues.push(FakeQueue {
+                idx: queue,
+                size: size as u16,
+                areas,
+                packed,
+                last_avail_idx: if packed { PACKED_WRAP } else { 0 },
+            });
+        }
+    }
+}
+
+/// The device side state of a queue.
+struct FakeQueue {
+    idx: u32,
+    size: u16,
+    /// The physical addresses of the descriptor table, the available ring
+    /// and the used ring, or of the descriptor ring and the event
+    /// suppression structures of a packed queue.
+    areas: [usize; 3],
+    /// Whether the driver negotiated packed queues.
+    packed: bool,
+    /// The index of the next chain to process, or in a packed queue the
+    /// position of its head with the wrap counter in `PACKED_WRAP`.
+    last_avail_idx: u16,
+}
+
+impl FakeQueue {
+    /// Process the next available chain, if any, and the backend consumes
+    /// it. Malformed chains are skipped and recorded in `accesses`.
+    fn process_one(
+        &mut self,
+        backend: &mut dyn FakeBackend,
+        accesses: &mut Vec<Access>,
+    ) -> Option<()> {
+        if self.packed {
+            return self.process_one_packed(backend, accesses);
+        }
+        let [base, avail, used] = self.areas.map(phys_to_virt);
+        let read_u16 = |addr: usize| unsafe { u16::from_le((addr as *const u16).read_volatile()) };
+
+        let avail_idx = read_u16(avail + 2);
+        if avail_idx == self.last_avail_idx {
+            return None;
+        }
+        if avail_idx.wrapping_sub(self.last_avail_idx) > self.size {
+            accesses.push(Access::AvailOverflow(self.idx));
+            self.last_avail_idx = avail_idx;
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let slot = (self.last_avail_idx % self.size) as usize;
+        let head = read_u16(avail + 4 + 2 * slot);
+
+        // collect the chain, following an indirect table at its head
+        let mut inputs: Vec<&[u8]> = Vec::new();
+        let mut outputs: Vec<&mut [u8]> = Vec::new();
+        let (mut table, mut table_len, mut indirect) = (base, self.size as usize, false);
+        let mut index = head as usize;
+        let valid = loop {
+            if index >= table_len || inputs.len() + outputs.len() >= table_len {
+                break false;
+            }
+            let desc = table + DESC_SIZE * index;
+            let addr = unsafe { u64::from_le((desc as *const u64).read_volatile()) } as usize;
+            let len = unsafe { u32::from_le(((desc + 8) as *const u32).read_volatile()) } as usize;
+            let flags = read_u16(desc + 12);
+            let next = read_u16(desc + 14);
+            let addr = phys_to_virt(addr);
+            if flags & DESC_F_INDIRECT != 0 {
+                if indirect
+                    || flags & DESC_F_NEXT != 0
+                    || !inputs.is_empty()
+                    || !outputs.is_empty()
+                    || !len.is_multiple_of(DESC_SIZE)
+                {
+                    break false;
+                }
+                table = addr;
+                table_len = len / DESC_SIZE;
+                indirect = true;
+                index = 0;
+                continue;
+            }
+            if flags & DESC_F_WRITE != 0 {
+                outputs.push(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) });
+            } else {
+                inputs.push(unsafe { core::slice::from_raw_parts(addr as *const u8, len) });
+            }
+            if flags & DESC_F_NEXT == 0 {
+                break true;
+            }
+            index = next as usize;
+        };
+        if !valid {
+            accesses.push(Access::BadChain {
+                queue: self.idx,
+                head,
+            });
+            self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+            return Some(());
+        }
+
+        let len = backend.process(self.idx, &inputs, &mut outputs)?;
+        let id = backend.used_id(self.idx, head);
+        self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+
+        // fill in the used ring
+        let used_idx = read_u16(used + 2);
+        let elem = used + 4 + 8 * (used_idx % self.size) as usize;
+        unsafe {
+            (elem as *mut u32).write_volatile(id.to_le());
+            ((elem + 4) as *mut u32).write_volatile(len.to_le());
+            fence(Ordering::SeqCst);
+            ((used + 2) as *mut u16).write_volatile(used_idx.wrapping_add(1).to_le());
+        }
+        Some(())
+    }
+
+    /// Process the next available chain of a packed queue as
+    /// [`process_one`](Self::process_one) does. The chains are used in
+    /// order, so the used descriptor of a chain overwrites its head.
+    fn process_one_packed(
+        &mut self,
+        backend: &mut dyn FakeBackend,
+        accesses: &mut Vec<Access>,
+    ) -> Option<()> {
+        let ring = phys_to_virt(self.areas[0]);
+        let desc_at = |idx: u16| ring + DESC_SIZE * (idx & !PACKED_WRAP) as usize;
+        let available = |idx: u16| {
+            let (_, _, _, flags) = read_packed_desc(desc_at(idx));
+            let wrap = idx & PACKED_WRAP != 0;
+            (flags & DESC_F_AVAIL != 0) == wrap && (flags & DESC_F_USED != 0) != wrap
+        };
+        let head = self.last_avail_idx;
+        if !available(head) {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+
+        // collect the chain from consecutive descriptors, or from the
+        // indirect table at its head
+        let mut inputs: Vec<&[u8]> = Vec::new();
+        let mut outputs: Vec<&mut [u8]> = Vec::new();
+        let mut push = |addr: usize, len: usize, flags: u16| {
+            let addr = phys_to_virt(addr);
+            if flags & DESC_F_WRITE != 0 {
+                outputs.push(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) });
+            } else {
+                inputs.push(unsafe { core::slice::from_raw_parts(addr as *const u8, len) });
+            }
+        };
+        let (mut idx, mut count, mut id) = (head, 0, 0);
+        let valid = loop {
+            if count >= self.size || !available(idx) {
+                break false;
+            }
+            let (addr, len, desc_id, flags) = read_packed_desc(desc_at(idx));
+            id = desc_id;
+            count += 1;
+            idx = packed_advance(idx, 1, self.size);
+            if flags & DESC_F_INDIRECT != 0 {
+                if count != 1 || flags & DESC_F_NEXT != 0 || !len.is_multiple_of(DESC_SIZE) {
+                    break false;
+                }
+                let table = phys_to_virt(addr);
+                break (0..len / DESC_SIZE).all(|i| {
+                    let (addr, len, _, flags) = read_packed_desc(table + DESC_SIZE * i);
+                    push(addr, len, flags);
+                    flags & DESC_F_INDIRECT == 0
+                });
+            }
+            push(addr, len, flags);
+            if flags & DESC_F_NEXT == 0 {
+                break true;
+            }
+        };
+        if !valid {
+            accesses.push(Access::BadChain {
+                queue: self.idx,
+                head: id,
+            });
+            self.last_avail_idx = idx;
+            return Some(());
+        }
+
+        let len = backend.process(self.idx, &inputs, &mut outputs)?;
+        let id = backend.used_id(self.idx, id) as u16;
+        self.last_avail_idx = idx;
+
+        // mark the head used, with both AVAIL and USED as the wrap counter
+        let desc = desc_at(head);
+        let flags = if head & PACKED_WRAP != 0 {
+            DESC_F_AVAIL | DESC_F_USED
+        } else {
+            0
+        };
+        unsafe {
+            ((desc + 8) as *mut u32).write_volatile(len.to_le());
+            ((desc + 12) as *mut u16).write_volatile(id.to_le());
+            fence(Ordering::SeqCst);
+            ((desc + 14) as *mut u16).write_volatile(flags.to_le());
+        }
+        Some(())
+    }
+}
+
+/// Read the descriptor of a packed ring at `desc`, as (address, length, ID,
+/// flags).
+fn read_packed_desc(desc: usize) -> (usize, usize, u16, u16) {
+    unsafe {
+        (
+            u64::from_le((desc as *const u64).read_volatile()) as usize,
+            u32::from_le(((desc + 8) as *const u32).read_volatile()) as usize,
+            u16::from_le(((desc + 12) as *const u16).read_volatile()),
+            u16::from_le(((desc + 14) as *const u16).read_volatile()),
+        )
+    }
+}
+
+/// Advance `idx` of a packed ring of `size` descriptors by `count`, flipping
+/// the wrap counter in `PACKED_WRAP` if it wraps around.
+fn packed_advance(idx: u16, count: u16, size: u16) -> u16 {
+    let position = (idx & !PACKED_WRAP) + count;
+    if position >= size {
+        (position - size) | (!idx & PACKED_WRAP)
+    } else {
+        position | (idx & PACKED_WRAP)
+    }
+}
+
+static DEVICES: Mutex<Vec<FakeState>> = Mutex::new(Vec::new());
+
+/// DMA regions allocated by the HAL, as (physical address, virtual address,
+/// pages).
+static DMA_REGIONS: Mutex<Vec<(usize, usize, usize)>> = Mutex::new(Vec::new());
+
+/// The next fake physical address handed out by the HAL.
+///
+/// Legacy devices take the page frame numbers of queues as 32-bit values, so
+/// DMA regions get fake physical addresses below 4 GiB, while other buffers
+/// are identity mapped.
+static NEXT_PADDR: AtomicUsize = AtomicUsize::new(DMA_PADDR_BASE);
+
+#[no_mangle]
+extern "C" fn virtio_dma_alloc(pages: usize) -> usize {
+    let layout = Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap();
+    let vaddr = unsafe { alloc_zeroed(layout) } as usize;
+    if vaddr == 0 {
+        return 0;
+    }
+    let paddr = NEXT_PADDR.fetch_add(pages * PAGE_SIZE, Ordering::SeqCst);
+    DMA_REGIONS.lock().unwrap().push((paddr, vaddr, pages));
+    paddr
+}
+
+#[no_mangle]
+extern "C" fn virtio_dma_dealloc(paddr: usize, pages: usize) -> i32 {
+    let mut regions = DMA_REGIONS.lock().unwrap();
+    match regions
+        .iter()
+        .position(|&(p, _, n)| p == paddr && n == pages)
+    {
+        Some(index) => {
+            let (_, vaddr, _) = regions.remove(index);
+            let layout = Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap();
+            unsafe { dealloc(vaddr as *mut u8, layout) };
+            0
+        }
+        None => -1,
+    }
+}
+
+/// Whether the DMA region at `paddr` is still allocated, e.g. to check that
+/// a driver freed its queues.
+pub fn dma_allocated(paddr: usize) -> bool {
+    DMA_REGIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|&(p, _, _)| p == paddr)
+}
+
+#[no_mangle]
+extern "C" fn virtio_phys_to_virt(paddr: usize) -> usize {
+    let regions = DMA_REGIONS.lock().unwrap();
+    regions
+        .iter()
+        .find(|&&(p, _, n)| (p..p + n * PAGE_SIZE).contains(&paddr))
+        .map_or(paddr, |&(p, v, _)| v + (paddr - p))
+}
+
+#[no_mangle]
+extern "C" fn virtio_virt_to_phys(vaddr: usize) -> usize {
+    let regions = DMA_REGIONS.lock().unwrap();
+    regions
+        .iter()
+        .find(|&&(_, v, n)| (v..v + n * PAGE_SIZE).contains(&vaddr))
+        .map_or(vaddr, |&(p, v, _)| p + (vaddr - v))
+}
+
+/// The interrupt handlers registered through the HAL, as
+/// `(irq, handler, data)`.
+#[cfg(feature = "irq-hal")]
+static IRQ_HANDLERS: Mutex<Vec<(usize, IrqHandlerFn, usize)>> = Mutex::new(Vec::new());
+
+/// Raise interrupt line `irq`, calling the handlers registered for it.
+///
+/// Fake devices have no interrupt lines of their own, so tests call this
+/// after a device used buffers.
+#[cfg(feature = "irq-hal")]
+pub fn raise_irq(irq: usize) {
+    let handlers: Vec<_> = IRQ_HANDLERS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|&&(i, _, _)| i == irq)
+        .copied()
+        .collect();
+    for (_, handler, data) in handlers {
+        unsafe { handler(data) };
+    }
+}
+
+#[cfg(feature = "irq-hal")]
+#[no_mangle]
+extern "C" fn virtio_irq_register(irq: usize, handler: IrqHandlerFn, data: usize) -> i32 {
+    IRQ_HANDLERS.lock().unwrap().push((irq, handler, data));
+    0
+}
+
+#[cfg(feature = "irq-hal")]
+#[no_mangle]
+extern "C" fn virtio_irq_unregister(irq: usize, data: usize) -> i32 {
+    let mut handlers = IRQ_HANDLERS.lock().unwrap();
+    match handlers.iter().position(|&(i, _, d)| i == irq && d == data) {
+        Some(index) => {
+            handlers.remove(index);
+            0
+        }
+        None => -1,
+    }
+}
+
+/// The ticks of the fake HAL clock, which advances by one each time it is
+/// read so that timeouts expire.
+#[cfg(feature = "time-hal")]
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(feature = "time-hal")]
+#[no_mangle]
+extern "C" fn virtio_ticks() -> u64 {
+    TICKS.fetch_add(1, Ordering::SeqCst)
+}
+
+#[cfg(feature = "wait-hal")]
+#[no_mangle]
+extern "C" fn virtio_wait() {
+    std::thread::yield_now();
+}
+
+/// The I/O space of the fake HAL, in which the ports are plain memory.
+#[cfg(feature = "port-io-hal")]
+static IO_PORTS: Mutex<[u8; 0x10000]> = Mutex::new([0; 0x10000]);
+
+/// The I/O space of the fake HAL, e.g. to lay out the registers of a fake
+/// legacy PCI device in its I/O BAR0, or to check those the driver wrote.
+#[cfg(feature = "port-io-hal")]
+pub fn io_ports() -> std::sync::MutexGuard<'static, [u8; 0x10000]> {
+    IO_PORTS.lock().unwrap()
+}
+
+#[cfg(feature = "port-io-hal")]
+#[no_mangle]
+extern "C" fn virtio_port_read(port: u16, width: u8) -> u32 {
+    let ports = IO_PORTS.lock().unwrap();
+    let mut bytes = [0; 4];
+    for (i, byte) in bytes.iter_mut().take(width as usize).enumerate() {
+        *byte = ports[(port as usize + i) & 0xffff];
+    }
+    u32::from_le_bytes(bytes)
+}
+
+#[cfg(feature = "port-io-hal")]
+#[no_mangle]
+extern "C" fn virtio_port_write(port: u16, width: u8, value: u32) {
+    let mut ports = IO_PORTS.lock().unwrap();
+    for (i, byte) in value.to_le_bytes().iter().take(width as usize).enumerate() {
+        ports[(port as usize + i) & 0xffff] = *byte;
+    }
+}
+
+/// Run a channel command on a fake subchannel, given its command code and
+/// data, returning the number of bytes transferred, or `None` to reject it.
+#[cfg(feature = "ccw-hal")]
+pub type CcwHandler = Box<dyn FnMut(u8, &mut [u8]) -> Option<usize> + Send>;
+
+/// The fake subchannels, with their handlers.
+#[cfg(feature = "ccw-hal")]
+static CCW_DEVICES: Mutex<Vec<(u32, CcwHandler)>> = Mutex::new(Vec::new());
+
+/// The queues notified through the fake HAL, with their subchannels.
+#[cfg(feature = "ccw-hal")]
+static CCW_NOTIFICATIONS: Mutex<Vec<(u32, u16)>> = Mutex::new(Vec::new());
+
+/// Install `handler` to run the channel commands started on subchannel
+/// `schid`, replacing its previous one.
+#[cfg(feature = "ccw-hal")]
+pub fn set_ccw_device(
+    schid: u32,
+    handler: impl FnMut(u8, &mut [u8]) -> Option<usize> + Send + 'static,
+) {
+    let mut devices = CCW_DEVICES.lock().unwrap();
+    devices.retain(|(id, _)| *id != schid);
+    devices.push((schid, Box::new(handler)));
+}
+
+/// Take the queue notifications made so far, as pairs of subchannel and
+/// queue.
+#[cfg(feature = "ccw-hal")]
+pub fn take_ccw_notifications() -> Vec<(u32, u16)> {
+    core::mem::take(&mut *CCW_NOTIFICATIONS.lock().unwrap())
+}
+
+#[cfg(feature = "ccw-hal")]
+#[no_mangle]
+extern "C" fn virtio_ccw_start(schid: u32, ccw: usize) -> i32 {
+    let ccw = virtio_phys_to_virt(ccw) as *const [u8; 8];
+    let ccw = unsafe { ccw.read() };
+    let count = u16::from_be_bytes([ccw[2], ccw[3]]) as usize;
+    let data = u32::from_be_bytes([ccw[4], ccw[5], ccw[6], ccw[7]]) as usize;
+    let data =
+        unsafe { core::slice::from_raw_parts_mut(virtio_phys_to_virt(data) as *mut u8, count) };
+    let mut devices = CCW_DEVICES.lock().unwrap();
+    let Some((_, handler)) = devices.iter_mut().find(|(id, _)| *id == schid) else {
+        return -1;
+    };
+    match handler(ccw[0], data) {
+        Some(transferred) if transferred <= count => (count - transferred) as i32,
+        _ => -1,
+    }
+}
+
+#[cfg(feature = "ccw-hal")]
+#[no_mangle]
+extern "C" fn virtio_ccw_notify(schid: u32, queue: u16) {
+    CCW_NOTIFICATIONS.lock().unwrap().push((schid, queue));
+}
+
+const DMA_PADDR_BASE: usize = 0x4000_0000;
+
+const RING_INDIRECT_DESC: u64 = 1 << 28;
+const RING_PACKED: u64 = 1 << 34;
+const RING_RESET: u64 = 1 << 40;
+
+/// The maximum size of the queues of the fake devices.
+const QUEUE_NUM_MAX: u32 = 1024;
+/// The smallest config space of the fake devices, which MMIO devices have.
+const CONFIG_SPACE_SIZE: usize = 0x100;
+
+const DESC_SIZE: usize = 16;
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const DESC_F_INDIRECT: u16 = 4;
+const DESC_F_AVAIL: u16 = 1 << 7;
+const DESC_F_USED: u16 = 1 << 15;
+/// The bit of the indices of a packed queue holding the wrap counter.
+const PACKED_WRAP: u16 = 1 << 15;
+
+const SECTOR_SIZE: usize = 512;
+const BLK_T_IN: u32 = 0;
+const BLK_T_OUT: u32 = 1;
+const BLK_S_OK: u8 = 0;
+const BLK_S_IOERR: u8 = 1;
+const BLK_S_UNSUPP: u8 = 2;
+
+const NET_QUEUE_RECEIVE: u32 = 0;
+const NET_QUEUE_TRANSMIT: u32 = 1;
+const NET_HDR_SIZE: usize = 10;
+const NET_F_MAC: u64 = 1 << 5;
+const NET_F_STATUS: u64 = 1 << 16;
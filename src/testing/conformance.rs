@@ -0,0 +1,607 @@
+//! Checks of the drivers against the behaviors required by the virtio 1.2
+//! specification.
+//!
+//! Each check drives the drivers against fake devices and returns the
+//! [`Violation`]s it found, so a test only has to assert that there are
+//! none:
+//!
+//! ```ignore
+//! #[test]
+//! fn conformance() {
+//!     assert_eq!(virtio_drivers::testing::conformance::check_all(), []);
+//! }
+//! ```
+//!
+//! The checks cover the order of the device status bits and the feature
+//! handshake during initialization, the descriptor chains made available to
+//! the device, and the handling of bad responses from the device, which must
+//! fail the request instead of panicking. Memory barriers cannot be observed
+//! by a fake device on the same core, so their placement is checked by their
+//! effect: each chain published in the available ring must be complete when
+//! the device is notified.
+
+// a build without drivers has nothing to check
+#![cfg_attr(
+    not(any(
+        feature = "blk",
+        feature = "bluetooth",
+        feature = "can",
+        feature = "gpu",
+        feature = "hwsim",
+        feature = "input",
+        feature = "net",
+        feature = "pmem",
+        feature = "scmi",
+        feature = "sound",
+        feature = "video",
+        feature = "wl"
+    )),
+    allow(dead_code, unused_mut, unused_variables)
+)]
+
+use super::*;
+use std::format;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::string::String;
+
+const ACKNOWLEDGE: u32 = 1;
+const DRIVER: u32 = 2;
+const DRIVER_OK: u32 = 4;
+const FEATURES_OK: u32 = 8;
+const FAILED: u32 = 128;
+
+/// A behavior of a driver which the spec forbids.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Violation {
+    /// The driver, named as its module.
+    pub driver: &'static str,
+    /// The section of the spec with the requirement.
+    pub section: &'static str,
+    /// What the driver did.
+    pub detail: String,
+}
+
+impl Violation {
+    fn new(driver: &'static str, section: &'static str, detail: String) -> Self {
+        Violation {
+            driver,
+            section,
+            detail,
+        }
+    }
+}
+
+/// Run all checks.
+pub fn check_all() -> Vec<Violation> {
+    let mut violations = Vec::new();
+    violations.extend(check_init());
+    #[cfg(feature = "blk")]
+    violations.extend(check_blk());
+    #[cfg(feature = "net")]
+    violations.extend(check_net());
+    violations.extend(check_used_ids());
+    violations
+}
+
+/// Check the accesses of `driver` to a fake device offering `offered`
+/// features, as returned by [`accesses`].
+///
+/// Ref: virtio 2.1 Device Status Field, 3.1.1 Device Initialization
+pub fn check_accesses(driver: &'static str, accesses: &[Access], offered: u64) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let mut violation = |section, detail| violations.push(Violation::new(driver, section, detail));
+    let mut status = 0;
+    let mut reset = false;
+    let mut features_written = false;
+    let mut ready = false;
+    for access in accesses {
+        match *access {
+            Access::Reset => {
+                status = 0;
+                reset = true;
+                features_written = false;
+            }
+            Access::Status(new) => {
+                if !reset {
+                    violation("3.1.1", format!("wrote status {:#x} before a reset", new));
+                    reset = true;
+                }
+                if new & status != status {
+                    violation(
+                        "2.1",
+                        format!("wrote status {:#x}, clearing bits of {:#x}", new, status),
+                    );
+                }
+                let expected = match status & !FAILED {
+                    0 => ACKNOWLEDGE,
+                    ACKNOWLEDGE => DRIVER,
+                    x if x == ACKNOWLEDGE | DRIVER => FEATURES_OK,
+                    x if x == ACKNOWLEDGE | DRIVER | FEATURES_OK => DRIVER_OK,
+                    _ => 0,
+                };
+                let added = new & !status;
+                if added != expected && added != FAILED {
+                    violation(
+                        "3.1.1",
+                        format!("wrote status {:#x} after {:#x}", new, status),
+                    );
+                }
+                if added & FEATURES_OK != 0 && !features_written {
+                    violation(
+                        "3.1.1",
+                        "set FEATURES_OK without writing the features".into(),
+                    );
+                }
+                ready |= added & DRIVER_OK != 0;
+                status = new;
+            }
+            Access::DriverFeatures(features) => {
+                if status != ACKNOWLEDGE | DRIVER {
+                    violation(
+                        "3.1.1",
+                        format!("wrote features {:#x} in status {:#x}", features, status),
+                    );
+                }
+                if features & !offered != 0 {
+                    violation(
+                        "2.2",
+                        format!(
+                            "accepted features {:#x} which the device did not offer",
+                            features & !offered
+                        ),
+                    );
+                }
+                features_written = true;
+            }
+            Access::QueueSet { queue, pfn, .. } => {
+                if pfn != 0 && status & FEATURES_OK == 0 {
+                    violation(
+                        "3.1.1",
+                        format!("set up queue {} in status {:#x}", queue, status),
+                    );
+                }
+            }
+            Access::QueueReset(queue) => {
+                if status & DRIVER_OK == 0 {
+                    violation(
+                        "2.6.1",
+                        format!("reset queue {} in status {:#x}", queue, status),
+                    );
+                }
+            }
+            Access::Notify(queue) => {
+                if status & DRIVER_OK == 0 {
+                    violation(
+                        "3.1.1",
+                        format!("notified queue {} in status {:#x}", queue, status),
+                    );
+                }
+            }
+            Access::BadChain { queue, head } => violation(
+                "2.7.5",
+                format!("made malformed chain {} available in queue {}", head, queue),
+            ),
+            Access::AvailOverflow(queue) => violation(
+                "2.7.13",
+                format!("made more chains available in queue {} than it has", queue),
+            ),
+        }
+    }
+    if !ready {
+        violation("3.1.1", "never set DRIVER_OK".into());
+    }
+    violations
+}
+
+/// Check that each driver initializes its device as the spec requires, when
+/// the device offers all features or none.
+pub fn check_init() -> Vec<Violation> {
+    let mut violations = Vec::new();
+    for &offered in &[u64::MAX, 0] {
+        #[cfg(feature = "blk")]
+        violations.extend(check_init_of("blk", DeviceType::Block, offered, |header| {
+            VirtIOBlk::new(header).map(drop)
+        }));
+        #[cfg(feature = "bluetooth")]
+        violations.extend(check_init_of(
+            "bluetooth",
+            DeviceType::Bluetooth,
+            offered,
+            |header| VirtIOBluetooth::new(header).map(drop),
+        ));
+        #[cfg(feature = "can")]
+        violations.extend(check_init_of("can", DeviceType::Can, offered, |header| {
+            VirtIOCan::new(header).map(drop)
+        }));
+        #[cfg(feature = "gpu")]
+        violations.extend(check_init_of("gpu", DeviceType::GPU, offered, |header| {
+            VirtIOGpu::new(header).map(drop)
+        }));
+        #[cfg(feature = "hwsim")]
+        violations.extend(check_init_of(
+            "hwsim",
+            DeviceType::Mac80211Hwsim,
+            offered,
+            |header| VirtIOHwsim::new(header).map(drop),
+        ));
+        #[cfg(feature = "input")]
+        violations.extend(check_init_of(
+            "input",
+            DeviceType::Input,
+            offered,
+            |header| {
+                let mut event_buf = [0; 32];
+                VirtIOInput::new(header, &mut event_buf).map(drop)
+            },
+        ));
+        #[cfg(feature = "net")]
+        violations.extend(check_init_of(
+            "net",
+            DeviceType::Network,
+            offered,
+            |header| VirtIONet::new(header).map(drop),
+        ));
+        #[cfg(feature = "pmem")]
+        violations.extend(check_init_of("pmem", DeviceType::Pmem, offered, |header| {
+            VirtIOPmem::new(header).map(drop)
+        }));
+        #[cfg(feature = "scmi")]
+        violations.extend(check_init_of("scmi", DeviceType::Scmi, offered, |header| {
+            VirtIOScmi::new(header).map(drop)
+        }));
+        #[cfg(feature = "sound")]
+        violations.extend(check_init_of(
+            "sound",
+            DeviceType::Sound,
+            offered,
+            |header| VirtIOSound::new(header).map(drop),
+        ));
+        #[cfg(feature = "video")]
+        for &device_type in &[DeviceType::VideoEncoder, DeviceType::VideoDecoder] {
+            violations.extend(check_init_of("video", device_type, offered, |header| {
+                VirtIOVideo::new(header).map(drop)
+            }));
+        }
+        #[cfg(feature = "wl")]
+        violations.extend(check_init_of("wl", DeviceType::Wl, offered, |header| {
+            VirtIOWl::new(header).map(drop)
+        }));
+    }
+    violations
+}
+
+/// Check the initialization of `driver` by `init` against a device of
+/// `device_type` offering `offered` features.
+fn check_init_of(
+    driver: &'static str,
+    device_type: DeviceType,
+    offered: u64,
+    init: impl FnOnce(&'static mut FakeTransport) -> Result,
+) -> Vec<Violation> {
+    let device = ScriptedDevice::new(device_type)
+        .with_features(offered)
+        .with_config(vec![0; CONFIG_SIZE]);
+    run(driver, "3.1.1", device, offered, |header| {
+        init(header).map_err(|err| format!("failed to initialize: {:?}", err))
+    })
+}
+
+/// Check that the block driver fails requests which the device answers
+/// badly.
+#[cfg(feature = "blk")]
+pub fn check_blk() -> Vec<Violation> {
+    let device = || ScriptedDevice::new(DeviceType::Block).with_config(8u64.to_le_bytes().to_vec());
+    let read = |header: &'static mut FakeTransport| {
+        let mut blk = VirtIOBlk::new(header).map_err(|err| format!("{:?}", err))?;
+        let mut buf = [0; 512];
+        expect_err(blk.read_block(0, &mut buf))
+    };
+    let mut violations = Vec::new();
+    violations.extend(run(
+        "blk",
+        "2.7.14",
+        device().reply(0, Reply::BadId(OUT_OF_RANGE_ID)),
+        0,
+        read,
+    ));
+    // a descriptor of the chain, but not its head
+    violations.extend(run(
+        "blk",
+        "2.7.14",
+        device().reply(0, Reply::BadId(STALE_ID)),
+        0,
+        read,
+    ));
+    // the device uses the first chain of a batch twice
+    violations.extend(run(
+        "blk",
+        "2.7.14",
+        device()
+            .reply(0, Reply::Data(vec![0; 513]))
+            .reply(0, Reply::BadId(0)),
+        0,
+        |header| {
+            let mut blk = VirtIOBlk::new(header).map_err(|err| format!("{:?}", err))?;
+            let (mut first, mut second) = ([0; 512], [0; 512]);
+            expect_err(blk.read_blocks(0, &mut [&mut first, &mut second]))
+        },
+    ));
+    let mut failed = vec![0; 512];
+    failed.push(1);
+    violations.extend(run(
+        "blk",
+        "2.7.14",
+        device().reply(0, Reply::Data(failed)),
+        0,
+        read,
+    ));
+    let mut unsupported = vec![0; 512];
+    unsupported.push(2);
+    violations.extend(run(
+        "blk",
+        "2.7.14",
+        device().reply(0, Reply::Data(unsupported)),
+        0,
+        read,
+    ));
+    violations
+}
+
+/// Check that the network driver fails receiving and sending packets which
+/// the device answers badly.
+#[cfg(feature = "net")]
+pub fn check_net() -> Vec<Violation> {
+    let device = || ScriptedDevice::new(DeviceType::Network).with_config(vec![0; 8]);
+    let recv = |header: &'static mut FakeTransport| {
+        let mut net = VirtIONet::new(header).map_err(|err| format!("{:?}", err))?;
+        let mut buf = [0; 64];
+        expect_err(net.recv(&mut buf))
+    };
+    let send = |header: &'static mut FakeTransport| {
+        let mut net = VirtIONet::new(header).map_err(|err| format!("{:?}", err))?;
+        expect_err(net.send(&[0; 64]))
+    };
+    let mut violations = Vec::new();
+    violations.extend(run(
+        "net",
+        "2.7.14",
+        device().reply(0, Reply::BadId(OUT_OF_RANGE_ID)),
+        0,
+        recv,
+    ));
+    // longer than the buffer
+    violations.extend(run(
+        "net",
+        "2.7.14",
+        device().reply(0, Reply::Len(4096)),
+        0,
+        recv,
+    ));
+    // shorter than the header
+    violations.extend(run(
+        "net",
+        "2.7.14",
+        device().reply(0, Reply::Len(4)),
+        0,
+        recv,
+    ));
+    violations.extend(run(
+        "net",
+        "2.7.14",
+        device().reply(1, Reply::BadId(OUT_OF_RANGE_ID)),
+        0,
+        send,
+    ));
+    violations
+}
+
+/// Check that each driver fails a request which the device reports used
+/// with an ID out of the range of the queue, or with an ID in range which
+/// is not the head of a chain in flight.
+pub fn check_used_ids() -> Vec<Violation> {
+    let mut violations = Vec::new();
+    #[cfg(feature = "bluetooth")]
+    violations.extend(check_used_ids_of(
+        "bluetooth",
+        DeviceType::Bluetooth,
+        0,
+        &[OUT_OF_RANGE_ID, STALE_ID],
+        |header| {
+            let mut bluetooth = VirtIOBluetooth::new(header).map_err(|err| format!("{:?}", err))?;
+            // HCI_Reset
+            expect_err(bluetooth.send(HciPacketType::Command, &[0x03, 0x0c, 0]))
+        },
+    ));
+    #[cfg(feature = "can")]
+    violations.extend(check_used_ids_of(
+        "can",
+        DeviceType::Can,
+        0,
+        &[OUT_OF_RANGE_ID, STALE_ID],
+        |header| {
+            let mut can = VirtIOCan::new(header).map_err(|err| format!("{:?}", err))?;
+            let frame = CanFrame::new(0x123, false, &[1, 2]).ok_or("bad frame")?;
+            expect_err(can.send(&frame))
+        },
+    ));
+    #[cfg(feature = "gpu")]
+    violations.extend(check_used_ids_of(
+        "gpu",
+        DeviceType::GPU,
+        0,
+        &[OUT_OF_RANGE_ID, STALE_ID],
+        |header| {
+            let mut gpu = VirtIOGpu::new(header).map_err(|err| format!("{:?}", err))?;
+            expect_err(gpu.setup_framebuffer().map(|fb| fb.len()))
+        },
+    ));
+    #[cfg(feature = "hwsim")]
+    violations.extend(check_used_ids_of(
+        "hwsim",
+        DeviceType::Mac80211Hwsim,
+        0,
+        &[OUT_OF_RANGE_ID, STALE_ID],
+        |header| {
+            let mut hwsim = VirtIOHwsim::new(header).map_err(|err| format!("{:?}", err))?;
+            // a netlink header with a generic netlink header, of 20 bytes
+            let mut msg = [0; 20];
+            msg[0] = 20;
+            expect_err(hwsim.send(&msg))
+        },
+    ));
+    // all the event buffers are in flight, so only an ID out of range is
+    // bad
+    #[cfg(feature = "input")]
+    violations.extend(check_used_ids_of(
+        "input",
+        DeviceType::Input,
+        0,
+        &[OUT_OF_RANGE_ID],
+        |header| {
+            let mut event_buf = [0; 32];
+            let mut input =
+                VirtIOInput::new(header, &mut event_buf).map_err(|err| format!("{:?}", err))?;
+            expect_err(input.ack_interrupt())
+        },
+    ));
+    #[cfg(feature = "net")]
+    violations.extend(check_used_ids_of(
+        "net",
+        DeviceType::Network,
+        1,
+        &[OUT_OF_RANGE_ID, STALE_ID],
+        |header| {
+            let mut net = VirtIONet::new(header).map_err(|err| format!("{:?}", err))?;
+            expect_err(net.send(&[0; 64]))
+        },
+    ));
+    #[cfg(feature = "pmem")]
+    violations.extend(check_used_ids_of(
+        "pmem",
+        DeviceType::Pmem,
+        0,
+        &[OUT_OF_RANGE_ID, STALE_ID],
+        |header| {
+            let mut pmem = VirtIOPmem::new(header).map_err(|err| format!("{:?}", err))?;
+            expect_err(pmem.flush())
+        },
+    ));
+    #[cfg(feature = "scmi")]
+    violations.extend(check_used_ids_of(
+        "scmi",
+        DeviceType::Scmi,
+        0,
+        &[OUT_OF_RANGE_ID, STALE_ID],
+        |header| {
+            let mut scmi = VirtIOScmi::new(header).map_err(|err| format!("{:?}", err))?;
+            // the base protocol
+            expect_err(scmi.protocol_version(0x10))
+        },
+    ));
+    #[cfg(feature = "sound")]
+    violations.extend(check_used_ids_of(
+        "sound",
+        DeviceType::Sound,
+        0,
+        &[OUT_OF_RANGE_ID, STALE_ID],
+        |header| {
+            let mut sound = VirtIOSound::new(header).map_err(|err| format!("{:?}", err))?;
+            expect_err(sound.jack_info(0))
+        },
+    ));
+    #[cfg(feature = "video")]
+    violations.extend(check_used_ids_of(
+        "video",
+        DeviceType::VideoDecoder,
+        0,
+        &[OUT_OF_RANGE_ID, STALE_ID],
+        |header| {
+            let mut video = VirtIOVideo::new(header).map_err(|err| format!("{:?}", err))?;
+            let mut buf = [0; 64];
+            expect_err(video.query_capability(QueueType::Input, &mut buf))
+        },
+    ));
+    #[cfg(feature = "wl")]
+    violations.extend(check_used_ids_of(
+        "wl",
+        DeviceType::Wl,
+        1,
+        &[OUT_OF_RANGE_ID, STALE_ID],
+        |header| {
+            let mut wl = VirtIOWl::new(header).map_err(|err| format!("{:?}", err))?;
+            expect_err(wl.new_context(1))
+        },
+    ));
+    violations
+}
+
+/// Check that `request` fails against a device of `device_type`, which
+/// reports the chain made available in `queue` used with each of `ids`.
+fn check_used_ids_of(
+    driver: &'static str,
+    device_type: DeviceType,
+    queue: u32,
+    ids: &[u32],
+    request: impl Fn(&'static mut FakeTransport) -> core::result::Result<(), String>,
+) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    for &id in ids {
+        // one jack, so that the sound driver queries it
+        let mut config = vec![0; CONFIG_SIZE];
+        config[0] = 1;
+        let device = ScriptedDevice::new(device_type)
+            .with_features(CAN_F_CAN_CLASSIC)
+            .with_config(config)
+            .reply(queue, Reply::BadId(id));
+        violations.extend(run(driver, "2.7.14", device, CAN_F_CAN_CLASSIC, &request));
+    }
+    violations
+}
+
+/// Run `f` with a fake device served by `device`, which offers `offered`
+/// features, and check the accesses of `driver` to the device.
+///
+/// `f` returns what went wrong, if anything, as a violation of `section`,
+/// and should drop the driver.
+fn run(
+    driver: &'static str,
+    section: &'static str,
+    device: ScriptedDevice,
+    offered: u64,
+    f: impl FnOnce(&'static mut FakeTransport) -> core::result::Result<(), String>,
+) -> Vec<Violation> {
+    let header = fake_device(device);
+    let ptr = header as *mut FakeTransport;
+    let mut violations = Vec::new();
+    match catch_unwind(AssertUnwindSafe(move || f(header))) {
+        Ok(Ok(())) => {}
+        Ok(Err(detail)) => violations.push(Violation::new(driver, section, detail)),
+        Err(_) => violations.push(Violation::new(driver, section, "panicked".into())),
+    }
+    violations.extend(check_accesses(driver, &accesses(ptr), offered));
+    unsafe { destroy_fake_device(ptr) };
+    violations
+}
+
+/// Expect a request to fail, as the device answered it badly.
+fn expect_err<T: core::fmt::Debug>(result: Result<T>) -> core::result::Result<(), String> {
+    match result {
+        Ok(value) => Err(format!("accepted a bad response as {:?}", value)),
+        Err(_) => Ok(()),
+    }
+}
+
+/// The size of the config space of the fake devices, which is zeroed.
+const CONFIG_SIZE: usize = 256;
+
+/// A descriptor ID out of the range of every queue.
+const OUT_OF_RANGE_ID: u32 = 1000;
+
+/// A descriptor ID in the range of every queue, which is not the head of the
+/// chain of a request, as the chain starts at descriptor 0 of an idle queue
+/// and continues with this one.
+const STALE_ID: u32 = 1;
+
+/// The feature of CAN devices supporting classic frames, without which the
+/// CAN driver sends none.
+const CAN_F_CAN_CLASSIC: u64 = 1 << 0;
@@ -0,0 +1,507 @@
+use crate::endian::{Le16, Le32, Le64};
+use crate::header::DeviceType;
+use crate::{Error, Result, NO_VECTOR, PAGE_SIZE};
+use bitflags::*;
+use core::fmt;
+use core::hint::spin_loop;
+use core::mem::{align_of, size_of, MaybeUninit};
+use core::slice;
+
+/// A field of the configuration space of a device, which the `read_config`
+/// and `write_config` methods of a [`Transport`] access.
+///
+/// It is only implemented for plain data types, which have no padding and are
+/// valid for any bytes, so that they can be filled from and written as the
+/// bytes of the configuration space: the integers, their little-endian
+/// [`Le16`], [`Le32`] and [`Le64`], and arrays of them.
+pub trait ConfigField: Copy + sealed::Sealed {}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+macro_rules! config_field {
+    ($($ty:ty),*) => {
+        $(
+            impl sealed::Sealed for $ty {}
+            impl ConfigField for $ty {}
+        )*
+    };
+}
+
+config_field!(u8, u16, u32, u64, Le16, Le32, Le64);
+
+impl<T: ConfigField, const N: usize> sealed::Sealed for [T; N] {}
+impl<T: ConfigField, const N: usize> ConfigField for [T; N] {}
+
+/// The interface to a virtio device, through one of the transports defined by
+/// the spec, e.g. the registers of a [`VirtIOHeader`](crate::VirtIOHeader)
+/// for MMIO devices or the capabilities of a [`PciTransport`](crate::PciTransport).
+///
+/// The drivers hold a `&'static mut dyn Transport`, so a device on any
+/// transport is driven by the same driver. The methods taking `&self` only
+/// access registers which may be accessed concurrently, e.g. by an interrupt
+/// handler or by the halves of a split driver, while the driver uses the
+/// others through `&mut self`.
+pub trait Transport: Send + Sync {
+    /// The type of the device.
+    fn device_type(&self) -> DeviceType;
+
+    /// Read the features the device offers.
+    fn read_device_features(&mut self) -> u64;
+
+    /// Write the features the driver accepts.
+    fn write_driver_features(&mut self, driver_features: u64);
+
+    /// The maximum number of descriptors of `queue`, or 0 if the device does
+    /// not have it.
+    fn max_queue_size(&mut self, queue: u32) -> u32;
+
+    /// Notify the device of new buffers in `queue`.
+    fn notify(&self, queue: u32);
+
+    /// The device status.
+    fn status(&self) -> DeviceStatus;
+
+    /// Write the device status, which resets the device if it is empty.
+    fn set_status(&self, status: DeviceStatus);
+
+    /// Set the size of the guest pages, by which the legacy interfaces
+    /// address the queues. Other transports ignore it.
+    fn set_guest_page_size(&mut self, guest_page_size: u32);
+
+    /// Set up `queue` with `size` descriptors, whose descriptor table, driver
+    /// area and device area are at the given physical addresses.
+    ///
+    /// The legacy interfaces take the address of the descriptor table only,
+    /// so the areas must follow it in the layout the legacy interfaces
+    /// define, as the queues of this crate do.
+    fn queue_set(
+        &mut self,
+        queue: u32,
+        size: u32,
+        descriptors: usize,
+        driver_area: usize,
+        device_area: usize,
+    );
+
+    /// Stop the device from using `queue`.
+    ///
+    /// Transports which can neither reset nor disable a queue alone reset
+    /// the whole device.
+    fn queue_unset(&mut self, queue: u32);
+
+    /// Reset `queue` alone, without resetting the device, so that it can be
+    /// set up again with [`queue_set`](Self::queue_set).
+    ///
+    /// Fails with [`Error::InvalidParam`] unless the device negotiated
+    /// `VIRTIO_F_RING_RESET`, which only modern transports offer, or with
+    /// [`Error::Timeout`] if the device does not finish resetting the queue.
+    fn queue_reset(&mut self, _queue: u32) -> Result {
+        Err(Error::InvalidParam)
+    }
+
+    /// The physical address of the descriptor table of `queue`, or 0 if the
+    /// queue is not set up.
+    fn queue_descriptors(&mut self, queue: u32) -> usize;
+
+    /// Acknowledge an interrupt, and return its causes, which are empty if
+    /// the device did not raise it.
+    ///
+    /// MMIO devices acknowledge the causes written to `InterruptACK`, while
+    /// PCI devices clear their ISR status when it is read, in which devices
+    /// using MSI-X only report configuration changes.
+    fn ack_interrupt_status(&self) -> InterruptStatus;
+
+    /// Get the pointer to the configuration space of the device.
+    fn config_space(&self) -> *mut u64;
+
+    /// The size of the configuration space of the device in bytes, which
+    /// [`read_config`](#method.read_config) and
+    /// [`write_config`](#method.write_config) check the fields against.
+    fn config_space_size(&self) -> usize;
+
+    /// Read the field of `width` bytes, 1, 2 or 4, at `offset` in the
+    /// configuration space, with a single access.
+    ///
+    /// Transports whose configuration space is mapped into memory read it
+    /// through [`config_space`](Self::config_space), while those accessing
+    /// it through I/O ports, whose `config_space` is null, override this.
+    ///
+    /// # Safety
+    ///
+    /// The field must be within the configuration space and aligned to
+    /// `width`, as [`read_config`](#method.read_config) checks.
+    unsafe fn config_read(&self, offset: usize, width: usize) -> u32 {
+        unsafe { read_mapped((self.config_space() as *const u8).add(offset), width) }
+    }
+
+    /// Write the field of `width` bytes at `offset` in the configuration
+    /// space, as [`config_read`](Self::config_read) reads it.
+    ///
+    /// # Safety
+    ///
+    /// As for [`config_read`](Self::config_read).
+    unsafe fn config_write(&self, offset: usize, width: usize, value: u32) {
+        unsafe { write_mapped((self.config_space() as *mut u8).add(offset), width, value) }
+    }
+
+    /// The shared memory region `id` of the device, or `None` if it lacks
+    /// it, e.g. on transports without shared memory.
+    fn shared_memory_region(&mut self, _id: u8) -> Option<SharedMemoryRegion> {
+        None
+    }
+
+    /// The configuration generation, which the device changes whenever it
+    /// changes its configuration, or 0 for the legacy interfaces, which lack
+    /// it.
+    fn config_generation(&self) -> u32 {
+        0
+    }
+
+    /// Whether the transport uses the legacy interface of the device, which
+    /// lacks FEATURES_OK, so the device cannot reject the features.
+    fn is_legacy(&self) -> bool {
+        false
+    }
+
+    /// Whether the queues must have the maximum size of the device, as with
+    /// legacy PCI devices, whose queue size register is read-only.
+    fn queue_size_fixed(&self) -> bool {
+        false
+    }
+
+    /// Assign MSI-X `vector` to configuration changes, or unassign it with
+    /// [`NO_VECTOR`].
+    ///
+    /// Fails with [`Error::InvalidParam`] if the transport has no MSI-X
+    /// enabled, and with [`Error::IoError`] if the device could not assign
+    /// the vector.
+    fn set_config_vector(&mut self, vector: u16) -> Result {
+        match vector {
+            NO_VECTOR => Ok(()),
+            _ => Err(Error::InvalidParam),
+        }
+    }
+
+    /// Assign MSI-X `vector` to `queue`, before it is set up, or unassign
+    /// it with [`NO_VECTOR`], failing as
+    /// [`set_config_vector`](Self::set_config_vector) does.
+    fn set_queue_vector(&mut self, _queue: u32, vector: u16) -> Result {
+        match vector {
+            NO_VECTOR => Ok(()),
+            _ => Err(Error::InvalidParam),
+        }
+    }
+
+    /// Whether `queue` is set up.
+    fn queue_used(&mut self, queue: u32) -> bool {
+        self.queue_descriptors(queue) != 0
+    }
+
+    /// Acknowledge an interrupt, and return whether the device raised it.
+    fn ack_interrupt(&self) -> bool {
+        !self.ack_interrupt_status().is_empty()
+    }
+
+    /// Reset the device.
+    ///
+    /// The device stops using its queues until they are set up again, so
+    /// their memory can be freed once this returns.
+    fn reset(&self) {
+        self.set_status(DeviceStatus::empty());
+        while !self.status().is_empty() {
+            spin_loop();
+        }
+    }
+
+    /// Whether the device set [`DeviceStatus::DEVICE_NEEDS_RESET`] after an
+    /// error, which it signals with a configuration change interrupt.
+    ///
+    /// The device may not use its queues until it is reset, so its driver is
+    /// to [`recover`](crate::Driver::recover) it.
+    fn needs_reset(&self) -> bool {
+        self.status().contains(DeviceStatus::DEVICE_NEEDS_RESET)
+    }
+
+    /// Finish initializing the device.
+    fn finish_init(&mut self) {
+        self.add_status(DeviceStatus::DRIVER_OK);
+        debug!("Device {:?} is ready", self.device_type());
+    }
+
+    /// Set `status` in the device status, keeping the bits already set.
+    fn add_status(&self, status: DeviceStatus) {
+        self.set_status(self.status() | status);
+    }
+}
+
+impl dyn Transport + '_ {
+    /// Begin initializing the device, and return the features negotiated by
+    /// the driver.
+    ///
+    /// Fails with [`Error::FeaturesRejected`] if the device rejects them.
+    ///
+    /// Ref: virtio 3.1.1 Device Initialization
+    pub fn begin_init(&mut self, negotiate_features: impl FnOnce(u64) -> u64) -> Result<u64> {
+        let features = self.acknowledge();
+        let driver_features = negotiate_features(features);
+        debug!(
+            "Negotiated features {:#x} of device features {:#x}",
+            driver_features, features
+        );
+        self.accept_features(driver_features, features)?;
+        Ok(driver_features)
+    }
+
+    /// Read the configuration of the device with `read`, again until the
+    /// configuration generation is the same before and after it, so that
+    /// fields wider than the device writes atomically, or several fields, are
+    /// not torn by a concurrent change.
+    ///
+    /// Ref: virtio 2.5.1 Driver Requirements: Device Configuration Space
+    pub fn read_config_atomic<T>(&self, mut read: impl FnMut() -> T) -> T {
+        loop {
+            let before = self.config_generation();
+            let value = read();
+            if self.config_generation() == before {
+                return value;
+            }
+            spin_loop();
+        }
+    }
+
+    /// Read the field of type `T` at `offset` in the configuration space of
+    /// the device, with volatile accesses as wide as its alignment, up to 32
+    /// bits, so that 64-bit fields are read in two halves as the spec
+    /// requires.
+    ///
+    /// Fails with [`Error::InvalidParam`] if the field is not within the
+    /// configuration space, or not aligned for `T`.
+    pub fn read_config<T: ConfigField>(&self, offset: usize) -> Result<T> {
+        let width = self.check_config_field::<T>(offset)?;
+        // `ConfigField` types are valid for any bytes
+        let mut value = MaybeUninit::<T>::zeroed();
+        let bytes =
+            unsafe { slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        for (i, chunk) in bytes.chunks_exact_mut(width).enumerate() {
+            let read = unsafe { self.config_read(offset + i * width, width) };
+            match width {
+                1 => chunk.copy_from_slice(&[read as u8]),
+                2 => chunk.copy_from_slice(&(read as u16).to_ne_bytes()),
+                _ => chunk.copy_from_slice(&read.to_ne_bytes()),
+            }
+        }
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Write `value` to the field of type `T` at `offset` in the
+    /// configuration space of the device, with accesses as
+    /// [`read_config`](#method.read_config) makes, failing as it does.
+    pub fn write_config<T: ConfigField>(&self, offset: usize, value: T) -> Result {
+        let width = self.check_config_field::<T>(offset)?;
+        // `ConfigField` types have no padding
+        let bytes =
+            unsafe { slice::from_raw_parts(&value as *const T as *const u8, size_of::<T>()) };
+        for (i, chunk) in bytes.chunks_exact(width).enumerate() {
+            let mut word = [0; 4];
+            word[..width].copy_from_slice(chunk);
+            let write = match width {
+                1 => word[0] as u32,
+                2 => u16::from_ne_bytes([word[0], word[1]]) as u32,
+                _ => u32::from_ne_bytes(word),
+            };
+            unsafe { self.config_write(offset + i * width, width, write) };
+        }
+        Ok(())
+    }
+
+    /// Check that the field of type `T` at `offset` is within the
+    /// configuration space and aligned, and return the width of the accesses
+    /// to it.
+    fn check_config_field<T>(&self, offset: usize) -> Result<usize> {
+        let end = offset.checked_add(size_of::<T>());
+        if end.is_none_or(|end| end > self.config_space_size()) {
+            warn!(
+                "Config field of {} bytes at {:#x} is out of the {} bytes of the config space",
+                size_of::<T>(),
+                offset,
+                self.config_space_size()
+            );
+            return Err(Error::InvalidParam);
+        }
+        if !offset.is_multiple_of(align_of::<T>()) {
+            warn!("Config field at {:#x} is misaligned", offset);
+            return Err(Error::InvalidParam);
+        }
+        Ok(align_of::<T>().min(size_of::<u32>()))
+    }
+
+    /// Reset and acknowledge the device, and return the features it offers.
+    pub(crate) fn acknowledge(&mut self) -> u64 {
+        self.reset();
+        self.add_status(DeviceStatus::ACKNOWLEDGE);
+        self.add_status(DeviceStatus::DRIVER);
+        self.read_device_features()
+    }
+
+    /// Accept the features negotiated by the driver of those the device
+    /// offers, and check that the device accepts them as well.
+    ///
+    /// The device is marked FAILED if it rejects them, with
+    /// [`Error::FeaturesRejected`].
+    pub(crate) fn accept_features(&mut self, features: u64, device_features: u64) -> Result {
+        self.write_driver_features(features);
+        self.add_status(DeviceStatus::FEATURES_OK);
+        if !self.is_legacy() && !self.status().contains(DeviceStatus::FEATURES_OK) {
+            let unoffered = features & !device_features;
+            let rejected = if unoffered != 0 { unoffered } else { features };
+            warn!(
+                "Device rejected features {:#x} of negotiated features {:#x}",
+                rejected, features
+            );
+            self.add_status(DeviceStatus::FAILED);
+            return Err(Error::FeaturesRejected(rejected));
+        }
+        self.set_guest_page_size(PAGE_SIZE as u32);
+        Ok(())
+    }
+
+    /// Reset the device and begin initializing it again with the features
+    /// negotiated when it was first initialized, e.g. to resume it.
+    ///
+    /// Fails if the device no longer offers all of the features.
+    pub(crate) fn begin_reinit(&mut self, features: u64) -> Result {
+        let mut device_features = 0;
+        self.begin_init(|offered| {
+            device_features = offered;
+            features & offered
+        })?;
+        if device_features & features != features {
+            warn!(
+                "Device features {:#x} lack negotiated features {:#x}",
+                device_features, features
+            );
+            self.add_status(DeviceStatus::FAILED);
+            return Err(Error::IoError);
+        }
+        Ok(())
+    }
+
+    /// Check that `queue` can be set up with `size` descriptors.
+    pub(crate) fn check_queue_size(&mut self, queue: u32, size: u32) -> Result {
+        let max = self.max_queue_size(queue);
+        if !size.is_power_of_two() || size > max || (self.queue_size_fixed() && size != max) {
+            warn!(
+                "Invalid size {} for queue {} of maximum size {}",
+                size, queue, max
+            );
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for dyn Transport + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Transport")
+            .field("device_type", &self.device_type())
+            .field("status", &self.status())
+            .finish()
+    }
+}
+
+/// Read the field of `width` bytes, 1, 2 or 4, at `field` in memory with a
+/// single volatile access.
+///
+/// # Safety
+///
+/// `field` must be valid for volatile reads of `width` bytes and aligned to
+/// them.
+pub(crate) unsafe fn read_mapped(field: *const u8, width: usize) -> u32 {
+    unsafe {
+        match width {
+            1 => field.read_volatile() as u32,
+            2 => (field as *const u16).read_volatile() as u32,
+            _ => (field as *const u32).read_volatile(),
+        }
+    }
+}
+
+/// Write the field of `width` bytes at `field` in memory with a single
+/// volatile access.
+///
+/// # Safety
+///
+/// `field` must be valid for volatile writes of `width` bytes and aligned to
+/// them.
+pub(crate) unsafe fn write_mapped(field: *mut u8, width: usize, value: u32) {
+    unsafe {
+        match width {
+            1 => field.write_volatile(value as u8),
+            2 => (field as *mut u16).write_volatile(value as u16),
+            _ => (field as *mut u32).write_volatile(value),
+        }
+    }
+}
+
+/// A shared memory region of a device, which the device and the driver both
+/// access directly, e.g. the DAX window of virtio-fs or the host visible
+/// memory of virtio-gpu.
+///
+/// Ref: virtio 2.10 Shared Memory Regions
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SharedMemoryRegion {
+    /// The physical address of the region.
+    pub paddr: u64,
+    /// The length of the region in bytes.
+    pub len: u64,
+}
+
+impl SharedMemoryRegion {
+    /// The virtual address of the region, as the HAL maps it.
+    pub fn vaddr(&self) -> usize {
+        crate::hal::phys_to_virt(self.paddr as usize)
+    }
+}
+
+bitflags! {
+    /// The causes of an interrupt.
+    pub struct InterruptStatus: u32 {
+        /// The device has used a buffer in at least one of the active
+        /// virtual queues.
+        const USED_BUFFER = 1 << 0;
+
+        /// The configuration of the device has changed.
+        const CONFIG_CHANGE = 1 << 1;
+    }
+}
+
+bitflags! {
+    /// The device status field.
+    pub struct DeviceStatus: u32 {
+        /// Indicates that the guest OS has found the device and recognized it
+        /// as a valid virtio device.
+        const ACKNOWLEDGE = 1;
+
+        /// Indicates that the guest OS knows how to drive the device.
+        const DRIVER = 2;
+
+        /// Indicates that something went wrong in the guest, and it has given
+        /// up on the device. This could be an internal error, or the driver
+        /// didn’t like the device for some reason, or even a fatal error
+        /// during device operation.
+        const FAILED = 128;
+
+        /// Indicates that the driver has acknowledged all the features it
+        /// understands, and feature negotiation is complete.
+        const FEATURES_OK = 8;
+
+        /// Indicates that the driver is set up and ready to drive the device.
+        const DRIVER_OK = 4;
+
+        /// Indicates that the device has experienced an error from which it
+        /// can’t recover.
+        const DEVICE_NEEDS_RESET = 64;
+    }
+}
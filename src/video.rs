@@ -0,0 +1,978 @@
+use super::*;
+use crate::queue::VirtQueue;
+use crate::volatile::ReadOnly;
+use bitflags::*;
+use core::hint::spin_loop;
+use core::mem::offset_of;
+use core::ptr;
+
+/// A virtio video encoder or decoder device.
+///
+/// Streams are created and configured through the command queue. A decoder
+/// consumes bitstream buffers queued on the input queue of a stream and
+/// returns decoded frames in buffers queued on its output queue, while an
+/// encoder consumes raw frames and returns encoded bitstream. A `RESOURCE_QUEUE`
+/// command only completes once the device is done with the buffer, so the
+/// driver keeps several commands in flight and hands the completed buffers
+/// back through [`VirtIOVideo::dequeue`].
+pub struct VirtIOVideo<'a> {
+    header: &'static mut dyn Transport,
+    /// Queue for sending commands.
+    command_queue: VirtQueue<'a>,
+    /// Queue for receiving device events.
+    event_queue: VirtQueue<'a>,
+    /// DMA area of command slots and event buffers.
+    queue_buf_dma: DMA,
+    /// Commands which have been sent to the device.
+    slots: [Option<Slot>; MAX_PENDING],
+    /// Buffers posted to the event queue.
+    event_buf: &'a mut [Event],
+    /// Whether the device is an encoder.
+    encoder: bool,
+    features: Features,
+}
+
+impl VirtIOVideo<'_> {
+    /// Create a new VirtIO-Video driver.
+    pub fn new(header: &'static mut dyn Transport) -> Result<Self> {
+        Self::from_init(DeviceInit::new(header))
+    }
+
+    /// Create a new VirtIO-Video driver, initializing the device as customized
+    /// by `init`.
+    pub fn from_init(init: DeviceInit<Acknowledged>) -> Result<Self> {
+        let encoder = match init.device_type() {
+            DeviceType::VideoEncoder => true,
+            DeviceType::VideoDecoder => false,
+            _ => return Err(Error::InvalidParam),
+        };
+        let features = Features::from_bits_truncate(init.device_features());
+        info!("Device features {:?}", features);
+        let supported_features = Features::empty();
+        let mut init = init.negotiate(supported_features.bits())?;
+
+        // read configuration space
+        let version = init.read_config::<Le32>(offset_of!(Config, version))?.get();
+        info!("protocol version {}", version);
+
+        let command_queue = init.fixed_queue(QUEUE_COMMAND, QUEUE_SIZE)?;
+        let mut event_queue = init.fixed_queue(QUEUE_EVENT, QUEUE_SIZE)?;
+
+        let queue_buf_dma = DMA::new(SLOT_PAGES + 1)?;
+        let event_buf = unsafe {
+            core::slice::from_raw_parts_mut(
+                (queue_buf_dma.vaddr() + SLOT_PAGES * PAGE_SIZE) as *mut Event,
+                QUEUE_SIZE as usize,
+            )
+        };
+        for (i, event) in event_buf.iter_mut().enumerate() {
+            let token = event_queue.add(&[], &[event.as_buf_mut()])?;
+            if token != i as u16 {
+                return Err(Error::WrongToken);
+            }
+        }
+
+        let negotiated = init.features();
+        let header = init.finish();
+
+        Ok(VirtIOVideo {
+            header,
+            command_queue,
+            event_queue,
+            queue_buf_dma,
+            slots: [None; MAX_PENDING],
+            event_buf,
+            encoder,
+            features: Features::from_bits_truncate(negotiated),
+        })
+    }
+
+    /// Acknowledge interrupt.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.header.ack_interrupt()
+    }
+
+    /// Suspend the device, e.g. before the guest enters S3.
+    ///
+    /// The device is reset so that it stops using the queues, which are set
+    /// up again by [`resume`](Self::resume). Fails with
+    /// [`Error::NotReady`] while commands are in flight, so drain the
+    /// streams and [`dequeue`](Self::dequeue) their buffers first.
+    pub fn suspend(&mut self) -> Result {
+        if self.slots.iter().any(Option::is_some) {
+            return Err(Error::NotReady);
+        }
+        self.header.reset();
+        Ok(())
+    }
+
+    /// Resume the device after [`suspend`](Self::suspend), negotiating the
+    /// same features, setting up the queues again and posting the event
+    /// buffers.
+    ///
+    /// The device forgets its streams and resources when it is reset, so
+    /// they must be created again.
+    pub fn resume(&mut self) -> Result {
+        self.header.begin_reinit(self.features.bits())?;
+        self.command_queue.reinit(self.header);
+        self.event_queue.reinit(self.header);
+        for event in self.event_buf.iter_mut() {
+            self.event_queue.add(&[], &[event.as_buf_mut()])?;
+        }
+        self.header.finish_init();
+        Ok(())
+    }
+
+    /// Whether the device is an encoder rather than a decoder.
+    pub fn is_encoder(&self) -> bool {
+        self.encoder
+    }
+
+    /// Query the capabilities of the device for one side of a stream.
+    ///
+    /// The raw response, a list of format descriptors, is copied into `buf`.
+    /// Returns the number of bytes written.
+    pub fn query_capability(&mut self, queue_type: QueueType, buf: &mut [u8]) -> Result<usize> {
+        let req = QueryCapability {
+            header: CmdHeader::new(Command::QueryCapability, 0),
+            queue_type: queue_type as u32,
+            padding: 0,
+        };
+        let slot = self.submit(req.as_buf(), SLOT_RSP_SIZE, None)?;
+        let len = self.wait(slot)?;
+        let rsp = self.slot_rsp(slot);
+        let result = check_response(rsp, Command::OkQueryCapability).map(|_| {
+            let len = len.min(rsp.len()).min(buf.len());
+            buf[..len].copy_from_slice(&rsp[..len]);
+            len
+        });
+        self.slots[slot] = None;
+        result
+    }
+
+    /// Create a stream with the given coded format.
+    ///
+    /// The coded format is consumed on the input side of a decoder stream, and
+    /// produced on the output side of an encoder stream. Both sides of the stream are backed by guest pages.
+    pub fn stream_create(&mut self, stream_id: u32, coded_format: VideoFormat) -> Result {
+        let req = StreamCreate {
+            header: CmdHeader::new(Command::StreamCreate, stream_id),
+            in_mem_type: MEM_TYPE_GUEST_PAGES,
+            out_mem_type: MEM_TYPE_GUEST_PAGES,
+            coded_format: coded_format as u32,
+            padding: 0,
+            tag: [0; 64],
+        };
+        self.command(req.as_buf())
+    }
+
+    /// Destroy a stream, releasing all its resources.
+    pub fn stream_destroy(&mut self, stream_id: u32) -> Result {
+        let req = CmdHeader::new(Command::StreamDestroy, stream_id);
+        self.command(req.as_buf())
+    }
+
+    /// Ask the device to process all queued input buffers of a stream.
+    ///
+    /// Completes once the last output buffer, marked with
+    /// [`BufferFlags::EOS`], has been returned.
+    pub fn stream_drain(&mut self, stream_id: u32) -> Result {
+        let req = CmdHeader::new(Command::StreamDrain, stream_id);
+        self.command(req.as_buf())
+    }
+
+    /// Get the parameters of one side of a stream.
+    pub fn get_params(&mut self, stream_id: u32, queue_type: QueueType) -> Result<VideoParams> {
+        let req = GetParams {
+            header: CmdHeader::new(Command::GetParams, stream_id),
+            queue_type: queue_type as u32,
+            padding: 0,
+        };
+        let slot = self.submit(req.as_buf(), size_of::<GetParamsResp>(), None)?;
+        self.wait(slot)?;
+        let rsp = self.slot_rsp(slot);
+        let result = check_response(rsp, Command::OkGetParams)
+            .map(|_| unsafe { ptr::read_unaligned(rsp.as_ptr() as *const GetParamsResp) }.params);
+        self.slots[slot] = None;
+        result
+    }
+
+    /// Set the parameters of one side of a stream.
+    pub fn set_params(&mut self, stream_id: u32, params: &VideoParams) -> Result {
+        let req = SetParams {
+            header: CmdHeader::new(Command::SetParams, stream_id),
+            params: *params,
+        };
+        self.command(req.as_buf())
+    }
+
+    /// Get the current value of an encoder control of a stream.
+    pub fn get_control(&mut self, stream_id: u32, control: VideoControl) -> Result<u32> {
+        if !self.encoder {
+            return Err(Error::InvalidParam);
+        }
+        let req = GetControl {
+            header: CmdHeader::new(Command::GetControl, stream_id),
+            control: control as u32,
+            padding: 0,
+        };
+        let slot = self.submit(req.as_buf(), size_of::<GetControlResp>(), None)?;
+        self.wait(slot)?;
+        let rsp = self.slot_rsp(slot);
+        let result = check_response(rsp, Command::OkGetControl)
+            .map(|_| unsafe { ptr::read_unaligned(rsp.as_ptr() as *const GetControlResp) }.value);
+        self.slots[slot] = None;
+        result
+    }
+
+    /// Set an encoder control of a stream, such as the bitrate or profile.
+    ///
+    /// Profiles and levels take the `VIRTIO_VIDEO_PROFILE_*` and
+    /// `VIRTIO_VIDEO_LEVEL_*` values of the spec.
+    pub fn set_control(&mut self, stream_id: u32, control: VideoControl, value: u32) -> Result {
+        if !self.encoder {
+            return Err(Error::InvalidParam);
+        }
+        let req = SetControl {
+            header: CmdHeader::new(Command::SetControl, stream_id),
+            control: control as u32,
+            padding: 0,
+            value,
+            value_padding: 0,
+        };
+        self.command(req.as_buf())
+    }
+
+    /// Attach guest memory to a resource of one side of a stream.
+    ///
+    /// The memory is described by a list of guest physical ranges which hold
+    /// all planes of the frame back to back, starting at `plane_offsets`.
+    /// The memory must stay valid until the resources of the queue are
+    /// destroyed.
+    pub fn resource_create(
+        &mut self,
+        stream_id: u32,
+        queue_type: QueueType,
+        resource_id: u32,
+        plane_offsets: &[u32],
+        entries: &[MemEntry],
+    ) -> Result {
+        if plane_offsets.is_empty()
+            || plane_offsets.len() > MAX_PLANES
+            || entries.is_empty()
+            || entries.len() > MAX_MEM_ENTRIES
+        {
+            return Err(Error::InvalidParam);
+        }
+        let mut req = ResourceCreate {
+            header: CmdHeader::new(Command::ResourceCreate, stream_id),
+            queue_type: queue_type as u32,
+            resource_id,
+            planes_layout: PLANES_LAYOUT_SINGLE_BUFFER,
+            num_planes: plane_offsets.len() as u32,
+            plane_offsets: [0; MAX_PLANES],
+            num_entries: [0; MAX_PLANES],
+            entries: [MemEntry::default(); MAX_MEM_ENTRIES],
+        };
+        req.plane_offsets[..plane_offsets.len()].copy_from_slice(plane_offsets);
+        // with a single buffer layout, all entries are counted on plane 0
+        req.num_entries[0] = entries.len() as u32;
+        req.entries[..entries.len()].copy_from_slice(entries);
+        let len =
+            size_of::<ResourceCreate>() - core::mem::size_of_val(&req.entries[entries.len()..]);
+        self.command(&req.as_buf()[..len])
+    }
+
+    /// Destroy all resources of one side of a stream.
+    pub fn resource_destroy_all(&mut self, stream_id: u32, queue_type: QueueType) -> Result {
+        let req = QueueCommand {
+            header: CmdHeader::new(Command::ResourceDestroyAll, stream_id),
+            queue_type: queue_type as u32,
+            padding: 0,
+        };
+        self.command(req.as_buf())
+    }
+
+    /// Return all queued buffers of one side of a stream without processing.
+    pub fn queue_clear(&mut self, stream_id: u32, queue_type: QueueType) -> Result {
+        let req = QueueCommand {
+            header: CmdHeader::new(Command::QueueClear, stream_id),
+            queue_type: queue_type as u32,
+            padding: 0,
+        };
+        self.command(req.as_buf())
+    }
+
+    /// Queue a resource to one side of a stream.
+    ///
+    /// For the input side of a decoder, `data_sizes` holds the number of
+    /// bytes of bitstream in the buffer, and for the input side of an encoder
+    /// the number of bytes of each plane of the raw frame. For the output
+    /// side, it is ignored by the device. The buffer is returned by [`VirtIOVideo::dequeue`] once
+    /// the device is done with it.
+    pub fn resource_queue(
+        &mut self,
+        stream_id: u32,
+        queue_type: QueueType,
+        resource_id: u32,
+        timestamp: u64,
+        data_sizes: &[u32],
+    ) -> Result {
+        if data_sizes.len() > MAX_PLANES {
+            return Err(Error::InvalidParam);
+        }
+        let mut req = ResourceQueue {
+            header: CmdHeader::new(Command::ResourceQueue, stream_id),
+            queue_type: queue_type as u32,
+            resource_id,
+            timestamp,
+            num_data_sizes: data_sizes.len() as u32,
+            data_sizes: [0; MAX_PLANES],
+            padding: 0,
+        };
+        req.data_sizes[..data_sizes.len()].copy_from_slice(data_sizes);
+        let resource = Resource {
+            stream_id,
+            queue_type,
+            resource_id,
+        };
+        self.submit(req.as_buf(), size_of::<ResourceQueueResp>(), Some(resource))?;
+        Ok(())
+    }
+
+    /// Get a buffer which the device has finished processing, if any.
+    pub fn dequeue(&mut self) -> Result<Option<DequeuedBuffer>> {
+        self.process_used()?;
+        let found = self.slots.iter().enumerate().find_map(|(slot, s)| match s {
+            Some(s) if s.done => Some((slot, s.resource?)),
+            _ => None,
+        });
+        let (slot, resource) = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let rsp = self.slot_rsp(slot);
+        let result = check_response(rsp, Command::OkResourceQueue).map(|_| {
+            let rsp = unsafe { ptr::read_unaligned(rsp.as_ptr() as *const ResourceQueueResp) };
+            DequeuedBuffer {
+                stream_id: resource.stream_id,
+                queue_type: resource.queue_type,
+                resource_id: resource.resource_id,
+                timestamp: rsp.timestamp,
+                flags: BufferFlags::from_bits_truncate(rsp.flags),
+                offset: rsp.offset,
+                size: rsp.size,
+            }
+        });
+        self.slots[slot] = None;
+        result.map(Some)
+    }
+
+    /// Pop a pending event sent by the device, if any.
+    pub fn pop_event(&mut self) -> Result<Option<VideoEvent>> {
+        if !self.event_queue.can_pop() {
+            return Ok(None);
+        }
+        let (token, _) = self.event_queue.pop_used()?;
+        let event = &mut self.event_buf[token as usize];
+        let repr = VideoEvent::from(*event);
+        // requeue
+        self.event_queue
+            .add_notify(self.header, &[], &[event.as_buf_mut()])?;
+        Ok(Some(repr))
+    }
+
+    /// Send a command which is answered with no data, and block for the
+    /// response.
+    fn command(&mut self, req: &[u8]) -> Result {
+        let slot = self.submit(req, size_of::<CmdHeader>(), None)?;
+        self.wait(slot)?;
+        let result = check_response(self.slot_rsp(slot), Command::OkNodata);
+        self.slots[slot] = None;
+        result
+    }
+
+    /// Copy a command into a free slot and send it to the device.
+    fn submit(&mut self, req: &[u8], rsp_len: usize, resource: Option<Resource>) -> Result<usize> {
+        if req.len() > SLOT_REQ_SIZE || rsp_len > SLOT_RSP_SIZE {
+            return Err(Error::BufferTooSmall);
+        }
+        let slot = self
+            .slots
+            .iter()
+            .position(Option::is_none)
+            .ok_or(Error::NotReady)?;
+        let buf = self.slot_buf(slot);
+        let (req_buf, rsp_buf) = buf.split_at_mut(SLOT_REQ_SIZE);
+        req_buf[..req.len()].copy_from_slice(req);
+        rsp_buf.fill(0);
+        let token = self
+            .command_queue
+            .add(&[&req_buf[..req.len()]], &[&mut rsp_buf[..rsp_len]])?;
+        if self.command_queue.should_notify() {
+            self.header.notify(QUEUE_COMMAND as u32);
+        }
+        self.slots[slot] = Some(Slot {
+            token,
+            done: false,
+            len: 0,
+            resource,
+        });
+        Ok(slot)
+    }
+
+    /// Block until the command in the slot is completed, returning the
+    /// length of its response.
+    fn wait(&mut self, slot: usize) -> Result<usize> {
+        loop {
+            self.process_used()?;
+            match self.slots[slot] {
+                Some(s) if s.done => return Ok(s.len as usize),
+                Some(_) => spin_loop(),
+                None => return Err(Error::InvalidParam),
+            }
+        }
+    }
+
+    /// Mark the slots of all completed commands as done.
+    fn process_used(&mut self) -> Result {
+        while self.command_queue.can_pop() {
+            let (token, len) = self.command_queue.pop_used()?;
+            match self
+                .slots
+                .iter_mut()
+                .flatten()
+                .find(|s| !s.done && s.token == token)
+            {
+                Some(s) => {
+                    s.done = true;
+                    s.len = len;
+                }
+                None => warn!("unexpected token {} in command queue", token),
+            }
+        }
+        Ok(())
+    }
+
+    /// The request and response buffer of a slot.
+    fn slot_buf(&self, slot: usize) -> &'static mut [u8] {
+        unsafe { &mut self.queue_buf_dma.as_buf()[slot * SLOT_SIZE..(slot + 1) * SLOT_SIZE] }
+    }
+
+    /// The response buffer of a slot.
+    fn slot_rsp(&self, slot: usize) -> &'static [u8] {
+        &self.slot_buf(slot)[SLOT_REQ_SIZE..]
+    }
+}
+
+impl Drop for VirtIOVideo<'_> {
+    fn drop(&mut self) {
+        // stop the device from using the queues, so that they are freed
+        self.header.reset();
+        self.command_queue.unset(self.header);
+        self.event_queue.unset(self.header);
+    }
+}
+
+impl Driver for VirtIOVideo<'_> {
+    fn device_type(&self) -> DeviceType {
+        self.header.device_type()
+    }
+
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
+        self.header.ack_interrupt_status()
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.features.bits()
+    }
+
+    fn enable_notifications(&mut self) {
+        self.command_queue.set_used_notifications(true);
+        self.event_queue.set_used_notifications(true);
+    }
+
+    fn disable_notifications(&mut self) {
+        self.command_queue.set_used_notifications(false);
+        self.event_queue.set_used_notifications(false);
+    }
+
+    fn metrics(&self, f: &mut dyn FnMut(Metric)) {
+        self.command_queue.metrics().report("commandq", f);
+        self.event_queue.metrics().report("eventq", f);
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.header.needs_reset()
+    }
+
+    fn recover(&mut self, lost: &mut dyn FnMut(LostRequest)) -> Result {
+        self.command_queue.report_lost("commandq", lost);
+        self.header.reset();
+        self.resume()
+    }
+
+    #[cfg(feature = "time-hal")]
+    fn set_timeout(&mut self, ticks: Option<u64>) {
+        self.command_queue.set_timeout(ticks);
+        self.event_queue.set_timeout(ticks);
+    }
+}
+
+/// Return error if the response type is not same as expected.
+fn check_response(rsp: &[u8], expected: Command) -> Result {
+    let header = unsafe { ptr::read_unaligned(rsp.as_ptr() as *const CmdHeader) };
+    if header.type_ == expected as u32 {
+        Ok(())
+    } else if header.type_ == Command::ErrInvalidParameter as u32
+        || header.type_ == Command::ErrInvalidStreamId as u32
+        || header.type_ == Command::ErrInvalidResourceId as u32
+    {
+        Err(Error::InvalidParam)
+    } else {
+        Err(Error::IoError)
+    }
+}
+
+/// A command sent to the device.
+#[derive(Debug, Copy, Clone)]
+struct Slot {
+    /// The token of the descriptor chain.
+    token: u16,
+    /// Whether the device has responded.
+    done: bool,
+    /// The length of the response.
+    len: u32,
+    /// The resource queued by a `RESOURCE_QUEUE` command.
+    resource: Option<Resource>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Resource {
+    stream_id: u32,
+    queue_type: QueueType,
+    resource_id: u32,
+}
+
+/// A buffer returned by the device.
+#[derive(Debug, Copy, Clone)]
+pub struct DequeuedBuffer {
+    /// The stream the buffer belongs to.
+    pub stream_id: u32,
+    /// The side of the stream the buffer was queued to.
+    pub queue_type: QueueType,
+    /// The resource ID of the buffer.
+    pub resource_id: u32,
+    /// The timestamp of the frame in the buffer.
+    pub timestamp: u64,
+    /// Flags describing the content of the buffer.
+    pub flags: BufferFlags,
+    /// The offset of the data in the buffer.
+    pub offset: u32,
+    /// The size of the data in the buffer.
+    pub size: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct Config {
+    /// The protocol version supported by the device.
+    version: ReadOnly<Le32>,
+    /// The maximum length of a capability response.
+    max_caps_length: ReadOnly<Le32>,
+    /// The maximum length of any response.
+    max_resp_length: ReadOnly<Le32>,
+}
+
+bitflags! {
+    struct Features: u64 {
+        /// Guest pages can be used for video buffers.
+        const RESOURCE_GUEST_PAGES  = 1 << 0;
+        /// The device can use non-contiguous memory for video buffers.
+        const RESOURCE_NON_CONTIG   = 1 << 1;
+        /// Objects exported by another virtio device can be used.
+        const RESOURCE_VIRTIO_OBJECT = 1 << 2;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Command {
+    QueryCapability = 0x0100,
+    StreamCreate = 0x0101,
+    StreamDestroy = 0x0102,
+    StreamDrain = 0x0103,
+    ResourceCreate = 0x0104,
+    ResourceQueue = 0x0105,
+    ResourceDestroyAll = 0x0106,
+    QueueClear = 0x0107,
+    GetParams = 0x0108,
+    SetParams = 0x0109,
+    QueryControl = 0x010a,
+    GetControl = 0x010b,
+    SetControl = 0x010c,
+
+    OkNodata = 0x0200,
+    OkQueryCapability = 0x0201,
+    OkResourceQueue = 0x0202,
+    OkGetParams = 0x0203,
+    OkQueryControl = 0x0204,
+    OkGetControl = 0x0205,
+
+    ErrInvalidOperation = 0x0300,
+    ErrOutOfMemory = 0x0301,
+    ErrInvalidStreamId = 0x0302,
+    ErrInvalidResourceId = 0x0303,
+    ErrInvalidParameter = 0x0304,
+    ErrUnsupportedControl = 0x0305,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct CmdHeader {
+    type_: u32,
+    stream_id: u32,
+}
+
+impl CmdHeader {
+    fn new(type_: Command, stream_id: u32) -> CmdHeader {
+        CmdHeader {
+            type_: type_ as u32,
+            stream_id,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct QueryCapability {
+    header: CmdHeader,
+    queue_type: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct StreamCreate {
+    header: CmdHeader,
+    in_mem_type: u32,
+    out_mem_type: u32,
+    coded_format: u32,
+    padding: u32,
+    tag: [u8; 64],
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct QueueCommand {
+    header: CmdHeader,
+    queue_type: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct ResourceCreate {
+    header: CmdHeader,
+    queue_type: u32,
+    resource_id: u32,
+    planes_layout: u32,
+    num_planes: u32,
+    plane_offsets: [u32; MAX_PLANES],
+    num_entries: [u32; MAX_PLANES],
+    // followed by the used part of `entries` only
+    entries: [MemEntry; MAX_MEM_ENTRIES],
+}
+
+/// A range of guest physical memory backing a resource.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct MemEntry {
+    /// The guest physical address of the range.
+    pub addr: u64,
+    /// The length of the range in bytes.
+    pub length: u32,
+    padding: u32,
+}
+
+impl MemEntry {
+    /// Create a memory entry from a guest physical range.
+    pub fn new(addr: u64, length: u32) -> Self {
+        MemEntry {
+            addr,
+            length,
+            padding: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct ResourceQueue {
+    header: CmdHeader,
+    queue_type: u32,
+    resource_id: u32,
+    timestamp: u64,
+    num_data_sizes: u32,
+    data_sizes: [u32; MAX_PLANES],
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct ResourceQueueResp {
+    header: CmdHeader,
+    timestamp: u64,
+    flags: u32,
+    offset: u32,
+    size: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct GetParams {
+    header: CmdHeader,
+    queue_type: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct GetParamsResp {
+    header: CmdHeader,
+    params: VideoParams,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct SetParams {
+    header: CmdHeader,
+    params: VideoParams,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct GetControl {
+    header: CmdHeader,
+    control: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct GetControlResp {
+    header: CmdHeader,
+    value: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct SetControl {
+    header: CmdHeader,
+    control: u32,
+    padding: u32,
+    value: u32,
+    value_padding: u32,
+}
+
+/// Encoder controls of a stream.
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VideoControl {
+    /// The target bitrate in bits per second.
+    Bitrate = 1,
+    /// The profile of the coded format.
+    Profile = 2,
+    /// The level of the coded format.
+    Level = 3,
+    /// Force the next frame to be encoded as a key frame.
+    ForceKeyframe = 4,
+    /// Constant (1) or variable (0) bitrate.
+    BitrateMode = 5,
+    /// The peak bitrate in bits per second for variable bitrate.
+    BitratePeak = 6,
+    /// Whether to prepend SPS/PPS to every IDR frame.
+    PrependSpsPpsToIdr = 7,
+}
+
+/// The parameters of one side of a stream.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct VideoParams {
+    /// The side of the stream, a [`QueueType`] value.
+    pub queue_type: u32,
+    /// The format of the data, a [`VideoFormat`] value.
+    pub format: u32,
+    /// The width of a frame in pixels.
+    pub frame_width: u32,
+    /// The height of a frame in pixels.
+    pub frame_height: u32,
+    /// The minimum number of buffers the stream needs.
+    pub min_buffers: u32,
+    /// The maximum number of buffers the stream supports.
+    pub max_buffers: u32,
+    /// The visible rectangle of a frame.
+    pub crop: Crop,
+    /// The number of frames per second.
+    pub frame_rate: u32,
+    /// The number of planes of a frame.
+    pub num_planes: u32,
+    /// The layout of each plane.
+    pub plane_formats: [PlaneFormat; MAX_PLANES],
+    /// The memory type of the resources.
+    pub resource_type: u32,
+    padding: u32,
+}
+
+/// A rectangle within a frame.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+#[allow(missing_docs)]
+pub struct Crop {
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The layout of a plane of a frame.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PlaneFormat {
+    /// The size of the plane in bytes.
+    pub plane_size: u32,
+    /// The length of a line of the plane in bytes.
+    pub stride: u32,
+}
+
+/// The side of a stream.
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum QueueType {
+    /// The side consuming data, bitstream for a decoder and raw frames for
+    /// an encoder.
+    Input = 0x100,
+    /// The side producing data, raw frames for a decoder and bitstream for
+    /// an encoder.
+    Output = 0x101,
+}
+
+/// Video formats.
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[allow(missing_docs)]
+pub enum VideoFormat {
+    // raw formats
+    ARGB8888 = 1,
+    BGRA8888 = 2,
+    NV12 = 3,
+    YUV420 = 4,
+    YVU420 = 5,
+
+    // coded formats
+    MPEG2 = 0x1000,
+    MPEG4 = 0x1001,
+    H264 = 0x1002,
+    HEVC = 0x1003,
+    VP8 = 0x1004,
+    VP9 = 0x1005,
+}
+
+bitflags! {
+    /// Flags of a returned buffer.
+    pub struct BufferFlags: u32 {
+        /// The device failed to process the buffer.
+        const ERR = 1 << 0;
+        /// The buffer is the last one of the stream.
+        const EOS = 1 << 1;
+        /// The buffer holds a key frame.
+        const IFRAME = 1 << 2;
+        /// The buffer holds a predicted frame.
+        const PFRAME = 1 << 3;
+        /// The buffer holds a bidirectionally predicted frame.
+        const BFRAME = 1 << 4;
+    }
+}
+
+/// An event written by the device into the event queue.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Event {
+    event_type: u32,
+    stream_id: u32,
+}
+
+/// A notification sent by the device.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VideoEvent {
+    /// An unrecoverable error occurred on a stream.
+    Error(u32),
+    /// The resolution of the decoded frames of a stream has changed. The new
+    /// parameters of its output side have to be queried.
+    ResolutionChanged(u32),
+    /// An event type unknown to the driver, with its stream ID.
+    Unknown(u32, u32),
+}
+
+impl From<Event> for VideoEvent {
+    fn from(e: Event) -> Self {
+        match e.event_type {
+            EVENT_ERROR => VideoEvent::Error(e.stream_id),
+            EVENT_DECODER_RESOLUTION_CHANGED => VideoEvent::ResolutionChanged(e.stream_id),
+            _ => VideoEvent::Unknown(e.event_type, e.stream_id),
+        }
+    }
+}
+
+const EVENT_ERROR: u32 = 0x0100;
+const EVENT_DECODER_RESOLUTION_CHANGED: u32 = 0x0200;
+
+const MEM_TYPE_GUEST_PAGES: u32 = 0;
+const PLANES_LAYOUT_SINGLE_BUFFER: u32 = 1 << 0;
+
+unsafe impl AsBuf for CmdHeader {}
+unsafe impl AsBuf for QueryCapability {}
+unsafe impl AsBuf for StreamCreate {}
+unsafe impl AsBuf for QueueCommand {}
+unsafe impl AsBuf for ResourceCreate {}
+unsafe impl AsBuf for ResourceQueue {}
+unsafe impl AsBuf for GetParams {}
+unsafe impl AsBuf for SetParams {}
+unsafe impl AsBuf for GetControl {}
+unsafe impl AsBuf for SetControl {}
+unsafe impl AsBuf for Event {}
+
+const QUEUE_COMMAND: usize = 0;
+const QUEUE_EVENT: usize = 1;
+
+/// The maximum number of planes of a frame.
+const MAX_PLANES: usize = 8;
+/// The maximum number of memory entries of a resource.
+const MAX_MEM_ENTRIES: usize = 16;
+
+/// The maximum number of commands in flight, each taking two descriptors.
+const MAX_PENDING: usize = QUEUE_SIZE as usize / 2;
+const SLOT_REQ_SIZE: usize = 512;
+const SLOT_RSP_SIZE: usize = 512;
+const SLOT_SIZE: usize = SLOT_REQ_SIZE + SLOT_RSP_SIZE;
+const SLOT_PAGES: usize = MAX_PENDING * SLOT_SIZE / PAGE_SIZE;
+
+// a parameter that can change
+const QUEUE_SIZE: u16 = 16;
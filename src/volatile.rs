@@ -0,0 +1,76 @@
+//! Volatile accessors for memory shared with the device.
+//!
+//! The device may read or write the registers, configuration space and
+//! virtqueues at any time, so the compiler must neither elide nor merge
+//! accesses to them. Each field of such a structure is wrapped in one of the
+//! types here, which only access the value with a single volatile read or
+//! write, and whose name states which side writes it.
+//!
+//! The value lives in an `UnsafeCell`, so the structures can be accessed
+//! through shared references while the device changes them.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+
+/// A field which is only read by the driver.
+#[repr(transparent)]
+pub struct ReadOnly<T: Copy>(UnsafeCell<T>);
+
+/// A field which is only written by the driver.
+#[repr(transparent)]
+pub struct WriteOnly<T: Copy>(UnsafeCell<T>);
+
+/// A field which is both read and written by the driver.
+#[repr(transparent)]
+pub struct Volatile<T: Copy>(UnsafeCell<T>);
+
+impl<T: Copy> ReadOnly<T> {
+    /// Read the value.
+    pub fn read(&self) -> T {
+        unsafe { self.0.get().read_volatile() }
+    }
+}
+
+impl<T: Copy> WriteOnly<T> {
+    /// Write the value.
+    pub fn write(&self, value: T) {
+        unsafe { self.0.get().write_volatile(value) }
+    }
+}
+
+impl<T: Copy> Volatile<T> {
+    /// Read the value.
+    pub fn read(&self) -> T {
+        unsafe { self.0.get().read_volatile() }
+    }
+
+    /// Write the value.
+    pub fn write(&self, value: T) {
+        unsafe { self.0.get().write_volatile(value) }
+    }
+}
+
+// SAFETY: every access is a single volatile read or write of a `Copy` value,
+// and the drivers only use these types for naturally aligned fields of at
+// most 64 bits.
+unsafe impl<T: Copy + Send> Sync for ReadOnly<T> {}
+unsafe impl<T: Copy + Send> Sync for WriteOnly<T> {}
+unsafe impl<T: Copy + Send> Sync for Volatile<T> {}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for ReadOnly<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ReadOnly").field(&self.read()).finish()
+    }
+}
+
+impl<T: Copy> fmt::Debug for WriteOnly<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WriteOnly")
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for Volatile<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Volatile").field(&self.read()).finish()
+    }
+}
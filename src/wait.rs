@@ -0,0 +1,367 @@
+//! Primitives for waiting on devices from `async` code.
+//!
+//! They only use [`Waker`], so they work with any executor. The waking side
+//! never blocks, so it can run in an interrupt handler, e.g. after
+//! [`IrqDispatcher::handle_interrupt`](crate::IrqDispatcher::handle_interrupt)
+//! or [`DeviceManager::handle_irq`](crate::DeviceManager::handle_irq):
+//!
+//! - [`WakerCell`] holds the waker of a single task,
+//! - [`TokenWakers`] holds one per token of a queue, for tasks waiting on
+//!   their own requests,
+//! - [`WaitQueue`] is an intrusive list of any number of waiting tasks.
+
+use super::*;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::hint::spin_loop;
+use core::marker::PhantomPinned;
+use core::pin::Pin;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::{Context, Poll, Waker};
+
+const WAITING: u8 = 0;
+const REGISTERING: u8 = 1;
+const WAKING: u8 = 2;
+
+/// The waker of a task waiting for an event.
+///
+/// A waker registered while the cell is woken is woken right away, so no
+/// event is lost.
+pub struct WakerCell {
+    state: AtomicU8,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+// SAFETY: `waker` is only accessed by the side which moved `state` away from
+// `WAITING`.
+unsafe impl Send for WakerCell {}
+unsafe impl Sync for WakerCell {}
+
+impl WakerCell {
+    /// Create a cell without a waker.
+    pub const fn new() -> Self {
+        WakerCell {
+            state: AtomicU8::new(WAITING),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    /// Register `waker` to be woken by the next [`wake`](Self::wake),
+    /// replacing the previous one.
+    pub fn register(&self, waker: &Waker) {
+        match self.state.compare_exchange(
+            WAITING,
+            REGISTERING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                let slot = unsafe { &mut *self.waker.get() };
+                if !matches!(slot, Some(old) if old.will_wake(waker)) {
+                    *slot = Some(waker.clone());
+                }
+                if self
+                    .state
+                    .compare_exchange(REGISTERING, WAITING, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    // woken while registering
+                    let waker = slot.take();
+                    self.state.store(WAITING, Ordering::Release);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            // being woken, so poll again
+            Err(WAKING) => waker.wake_by_ref(),
+            // registered concurrently by another task, which is a misuse
+            Err(_) => {}
+        }
+    }
+
+    /// Wake the registered waker, if any.
+    pub fn wake(&self) {
+        if self.state.fetch_or(WAKING, Ordering::AcqRel) == WAITING {
+            let waker = unsafe { (*self.waker.get()).take() };
+            self.state.fetch_and(!WAKING, Ordering::Release);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Poll for an event with `poll`, which returns `Some` once it happened,
+    /// registering the waker of `cx` if it has not.
+    pub fn poll_with<T>(
+        &self,
+        cx: &mut Context<'_>,
+        mut poll: impl FnMut() -> Option<T>,
+    ) -> Poll<T> {
+        if let Some(value) = poll() {
+            return Poll::Ready(value);
+        }
+        self.register(cx.waker());
+        // check again in case the event happened before registering
+        match poll() {
+            Some(value) => Poll::Ready(value),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Default for WakerCell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The wakers of tasks waiting for the device to use the buffers of a queue,
+/// one for each of the `N` tokens.
+pub struct TokenWakers<const N: usize> {
+    wakers: [WakerCell; N],
+}
+
+impl<const N: usize> TokenWakers<N> {
+    /// Create an empty set of wakers.
+    pub const fn new() -> Self {
+        TokenWakers {
+            wakers: [const { WakerCell::new() }; N],
+        }
+    }
+
+    /// Register `waker` to be woken when the buffers with `token` are used.
+    pub fn register(&self, token: u16, waker: &Waker) -> Result {
+        self.get(token)?.register(waker);
+        Ok(())
+    }
+
+    /// Wake the waker registered for `token`, if any.
+    pub fn wake(&self, token: u16) {
+        if let Ok(waker) = self.get(token) {
+            waker.wake();
+        }
+    }
+
+    /// Wake all registered wakers, e.g. when the interrupt does not tell
+    /// which buffers were used.
+    pub fn wake_all(&self) {
+        for waker in self.wakers.iter() {
+            waker.wake();
+        }
+    }
+
+    /// Poll with `poll` for the buffers with `token` to be used, as in
+    /// [`WakerCell::poll_with`].
+    pub fn poll_with<T>(
+        &self,
+        token: u16,
+        cx: &mut Context<'_>,
+        poll: impl FnMut() -> Option<T>,
+    ) -> Poll<Result<T>> {
+        match self.get(token) {
+            Ok(waker) => waker.poll_with(cx, poll).map(Ok),
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+
+    fn get(&self, token: u16) -> Result<&WakerCell> {
+        self.wakers.get(token as usize).ok_or(Error::InvalidParam)
+    }
+}
+
+impl<const N: usize> Default for TokenWakers<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The number of wakers taken from a [`WaitQueue`] before waking them.
+const WAKE_BATCH: usize = 8;
+
+/// A list of any number of tasks waiting for an event.
+///
+/// Each [`Wait`] future links itself into the list while it is pending, so
+/// the list needs no storage of its own.
+pub struct WaitQueue {
+    lock: AtomicBool,
+    /// Whether the waiters should be woken once the lock is free.
+    pending: AtomicBool,
+    head: UnsafeCell<Option<NonNull<Waiter>>>,
+}
+
+// SAFETY: The list is only accessed while holding `lock`.
+unsafe impl Send for WaitQueue {}
+unsafe impl Sync for WaitQueue {}
+
+/// A node of a [`WaitQueue`], owned by a [`Wait`] future.
+#[derive(Default)]
+struct Waiter {
+    waker: Option<Waker>,
+    woken: bool,
+    linked: bool,
+    prev: Option<NonNull<Waiter>>,
+    next: Option<NonNull<Waiter>>,
+}
+
+impl WaitQueue {
+    /// Create an empty list.
+    pub const fn new() -> Self {
+        WaitQueue {
+            lock: AtomicBool::new(false),
+            pending: AtomicBool::new(false),
+            head: UnsafeCell::new(None),
+        }
+    }
+
+    /// Wait for a call to [`wake_all`](Self::wake_all) after the future is
+    /// first polled.
+    ///
+    /// The future may also complete spuriously, so the caller should check
+    /// for the event it waits for and wait again if it did not happen.
+    pub fn wait(&self) -> Wait<'_> {
+        Wait {
+            queue: self,
+            waiter: UnsafeCell::new(Waiter::default()),
+            _pinned: PhantomPinned,
+        }
+    }
+
+    /// Wake all waiting tasks.
+    ///
+    /// This does not block: if the list is locked, e.g. by a task interrupted
+    /// on this core, the tasks are woken when it is unlocked.
+    pub fn wake_all(&self) {
+        self.pending.store(true, Ordering::Release);
+        self.wake_pending();
+    }
+
+    fn wake_pending(&self) {
+        while self.pending.load(Ordering::Acquire) {
+            if !self.try_lock() {
+                // the holder of the lock wakes them when it unlocks
+                return;
+            }
+            self.pending.store(false, Ordering::Relaxed);
+            let mut wakers: [Option<Waker>; WAKE_BATCH] = Default::default();
+            for slot in wakers.iter_mut() {
+                let head = unsafe { &mut *self.head.get() };
+                let node = match *head {
+                    Some(node) => node,
+                    None => break,
+                };
+                let waiter = unsafe { &mut *node.as_ptr() };
+                self.unlink(waiter);
+                waiter.woken = true;
+                *slot = waiter.waker.take();
+            }
+            if unsafe { (*self.head.get()).is_some() } {
+                // wake the rest in the next round
+                self.pending.store(true, Ordering::Relaxed);
+            }
+            self.lock.store(false, Ordering::Release);
+            for waker in IntoIterator::into_iter(wakers).flatten() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Run `f` while holding the lock, then wake the tasks woken meanwhile.
+    fn with_lock<T>(&self, f: impl FnOnce() -> T) -> T {
+        while !self.try_lock() {
+            spin_loop();
+        }
+        let result = f();
+        self.lock.store(false, Ordering::Release);
+        self.wake_pending();
+        result
+    }
+
+    fn try_lock(&self) -> bool {
+        self.lock
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Link `waiter` at the head of the list. Must hold the lock.
+    fn link(&self, waiter: &mut Waiter) {
+        let head = unsafe { &mut *self.head.get() };
+        waiter.prev = None;
+        waiter.next = *head;
+        if let Some(next) = *head {
+            unsafe { (*next.as_ptr()).prev = Some(NonNull::from(&mut *waiter)) };
+        }
+        *head = Some(NonNull::from(&mut *waiter));
+        waiter.linked = true;
+    }
+
+    /// Unlink `waiter` from the list. Must hold the lock.
+    fn unlink(&self, waiter: &mut Waiter) {
+        match waiter.prev {
+            Some(prev) => unsafe { (*prev.as_ptr()).next = waiter.next },
+            None => unsafe { *self.head.get() = waiter.next },
+        }
+        if let Some(next) = waiter.next {
+            unsafe { (*next.as_ptr()).prev = waiter.prev };
+        }
+        waiter.prev = None;
+        waiter.next = None;
+        waiter.linked = false;
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A future waiting in a [`WaitQueue`], returned by [`WaitQueue::wait`].
+pub struct Wait<'a> {
+    queue: &'a WaitQueue,
+    /// Linked into the queue while pending, so the future must not move.
+    waiter: UnsafeCell<Waiter>,
+    _pinned: PhantomPinned,
+}
+
+// SAFETY: `waiter` is only accessed while holding the lock of the queue.
+unsafe impl Send for Wait<'_> {}
+unsafe impl Sync for Wait<'_> {}
+
+impl Future for Wait<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let queue = self.queue;
+        let waiter = self.waiter.get();
+        queue.with_lock(|| {
+            // the waiter is not moved out, and unlinked before it is dropped,
+            // and other tasks only access it while holding the lock
+            let waiter = unsafe { &mut *waiter };
+            if waiter.woken {
+                return Poll::Ready(());
+            }
+            if !matches!(&waiter.waker, Some(old) if old.will_wake(cx.waker())) {
+                waiter.waker = Some(cx.waker().clone());
+            }
+            if !waiter.linked {
+                queue.link(waiter);
+            }
+            Poll::Pending
+        })
+    }
+}
+
+impl Drop for Wait<'_> {
+    fn drop(&mut self) {
+        let queue = self.queue;
+        let waiter = self.waiter.get();
+        queue.with_lock(|| {
+            let waiter = unsafe { &mut *waiter };
+            if waiter.linked {
+                queue.unlink(waiter);
+            }
+        });
+    }
+}
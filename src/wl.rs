@@ -0,0 +1,595 @@
+use super::*;
+use crate::queue::VirtQueue;
+use bitflags::*;
+use core::ptr;
+
+/// The crosvm virtio Wayland device.
+///
+/// It forwards Wayland protocol messages between guest clients and the host
+/// compositor. Host-side file descriptors are represented in the guest as
+/// virtual file descriptors (VFDs): connections to the compositor, pipes,
+/// and shared memory buffers which the host maps into the guest physical
+/// address space. VFD IDs sent along with a message are translated by the
+/// device into host file descriptors.
+///
+/// Commands are sent through the out queue, while messages and new VFDs from
+/// the host arrive in buffers posted to the in queue.
+pub struct VirtIOWl<'a> {
+    header: &'static mut dyn Transport,
+    /// Queue for receiving messages from the host.
+    in_queue: VirtQueue<'a>,
+    /// Queue for sending commands to the host.
+    out_queue: VirtQueue<'a>,
+    /// DMA area of the in buffers.
+    in_buf_dma: DMA,
+    /// DMA area of the command and response buffers.
+    out_buf_dma: DMA,
+    features: Features,
+}
+
+impl VirtIOWl<'_> {
+    /// Create a new VirtIO-Wl driver.
+    pub fn new(header: &'static mut dyn Transport) -> Result<Self> {
+        Self::from_init(DeviceInit::new(header))
+    }
+
+    /// Create a new VirtIO-Wl driver, initializing the device as customized
+    /// by `init`.
+    pub fn from_init(init: DeviceInit<Acknowledged>) -> Result<Self> {
+        let features = Features::from_bits_truncate(init.device_features());
+        info!("Device features {:?}", features);
+        let supported_features = Features::TRANS_FLAGS;
+        let mut init = init.negotiate(supported_features.bits())?;
+        let negotiated = Features::from_bits_truncate(init.features());
+
+        let mut in_queue = init.fixed_queue(QUEUE_IN, QUEUE_SIZE)?;
+        let out_queue = init.queue(QUEUE_OUT, 2)?;
+
+        let in_buf_dma = DMA::new(QUEUE_SIZE as usize * IN_BUFFER_SIZE / PAGE_SIZE)?;
+        let out_buf_dma = DMA::new(2 * OUT_BUFFER_SIZE / PAGE_SIZE)?;
+        let in_buf = unsafe { in_buf_dma.as_buf() };
+        for (i, buf) in in_buf.chunks_exact_mut(IN_BUFFER_SIZE).enumerate() {
+            let token = in_queue.add(&[], &[buf])?;
+            if token != i as u16 {
+                return Err(Error::WrongToken);
+            }
+        }
+
+        let header = init.finish();
+
+        Ok(VirtIOWl {
+            header,
+            in_queue,
+            out_queue,
+            in_buf_dma,
+            out_buf_dma,
+            features: negotiated,
+        })
+    }
+
+    /// Acknowledge interrupt.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.header.ack_interrupt()
+    }
+
+    /// Suspend the device, e.g. before the guest enters S3.
+    ///
+    /// The device is reset so that it stops using the queues, which are set
+    /// up again by [`resume`](Self::resume). Messages which have not been
+    /// received are dropped.
+    pub fn suspend(&mut self) -> Result {
+        self.header.reset();
+        Ok(())
+    }
+
+    /// Resume the device after [`suspend`](Self::suspend), negotiating the
+    /// same features, setting up the queues again and posting the in
+    /// buffers.
+    ///
+    /// The host closes all VFDs when the device is reset, so they must be
+    /// created again.
+    pub fn resume(&mut self) -> Result {
+        self.header.begin_reinit(self.features.bits())?;
+        self.in_queue.reinit(self.header);
+        self.out_queue.reinit(self.header);
+        let in_buf = unsafe { self.in_buf_dma.as_buf() };
+        for buf in in_buf.chunks_exact_mut(IN_BUFFER_SIZE) {
+            self.in_queue.add(&[], &[buf])?;
+        }
+        self.header.finish_init();
+        Ok(())
+    }
+
+    /// Open a new connection to the host compositor as VFD `vfd_id`.
+    ///
+    /// IDs chosen by the guest must not have the most significant bit set,
+    /// which marks VFDs allocated by the host.
+    pub fn new_context(&mut self, vfd_id: u32) -> Result {
+        let req = VfdNew::new(Command::VfdNewCtx, vfd_id, VfdFlags::empty(), 0);
+        self.vfd_new(vfd_id, req).map(|_| ())
+    }
+
+    /// Open a new connection to the named host compositor socket as VFD
+    /// `vfd_id`.
+    pub fn new_context_named(&mut self, vfd_id: u32, name: &str) -> Result {
+        let mut req = VfdNew::new(Command::VfdNewCtxNamed, vfd_id, VfdFlags::empty(), 0);
+        if name.len() >= req.union.len() {
+            return Err(Error::InvalidParam);
+        }
+        req.union[..name.len()].copy_from_slice(name.as_bytes());
+        self.vfd_new(vfd_id, req).map(|_| ())
+    }
+
+    /// Create a pipe as VFD `vfd_id`, which the guest writes to if `write`
+    /// is true and reads from otherwise.
+    pub fn new_pipe(&mut self, vfd_id: u32, write: bool) -> Result {
+        let flags = if write {
+            VfdFlags::WRITE
+        } else {
+            VfdFlags::READ
+        };
+        let req = VfdNew::new(Command::VfdNewPipe, vfd_id, flags, 0);
+        self.vfd_new(vfd_id, req).map(|_| ())
+    }
+
+    /// Allocate a shared memory buffer of `size` bytes as VFD `vfd_id`.
+    ///
+    /// The host maps the buffer into the guest physical address space, and
+    /// the returned [`VfdInfo`] tells where.
+    pub fn new_alloc(&mut self, vfd_id: u32, size: u32) -> Result<VfdInfo> {
+        let req = VfdNew::new(
+            Command::VfdNew,
+            vfd_id,
+            VfdFlags::READ | VfdFlags::WRITE,
+            size,
+        );
+        self.vfd_new(vfd_id, req)
+    }
+
+    /// Close VFD `vfd_id`.
+    pub fn close(&mut self, vfd_id: u32) -> Result {
+        let req = Vfd {
+            header: CtrlHeader::with_type(Command::VfdClose),
+            vfd_id,
+        };
+        self.request(req.as_buf(), &[])?;
+        self.check_ok()
+    }
+
+    /// Send a message on VFD `vfd_id`, passing the VFDs `vfds` along with
+    /// it.
+    pub fn send(&mut self, vfd_id: u32, vfds: &[u32], data: &[u8]) -> Result {
+        let id_size = if self.features.contains(Features::TRANS_FLAGS) {
+            size_of::<SendVfd>()
+        } else {
+            size_of::<u32>()
+        };
+        if size_of::<VfdSend>() + vfds.len() * id_size + data.len() > OUT_BUFFER_SIZE {
+            return Err(Error::BufferTooSmall);
+        }
+        let req = VfdSend {
+            header: CtrlHeader::with_type(Command::VfdSend),
+            vfd_id,
+            vfd_count: vfds.len() as u32,
+        };
+        let buf = self.out_buf();
+        let mut len = 0;
+        let mut push = |bytes: &[u8]| {
+            buf[len..len + bytes.len()].copy_from_slice(bytes);
+            len += bytes.len();
+        };
+        push(req.as_buf());
+        for &id in vfds {
+            if id_size == size_of::<SendVfd>() {
+                let send_vfd = SendVfd {
+                    kind: SEND_KIND_LOCAL,
+                    id,
+                };
+                push(send_vfd.as_buf());
+            } else {
+                push(&id.to_le_bytes());
+            }
+        }
+        push(data);
+        self.request_out_buf(len)?;
+        self.check_ok()
+    }
+
+    /// Whether a message from the host is pending.
+    pub fn can_recv(&self) -> bool {
+        self.in_queue.can_pop()
+    }
+
+    /// Get a message sent by the host, if any.
+    ///
+    /// For a [`WlEvent::Recv`], the IDs of the received VFDs are copied into
+    /// `vfds` and the data into `data`.
+    pub fn recv(&mut self, vfds: &mut [u32], data: &mut [u8]) -> Result<Option<WlEvent>> {
+        if !self.in_queue.can_pop() {
+            return Ok(None);
+        }
+        let (token, len) = self.in_queue.pop_used()?;
+        let buf = self.in_buf(token);
+        let len = (len as usize).min(IN_BUFFER_SIZE);
+        let result = parse_in_message(&buf[..len], vfds, data);
+        // requeue
+        self.in_queue.add_notify(self.header, &[], &[buf])?;
+        result.map(Some)
+    }
+
+    /// Send a request creating a VFD and check the response.
+    fn vfd_new(&mut self, vfd_id: u32, req: VfdNew) -> Result<VfdInfo> {
+        if vfd_id & VFD_ID_HOST_MASK != 0 {
+            return Err(Error::InvalidParam);
+        }
+        let rsp_len = self.request(req.as_buf(), &[])?;
+        let rsp = &self.rsp_buf()[..rsp_len];
+        if header_type(rsp) != Some(Command::RespVfdNew as u32) || rsp.len() < size_of::<VfdNew>() {
+            return self.check_ok().and(Err(Error::IoError));
+        }
+        let rsp = unsafe { ptr::read_unaligned(rsp.as_ptr() as *const VfdNew) };
+        Ok(VfdInfo {
+            id: rsp.vfd_id,
+            flags: VfdFlags::from_bits_truncate(rsp.flags),
+            pfn: rsp.pfn,
+            size: rsp.size,
+        })
+    }
+
+    /// Copy a request into the command buffer, send it and block for the
+    /// response. Returns the length of the response.
+    fn request(&mut self, req: &[u8], payload: &[u8]) -> Result<usize> {
+        let len = req.len() + payload.len();
+        if len > OUT_BUFFER_SIZE {
+            return Err(Error::BufferTooSmall);
+        }
+        let buf = self.out_buf();
+        buf[..req.len()].copy_from_slice(req);
+        buf[req.len()..len].copy_from_slice(payload);
+        self.request_out_buf(len)
+    }
+
+    /// Send the first `len` bytes of the command buffer and block for the
+    /// response. Returns the length of the response.
+    fn request_out_buf(&mut self, len: usize) -> Result<usize> {
+        let out_buf = self.out_buf();
+        let rsp_buf = self.rsp_buf();
+        rsp_buf[..size_of::<CtrlHeader>()].fill(0);
+        let token = self
+            .out_queue
+            .add_notify(self.header, &[&out_buf[..len]], &[rsp_buf])?;
+        let rsp_len = self.out_queue.wait_for(self.header, token)?;
+        Ok((rsp_len as usize).min(OUT_BUFFER_SIZE))
+    }
+
+    /// Return error if the last response is not `RESP_OK`.
+    fn check_ok(&self) -> Result {
+        match header_type(self.rsp_buf()) {
+            Some(t) if t == Command::RespOk as u32 => Ok(()),
+            Some(t)
+                if t == Command::RespInvalidId as u32 || t == Command::RespInvalidFlags as u32 =>
+            {
+                Err(Error::InvalidParam)
+            }
+            Some(t) if t == Command::RespOutOfMemory as u32 => Err(Error::DmaError),
+            _ => Err(Error::IoError),
+        }
+    }
+
+    /// The in buffer used by the descriptor chain with the token.
+    fn in_buf(&self, token: u16) -> &'static mut [u8] {
+        let offset = token as usize * IN_BUFFER_SIZE;
+        unsafe { &mut self.in_buf_dma.as_buf()[offset..offset + IN_BUFFER_SIZE] }
+    }
+
+    /// The buffer holding commands.
+    fn out_buf(&self) -> &'static mut [u8] {
+        unsafe { &mut self.out_buf_dma.as_buf()[..OUT_BUFFER_SIZE] }
+    }
+
+    /// The buffer holding responses.
+    fn rsp_buf(&self) -> &'static mut [u8] {
+        unsafe { &mut self.out_buf_dma.as_buf()[OUT_BUFFER_SIZE..] }
+    }
+}
+
+impl Drop for VirtIOWl<'_> {
+    fn drop(&mut self) {
+        // stop the device from using the queues, so that they are freed
+        self.header.reset();
+        self.in_queue.unset(self.header);
+        self.out_queue.unset(self.header);
+    }
+}
+
+impl Driver for VirtIOWl<'_> {
+    fn device_type(&self) -> DeviceType {
+        self.header.device_type()
+    }
+
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
+        self.header.ack_interrupt_status()
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.features.bits()
+    }
+
+    fn enable_notifications(&mut self) {
+        self.in_queue.set_used_notifications(true);
+        self.out_queue.set_used_notifications(true);
+    }
+
+    fn disable_notifications(&mut self) {
+        self.in_queue.set_used_notifications(false);
+        self.out_queue.set_used_notifications(false);
+    }
+
+    fn metrics(&self, f: &mut dyn FnMut(Metric)) {
+        self.in_queue.metrics().report("in", f);
+        self.out_queue.metrics().report("out", f);
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.header.needs_reset()
+    }
+
+    fn recover(&mut self, lost: &mut dyn FnMut(LostRequest)) -> Result {
+        self.out_queue.report_lost("out", lost);
+        self.header.reset();
+        self.resume()
+    }
+
+    #[cfg(feature = "time-hal")]
+    fn set_timeout(&mut self, ticks: Option<u64>) {
+        self.in_queue.set_timeout(ticks);
+        self.out_queue.set_timeout(ticks);
+    }
+}
+
+/// Parse a message received from the host.
+fn parse_in_message(msg: &[u8], vfds: &mut [u32], data: &mut [u8]) -> Result<WlEvent> {
+    let type_ = header_type(msg).ok_or(Error::IoError)?;
+    if type_ == Command::VfdNew as u32 {
+        if msg.len() < size_of::<VfdNew>() {
+            return Err(Error::IoError);
+        }
+        let msg = unsafe { ptr::read_unaligned(msg.as_ptr() as *const VfdNew) };
+        Ok(WlEvent::NewVfd(VfdInfo {
+            id: msg.vfd_id,
+            flags: VfdFlags::from_bits_truncate(msg.flags),
+            pfn: msg.pfn,
+            size: msg.size,
+        }))
+    } else if type_ == Command::VfdRecv as u32 {
+        if msg.len() < size_of::<VfdRecv>() {
+            return Err(Error::IoError);
+        }
+        let recv = unsafe { ptr::read_unaligned(msg.as_ptr() as *const VfdRecv) };
+        let ids = &msg[size_of::<VfdRecv>()..];
+        let ids_len = recv.vfd_count as usize * size_of::<u32>();
+        if ids.len() < ids_len {
+            return Err(Error::IoError);
+        }
+        let (ids, payload) = ids.split_at(ids_len);
+        if vfds.len() < recv.vfd_count as usize || data.len() < payload.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        for (vfd, id) in vfds.iter_mut().zip(ids.chunks_exact(4)) {
+            *vfd = u32::from_le_bytes([id[0], id[1], id[2], id[3]]);
+        }
+        data[..payload.len()].copy_from_slice(payload);
+        Ok(WlEvent::Recv {
+            vfd_id: recv.vfd_id,
+            vfds: recv.vfd_count as usize,
+            len: payload.len(),
+        })
+    } else if type_ == Command::VfdHup as u32 {
+        if msg.len() < size_of::<Vfd>() {
+            return Err(Error::IoError);
+        }
+        let msg = unsafe { ptr::read_unaligned(msg.as_ptr() as *const Vfd) };
+        Ok(WlEvent::Hangup(msg.vfd_id))
+    } else {
+        warn!("unknown message type {:#x} from host", type_);
+        Err(Error::IoError)
+    }
+}
+
+/// The type of the control header at the start of a message, if any.
+fn header_type(msg: &[u8]) -> Option<u32> {
+    msg.get(..4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// A message sent by the host.
+#[derive(Debug, Copy, Clone)]
+pub enum WlEvent {
+    /// The host created a VFD, for example one passed along with a message.
+    NewVfd(VfdInfo),
+    /// A message was received on a VFD.
+    Recv {
+        /// The VFD the message was received on.
+        vfd_id: u32,
+        /// The number of VFD IDs passed along with the message.
+        vfds: usize,
+        /// The length of the data.
+        len: usize,
+    },
+    /// The other end of a VFD has hung up.
+    Hangup(u32),
+}
+
+/// Information about a VFD created by the host.
+#[derive(Debug, Copy, Clone)]
+pub struct VfdInfo {
+    /// The ID of the VFD.
+    pub id: u32,
+    /// How the guest may use the VFD.
+    pub flags: VfdFlags,
+    /// The guest physical page frame number of shared memory.
+    pub pfn: u64,
+    /// The size of shared memory in bytes.
+    pub size: u32,
+}
+
+bitflags! {
+    /// How the guest may use a VFD.
+    pub struct VfdFlags: u32 {
+        /// The VFD is intended to be written by the guest.
+        const WRITE = 1 << 0;
+        /// The VFD is intended to be read by the guest.
+        const READ = 1 << 1;
+    }
+}
+
+bitflags! {
+    struct Features: u64 {
+        /// VFDs passed with a message carry a kind along with their ID.
+        const TRANS_FLAGS           = 1 << 0;
+        /// The device supports sending fences.
+        const SEND_FENCES           = 1 << 1;
+        /// Shared memory is exposed as a shared memory region.
+        const USE_SHMEM             = 1 << 2;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Command {
+    VfdNew = 0x100,
+    VfdClose = 0x101,
+    VfdSend = 0x102,
+    VfdRecv = 0x103,
+    VfdNewCtx = 0x104,
+    VfdNewPipe = 0x105,
+    VfdHup = 0x106,
+    VfdNewDmabuf = 0x107,
+    VfdDmabufSync = 0x108,
+    VfdSendForeignId = 0x109,
+    VfdNewCtxNamed = 0x10a,
+
+    RespOk = 0x1000,
+    RespVfdNew = 0x1001,
+    RespVfdNewDmabuf = 0x1002,
+
+    RespErr = 0x1100,
+    RespOutOfMemory = 0x1101,
+    RespInvalidId = 0x1102,
+    RespInvalidType = 0x1103,
+    RespInvalidFlags = 0x1104,
+    RespInvalidCmd = 0x1105,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct CtrlHeader {
+    type_: u32,
+    flags: u32,
+}
+
+impl CtrlHeader {
+    fn with_type(type_: Command) -> CtrlHeader {
+        CtrlHeader {
+            type_: type_ as u32,
+            flags: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct Vfd {
+    header: CtrlHeader,
+    vfd_id: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct VfdNew {
+    header: CtrlHeader,
+    /// The most significant bit is set for VFDs allocated by the host.
+    vfd_id: u32,
+    flags: u32,
+    /// The first guest physical page frame number of shared memory.
+    pfn: u64,
+    /// The size of shared memory in bytes.
+    size: u32,
+    /// A dmabuf description or a socket name.
+    union: [u8; 36],
+}
+
+impl VfdNew {
+    fn new(type_: Command, vfd_id: u32, flags: VfdFlags, size: u32) -> VfdNew {
+        VfdNew {
+            header: CtrlHeader::with_type(type_),
+            vfd_id,
+            flags: flags.bits(),
+            pfn: 0,
+            size,
+            union: [0; 36],
+        }
+    }
+}
+
+/// Followed by the VFD IDs and the data.
+#[repr(C)]
+#[derive(Debug)]
+struct VfdSend {
+    header: CtrlHeader,
+    vfd_id: u32,
+    vfd_count: u32,
+}
+
+/// Followed by the VFD IDs and the data.
+#[repr(C)]
+#[derive(Debug)]
+struct VfdRecv {
+    header: CtrlHeader,
+    vfd_id: u32,
+    vfd_count: u32,
+}
+
+/// A VFD passed with a message when `TRANS_FLAGS` is negotiated.
+#[repr(C)]
+#[derive(Debug)]
+struct SendVfd {
+    kind: u32,
+    id: u32,
+}
+
+unsafe impl AsBuf for Vfd {}
+unsafe impl AsBuf for VfdNew {}
+unsafe impl AsBuf for VfdSend {}
+unsafe impl AsBuf for SendVfd {}
+
+/// The VFD is local to the device.
+const SEND_KIND_LOCAL: u32 = 0;
+
+/// Set in the IDs of VFDs allocated by the host.
+const VFD_ID_HOST_MASK: u32 = 1 << 31;
+
+const IN_BUFFER_SIZE: usize = 0x1000;
+const OUT_BUFFER_SIZE: usize = 0x1000;
+
+const QUEUE_IN: usize = 0;
+const QUEUE_OUT: usize = 1;
+
+// a parameter that can change
+const QUEUE_SIZE: u16 = 4;
@@ -0,0 +1,146 @@
+//! Requests of the block driver.
+
+use virtio_drivers::testing::{
+    accesses, destroy_fake_device, fake_device, fake_modern_device, Access, FakeBlk, Reply,
+    ScriptedDevice,
+};
+use virtio_drivers::{DeviceInit, DeviceType, Error, VirtIOBlk};
+
+const RING_INDIRECT_DESC: u64 = 1 << 28;
+const RING_PACKED: u64 = 1 << 34;
+
+/// The data and status the device writes for a successful block read.
+fn block(byte: u8) -> Reply {
+    let mut data = vec![byte; 512];
+    data.push(0);
+    Reply::Data(data)
+}
+
+#[test]
+fn blk_batch_completes() {
+    let device = ScriptedDevice::new(DeviceType::Block)
+        .with_config(16u64.to_le_bytes().to_vec())
+        .reply(0, block(1))
+        .reply(0, block(2));
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut blk = VirtIOBlk::new(header).unwrap();
+
+    let (mut first, mut second) = ([0; 512], [0; 512]);
+    blk.read_blocks(0, &mut [&mut first, &mut second]).unwrap();
+    assert_eq!((first[0], second[0]), (1, 2));
+
+    drop(blk);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn blk_batch_rejects_stale_token() {
+    // the device uses the first chain twice instead of using the second one,
+    // in a split and in a packed queue
+    for packed in [false, true] {
+        let device = ScriptedDevice::new(DeviceType::Block)
+            .with_features(RING_PACKED)
+            .with_config(16u64.to_le_bytes().to_vec())
+            .reply(0, block(1))
+            .reply(0, Reply::BadId(0));
+        let header = if packed {
+            fake_modern_device(device)
+        } else {
+            fake_device(device)
+        };
+        let header_ptr = header as *mut _;
+        let mut blk = VirtIOBlk::new(header).unwrap();
+
+        let (mut first, mut second) = ([0; 512], [0; 512]);
+        assert_eq!(
+            blk.read_blocks(0, &mut [&mut first, &mut second]),
+            Err(Error::WrongToken)
+        );
+        assert_eq!(accesses(header_ptr).last(), Some(&Access::Reset));
+
+        drop(blk);
+        unsafe { destroy_fake_device(header_ptr) };
+    }
+}
+
+#[test]
+fn blk_premapped_read() {
+    let device = ScriptedDevice::new(DeviceType::Block)
+        .with_config(16u64.to_le_bytes().to_vec())
+        .reply(0, block(7));
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut blk = VirtIOBlk::new(header).unwrap();
+
+    // the fake HAL maps memory not allocated for DMA one to one
+    let mut buf = [0u8; 512];
+    unsafe { blk.read_block_premapped(0, buf.as_mut_ptr() as usize) }.unwrap();
+    assert_eq!(buf[0], 7);
+
+    drop(blk);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn blk_max_segments() {
+    // the queue of 16 descriptors limits the segments as well as the device
+    for (seg_max, segments) in [(5, 5), (100, 14)] {
+        let mut config = 16u64.to_le_bytes().to_vec();
+        config.extend_from_slice(&0u32.to_le_bytes());
+        config.extend_from_slice(&(seg_max as u32).to_le_bytes());
+        let device = ScriptedDevice::new(DeviceType::Block)
+            .with_features(1 << 2)
+            .with_config(config);
+        let header = fake_device(device);
+        let header_ptr = header as *mut _;
+        let blk = VirtIOBlk::new(header).unwrap();
+        assert_eq!(blk.max_segments(), segments);
+
+        drop(blk);
+        unsafe { destroy_fake_device(header_ptr) };
+    }
+}
+
+#[test]
+fn blk_on_modern_device() {
+    // the device takes the areas of the queue, allocated separately
+    let header = fake_modern_device(FakeBlk::new(16));
+    let header_ptr = header as *mut _;
+    let mut blk = VirtIOBlk::new(header).unwrap();
+
+    blk.write_block(3, &[5; 512]).unwrap();
+    let mut buf = [0; 512];
+    blk.read_block(3, &mut buf).unwrap();
+    assert_eq!(buf, [5; 512]);
+
+    drop(blk);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn blk_on_packed_queues() {
+    // with indirect tables, and with chains of descriptors in the ring
+    for mask in [u64::MAX, !RING_INDIRECT_DESC] {
+        let header = fake_modern_device(FakeBlk::new(16).with_features(RING_PACKED));
+        let header_ptr = header as *mut _;
+        let init = DeviceInit::new(header).mask_features(mask);
+        let mut blk = VirtIOBlk::from_init(init).unwrap();
+
+        // wrap around the ring of 16 descriptors several times
+        for block in 0..16 {
+            blk.write_block(block, &[block as u8; 512]).unwrap();
+        }
+        for block in 0..16 {
+            let mut buf = [0; 512];
+            blk.read_block(block, &mut buf).unwrap();
+            assert_eq!(buf, [block as u8; 512]);
+        }
+        let (mut first, mut second) = ([0; 512], [0; 512]);
+        blk.read_blocks(14, &mut [&mut first, &mut second]).unwrap();
+        assert_eq!((first, second), ([14; 512], [15; 512]));
+
+        drop(blk);
+        unsafe { destroy_fake_device(header_ptr) };
+    }
+}
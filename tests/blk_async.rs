@@ -0,0 +1,140 @@
+//! Block requests submitted without waiting for them.
+
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use virtio_drivers::testing::{
+    destroy_fake_device, fake_device, write_chain, FakeBackend, Reply, ScriptedDevice,
+};
+use virtio_drivers::{BlkReq, BlkResp, DeviceType, Driver, Error, LostRequest, VirtIOBlk};
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+/// The data and status the device writes for a successful block read.
+fn block(byte: u8) -> Reply {
+    let mut data = vec![byte; 512];
+    data.push(0);
+    Reply::Data(data)
+}
+
+/// A block device which reads blocks filled with their sector number, but
+/// leaves the requests in the queue while `hold` is set.
+struct HeldBlk {
+    hold: Arc<AtomicBool>,
+}
+
+impl FakeBackend for HeldBlk {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn config(&self) -> Vec<u8> {
+        16u64.to_le_bytes().to_vec()
+    }
+
+    fn process(&mut self, _queue: u32, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Option<u32> {
+        if self.hold.load(Ordering::SeqCst) {
+            return None;
+        }
+        let sector = inputs[0][8];
+        let mut data = vec![sector; 512];
+        data.push(0);
+        Some(write_chain(outputs, &data) as u32)
+    }
+}
+
+#[test]
+fn blk_requests_complete_in_any_order() {
+    let device = ScriptedDevice::new(DeviceType::Block)
+        .with_config(16u64.to_le_bytes().to_vec())
+        .reply(0, block(1))
+        .reply(0, block(2));
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut blk = VirtIOBlk::new(header).unwrap();
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+
+    let (mut reqs, mut resps) = (<[BlkReq; 2]>::default(), <[BlkResp; 2]>::default());
+    let (mut first, mut second) = ([0; 512], [0; 512]);
+    let [req0, req1] = &mut reqs;
+    let [resp0, resp1] = &mut resps;
+    let token0 = unsafe { blk.read_block_nb(0, req0, &mut first, resp0) }.unwrap();
+    let token1 = unsafe { blk.read_block_nb(1, req1, &mut second, resp1) }.unwrap();
+
+    assert_eq!(blk.on_interrupt(), Ok(2));
+    assert_eq!(
+        blk.poll_complete(&mut cx, token1, resp1),
+        Poll::Ready(Ok(()))
+    );
+    let mut future = pin!(blk.complete(token0, resp0));
+    assert_eq!(future.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+    assert_eq!((first[0], second[0]), (1, 2));
+
+    drop(blk);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn blk_request_lost_on_recovery() {
+    let device = ScriptedDevice::new(DeviceType::Block).with_config(16u64.to_le_bytes().to_vec());
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut blk = VirtIOBlk::new(header).unwrap();
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+
+    let (mut req, mut resp, mut buf) = (BlkReq::default(), BlkResp::default(), [0; 512]);
+    let token = unsafe { blk.read_block_nb(3, &mut req, &mut buf, &mut resp) }.unwrap();
+    assert_eq!(blk.poll_complete(&mut cx, token, &resp), Poll::Pending);
+
+    let mut lost = Vec::new();
+    blk.recover(&mut |request| lost.push(request)).unwrap();
+    assert_eq!(
+        lost,
+        [LostRequest {
+            queue: "requestq",
+            token
+        }]
+    );
+    assert_eq!(
+        blk.poll_complete(&mut cx, token, &resp),
+        Poll::Ready(Err(Error::DeviceReset))
+    );
+
+    drop(blk);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn blk_request_survives_suspend() {
+    let hold = Arc::new(AtomicBool::new(true));
+    let header = fake_device(HeldBlk { hold: hold.clone() });
+    let header_ptr = header as *mut _;
+    let mut blk = VirtIOBlk::new(header).unwrap();
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+
+    let (mut req, mut resp, mut buf) = (BlkReq::default(), BlkResp::default(), [0; 512]);
+    let token = unsafe { blk.read_block_nb(3, &mut req, &mut buf, &mut resp) }.unwrap();
+    assert_eq!(blk.poll_complete(&mut cx, token, &resp), Poll::Pending);
+
+    blk.suspend().unwrap();
+    hold.store(false, Ordering::SeqCst);
+    blk.resume().unwrap();
+    // the device gets the request again and completes it
+    assert_eq!(
+        blk.poll_complete(&mut cx, token, &resp),
+        Poll::Ready(Ok(()))
+    );
+    assert_eq!(buf[0], 3);
+
+    drop(blk);
+    unsafe { destroy_fake_device(header_ptr) };
+}
@@ -0,0 +1,74 @@
+//! HCI packets through the Bluetooth driver.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use virtio_drivers::testing::{
+    destroy_fake_device, fake_device, read_chain, write_chain, FakeBackend,
+};
+use virtio_drivers::{DeviceType, Error, HciPacketType, VirtIOBluetooth};
+
+const QUEUE_TX: u32 = 0;
+const QUEUE_RX: u32 = 1;
+
+/// A fake Bluetooth controller.
+#[derive(Default)]
+struct FakeBt {
+    /// The packets sent to the controller, with their packet type byte.
+    sent: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// The packets to receive from the controller, with their packet type
+    /// byte.
+    rx: Arc<Mutex<VecDeque<Vec<u8>>>>,
+}
+
+impl FakeBackend for FakeBt {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Bluetooth
+    }
+
+    fn config(&self) -> Vec<u8> {
+        vec![0; 6]
+    }
+
+    fn process(&mut self, queue: u32, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Option<u32> {
+        match queue {
+            QUEUE_TX => {
+                self.sent.lock().unwrap().push(read_chain(inputs));
+                Some(0)
+            }
+            QUEUE_RX => {
+                let packet = self.rx.lock().unwrap().pop_front()?;
+                Some(write_chain(outputs, &packet) as u32)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The Command Complete event of the HCI Reset command.
+const RESET_COMPLETE: [u8; 7] = [0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00];
+
+#[test]
+fn bluetooth_keeps_packet_for_larger_buffer() {
+    let device = FakeBt::default();
+    device.rx.lock().unwrap().push_back(RESET_COMPLETE.to_vec());
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut bt = VirtIOBluetooth::new(header).unwrap();
+    // the controller sends the event once notified
+    bt.send(HciPacketType::Command, &[0x03, 0x0c, 0x00])
+        .unwrap();
+
+    let mut small = [0; 4];
+    assert_eq!(bt.recv(&mut small), Err(Error::BufferTooSmall));
+    assert!(bt.can_recv());
+    let mut buf = [0; 16];
+    assert_eq!(
+        bt.recv(&mut buf),
+        Ok(Some((HciPacketType::Event, RESET_COMPLETE.len() - 1)))
+    );
+    assert_eq!(&buf[..6], &RESET_COMPLETE[1..]);
+    assert_eq!(bt.recv(&mut buf), Ok(None));
+
+    drop(bt);
+    unsafe { destroy_fake_device(header_ptr) };
+}
@@ -0,0 +1,211 @@
+//! Frames through the CAN driver.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use virtio_drivers::testing::{
+    destroy_fake_device, fake_device, read_chain, write_chain, FakeBackend,
+};
+use virtio_drivers::{CanFilter, CanFrame, DeviceType, Error, VirtIOCan};
+
+const CAN_CLASSIC: u64 = 1 << 0;
+const CAN_FD: u64 = 1 << 1;
+const QUEUE_TX: u32 = 0;
+const QUEUE_RX: u32 = 1;
+const MSG_RX: u16 = 0x0101;
+/// The size of the header of TX and RX messages.
+const FRAME_HEADER_SIZE: usize = 16;
+
+/// A fake CAN controller which sends and receives classic and FD frames.
+#[derive(Default)]
+struct FakeCan {
+    /// The TX messages sent on the bus.
+    sent: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// The RX messages to receive from the bus.
+    rx: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    /// Whether to leave the TX messages in the queue, as if the bus was busy.
+    hold_tx: Arc<AtomicBool>,
+}
+
+impl FakeBackend for FakeCan {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Can
+    }
+
+    fn config(&self) -> Vec<u8> {
+        vec![0, 0]
+    }
+
+    fn features(&self) -> u64 {
+        CAN_CLASSIC | CAN_FD
+    }
+
+    fn process(&mut self, queue: u32, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Option<u32> {
+        match queue {
+            QUEUE_TX => {
+                if self.hold_tx.load(Ordering::SeqCst) {
+                    return None;
+                }
+                self.sent.lock().unwrap().push(read_chain(inputs));
+                Some(write_chain(outputs, &[0]) as u32)
+            }
+            QUEUE_RX => {
+                let message = self.rx.lock().unwrap().pop_front()?;
+                Some(write_chain(outputs, &message) as u32)
+            }
+            _ => Some(write_chain(outputs, &[0]) as u32),
+        }
+    }
+}
+
+/// An RX message carrying a classic frame with `id` and `data`.
+fn rx_message(id: u32, data: &[u8]) -> Vec<u8> {
+    let mut message = Vec::new();
+    message.extend_from_slice(&MSG_RX.to_le_bytes());
+    message.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    message.extend_from_slice(&[0; 8]);
+    message.extend_from_slice(&id.to_le_bytes());
+    message.extend_from_slice(data);
+    message
+}
+
+#[test]
+fn can_filters_received_frames() {
+    let device = FakeCan::default();
+    let rx = device.rx.clone();
+    rx.lock().unwrap().extend([
+        rx_message(0x100, b"first"),
+        rx_message(0x201, b"second"),
+        rx_message(0x300, b"third"),
+    ]);
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut can = VirtIOCan::new(header).unwrap();
+    // the device takes the frames from the bus once the controller starts
+    can.start().unwrap();
+
+    can.set_filters(&[CanFilter::new(0x200, 0x700, false)])
+        .unwrap();
+    let frame = can.recv().unwrap().unwrap();
+    assert_eq!((frame.id(), frame.data()), (0x201, &b"second"[..]));
+    assert!(can.recv().unwrap().is_none());
+
+    let filters = [CanFilter::new(0, 0, false); 9];
+    assert_eq!(can.set_filters(&filters), Err(Error::InvalidParam));
+
+    drop(can);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn can_queues_frames_without_blocking() {
+    let device = FakeCan::default();
+    let sent = device.sent.clone();
+    let hold_tx = device.hold_tx.clone();
+    hold_tx.store(true, Ordering::SeqCst);
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut can = VirtIOCan::new(header).unwrap();
+
+    let tokens: Vec<u16> = (0..8u8)
+        .map(|i| {
+            let frame = CanFrame::new(0x100 + i as u32, false, &[i]).unwrap();
+            can.queue_send(&frame).unwrap()
+        })
+        .collect();
+    let frame = CanFrame::new(0x108, false, &[8]).unwrap();
+    assert_eq!(can.queue_send(&frame), Err(Error::NotReady));
+    assert_eq!(can.handle_sent(|_, _| panic!("not sent yet")), Ok(0));
+
+    hold_tx.store(false, Ordering::SeqCst);
+    can.start().unwrap();
+    let mut reported = Vec::new();
+    assert_eq!(
+        can.handle_sent(|token, result| reported.push((token, result))),
+        Ok(8)
+    );
+    reported.sort_by_key(|&(token, _)| token);
+    assert_eq!(
+        reported,
+        tokens
+            .iter()
+            .map(|&token| (token, Ok(())))
+            .collect::<Vec<_>>()
+    );
+    let sent = sent.lock().unwrap();
+    assert_eq!(sent.len(), 8);
+    assert_eq!(&sent[3][12..FRAME_HEADER_SIZE], &0x103u32.to_le_bytes());
+    assert_eq!(&sent[3][FRAME_HEADER_SIZE..], &[3]);
+    drop(sent);
+
+    drop(can);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[cfg(feature = "embedded-can")]
+#[test]
+fn can_nb_transmit_does_not_wait_for_the_device() {
+    use embedded_can::nb::Can;
+
+    let device = FakeCan::default();
+    let sent = device.sent.clone();
+    let hold_tx = device.hold_tx.clone();
+    hold_tx.store(true, Ordering::SeqCst);
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut can = VirtIOCan::new(header).unwrap();
+
+    let frame = CanFrame::new_fd(0x123, false, &[0xaa; 12]).unwrap();
+    for _ in 0..8 {
+        assert!(matches!(can.transmit(&frame), Ok(None)));
+    }
+    assert!(matches!(can.transmit(&frame), Err(nb::Error::WouldBlock)));
+    assert!(sent.lock().unwrap().is_empty());
+
+    hold_tx.store(false, Ordering::SeqCst);
+    can.start().unwrap();
+    // the buffers of the sent frames are reclaimed for the next one
+    assert!(matches!(can.transmit(&frame), Ok(None)));
+    assert_eq!(sent.lock().unwrap().len(), 9);
+
+    drop(can);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn can_fd_frames_take_only_dlc_lengths() {
+    for len in [0, 8, 12, 16, 20, 24, 32, 48, 64] {
+        let frame = CanFrame::new_fd(0x123, false, &[0; 64][..len]).unwrap();
+        assert_eq!(frame.len(), len);
+    }
+    for len in [9, 13, 33, 63, 65] {
+        assert!(CanFrame::new_fd(0x123, false, &vec![0; len]).is_none());
+    }
+    assert!(CanFrame::new(0x123, false, &[0; 9]).is_none());
+    assert!(CanFrame::new(0x800, false, &[]).is_none());
+    assert!(CanFrame::new(0x800, true, &[]).is_some());
+}
+
+#[test]
+fn can_sends_frames() {
+    let device = FakeCan::default();
+    let sent = device.sent.clone();
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut can = VirtIOCan::new(header).unwrap();
+
+    let frame = CanFrame::new_fd(0x1234, true, &[0x55; 12]).unwrap();
+    assert_eq!(frame.dlc(), 9);
+    can.send(&frame).unwrap();
+    let sent = sent.lock().unwrap();
+    let message = &sent[0];
+    // an extended FD frame, with its length in the header
+    assert_eq!(&message[2..4], &12u16.to_le_bytes());
+    assert_eq!(&message[8..12], &(1u32 << 1 | 1 << 2).to_le_bytes());
+    assert_eq!(&message[12..FRAME_HEADER_SIZE], &0x1234u32.to_le_bytes());
+    assert_eq!(&message[FRAME_HEADER_SIZE..], &[0x55; 12]);
+    drop(sent);
+
+    drop(can);
+    unsafe { destroy_fake_device(header_ptr) };
+}
@@ -0,0 +1,185 @@
+//! The virtio-ccw transport against fake subchannels.
+
+use std::sync::{Arc, Mutex};
+use virtio_drivers::testing::set_ccw_device;
+use virtio_drivers::{CcwTransport, DeviceType, Error, Transport};
+
+const CCW_CMD_SET_VQ: u8 = 0x13;
+const CCW_CMD_VDEV_RESET: u8 = 0x33;
+const CCW_CMD_READ_FEAT: u8 = 0x12;
+const CCW_CMD_WRITE_FEAT: u8 = 0x11;
+const CCW_CMD_READ_CONF: u8 = 0x22;
+const CCW_CMD_SET_VIRTIO_REV: u8 = 0x83;
+const CCW_CMD_SENSE_ID: u8 = 0xe4;
+
+/// The config of the fake block devices, with a capacity of 0x800 sectors.
+const CONFIG: [u8; 8] = [0, 8, 0, 0, 0, 0, 0, 0];
+
+/// The channel commands run on a subchannel, with the data the driver sent.
+type Commands = Vec<(u8, Vec<u8>)>;
+
+/// A fake virtio block device, of which the channel commands are recorded.
+struct FakeCcw {
+    /// The highest revision of the transport the device accepts.
+    max_revision: u16,
+    /// The control unit type the device senses.
+    cu_type: u16,
+    /// The channel commands run.
+    commands: Arc<Mutex<Commands>>,
+}
+
+impl FakeCcw {
+    /// Run the channel `command` with `data`, returning the number of bytes
+    /// transferred.
+    fn run(&mut self, command: u8, data: &mut [u8]) -> Option<usize> {
+        self.commands.lock().unwrap().push((command, data.to_vec()));
+        match command {
+            CCW_CMD_SENSE_ID => {
+                let [high, low] = self.cu_type.to_be_bytes();
+                let sense_id = [0xff, high, low, DeviceType::Block.id() as u8];
+                data[..4].copy_from_slice(&sense_id);
+                Some(4)
+            }
+            CCW_CMD_SET_VIRTIO_REV => {
+                let revision = u16::from_be_bytes([data[0], data[1]]);
+                (revision <= self.max_revision).then_some(data.len())
+            }
+            CCW_CMD_READ_CONF => {
+                let len = data.len().min(CONFIG.len());
+                data[..len].copy_from_slice(&CONFIG[..len]);
+                Some(len)
+            }
+            CCW_CMD_READ_FEAT => {
+                // VIRTIO_F_VERSION_1 in the second word
+                let word: u32 = if data[4] == 1 { 1 } else { 0 };
+                data[..4].copy_from_slice(&word.to_le_bytes());
+                Some(data.len())
+            }
+            _ => Some(data.len()),
+        }
+    }
+}
+
+/// Install a fake block device of `max_revision` on subchannel `schid`, and
+/// return the channel commands it runs.
+fn fake_ccw_device(schid: u32, max_revision: u16, cu_type: u16) -> Arc<Mutex<Commands>> {
+    let mut device = FakeCcw {
+        max_revision,
+        cu_type,
+        commands: Arc::default(),
+    };
+    let commands = device.commands.clone();
+    set_ccw_device(schid, move |command, data| device.run(command, data));
+    commands
+}
+
+/// The data of the commands with code `command` run so far.
+fn commands_of(commands: &Mutex<Commands>, command: u8) -> Vec<Vec<u8>> {
+    commands
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(code, _)| *code == command)
+        .map(|(_, data)| data.clone())
+        .collect()
+}
+
+#[test]
+fn ccw_negotiates_the_highest_revision() {
+    let commands = fake_ccw_device(0x1_0001, 1, 0x3832);
+    let mut transport = unsafe { CcwTransport::new(0x1_0001) }.unwrap();
+    assert_eq!(transport.device_type(), DeviceType::Block);
+    assert_eq!(transport.revision(), 1);
+    assert!(!transport.is_legacy());
+    assert_eq!(transport.config_space_size(), CONFIG.len());
+    let revisions: Vec<_> = commands_of(&commands, CCW_CMD_SET_VIRTIO_REV)
+        .iter()
+        .map(|data| u16::from_be_bytes([data[0], data[1]]))
+        .collect();
+    assert_eq!(revisions, [2, 1]);
+    assert_eq!(commands_of(&commands, CCW_CMD_VDEV_RESET).len(), 1);
+
+    assert_eq!(transport.read_device_features(), 1 << 32);
+    transport.write_driver_features(1 << 9);
+    // both words, with VIRTIO_F_VERSION_1
+    assert_eq!(
+        commands_of(&commands, CCW_CMD_WRITE_FEAT),
+        [vec![0, 2, 0, 0, 0], vec![1, 0, 0, 0, 1]]
+    );
+}
+
+#[test]
+fn ccw_reads_config_prefixes() {
+    let commands = fake_ccw_device(0x1_0002, 2, 0x3832);
+    let transport = unsafe { CcwTransport::new(0x1_0002) }.unwrap();
+    let transport: &dyn Transport = &transport;
+    commands.lock().unwrap().clear();
+    assert_eq!(transport.read_config::<u16>(0), Ok(0x800));
+    assert_eq!(transport.read_config::<u32>(4), Ok(0));
+    // the device transfers the config from its start up to the field
+    let lens: Vec<_> = commands_of(&commands, CCW_CMD_READ_CONF)
+        .iter()
+        .map(Vec::len)
+        .collect();
+    assert_eq!(lens, [2, 8]);
+    assert_eq!(transport.read_config::<u32>(8), Err(Error::InvalidParam));
+}
+
+#[test]
+fn ccw_sets_up_queues() {
+    let commands = fake_ccw_device(0x1_0003, 2, 0x3832);
+    let mut transport = unsafe { CcwTransport::new(0x1_0003) }.unwrap();
+    transport.queue_set(1, 16, 0x1000, 0x1100, 0x2000);
+    assert_eq!(transport.queue_descriptors(1), 0x1000);
+    assert!(transport.queue_used(1));
+    transport.queue_unset(1);
+    assert!(!transport.queue_used(1));
+
+    let infos = commands_of(&commands, CCW_CMD_SET_VQ);
+    let mut info = vec![0; 32];
+    info[0..8].copy_from_slice(&0x1000u64.to_be_bytes());
+    info[12..14].copy_from_slice(&1u16.to_be_bytes());
+    info[14..16].copy_from_slice(&16u16.to_be_bytes());
+    info[16..24].copy_from_slice(&0x1100u64.to_be_bytes());
+    info[24..32].copy_from_slice(&0x2000u64.to_be_bytes());
+    assert_eq!(infos[0], info);
+    assert_eq!(infos[1][12..16], [0, 1, 0, 0]);
+}
+
+#[test]
+fn ccw_falls_back_to_the_legacy_interface() {
+    let commands = fake_ccw_device(0x1_0004, 0, 0x3832);
+    let mut transport = unsafe { CcwTransport::new(0x1_0004) }.unwrap();
+    assert_eq!(transport.revision(), 0);
+    assert!(transport.is_legacy());
+
+    // only the first word of the features
+    assert_eq!(transport.read_device_features(), 0);
+    transport.write_driver_features(1 << 9);
+    assert_eq!(
+        commands_of(&commands, CCW_CMD_WRITE_FEAT),
+        [vec![0, 2, 0, 0, 0]]
+    );
+
+    // the rings follow the descriptor table, aligned to 4 KiB pages
+    transport.queue_set(0, 16, 0x3000, 0x3100, 0x4000);
+    let mut info = vec![0; 16];
+    info[0..8].copy_from_slice(&0x3000u64.to_be_bytes());
+    info[8..12].copy_from_slice(&0x1000u32.to_be_bytes());
+    info[14..16].copy_from_slice(&16u16.to_be_bytes());
+    assert_eq!(commands_of(&commands, CCW_CMD_SET_VQ), [info]);
+}
+
+#[test]
+fn ccw_rejects_other_control_units() {
+    fake_ccw_device(0x1_0005, 2, 0x3088);
+    assert!(matches!(
+        unsafe { CcwTransport::new(0x1_0005) },
+        Err(Error::InvalidParam)
+    ));
+    // subchannels without a device do not complete the channel programs
+    assert!(matches!(
+        unsafe { CcwTransport::new(0x1_0006) },
+        Err(Error::IoError)
+    ));
+}
@@ -0,0 +1,8 @@
+//! The drivers checked against the virtio spec.
+
+use virtio_drivers::testing::conformance::check_all;
+
+#[test]
+fn drivers_conform_to_the_spec() {
+    assert_eq!(check_all(), []);
+}
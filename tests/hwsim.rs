@@ -0,0 +1,100 @@
+//! Generic netlink messages through the mac80211_hwsim driver.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use virtio_drivers::testing::{
+    destroy_fake_device, fake_device, read_chain, write_chain, FakeBackend,
+};
+use virtio_drivers::{DeviceType, Error, HwsimCommand, VirtIOHwsim};
+
+const QUEUE_TX: u32 = 0;
+const QUEUE_RX: u32 = 1;
+
+/// A fake wireless medium.
+#[derive(Default)]
+struct FakeHwsim {
+    /// The messages sent to the medium.
+    sent: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// The messages to receive from the medium.
+    rx: Arc<Mutex<VecDeque<Vec<u8>>>>,
+}
+
+impl FakeBackend for FakeHwsim {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Mac80211Hwsim
+    }
+
+    fn process(&mut self, queue: u32, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Option<u32> {
+        match queue {
+            QUEUE_TX => {
+                self.sent.lock().unwrap().push(read_chain(inputs));
+                Some(0)
+            }
+            QUEUE_RX => {
+                let msg = self.rx.lock().unwrap().pop_front()?;
+                Some(write_chain(outputs, &msg) as u32)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A generic netlink message of `command` with `len` bytes in all.
+fn message(command: HwsimCommand, len: usize) -> Vec<u8> {
+    let mut msg = vec![0; len];
+    msg[..4].copy_from_slice(&(len as u32).to_le_bytes());
+    // the generic netlink header follows the 16 bytes of the netlink header
+    msg[16] = command as u8;
+    msg
+}
+
+#[test]
+fn hwsim_sends_whole_messages() {
+    let device = FakeHwsim::default();
+    let sent = device.sent.clone();
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut hwsim = VirtIOHwsim::new(header).unwrap();
+
+    let register = message(HwsimCommand::Register, 20);
+    hwsim.send(&register).unwrap();
+    // the length in the netlink header must match the message
+    assert_eq!(hwsim.send(&register[..19]), Err(Error::InvalidParam));
+    assert_eq!(hwsim.send(&[20, 0, 0]), Err(Error::InvalidParam));
+    assert_eq!(*sent.lock().unwrap(), [register]);
+
+    drop(hwsim);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn hwsim_keeps_message_for_larger_buffer() {
+    let device = FakeHwsim::default();
+    let frame = message(HwsimCommand::Frame, 40);
+    {
+        let mut rx = device.rx.lock().unwrap();
+        // shorter than its headers
+        rx.push_back(vec![8, 0, 0, 0, 0, 0, 0, 0]);
+        rx.push_back(frame.clone());
+    }
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut hwsim = VirtIOHwsim::new(header).unwrap();
+    // the medium sends the messages once notified
+    hwsim.send(&message(HwsimCommand::Register, 20)).unwrap();
+
+    let mut buf = [0; 64];
+    assert_eq!(hwsim.recv(&mut buf), Err(Error::IoError));
+    let mut small = [0; 32];
+    assert_eq!(hwsim.recv(&mut small), Err(Error::BufferTooSmall));
+    assert!(hwsim.can_recv());
+    assert_eq!(
+        hwsim.recv(&mut buf),
+        Ok(Some((HwsimCommand::Frame as u8, frame.len())))
+    );
+    assert_eq!(buf[..frame.len()], frame);
+    assert_eq!(hwsim.recv(&mut buf), Ok(None));
+
+    drop(hwsim);
+    unsafe { destroy_fake_device(header_ptr) };
+}
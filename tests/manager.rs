@@ -0,0 +1,121 @@
+//! Devices owned by a device manager.
+
+use std::ptr;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use virtio_drivers::testing::{
+    destroy_fake_device, fake_device, set_needs_reset, FakeBlk, ScriptedDevice,
+};
+use virtio_drivers::{
+    AddError, BlkReq, BlkResp, DeviceKind, DeviceManager, DeviceType, Driver, Error,
+    InterruptStatus, LostRequest,
+};
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+#[test]
+fn manager_hands_back_headers() {
+    let blk = fake_device(FakeBlk::new(16));
+    let blk_ptr = blk as *mut _;
+    let other = fake_device(FakeBlk::new(16));
+    let other_ptr = other as *mut _;
+    let invalid = fake_device(ScriptedDevice::new(DeviceType::Invalid));
+    let invalid_ptr = invalid as *mut _;
+
+    let mut manager = DeviceManager::<1>::new();
+    assert!(matches!(
+        manager.add(5, invalid),
+        Err(AddError::Probe(Error::InvalidParam, _))
+    ));
+    assert_eq!(manager.add(5, blk).ok(), Some(0));
+    let full = manager.add(5, other).unwrap_err();
+    assert!(matches!(full, AddError::Full(_)));
+    assert!(ptr::addr_eq(full.into_header(), other_ptr));
+
+    drop(manager);
+    unsafe {
+        destroy_fake_device(blk_ptr);
+        destroy_fake_device(other_ptr);
+        destroy_fake_device(invalid_ptr);
+    }
+}
+
+#[test]
+fn manager_dispatches_interrupts_to_drivers() {
+    let header = fake_device(FakeBlk::new(16));
+    let header_ptr = header as *mut _;
+    let mut manager = DeviceManager::<1>::new();
+    let index = manager.add(5, header).unwrap();
+    let Some(DeviceKind::Blk(blk)) = manager.get(index) else {
+        panic!("not a block device");
+    };
+    let (mut req, mut resp, mut buf) = (BlkReq::default(), BlkResp::default(), [0; 512]);
+    let token = unsafe { blk.read_block_nb(0, &mut req, &mut buf, &mut resp) }.unwrap();
+
+    let mut raised = Vec::new();
+    assert!(manager.handle_irq(
+        5,
+        |index, _, status| raised.push((index, status)),
+        |_, _| {}
+    ));
+    assert_eq!(raised, [(index, InterruptStatus::USED_BUFFER)]);
+    // the driver collected the completed request when it handled the interrupt
+    let Some(DeviceKind::Blk(blk)) = manager.get(index) else {
+        panic!("not a block device");
+    };
+    assert_eq!(blk.on_interrupt(), Ok(0));
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+    assert_eq!(
+        blk.poll_complete(&mut cx, token, &resp),
+        Poll::Ready(Ok(()))
+    );
+    assert!(!manager.handle_irq(5, |_, _, _| {}, |_, _| {}));
+
+    drop(manager);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn manager_recovers_devices() {
+    // the device never completes the request
+    let device = ScriptedDevice::new(DeviceType::Block).with_config(16u64.to_le_bytes().to_vec());
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut manager = DeviceManager::<1>::new();
+    let index = manager.add(5, header).unwrap();
+    let Some(DeviceKind::Blk(blk)) = manager.get(index) else {
+        panic!("not a block device");
+    };
+    let (mut req, mut resp, mut buf) = (BlkReq::default(), BlkResp::default(), [0; 512]);
+    let token = unsafe { blk.read_block_nb(0, &mut req, &mut buf, &mut resp) }.unwrap();
+    set_needs_reset(header_ptr);
+
+    let mut lost = Vec::new();
+    assert!(manager.handle_irq(
+        5,
+        |_, _, _| {},
+        |index, request| lost.push((index, request))
+    ));
+    assert_eq!(
+        lost,
+        [(
+            index,
+            LostRequest {
+                queue: "requestq",
+                token
+            }
+        )]
+    );
+    let Some(DeviceKind::Blk(blk)) = manager.get(index) else {
+        panic!("not a block device");
+    };
+    assert!(!blk.needs_reset());
+
+    drop(manager);
+    unsafe { destroy_fake_device(header_ptr) };
+}
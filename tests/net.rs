@@ -0,0 +1,74 @@
+//! Packets through the network driver.
+
+use std::mem::MaybeUninit;
+use virtio_drivers::testing::{
+    accesses, destroy_fake_device, fake_device, fake_modern_device, Access, FakeNet, Reply,
+    ScriptedDevice,
+};
+use virtio_drivers::{DeviceType, Error, VirtIONet};
+
+const VERSION_1: u64 = 1 << 32;
+const RING_RESET: u64 = 1 << 40;
+/// The size of the header of packets on modern devices.
+const NET_HDR_SIZE: usize = 12;
+
+#[test]
+fn net_receives_into_uninit_buffer() {
+    let device = FakeNet::new([2, 0, 0, 0, 0, 1]);
+    let rx = device.rx_packets();
+    rx.lock().unwrap().push_back(b"packet".to_vec());
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut net = VirtIONet::new(header).unwrap();
+
+    let mut buf = [MaybeUninit::uninit(); 1514];
+    assert_eq!(net.recv_uninit(&mut buf).unwrap(), b"packet");
+
+    drop(net);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn net_resets_recv_queue_alone() {
+    let mut config = vec![2, 0, 0, 0, 0, 1];
+    config.extend_from_slice(&1u16.to_le_bytes());
+    let mut packet = vec![0; NET_HDR_SIZE];
+    packet.extend_from_slice(b"packet");
+    let device = ScriptedDevice::new(DeviceType::Network)
+        .with_features(VERSION_1 | RING_RESET)
+        .with_config(config)
+        .reply(0, Reply::Data(packet));
+    let header = fake_modern_device(device);
+    let header_ptr = header as *mut _;
+    let mut net = VirtIONet::new(header).unwrap();
+
+    net.reset_recv_queue().unwrap();
+    let accesses = accesses(header_ptr);
+    let reset = accesses
+        .iter()
+        .position(|&access| access == Access::QueueReset(0))
+        .unwrap();
+    assert!(matches!(
+        accesses[reset + 1],
+        Access::QueueSet { queue: 0, pfn, .. } if pfn != 0
+    ));
+    let mut buf = [0; 1514];
+    let len = net.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"packet");
+
+    drop(net);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn net_recv_queue_reset_needs_ring_reset() {
+    let header = fake_device(FakeNet::new([2, 0, 0, 0, 0, 1]));
+    let header_ptr = header as *mut _;
+    let mut net = VirtIONet::new(header).unwrap();
+
+    assert_eq!(net.reset_recv_queue(), Err(Error::InvalidParam));
+    assert!(!accesses(header_ptr).contains(&Access::QueueReset(0)));
+
+    drop(net);
+    unsafe { destroy_fake_device(header_ptr) };
+}
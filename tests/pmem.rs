@@ -0,0 +1,59 @@
+//! Flush requests of the persistent memory driver.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use virtio_drivers::testing::{
+    destroy_fake_device, fake_device, read_chain, write_chain, FakeBackend,
+};
+use virtio_drivers::{DeviceType, Error, VirtIOPmem};
+
+const START: u64 = 0x1_0000_0000;
+const SIZE: u64 = 0x4000_0000;
+
+/// A fake persistent memory device, which answers flush requests with the
+/// scripted return values.
+#[derive(Default)]
+struct FakePmem {
+    /// The requests made to the device.
+    requests: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// The return values of the next requests.
+    ret: VecDeque<u32>,
+}
+
+impl FakeBackend for FakePmem {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Pmem
+    }
+
+    fn config(&self) -> Vec<u8> {
+        [START.to_le_bytes(), SIZE.to_le_bytes()].concat()
+    }
+
+    fn process(&mut self, _queue: u32, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Option<u32> {
+        let ret = self.ret.pop_front()?;
+        self.requests.lock().unwrap().push(read_chain(inputs));
+        Some(write_chain(outputs, &ret.to_le_bytes()) as u32)
+    }
+}
+
+#[test]
+fn pmem_flushes_the_range() {
+    let device = FakePmem {
+        ret: VecDeque::from([0, 1]),
+        ..FakePmem::default()
+    };
+    let requests = device.requests.clone();
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut pmem = VirtIOPmem::new(header).unwrap();
+    assert_eq!((pmem.start(), pmem.size()), (START, SIZE));
+
+    assert_eq!(pmem.flush(), Ok(()));
+    // the device failed to make the writes durable
+    assert_eq!(pmem.flush(), Err(Error::IoError));
+    // both were flush requests
+    assert_eq!(*requests.lock().unwrap(), [[0; 4], [0; 4]]);
+
+    drop(pmem);
+    unsafe { destroy_fake_device(header_ptr) };
+}
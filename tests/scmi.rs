@@ -0,0 +1,159 @@
+//! Commands and events through the SCMI driver.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use virtio_drivers::testing::{
+    destroy_fake_device, fake_device, read_chain, write_chain, FakeBackend,
+};
+use virtio_drivers::{DeviceType, Error, ScmiProtocol, VirtIOScmi};
+
+const P2A_CHANNELS: u64 = 1 << 0;
+const QUEUE_CMD: u32 = 0;
+const QUEUE_EVENT: u32 = 1;
+
+/// A fake SCMI platform.
+#[derive(Default)]
+struct FakeScmi {
+    features: u64,
+    /// The commands sent to the platform, with their message headers.
+    commands: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// The status and return values of the next commands, which follow the
+    /// message header of the command in the response.
+    responses: VecDeque<Vec<u8>>,
+    /// The notifications and delayed responses to send, with their message
+    /// headers.
+    events: VecDeque<Vec<u8>>,
+}
+
+impl FakeBackend for FakeScmi {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Scmi
+    }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn process(&mut self, queue: u32, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Option<u32> {
+        match queue {
+            QUEUE_CMD => {
+                let command = read_chain(inputs);
+                let response = [&command[..4], &self.responses.pop_front()?].concat();
+                self.commands.lock().unwrap().push(command);
+                Some(write_chain(outputs, &response) as u32)
+            }
+            QUEUE_EVENT => {
+                let event = self.events.pop_front()?;
+                Some(write_chain(outputs, &event) as u32)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A message header of `protocol_id`, `message_id`, `type_` and `token`.
+fn message_header(protocol_id: u8, message_id: u8, type_: u8, token: u16) -> [u8; 4] {
+    (message_id as u32 | (type_ as u32) << 8 | (protocol_id as u32) << 10 | (token as u32) << 18)
+        .to_le_bytes()
+}
+
+/// The status and return values of a response.
+fn response(status: i32, ret: &[u8]) -> Vec<u8> {
+    [&status.to_le_bytes(), ret].concat()
+}
+
+#[test]
+fn scmi_commands_return_their_values() {
+    let device = FakeScmi {
+        responses: VecDeque::from([
+            response(0, &0x2_0000u32.to_le_bytes()),
+            response(0, &[0xaa; 8]),
+            response(-1, &[]),
+            // without the status
+            Vec::new(),
+        ]),
+        ..FakeScmi::default()
+    };
+    let commands = device.commands.clone();
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut scmi = VirtIOScmi::new(header).unwrap();
+    assert!(!scmi.has_events());
+
+    assert_eq!(
+        scmi.protocol_version(ScmiProtocol::Base as u8),
+        Ok(0x2_0000)
+    );
+    let clock = ScmiProtocol::Clock as u8;
+    let mut ret = [0; 4];
+    // the return values are cut to the buffer
+    assert_eq!(scmi.command(clock, 3, &[1, 0, 0, 0], &mut ret), Ok(4));
+    assert_eq!(ret, [0xaa; 4]);
+    assert_eq!(
+        scmi.command(clock, 4, &[], &mut []),
+        Err(Error::ScmiStatus(-1))
+    );
+    assert_eq!(scmi.command(clock, 5, &[], &mut []), Err(Error::IoError));
+
+    // the tokens count the commands
+    assert_eq!(
+        *commands.lock().unwrap(),
+        [
+            message_header(0x10, 0, 0, 0).to_vec(),
+            [message_header(0x14, 3, 0, 1), [1, 0, 0, 0]].concat(),
+            message_header(0x14, 4, 0, 2).to_vec(),
+            message_header(0x14, 5, 0, 3).to_vec(),
+        ]
+    );
+
+    drop(scmi);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn scmi_pops_notifications_and_delayed_responses() {
+    let sensor = ScmiProtocol::Sensor as u8;
+    let device = FakeScmi {
+        features: P2A_CHANNELS,
+        responses: VecDeque::from([response(0, &[])]),
+        events: VecDeque::from([
+            [&message_header(sensor, 1, 3, 0)[..], &[1, 2, 3, 4]].concat(),
+            [&message_header(sensor, 2, 2, 5)[..], &[5; 8]].concat(),
+        ]),
+        ..FakeScmi::default()
+    };
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut scmi = VirtIOScmi::new(header).unwrap();
+    assert!(scmi.has_events());
+    // the platform sends the events once notified
+    scmi.command(sensor, 6, &[], &mut []).unwrap();
+
+    let mut buf = [0; 8];
+    let notification = scmi.pop_event(&mut buf).unwrap().unwrap();
+    assert_eq!(
+        (
+            notification.protocol_id,
+            notification.message_id,
+            notification.delayed_response,
+            notification.len
+        ),
+        (sensor, 1, false, 4)
+    );
+    assert_eq!(buf[..4], [1, 2, 3, 4]);
+    let delayed = scmi.pop_event(&mut buf).unwrap().unwrap();
+    assert_eq!(
+        (
+            delayed.message_id,
+            delayed.delayed_response,
+            delayed.token,
+            delayed.len
+        ),
+        (2, true, 5, 8)
+    );
+    assert_eq!(buf, [5; 8]);
+    assert!(scmi.pop_event(&mut buf).unwrap().is_none());
+
+    drop(scmi);
+    unsafe { destroy_fake_device(header_ptr) };
+}
@@ -0,0 +1,389 @@
+//! PCM streams of the sound driver.
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+use virtio_drivers::testing::{
+    destroy_fake_device, fake_device, read_chain, write_chain, FakeBackend,
+};
+use virtio_drivers::{
+    DeviceType, Direction, Error, JackFeatures, PcmFeatures, PcmFormat, PcmParameters, PcmRate,
+    PeriodElapsed, SoundEvent, VirtIOSound,
+};
+
+const QUEUE_CONTROL: u32 = 0;
+const QUEUE_EVENT: u32 = 1;
+const QUEUE_TX: u32 = 2;
+const QUEUE_RX: u32 = 3;
+
+const JACK_INFO: u32 = 0x0001;
+const JACK_REMAP: u32 = 0x0002;
+const PCM_INFO: u32 = 0x0100;
+const PCM_SET_PARAMS: u32 = 0x0101;
+const PCM_PREPARE: u32 = 0x0102;
+const PCM_RELEASE: u32 = 0x0103;
+const PCM_START: u32 = 0x0104;
+const PCM_STOP: u32 = 0x0105;
+const CHMAP_INFO: u32 = 0x0200;
+const JACK_CONNECTED: u32 = 0x1000;
+const JACK_DISCONNECTED: u32 = 0x1001;
+const S_OK: u32 = 0x8000;
+const S_BAD_MSG: u32 = 0x8001;
+const S_NOT_SUPP: u32 = 0x8002;
+const S_IO_ERR: u32 = 0x8003;
+
+/// Periods transferred through the TX or RX queue, with their stream ID.
+type Periods = Vec<(u32, Vec<u8>)>;
+
+/// Periods to capture, with the status the device returns for them.
+type Captures = VecDeque<(u32, Vec<u8>)>;
+
+/// A fake sound card with an output stream and an input stream.
+#[derive(Default)]
+struct FakeSound {
+    /// The control requests made to the device.
+    requests: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// The status codes of the next control requests, `S_OK` once empty.
+    statuses: VecDeque<u32>,
+    /// The periods played back, with their stream ID.
+    played: Arc<Mutex<Periods>>,
+    /// The status and latency of the next periods to play back; a period is
+    /// held until there is one.
+    playback: Arc<Mutex<VecDeque<(u32, u32)>>>,
+    /// The streams captured from.
+    captured: Arc<Mutex<Vec<u32>>>,
+    /// The status and frames of the next periods to capture; a period is held
+    /// until there is one.
+    capture: Arc<Mutex<Captures>>,
+    /// The code and data of the events to send.
+    events: Arc<Mutex<VecDeque<(u32, u32)>>>,
+}
+
+impl FakeSound {
+    fn control(&mut self, request: &[u8]) -> Vec<u8> {
+        let status = self.statuses.pop_front().unwrap_or(S_OK);
+        let mut response = status.to_le_bytes().to_vec();
+        match word(request, 0) {
+            JACK_INFO => response.extend(jack_info()),
+            PCM_INFO => response.extend(pcm_info(word(request, 4))),
+            CHMAP_INFO => response.extend(chmap_info()),
+            _ => {}
+        }
+        response
+    }
+}
+
+impl FakeBackend for FakeSound {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Sound
+    }
+
+    fn config(&self) -> Vec<u8> {
+        words(&[1, 2, 1])
+    }
+
+    fn process(&mut self, queue: u32, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Option<u32> {
+        match queue {
+            QUEUE_CONTROL => {
+                let request = read_chain(inputs);
+                self.requests.lock().unwrap().push(request.clone());
+                let response = self.control(&request);
+                Some(write_chain(outputs, &response) as u32)
+            }
+            QUEUE_EVENT => {
+                let (code, data) = self.events.lock().unwrap().pop_front()?;
+                Some(write_chain(outputs, &words(&[code, data])) as u32)
+            }
+            QUEUE_TX => {
+                let (status, latency) = self.playback.lock().unwrap().pop_front()?;
+                let xfer = read_chain(inputs);
+                let period = (word(&xfer, 0), xfer[4..].to_vec());
+                self.played.lock().unwrap().push(period);
+                Some(write_chain(outputs, &words(&[status, latency])) as u32)
+            }
+            QUEUE_RX => {
+                let (status, frames) = self.capture.lock().unwrap().pop_front()?;
+                self.captured
+                    .lock()
+                    .unwrap()
+                    .push(word(&read_chain(inputs), 0));
+                // the frames fill the data buffers and the status goes last
+                let (status_buf, data) = outputs.split_last_mut().unwrap();
+                let len = write_chain(data, &frames);
+                Some((len + write_chain(&mut [status_buf], &words(&[status, 0]))) as u32)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The information of the only jack, a connected one which can be remapped.
+fn jack_info() -> Vec<u8> {
+    let mut info = words(&[0x10, JackFeatures::REMAP.bits(), 0x0101_4010, 0x0001_0014]);
+    info.extend([1, 0, 0, 0, 0, 0, 0, 0]);
+    info
+}
+
+/// The information of the only channel map, front left and right of the
+/// output streams.
+fn chmap_info() -> Vec<u8> {
+    let mut info = words(&[0x10]);
+    info.extend([Direction::Output as u8, 2, 3, 4]);
+    info.resize(24, 0);
+    info
+}
+
+/// The information of stream 0, an output stream, or stream 1, an input
+/// stream.
+fn pcm_info(stream_id: u32) -> Vec<u8> {
+    let mut info = words(&[0, 0]);
+    info.extend((1u64 << PcmFormat::S16 as u8).to_le_bytes());
+    info.extend((1u64 << PcmRate::Rate48000 as u8).to_le_bytes());
+    info.extend([stream_id as u8, 1, 2, 0, 0, 0, 0, 0]);
+    info
+}
+
+fn word(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn words(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+fn params(buffer_bytes: u32, period_bytes: u32) -> PcmParameters {
+    PcmParameters {
+        buffer_bytes,
+        period_bytes,
+        features: PcmFeatures::empty(),
+        channels: 2,
+        format: PcmFormat::S16,
+        rate: PcmRate::Rate48000,
+    }
+}
+
+#[test]
+fn sound_plays_back_periods() {
+    let device = FakeSound {
+        statuses: VecDeque::from([S_OK, S_OK, S_OK, S_BAD_MSG]),
+        ..FakeSound::default()
+    };
+    let requests = device.requests.clone();
+    let played = device.played.clone();
+    let playback = device.playback.clone();
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut sound = VirtIOSound::new(header).unwrap();
+    assert_eq!((sound.jacks(), sound.streams(), sound.chmaps()), (1, 2, 1));
+
+    let info = sound.pcm_info(0).unwrap();
+    assert_eq!(info.direction(), Direction::Output);
+    assert!(info.supports_format(PcmFormat::S16));
+    assert!(!info.supports_format(PcmFormat::U8));
+    assert!(info.supports_rate(PcmRate::Rate48000));
+    assert_eq!((info.channels_min, info.channels_max), (1, 2));
+    assert_eq!(sound.pcm_info(2).unwrap_err(), Error::InvalidParam);
+
+    // the buffer must hold whole periods, which is checked by the driver
+    assert_eq!(
+        sound.pcm_set_params(0, params(1000, 256)),
+        Err(Error::InvalidParam)
+    );
+    assert_eq!(sound.pcm_set_params(0, params(1024, 256)), Ok(()));
+    assert_eq!(sound.pcm_prepare(0), Ok(()));
+    // the device rejected the request
+    assert_eq!(sound.pcm_start(0), Err(Error::SoundStatus(S_BAD_MSG)));
+    assert_eq!(sound.pcm_start(0), Ok(()));
+    assert_eq!(sound.pcm_start(2), Err(Error::InvalidParam));
+
+    playback
+        .lock()
+        .unwrap()
+        .extend([(S_OK, 512), (S_IO_ERR, 0)]);
+    assert_eq!(sound.pcm_xfer(0, &[1; 256]), Ok(512));
+    assert_eq!(
+        sound.pcm_xfer(0, &[2; 256]),
+        Err(Error::SoundStatus(S_IO_ERR))
+    );
+    assert_eq!(sound.pcm_xfer(2, &[3; 256]), Err(Error::InvalidParam));
+    assert_eq!(
+        *played.lock().unwrap(),
+        [(0, vec![1; 256]), (0, vec![2; 256])]
+    );
+
+    assert_eq!(sound.pcm_stop(0), Ok(()));
+    assert_eq!(sound.pcm_release(0), Ok(()));
+    let mut set_params = words(&[PCM_SET_PARAMS, 0, 1024, 256, 0]);
+    set_params.extend([2, PcmFormat::S16 as u8, PcmRate::Rate48000 as u8, 0]);
+    assert_eq!(
+        *requests.lock().unwrap(),
+        [
+            words(&[PCM_INFO, 0, 1, 32]),
+            set_params,
+            words(&[PCM_PREPARE, 0]),
+            words(&[PCM_START, 0]),
+            words(&[PCM_START, 0]),
+            words(&[PCM_STOP, 0]),
+            words(&[PCM_RELEASE, 0]),
+        ]
+    );
+
+    drop(sound);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn sound_captures_periods() {
+    let device = FakeSound::default();
+    let captured = device.captured.clone();
+    let capture = device.capture.clone();
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut sound = VirtIOSound::new(header).unwrap();
+
+    assert_eq!(sound.pcm_info(1).unwrap().direction(), Direction::Input);
+    assert_eq!(sound.pcm_set_params(1, params(1024, 256)), Ok(()));
+    assert_eq!(sound.pcm_prepare(1), Ok(()));
+    assert_eq!(sound.pcm_start(1), Ok(()));
+
+    capture.lock().unwrap().extend([
+        (S_OK, vec![1; 256]),
+        (S_OK, vec![2; 100]),
+        (S_IO_ERR, vec![]),
+    ]);
+    let mut frames = [0; 256];
+    assert_eq!(sound.pcm_capture(1, &mut frames), Ok(256));
+    assert_eq!(frames, [1; 256]);
+    // the device captured less than a whole period
+    assert_eq!(sound.pcm_capture(1, &mut frames), Ok(100));
+    assert_eq!(frames[..100], [2; 100]);
+    assert_eq!(
+        sound.pcm_capture(1, &mut frames),
+        Err(Error::SoundStatus(S_IO_ERR))
+    );
+    assert_eq!(sound.pcm_capture(2, &mut frames), Err(Error::InvalidParam));
+    assert_eq!(*captured.lock().unwrap(), [1, 1, 1]);
+
+    drop(sound);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn sound_queries_jacks_and_chmaps() {
+    let device = FakeSound {
+        statuses: VecDeque::from([S_OK, S_OK, S_NOT_SUPP]),
+        ..FakeSound::default()
+    };
+    let requests = device.requests.clone();
+    let events = device.events.clone();
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut sound = VirtIOSound::new(header).unwrap();
+
+    let jack = sound.jack_info(0).unwrap();
+    assert_eq!(jack.hda_fn_nid, 0x10);
+    assert_eq!(jack.features, JackFeatures::REMAP);
+    assert_eq!(
+        (jack.hda_reg_defconf, jack.hda_reg_caps),
+        (0x0101_4010, 0x0001_0014)
+    );
+    assert!(jack.connected());
+    assert_eq!(sound.jack_info(1).unwrap_err(), Error::InvalidParam);
+    assert_eq!(sound.jack_remap(0, 2, 3), Ok(()));
+    // the device doesn't support remapping after all
+    assert_eq!(
+        sound.jack_remap(0, 2, 3),
+        Err(Error::SoundStatus(S_NOT_SUPP))
+    );
+    assert_eq!(sound.jack_remap(1, 2, 3), Err(Error::InvalidParam));
+
+    let chmap = sound.chmap_info(0).unwrap();
+    assert_eq!(chmap.hda_fn_nid, 0x10);
+    assert_eq!(chmap.direction(), Direction::Output);
+    assert_eq!(chmap.positions(), [3, 4]);
+    assert_eq!(sound.chmap_info(1).unwrap_err(), Error::InvalidParam);
+    assert_eq!(
+        *requests.lock().unwrap(),
+        [
+            words(&[JACK_INFO, 0, 1, 24]),
+            words(&[JACK_REMAP, 0, 2, 3]),
+            words(&[JACK_REMAP, 0, 2, 3]),
+            words(&[CHMAP_INFO, 0, 1, 24]),
+        ]
+    );
+
+    // the jack is unplugged and plugged in again
+    assert_eq!(sound.pop_event(), Ok(None));
+    events
+        .lock()
+        .unwrap()
+        .extend([(JACK_DISCONNECTED, 0), (JACK_CONNECTED, 0), (0x2000, 5)]);
+    sound.jack_info(0).unwrap();
+    assert_eq!(sound.pop_event(), Ok(Some(SoundEvent::JackDisconnected(0))));
+    assert_eq!(sound.pop_event(), Ok(Some(SoundEvent::JackConnected(0))));
+    assert_eq!(sound.pop_event(), Ok(Some(SoundEvent::Unknown(0x2000, 5))));
+    assert_eq!(sound.pop_event(), Ok(None));
+
+    drop(sound);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn sound_handles_elapsed_periods() {
+    let device = FakeSound::default();
+    let played = device.played.clone();
+    let playback = device.playback.clone();
+    let capture = device.capture.clone();
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut sound = VirtIOSound::new(header).unwrap();
+    let mut elapsed = Vec::new();
+    let mut record = |period: PeriodElapsed, frames: &[u8]| {
+        elapsed.push((
+            period.stream_id,
+            period.direction,
+            period.token,
+            period.latency_bytes,
+            period.ok,
+            frames.to_vec(),
+        ))
+    };
+
+    let tokens: Vec<u16> = (1..=4)
+        .map(|i| sound.pcm_queue_xfer(0, &[i; 256]).unwrap())
+        .collect();
+    // all periods are in flight
+    assert_eq!(sound.pcm_queue_xfer(0, &[5; 256]), Err(Error::NotReady));
+    assert_eq!(sound.pcm_xfer(0, &[5; 256]), Err(Error::NotReady));
+    let capture_token = sound.pcm_queue_capture(1, 256).unwrap();
+    assert_eq!(sound.pcm_capture(1, &mut [0; 256]), Err(Error::NotReady));
+    assert_eq!(sound.pcm_queue_capture(1, 4097), Err(Error::InvalidParam));
+    assert_eq!(sound.handle_periods(&mut record), Ok(0));
+
+    // the device consumes two periods and fills the capture buffer
+    playback
+        .lock()
+        .unwrap()
+        .extend([(S_OK, 256), (S_IO_ERR, 0)]);
+    capture.lock().unwrap().push_back((S_OK, vec![6; 200]));
+    sound.pcm_start(0).unwrap();
+    assert_eq!(sound.handle_periods(&mut record), Ok(3));
+    // a slot is free again, and the frames were copied when queued
+    assert!(sound.pcm_queue_xfer(0, &[5; 256]).is_ok());
+    assert_eq!(
+        *played.lock().unwrap(),
+        [(0, vec![1; 256]), (0, vec![2; 256])]
+    );
+    assert_eq!(sound.handle_periods(&mut record), Ok(0));
+    assert_eq!(
+        elapsed,
+        [
+            (0, Direction::Output, tokens[0], 256, true, vec![]),
+            (0, Direction::Output, tokens[1], 0, false, vec![]),
+            (1, Direction::Input, capture_token, 0, true, vec![6; 200]),
+        ]
+    );
+
+    drop(sound);
+    unsafe { destroy_fake_device(header_ptr) };
+}
@@ -0,0 +1,34 @@
+//! Timeouts of the requests which drivers block on.
+
+use virtio_drivers::testing::{accesses, destroy_fake_device, fake_device, Access, ScriptedDevice};
+use virtio_drivers::{DeviceType, Driver, Error, LostRequest, VirtIOBlk};
+
+#[test]
+fn blk_request_times_out() {
+    // the device never uses the chains made available to it
+    let device = ScriptedDevice::new(DeviceType::Block).with_config(16u64.to_le_bytes().to_vec());
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut blk = VirtIOBlk::new(header).unwrap();
+    blk.set_timeout(Some(100));
+
+    let mut buf = [0; 512];
+    assert_eq!(blk.read_block(0, &mut buf), Err(Error::Timeout));
+    // the device is reset before the buffers on the stack go out of scope
+    assert_eq!(accesses(header_ptr).last(), Some(&Access::Reset));
+    assert_eq!(blk.read_block(0, &mut buf), Err(Error::NotReady));
+
+    let mut lost = Vec::new();
+    blk.recover(&mut |request| lost.push(request)).unwrap();
+    assert_eq!(
+        lost,
+        [LostRequest {
+            queue: "requestq",
+            token: 0
+        }]
+    );
+    assert_eq!(blk.write_block(0, &buf), Err(Error::Timeout));
+
+    drop(blk);
+    unsafe { destroy_fake_device(header_ptr) };
+}
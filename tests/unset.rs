@@ -0,0 +1,25 @@
+//! Queues which drivers unset from their devices before freeing them.
+
+use virtio_drivers::testing::{
+    accesses, destroy_fake_device, dma_allocated, fake_device, Access, FakeBlk,
+};
+use virtio_drivers::VirtIOBlk;
+
+#[test]
+fn blk_frees_its_queue() {
+    let header = fake_device(FakeBlk::new(16));
+    let header_ptr = header as *mut _;
+    let blk = VirtIOBlk::new(header).unwrap();
+    let paddr = accesses(header_ptr)
+        .iter()
+        .find_map(|access| match *access {
+            Access::QueueSet { queue: 0, pfn, .. } if pfn != 0 => Some(pfn as usize * 0x1000),
+            _ => None,
+        })
+        .unwrap();
+    assert!(dma_allocated(paddr));
+
+    drop(blk);
+    assert!(!dma_allocated(paddr));
+    unsafe { destroy_fake_device(header_ptr) };
+}
@@ -0,0 +1,298 @@
+//! Streams of the video driver.
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use virtio_drivers::testing::{
+    destroy_fake_device, fake_device, read_chain, write_chain, FakeBackend,
+};
+use virtio_drivers::{
+    BufferFlags, DeviceType, Error, MemEntry, QueueType, VideoControl, VideoEvent, VideoFormat,
+    VideoParams, VirtIOVideo,
+};
+
+const QUEUE_COMMAND: u32 = 0;
+const QUEUE_EVENT: u32 = 1;
+
+const STREAM_CREATE: u32 = 0x0101;
+const RESOURCE_CREATE: u32 = 0x0104;
+const RESOURCE_QUEUE: u32 = 0x0105;
+const QUERY_CAPABILITY: u32 = 0x0100;
+const GET_PARAMS: u32 = 0x0108;
+const SET_PARAMS: u32 = 0x0109;
+const GET_CONTROL: u32 = 0x010b;
+const SET_CONTROL: u32 = 0x010c;
+const OK_NODATA: u32 = 0x0200;
+const OK_QUERY_CAPABILITY: u32 = 0x0201;
+const OK_RESOURCE_QUEUE: u32 = 0x0202;
+const OK_GET_PARAMS: u32 = 0x0203;
+const OK_GET_CONTROL: u32 = 0x0205;
+const ERR_INVALID_STREAM_ID: u32 = 0x0302;
+const EVENT_DECODER_RESOLUTION_CHANGED: u32 = 0x0200;
+
+/// The capabilities the fake device reports.
+const CAPABILITIES: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+/// The stream which the fake device does not know.
+const UNKNOWN_STREAM: u32 = 99;
+
+/// A fake video device, which keeps the parameters and controls last set,
+/// and completes queued resources only once allowed to.
+struct FakeVideo {
+    device_type: DeviceType,
+    /// The commands sent to the device.
+    commands: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// The number of queued resources the device may still complete.
+    frames: Arc<AtomicUsize>,
+    /// The flags of the completed resources.
+    flags: BufferFlags,
+    /// The events to send, as their type and stream ID.
+    events: VecDeque<(u32, u32)>,
+    params: Vec<u8>,
+    control: u32,
+}
+
+impl FakeVideo {
+    fn new(device_type: DeviceType) -> Self {
+        FakeVideo {
+            device_type,
+            commands: Arc::default(),
+            frames: Arc::default(),
+            flags: BufferFlags::empty(),
+            events: VecDeque::new(),
+            params: Vec::new(),
+            control: 0,
+        }
+    }
+
+    /// The type and body of the response to `command` of `type_`, or `None`
+    /// to leave it with the device.
+    fn respond(&mut self, type_: u32, command: &[u8]) -> Option<(u32, Vec<u8>)> {
+        Some(match type_ {
+            QUERY_CAPABILITY => (OK_QUERY_CAPABILITY, CAPABILITIES.to_vec()),
+            SET_PARAMS => {
+                self.params = command[8..].to_vec();
+                (OK_NODATA, Vec::new())
+            }
+            GET_PARAMS => (OK_GET_PARAMS, self.params.clone()),
+            SET_CONTROL => {
+                self.control = word(command, 16);
+                (OK_NODATA, Vec::new())
+            }
+            GET_CONTROL => (OK_GET_CONTROL, words(&[self.control, 0])),
+            RESOURCE_QUEUE => {
+                self.frames
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .ok()?;
+                // the timestamp, flags, offset and size of the data
+                let mut rsp = command[16..24].to_vec();
+                rsp.extend(words(&[self.flags.bits(), 0, word(command, 28), 0]));
+                (OK_RESOURCE_QUEUE, rsp)
+            }
+            _ => (OK_NODATA, Vec::new()),
+        })
+    }
+}
+
+impl FakeBackend for FakeVideo {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn config(&self) -> Vec<u8> {
+        // the version and the maximum lengths of responses
+        words(&[2, 1024, 1024])
+    }
+
+    fn process(&mut self, queue: u32, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Option<u32> {
+        match queue {
+            QUEUE_COMMAND => {
+                let command = read_chain(inputs);
+                let (type_, stream_id) = (word(&command, 0), word(&command, 4));
+                let (type_, body) = if stream_id == UNKNOWN_STREAM {
+                    (ERR_INVALID_STREAM_ID, Vec::new())
+                } else {
+                    self.respond(type_, &command)?
+                };
+                self.commands.lock().unwrap().push(command);
+                let rsp = [words(&[type_, stream_id]), body].concat();
+                Some(write_chain(outputs, &rsp) as u32)
+            }
+            QUEUE_EVENT => {
+                let (type_, stream_id) = self.events.pop_front()?;
+                Some(write_chain(outputs, &words(&[type_, stream_id])) as u32)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The little-endian word at `offset` of `bytes`.
+fn word(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// The little-endian bytes of `words`.
+fn words(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+#[test]
+fn video_decodes_a_stream() {
+    let mut device = FakeVideo::new(DeviceType::VideoDecoder);
+    device.flags = BufferFlags::EOS;
+    device
+        .events
+        .push_back((EVENT_DECODER_RESOLUTION_CHANGED, 1));
+    let (commands, frames) = (device.commands.clone(), device.frames.clone());
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut video = VirtIOVideo::new(header).unwrap();
+    assert!(!video.is_encoder());
+
+    let mut caps = [0; 16];
+    // the raw response, with its header
+    assert_eq!(video.query_capability(QueueType::Input, &mut caps), Ok(16));
+    assert_eq!(caps[..8], words(&[OK_QUERY_CAPABILITY, 0]));
+    assert_eq!(caps[8..], CAPABILITIES);
+    video.stream_create(1, VideoFormat::H264).unwrap();
+    let mut params = VideoParams::default();
+    params.queue_type = QueueType::Output as u32;
+    params.format = VideoFormat::NV12 as u32;
+    params.frame_width = 640;
+    params.frame_height = 480;
+    video.set_params(1, &params).unwrap();
+    let got = video.get_params(1, QueueType::Output).unwrap();
+    assert_eq!(
+        (got.format, got.frame_width, got.frame_height),
+        (3, 640, 480)
+    );
+    // controls are only for encoders
+    assert_eq!(
+        video.get_control(1, VideoControl::Bitrate),
+        Err(Error::InvalidParam)
+    );
+    assert_eq!(
+        video.stream_destroy(UNKNOWN_STREAM),
+        Err(Error::InvalidParam)
+    );
+
+    video
+        .resource_create(
+            1,
+            QueueType::Input,
+            7,
+            &[0],
+            &[MemEntry::new(0x10_0000, 0x1000)],
+        )
+        .unwrap();
+    video
+        .resource_queue(1, QueueType::Input, 7, 33, &[100])
+        .unwrap();
+    // the device holds the bitstream until it has decoded it
+    assert!(video.dequeue().unwrap().is_none());
+    frames.store(1, Ordering::SeqCst);
+    video.stream_drain(1).unwrap();
+    let buffer = video.dequeue().unwrap().unwrap();
+    assert_eq!(
+        (
+            buffer.stream_id,
+            buffer.queue_type,
+            buffer.resource_id,
+            buffer.timestamp,
+            buffer.flags,
+            buffer.size
+        ),
+        (1, QueueType::Input, 7, 33, BufferFlags::EOS, 100)
+    );
+    assert!(video.dequeue().unwrap().is_none());
+    assert_eq!(
+        video.pop_event(),
+        Ok(Some(VideoEvent::ResolutionChanged(1)))
+    );
+    assert_eq!(video.pop_event(), Ok(None));
+
+    let commands = commands.lock().unwrap();
+    let create = commands
+        .iter()
+        .find(|c| word(c, 0) == STREAM_CREATE)
+        .unwrap();
+    // both sides in guest pages, decoding H.264
+    assert_eq!(create[..24], words(&[STREAM_CREATE, 1, 0, 0, 0x1002, 0]));
+    let resource = commands
+        .iter()
+        .find(|c| word(c, 0) == RESOURCE_CREATE)
+        .unwrap();
+    // only the memory entry used is sent
+    assert_eq!(resource.len(), 8 + 16 + 2 * 8 * 4 + 16);
+
+    drop(video);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn video_encodes_frames_with_controls() {
+    let mut device = FakeVideo::new(DeviceType::VideoEncoder);
+    device.flags = BufferFlags::IFRAME;
+    let (commands, frames) = (device.commands.clone(), device.frames.clone());
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut video = VirtIOVideo::new(header).unwrap();
+    assert!(video.is_encoder());
+
+    video.stream_create(1, VideoFormat::VP8).unwrap();
+    video
+        .set_control(1, VideoControl::Bitrate, 1_000_000)
+        .unwrap();
+    assert_eq!(video.get_control(1, VideoControl::Bitrate), Ok(1_000_000));
+
+    // a raw frame of two planes, and a buffer for the bitstream
+    let frame = [
+        MemEntry::new(0x10_0000, 0x1000),
+        MemEntry::new(0x20_0000, 0x800),
+    ];
+    video
+        .resource_create(1, QueueType::Input, 1, &[0, 0x1000], &frame)
+        .unwrap();
+    video
+        .resource_create(
+            1,
+            QueueType::Output,
+            2,
+            &[0],
+            &[MemEntry::new(0x30_0000, 0x1000)],
+        )
+        .unwrap();
+    // both buffers are with the device at once
+    frames.store(2, Ordering::SeqCst);
+    video
+        .resource_queue(1, QueueType::Input, 1, 5, &[0x1000, 0x800])
+        .unwrap();
+    video
+        .resource_queue(1, QueueType::Output, 2, 5, &[])
+        .unwrap();
+    let mut dequeued = [(); 2].map(|_| video.dequeue().unwrap().unwrap());
+    dequeued.sort_by_key(|buffer| buffer.resource_id);
+    assert_eq!(
+        dequeued.map(|buffer| (buffer.queue_type, buffer.flags, buffer.size)),
+        [
+            (QueueType::Input, BufferFlags::IFRAME, 0x1000),
+            (QueueType::Output, BufferFlags::IFRAME, 0),
+        ]
+    );
+    assert!(video.dequeue().unwrap().is_none());
+    video.resource_destroy_all(1, QueueType::Input).unwrap();
+
+    let commands = commands.lock().unwrap();
+    let control = commands.iter().find(|c| word(c, 0) == SET_CONTROL).unwrap();
+    assert_eq!(control[..], words(&[SET_CONTROL, 1, 1, 0, 1_000_000, 0]));
+    let queue = commands
+        .iter()
+        .find(|c| word(c, 0) == RESOURCE_QUEUE)
+        .unwrap();
+    // the sizes of both planes of the raw frame
+    assert_eq!(queue[24..36], words(&[2, 0x1000, 0x800]));
+
+    drop(video);
+    unsafe { destroy_fake_device(header_ptr) };
+}
@@ -0,0 +1,170 @@
+//! Commands and messages through the Wayland driver.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use virtio_drivers::testing::{
+    destroy_fake_device, fake_device, read_chain, write_chain, FakeBackend,
+};
+use virtio_drivers::{DeviceType, Error, VfdFlags, VirtIOWl, WlEvent};
+
+const TRANS_FLAGS: u64 = 1 << 0;
+const QUEUE_IN: u32 = 0;
+const QUEUE_OUT: u32 = 1;
+
+const VFD_NEW: u32 = 0x100;
+const VFD_CLOSE: u32 = 0x101;
+const VFD_SEND: u32 = 0x102;
+const VFD_RECV: u32 = 0x103;
+const VFD_NEW_CTX: u32 = 0x104;
+const VFD_HUP: u32 = 0x106;
+const RESP_OK: u32 = 0x1000;
+const RESP_VFD_NEW: u32 = 0x1001;
+const RESP_OUT_OF_MEMORY: u32 = 0x1101;
+const RESP_INVALID_ID: u32 = 0x1102;
+
+/// A fake Wayland compositor on the host.
+#[derive(Default)]
+struct FakeWl {
+    /// The commands sent to the host.
+    commands: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// The responses to the next commands.
+    responses: VecDeque<Vec<u8>>,
+    /// The messages to send to the guest.
+    messages: VecDeque<Vec<u8>>,
+}
+
+impl FakeBackend for FakeWl {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Wl
+    }
+
+    fn features(&self) -> u64 {
+        TRANS_FLAGS
+    }
+
+    fn process(&mut self, queue: u32, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Option<u32> {
+        match queue {
+            QUEUE_OUT => {
+                let response = self.responses.pop_front()?;
+                self.commands.lock().unwrap().push(read_chain(inputs));
+                Some(write_chain(outputs, &response) as u32)
+            }
+            QUEUE_IN => {
+                let message = self.messages.pop_front()?;
+                Some(write_chain(outputs, &message) as u32)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The little-endian bytes of `words`.
+fn words(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+/// A `VFD_NEW` message or response of `type_` for VFD `vfd_id`.
+fn vfd_new(type_: u32, vfd_id: u32, flags: VfdFlags, pfn: u64, size: u32) -> Vec<u8> {
+    let mut msg = words(&[type_, 0, vfd_id, flags.bits()]);
+    msg.extend_from_slice(&pfn.to_le_bytes());
+    msg.extend_from_slice(&size.to_le_bytes());
+    msg.extend_from_slice(&[0; 36]);
+    msg
+}
+
+#[test]
+fn wl_creates_and_uses_vfds() {
+    let device = FakeWl {
+        responses: VecDeque::from([
+            vfd_new(
+                RESP_VFD_NEW,
+                1,
+                VfdFlags::READ | VfdFlags::WRITE,
+                0x12345,
+                0x1000,
+            ),
+            vfd_new(RESP_VFD_NEW, 2, VfdFlags::empty(), 0, 0),
+            words(&[RESP_OK, 0]),
+            words(&[RESP_INVALID_ID, 0]),
+            words(&[RESP_OUT_OF_MEMORY, 0]),
+        ]),
+        ..FakeWl::default()
+    };
+    let commands = device.commands.clone();
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut wl = VirtIOWl::new(header).unwrap();
+
+    let info = wl.new_alloc(1, 0x1000).unwrap();
+    assert_eq!(
+        (info.id, info.flags, info.pfn, info.size),
+        (1, VfdFlags::READ | VfdFlags::WRITE, 0x12345, 0x1000)
+    );
+    wl.new_context(2).unwrap();
+    wl.send(2, &[1], b"hello").unwrap();
+    assert_eq!(wl.close(7), Err(Error::InvalidParam));
+    assert_eq!(wl.new_pipe(3, true), Err(Error::DmaError));
+    // the IDs with the most significant bit set are the host's
+    assert_eq!(wl.new_context(1 << 31), Err(Error::InvalidParam));
+
+    let commands = commands.lock().unwrap();
+    assert_eq!(commands.len(), 5);
+    assert_eq!(commands[0][..20], words(&[VFD_NEW, 0, 1, 3, 0]));
+    assert_eq!(commands[0][24..28], 0x1000u32.to_le_bytes());
+    assert_eq!(commands[1][..12], words(&[VFD_NEW_CTX, 0, 2]));
+    // the VFDs are sent with their kind, as TRANS_FLAGS is negotiated
+    assert_eq!(
+        commands[2],
+        [words(&[VFD_SEND, 0, 2, 1, 0, 1]), b"hello".to_vec()].concat()
+    );
+    assert_eq!(commands[3], words(&[VFD_CLOSE, 0, 7]));
+
+    drop(wl);
+    unsafe { destroy_fake_device(header_ptr) };
+}
+
+#[test]
+fn wl_receives_messages_from_the_host() {
+    let host_vfd = 1 << 31 | 1;
+    let device = FakeWl {
+        responses: VecDeque::from([vfd_new(RESP_VFD_NEW, 2, VfdFlags::empty(), 0, 0)]),
+        messages: VecDeque::from([
+            vfd_new(VFD_NEW, host_vfd, VfdFlags::READ, 0, 0),
+            [words(&[VFD_RECV, 0, 2, 1, host_vfd]), b"hi".to_vec()].concat(),
+            words(&[VFD_RECV, 0, 2, 2, 5, 6]),
+            words(&[VFD_HUP, 0, 2]),
+            words(&[0x1234, 0]),
+        ]),
+        ..FakeWl::default()
+    };
+    let header = fake_device(device);
+    let header_ptr = header as *mut _;
+    let mut wl = VirtIOWl::new(header).unwrap();
+    // the host sends the messages once notified
+    wl.new_context(2).unwrap();
+
+    let (mut vfds, mut data) = ([0; 1], [0; 16]);
+    match wl.recv(&mut vfds, &mut data) {
+        Ok(Some(WlEvent::NewVfd(info))) => assert_eq!(info.id, host_vfd),
+        event => panic!("unexpected {:?}", event),
+    }
+    match wl.recv(&mut vfds, &mut data) {
+        Ok(Some(WlEvent::Recv { vfd_id, vfds, len })) => assert_eq!((vfd_id, vfds, len), (2, 1, 2)),
+        event => panic!("unexpected {:?}", event),
+    }
+    assert_eq!((vfds, &data[..2]), ([host_vfd], &b"hi"[..]));
+    // more VFDs than fit
+    assert_eq!(
+        wl.recv(&mut vfds, &mut data).unwrap_err(),
+        Error::BufferTooSmall
+    );
+    match wl.recv(&mut vfds, &mut data) {
+        Ok(Some(WlEvent::Hangup(vfd_id))) => assert_eq!(vfd_id, 2),
+        event => panic!("unexpected {:?}", event),
+    }
+    assert_eq!(wl.recv(&mut vfds, &mut data).unwrap_err(), Error::IoError);
+    assert!(wl.recv(&mut vfds, &mut data).unwrap().is_none());
+
+    drop(wl);
+    unsafe { destroy_fake_device(header_ptr) };
+}